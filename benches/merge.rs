@@ -0,0 +1,120 @@
+//! Oplog write throughput on 100k-entry batches.
+//!
+//! Run with `cargo bench --bench merge`. Each case starts from a fresh
+//! in-memory database and reports the median of a few runs.
+
+use ahenk::crdt::{self, OP_INSERT, OP_MERGE_PATCH};
+use ahenk::db::{documents, operations};
+use ahenk::models::OplogEntry;
+use rusqlite::Connection;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const ENTRIES: usize = 100_000;
+const RUNS: usize = 3;
+
+fn fresh_db() -> Connection {
+    operations::initialize_database(":memory:").expect("in-memory database")
+}
+
+/// `count` inserts on an unregistered table, as an app recording its own ops would
+fn plain_ops(count: usize) -> Vec<OplogEntry> {
+    let device_id = Uuid::new_v4();
+    (0..count)
+        .map(|i| OplogEntry {
+            id: Uuid::new_v4(),
+            device_id,
+            timestamp: 1_000 + i as i64,
+            table: "events".to_string(),
+            op_type: "create".to_string(),
+            data: json!({"id": i.to_string(), "value": i}),
+            schema_version: 0,
+        })
+        .collect()
+}
+
+/// `count` collection ops: an insert per entity followed by merge patches
+fn collection_ops(count: usize) -> Vec<OplogEntry> {
+    let device_id = Uuid::new_v4();
+    (0..count)
+        .map(|i| {
+            let entity = (i % (count / 10)).to_string();
+            let (op_type, data) = if i < count / 10 {
+                (OP_INSERT, json!({"id": entity, "value": {"n": i}}))
+            } else {
+                (OP_MERGE_PATCH, json!({"id": entity, "patch": {"n": i}}))
+            };
+            OplogEntry {
+                id: Uuid::new_v4(),
+                device_id,
+                timestamp: 1_000 + i as i64,
+                table: "todos".to_string(),
+                op_type: op_type.to_string(),
+                data,
+                schema_version: 0,
+            }
+        })
+        .collect()
+}
+
+/// Median time of `RUNS` runs of `run` on a database prepared by `setup`
+fn bench<S, R>(name: &str, entries: usize, setup: S, run: R)
+where
+    S: Fn() -> Connection,
+    R: Fn(&mut Connection),
+{
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let mut conn = setup();
+            let start = Instant::now();
+            run(&mut conn);
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    let median = times[RUNS / 2];
+    println!(
+        "{:<36} {:>10.1} ms {:>12.0} entries/s",
+        name,
+        median.as_secs_f64() * 1_000.0,
+        entries as f64 / median.as_secs_f64()
+    );
+}
+
+fn main() {
+    let plain = plain_ops(ENTRIES);
+    let collection = collection_ops(ENTRIES);
+
+    bench("merge 100k new entries", ENTRIES, fresh_db, |conn| {
+        crdt::merge(conn, &plain).unwrap();
+    });
+    bench(
+        "merge 100k already-known entries",
+        ENTRIES,
+        || {
+            let mut conn = fresh_db();
+            crdt::merge(&mut conn, &plain).unwrap();
+            conn
+        },
+        |conn| crdt::merge(conn, &plain).unwrap(),
+    );
+    bench(
+        "merge 100k collection ops",
+        ENTRIES,
+        || {
+            let conn = fresh_db();
+            documents::register_collection(&conn, "todos").unwrap();
+            conn
+        },
+        |conn| crdt::merge(conn, &collection).unwrap(),
+    );
+    bench(
+        "local_apply_batch 100k entries",
+        ENTRIES,
+        fresh_db,
+        |conn| {
+            crdt::local_apply_batch(conn, &plain).unwrap();
+        },
+    );
+}
@@ -86,6 +86,13 @@ enum Commands {
         force: bool,
     },
 
+    /// Run a relay and rendezvous server for other devices
+    Relay {
+        /// Port to listen on (defaults to relay.listen_port)
+        #[arg(short, long)]
+        port: Option<u16>,
+    },
+
     /// Peer management commands
     #[command(subcommand)]
     Peer(PeerCommands),
@@ -98,6 +105,14 @@ enum Commands {
     #[command(subcommand)]
     Config(ConfigCommands),
 
+    /// Database schema migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommands),
+
+    /// Sync through files for devices that never share a network
+    #[command(subcommand)]
+    Bundle(BundleCommands),
+
     /// View logs
     Logs {
         /// Follow log output
@@ -121,6 +136,9 @@ enum Commands {
 
     /// View operation log
     Oplog {
+        #[command(subcommand)]
+        command: Option<OplogCommands>,
+
         /// Show entries since timestamp
         #[arg(long)]
         since: Option<i64>,
@@ -140,13 +158,21 @@ enum Commands {
     /// Diagnose system issues
     Doctor,
 
-    /// Export database
+    /// Export the database to an archive
     Export {
         /// Output path
         path: String,
+
+        /// gzip the archive
+        #[arg(long)]
+        compress: bool,
+
+        /// Encrypt the archive with a passphrase (prompted, or AHENK_ARCHIVE_PASSPHRASE)
+        #[arg(long)]
+        encrypt: bool,
     },
 
-    /// Import database
+    /// Import the database from an archive, stopping and restarting a running daemon
     Import {
         /// Input path
         path: String,
@@ -157,6 +183,77 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum OplogCommands {
+    /// Collapse superseded operations into per-entity snapshots
+    Compact {
+        /// Compact through this HLC timestamp instead of the one every device has synced
+        #[arg(long)]
+        before: Option<i64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BundleCommands {
+    /// Print this device's version vector, for `bundle export --since` on the other device
+    Vector,
+
+    /// Write a signed file of the oplog entries another device is missing
+    Export {
+        /// Output path
+        path: String,
+
+        /// Version vector of the receiving device (`<device-id>:<timestamp>,...`); everything if omitted
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Encrypt the bundle with a passphrase (prompted, or AHENK_BUNDLE_PASSPHRASE)
+        #[arg(long)]
+        encrypt: bool,
+    },
+
+    /// Verify a bundle and merge its entries
+    Import {
+        /// Input path
+        path: String,
+
+        /// Peer ID of a trusted signer; others are rejected (repeatable)
+        #[arg(long)]
+        trust: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum MigrateCommands {
+    /// Show applied and pending migrations
+    Status,
+
+    /// Apply pending migrations
+    Up {
+        /// Stop after this version
+        #[arg(long)]
+        to: Option<i32>,
+
+        /// Report the migrations that would be applied without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Revert applied migrations
+    Down {
+        /// Version to revert to
+        #[arg(long)]
+        to: i32,
+
+        /// Report the migrations that would be reverted without reverting them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check applied migrations against the shipped ones
+    Verify,
+}
+
 #[derive(Subcommand)]
 enum PeerCommands {
     /// List connected peers
@@ -283,13 +380,18 @@ async fn main() {
             daemon,
             port,
             config: config_path,
-        } => commands::daemon::start(daemon, port, config_path.as_deref(), &config).await,
+        } => {
+            // `start --config` takes precedence over the global option
+            let config_path = config_path.as_deref().or(cli.config.as_deref());
+            commands::daemon::start(daemon, port, config_path, &config).await
+        }
         Commands::Stop => commands::daemon::stop(&config).await,
         Commands::Restart { daemon } => commands::daemon::restart(daemon, &config).await,
         Commands::Status { watch, interval } => {
             commands::daemon::status(watch, interval, cli.json, &config).await
         }
         Commands::Sync { force } => commands::sync::sync(force, &config).await,
+        Commands::Relay { port } => commands::relay::serve(port, &config).await,
         Commands::Peer(peer_cmd) => match peer_cmd {
             PeerCommands::List => commands::peer::list(cli.json, &config).await,
             PeerCommands::Add { multiaddr } => commands::peer::add(&multiaddr, &config).await,
@@ -316,6 +418,29 @@ async fn main() {
             ConfigCommands::List => commands::config::list(cli.json, &config).await,
             ConfigCommands::Edit => commands::config::edit(&config).await,
         },
+        Commands::Migrate(migrate_cmd) => match migrate_cmd {
+            MigrateCommands::Status => commands::migrate::status(cli.json, &config).await,
+            MigrateCommands::Up { to, dry_run } => {
+                commands::migrate::up(to, dry_run, cli.json, &config).await
+            }
+            MigrateCommands::Down { to, dry_run } => {
+                commands::migrate::down(to, dry_run, cli.json, &config).await
+            }
+            MigrateCommands::Verify => commands::migrate::verify(cli.json, &config).await,
+        },
+        Commands::Bundle(bundle_cmd) => match bundle_cmd {
+            BundleCommands::Vector => commands::bundle::vector(cli.json, &config).await,
+            BundleCommands::Export {
+                path,
+                since,
+                encrypt,
+            } => {
+                commands::bundle::export(&path, since.as_deref(), encrypt, cli.json, &config).await
+            }
+            BundleCommands::Import { path, trust } => {
+                commands::bundle::import(&path, &trust, cli.json, &config).await
+            }
+        },
         Commands::Logs {
             follow,
             lines,
@@ -323,14 +448,25 @@ async fn main() {
         } => commands::logs::view(follow, lines, level.as_deref(), &config).await,
         Commands::Query { sql } => commands::utils::query(&sql, cli.json, &config).await,
         Commands::Oplog {
+            command: Some(OplogCommands::Compact { before }),
+            ..
+        } => commands::utils::oplog_compact(before, cli.json, &config).await,
+        Commands::Oplog {
+            command: None,
             since,
             device,
             limit,
         } => commands::utils::oplog(since, device.as_deref(), limit, cli.json, &config).await,
         Commands::Info => commands::utils::info(cli.json).await,
         Commands::Doctor => commands::utils::doctor(&config).await,
-        Commands::Export { path } => commands::utils::export(&path, &config).await,
-        Commands::Import { path, force } => commands::utils::import(&path, force, &config).await,
+        Commands::Export {
+            path,
+            compress,
+            encrypt,
+        } => commands::utils::export(&path, compress, encrypt, cli.json, &config).await,
+        Commands::Import { path, force } => {
+            commands::utils::import(&path, force, cli.json, &config).await
+        }
     };
 
     // Handle result
@@ -0,0 +1,152 @@
+use crate::cli::commands::utils::read_passphrase;
+use crate::cli::config::Config;
+use crate::cli::errors::{CliError, CliResult};
+use crate::cli::output;
+use crate::db::operations::initialize_database_with_config;
+use crate::logic::bundle::{self, VersionVector};
+use crate::logic::relay_server::load_or_create_keypair;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Environment variable read for bundle passphrases before prompting
+const PASSPHRASE_ENV: &str = "AHENK_BUNDLE_PASSPHRASE";
+
+fn open(config: &Config) -> CliResult<Connection> {
+    initialize_database_with_config(&config.db_path(), &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))
+}
+
+fn signing_key() -> CliResult<Keypair> {
+    load_or_create_keypair(&Config::device_key_path())
+        .map_err(|e| CliError::ConfigError(format!("Failed to load device key: {}", e)))
+}
+
+/// Print this device's version vector, to pass to `bundle export --since` on
+/// the device that exports a bundle for this one
+pub async fn vector(json: bool, config: &Config) -> CliResult<()> {
+    let conn = open(config)?;
+    let vector = bundle::version_vector(&conn).map_err(CliError::DatabaseError)?;
+
+    if json {
+        output::json(&serde_json::json!({
+            "vector": vector.to_string(),
+            "devices": vector
+                .iter()
+                .map(|(device_id, timestamp)| (device_id.to_string(), timestamp))
+                .collect::<std::collections::BTreeMap<_, _>>(),
+        }));
+    } else {
+        println!("{}", vector);
+    }
+
+    Ok(())
+}
+
+pub async fn export(
+    path: &str,
+    since: Option<&str>,
+    encrypt: bool,
+    json: bool,
+    config: &Config,
+) -> CliResult<()> {
+    let device_config = config.device.as_ref().ok_or_else(|| {
+        CliError::ConfigError("Device not configured. Run 'ahenk-cli init' first.".to_string())
+    })?;
+    let device_id = uuid::Uuid::parse_str(&device_config.id)
+        .map_err(|_| CliError::ConfigError("Invalid device ID".to_string()))?;
+    let since: VersionVector = since
+        .unwrap_or_default()
+        .parse()
+        .map_err(CliError::ValidationError)?;
+    let passphrase = if encrypt {
+        Some(read_passphrase(PASSPHRASE_ENV, true)?)
+    } else {
+        None
+    };
+
+    let conn = open(config)?;
+    let keypair = signing_key()?;
+    let bundle = bundle::export_bundle(
+        &conn,
+        device_id,
+        &since,
+        &keypair,
+        passphrase.as_deref(),
+        Path::new(path),
+    )
+    .map_err(CliError::SyncError)?;
+    let signer = keypair.public().to_peer_id();
+
+    if json {
+        output::json(&serde_json::json!({
+            "path": path,
+            "entries": bundle.entries.len(),
+            "signer": signer.to_string(),
+            "encrypted": encrypt,
+            "horizon": bundle.horizon,
+        }));
+    } else {
+        output::success(&format!(
+            "Wrote {} oplog entries to {}",
+            bundle.entries.len(),
+            path
+        ));
+        output::key_value("Signed by", &signer.to_string());
+    }
+
+    Ok(())
+}
+
+pub async fn import(path: &str, trust: &[String], json: bool, config: &Config) -> CliResult<()> {
+    let trusted = trust
+        .iter()
+        .map(|peer_id| {
+            peer_id.parse::<PeerId>().map_err(|e| {
+                CliError::ValidationError(format!("Invalid peer ID '{}': {}", peer_id, e))
+            })
+        })
+        .collect::<CliResult<Vec<_>>>()?;
+
+    let bytes = std::fs::read(path)?;
+    let passphrase = if bundle::is_bundle_encrypted(&bytes).map_err(CliError::ValidationError)? {
+        Some(read_passphrase(PASSPHRASE_ENV, false)?)
+    } else {
+        None
+    };
+
+    let mut conn = open(config)?;
+    let (verified, new_entries) = bundle::import_bundle(
+        &mut conn,
+        Path::new(path),
+        passphrase.as_deref(),
+        (!trusted.is_empty()).then_some(trusted.as_slice()),
+    )
+    .map_err(CliError::SyncError)?;
+
+    if json {
+        output::json(&serde_json::json!({
+            "path": path,
+            "entries": verified.bundle.entries.len(),
+            "new_entries": new_entries,
+            "signer": verified.signer.to_string(),
+            "device_id": verified.bundle.device_id.to_string(),
+            "created_at": verified.bundle.created_at.to_rfc3339(),
+        }));
+    } else {
+        output::success(&format!(
+            "Merged {} new of {} oplog entries from {}",
+            new_entries,
+            verified.bundle.entries.len(),
+            path
+        ));
+        output::key_value("Signed by", &verified.signer.to_string());
+        output::key_value("Exported by device", &verified.bundle.device_id.to_string());
+        if trusted.is_empty() {
+            output::warning("No --trust given; the signer was not checked against trusted keys");
+        }
+    }
+
+    Ok(())
+}
@@ -33,6 +33,7 @@ pub async fn list(json: bool, config: &Config) -> CliResult<()> {
         println!();
         output::key_value("Database Path", &config.database.path);
         output::key_value("Auto Migrate", &config.database.auto_migrate.to_string());
+        output::key_value("Backup Count", &config.database.backup_count.to_string());
 
         if let Some(user) = &config.user {
             println!();
@@ -53,10 +54,16 @@ pub async fn list(json: bool, config: &Config) -> CliResult<()> {
         output::key_value("Auto Start", &config.sync.auto_start.to_string());
         output::key_value("Enable mDNS", &config.sync.enable_mdns.to_string());
         output::key_value("Enable Relay", &config.sync.enable_relay.to_string());
+        output::key_value("Enable Kademlia", &config.sync.enable_kademlia.to_string());
 
         println!();
         output::key_value("Listen Port", &config.network.listen_port.to_string());
         output::key_value("Listen Address", &config.network.listen_address);
+        output::key_value("Enable QUIC", &config.network.enable_quic.to_string());
+        output::key_value(
+            "Enable WebSocket",
+            &config.network.enable_websocket.to_string(),
+        );
 
         println!();
         output::key_value("Log Level", &config.logging.level);
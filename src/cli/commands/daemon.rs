@@ -2,12 +2,12 @@ use crate::cli::config::Config;
 use crate::cli::daemon as daemon_utils;
 use crate::cli::errors::{CliError, CliResult};
 use crate::cli::output;
-use crate::db::operations::initialize_database;
+use crate::db::connection::Database;
+use crate::logic::relay_server::load_or_create_keypair;
 use crate::logic::sync::{create_swarm, P2PConfig};
 use crate::logic::sync_manager::SyncManager;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
 use std::time::Duration;
-use tokio::time;
 
 pub async fn start(
     daemon: bool,
@@ -24,6 +24,15 @@ pub async fn start(
         ));
     }
 
+    let loaded;
+    let config = match config_path {
+        Some(path) => {
+            loaded = Config::load(Some(path))?;
+            &loaded
+        }
+        None => config,
+    };
+
     // Check if user is configured
     let user_config = config.user.as_ref().ok_or_else(|| {
         CliError::ConfigError("User not configured. Run 'ahenk-cli init' first.".to_string())
@@ -33,6 +42,14 @@ pub async fn start(
         CliError::ConfigError("Device not configured. Run 'ahenk-cli init' first.".to_string())
     })?;
 
+    // Remember how the daemon was started, so `restart` and `import` start it
+    // again with the same port and configuration
+    let launch = daemon_utils::DaemonLaunch {
+        port,
+        config_path: config_path.map(std::path::absolute).transpose()?,
+    };
+    daemon_utils::write_launch(&Config::daemon_launch_file(), &launch)?;
+
     if daemon {
         output::step("Starting daemon in background mode");
 
@@ -40,6 +57,7 @@ pub async fn start(
         {
             daemon_utils::daemonize(&pid_file)?;
             // After daemonize, we're in the child process
+            let _pid_file = daemon_utils::PidFileGuard::new(&pid_file);
             run_sync_loop(port, config).await?;
         }
 
@@ -56,25 +74,44 @@ pub async fn start(
         // Write PID file
         let pid = std::process::id() as i32;
         daemon_utils::write_pid(&pid_file, pid)?;
-
-        // Setup Ctrl+C handler
-        let pid_file_clone = pid_file.clone();
-        ctrlc::set_handler(move || {
-            output::info("Received shutdown signal, cleaning up...");
-            daemon_utils::remove_pid_file(&pid_file_clone).ok();
-            std::process::exit(0);
-        })
-        .map_err(|e| CliError::DaemonError(format!("Failed to set Ctrl+C handler: {}", e)))?;
+        let _pid_file = daemon_utils::PidFileGuard::new(&pid_file);
 
         output::success("Sync started (press Ctrl+C to stop)");
 
-        // Run sync loop
+        // Run sync loop until SIGINT/SIGTERM
         run_sync_loop(port, config).await?;
+        output::info("Sync stopped");
     }
 
     Ok(())
 }
 
+/// Wait until the process receives SIGINT or SIGTERM (Ctrl+C elsewhere)
+pub(crate) async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to install SIGTERM handler: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+    }
+}
+
 async fn run_sync_loop(port: u16, config: &Config) -> CliResult<()> {
     let user_config = config.user.as_ref().unwrap();
     let device_config = config.device.as_ref().unwrap();
@@ -85,39 +122,64 @@ async fn run_sync_loop(port: u16, config: &Config) -> CliResult<()> {
     let device_id = uuid::Uuid::parse_str(&device_config.id)
         .map_err(|_| CliError::ConfigError("Invalid device ID".to_string()))?;
 
-    // Initialize database connection
-    let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
-    let conn = Arc::new(Mutex::new(conn));
+    // All database work runs on the worker threads, off the event loop
+    let database = Database::open_with_config(&config.db_path(), &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
-    // Generate keypair for P2P
-    let (peer_id, keypair) = crate::logic::sync::generate_device_id();
-    log::info!("Generated peer ID: {}", peer_id);
+    // The peer ID must survive restarts: the address book and peer network info
+    // are keyed by it
+    let keypair = load_or_create_keypair(&Config::device_key_path())
+        .map_err(|e| CliError::ConfigError(format!("Failed to load device key: {}", e)))?;
+    log::info!("Peer ID: {}", keypair.public().to_peer_id());
 
     // Create P2P config
     let p2p_config = P2PConfig {
         enable_mdns: config.sync.enable_mdns,
         enable_relay: config.sync.enable_relay,
+        enable_kademlia: config.sync.enable_kademlia,
         bootstrap_nodes: config.network.bootstrap_nodes.clone(),
         relay_servers: config.network.relay_servers.clone(),
+        rendezvous_points: config.network.rendezvous_points.clone(),
+        discovery_interval: Duration::from_secs(config.sync.discovery_interval_secs),
         heartbeat_interval: Duration::from_secs(config.sync.heartbeat_interval_secs),
         max_message_size: config.sync.max_message_size,
+        listen_address: config.network.listen_address.clone(),
+        listen_addresses: config.network.listen_addresses.clone(),
+        enable_quic: config.network.enable_quic,
+        enable_websocket: config.network.enable_websocket,
+        websocket_port: config.network.websocket_port,
+        ..P2PConfig::default()
     };
 
     // Create sync manager
-    let mut sync_manager = SyncManager::new(keypair, user_id, device_id, conn.clone(), p2p_config)
+    let mut sync_manager = SyncManager::new(keypair, user_id, device_id, database, p2p_config)
         .map_err(|e| CliError::SyncError(format!("Failed to create sync manager: {}", e)))?;
 
-    // Start listening
-    let listen_addr = format!("/ip4/{}/tcp/{}", config.network.listen_address, port);
+    // Pick up changes that were still queued when the previous run shut down
+    match sync_manager.restore_checkpoint().await {
+        Ok(0) => {}
+        Ok(restored) => log::info!("Restored {} pending changes from checkpoint", restored),
+        Err(e) => log::warn!("Failed to restore sync checkpoint: {}", e),
+    }
+
+    // Start listening on TCP, QUIC and WebSocket as configured
     sync_manager
         .listen(port)
         .map_err(|e| CliError::SyncError(format!("Failed to start listening: {}", e)))?;
 
-    log::info!("Listening on {}", listen_addr);
-
-    // Connect to bootstrap nodes and relay servers
-    if !config.network.bootstrap_nodes.is_empty() || !config.network.relay_servers.is_empty() {
+    log::info!(
+        "Listening on {} (port {}, QUIC: {}, WebSocket: {})",
+        config.network.listen_address,
+        port,
+        config.network.enable_quic,
+        config.network.enable_websocket
+    );
+
+    // Connect to bootstrap nodes, relay servers and rendezvous points
+    if !config.network.bootstrap_nodes.is_empty()
+        || !config.network.relay_servers.is_empty()
+        || !config.network.rendezvous_points.is_empty()
+    {
         sync_manager
             .connect_to_network(
                 &config.network.bootstrap_nodes,
@@ -126,6 +188,13 @@ async fn run_sync_loop(port: u16, config: &Config) -> CliResult<()> {
             .map_err(|e| CliError::SyncError(format!("Failed to connect to network: {}", e)))?;
     }
 
+    // Redial our other devices from the address book
+    match sync_manager.connect_to_known_peers().await {
+        Ok(0) => {}
+        Ok(dialed) => log::info!("Dialing {} known peers from the address book", dialed),
+        Err(e) => log::warn!("Failed to dial known peers: {}", e),
+    }
+
     // Announce presence
     sync_manager
         .announce_presence()
@@ -133,16 +202,28 @@ async fn run_sync_loop(port: u16, config: &Config) -> CliResult<()> {
 
     log::info!("Sync manager initialized and running");
 
+    // Translate SIGINT/SIGTERM into a graceful shutdown request
+    let shutdown = sync_manager.shutdown_handle();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        log::info!("Received shutdown signal, finishing in-flight transfers");
+        shutdown.shutdown();
+    });
+
     // Main event loop
-    loop {
-        // Process events
+    while !sync_manager.is_shutdown_requested() {
         if let Err(e) = sync_manager.process_event().await {
             log::error!("Error processing event: {}", e);
         }
-
-        // Small delay to prevent busy loop
-        time::sleep(Duration::from_millis(100)).await;
     }
+
+    let result = sync_manager
+        .shutdown()
+        .await
+        .map_err(|e| CliError::SyncError(format!("Failed to shut down cleanly: {}", e)));
+    log::info!("Sync manager shut down");
+
+    result
 }
 
 pub async fn stop(config: &Config) -> CliResult<()> {
@@ -159,6 +240,7 @@ pub async fn stop(config: &Config) -> CliResult<()> {
 
 pub async fn restart(daemon: bool, config: &Config) -> CliResult<()> {
     let pid_file = Config::pid_file();
+    let launch = daemon_utils::read_launch(&Config::daemon_launch_file())?.unwrap_or_default();
 
     // Stop if running
     if daemon_utils::is_running(&pid_file) {
@@ -170,8 +252,15 @@ pub async fn restart(daemon: bool, config: &Config) -> CliResult<()> {
     // Wait a moment
     tokio::time::sleep(Duration::from_secs(1)).await;
 
-    // Start again
-    start(daemon, 0, None, config).await?;
+    // Start again the way it was started last
+    if daemon {
+        output::step("Starting daemon in background mode");
+        daemon_utils::spawn_daemon(&pid_file, &launch)?;
+        output::success("Daemon started");
+    } else {
+        let config_path = launch.config_path.as_deref().and_then(Path::to_str);
+        start(false, launch.port, config_path, config).await?;
+    }
 
     Ok(())
 }
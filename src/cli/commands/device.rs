@@ -1,7 +1,9 @@
 use crate::cli::config::Config;
 use crate::cli::errors::{CliError, CliResult};
 use crate::cli::output;
-use crate::db::operations::{delete_device, get_devices_by_user_id, initialize_database};
+use crate::db::operations::{
+    delete_device, get_devices_by_user_id, initialize_database_with_config,
+};
 use crate::{AuthResult, AuthorizerWorkflow, NewDeviceWorkflow};
 use libp2p::identity::Keypair;
 
@@ -42,7 +44,8 @@ pub async fn list(json: bool, config: &Config) -> CliResult<()> {
         .map_err(|_| CliError::ConfigError("Invalid user ID".to_string()))?;
 
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     let devices = get_devices_by_user_id(&conn, user_id)
         .map_err(|e| CliError::DatabaseError(e.to_string()))?;
@@ -112,8 +115,7 @@ pub async fn pair(device_type: &str, name: Option<&str>, config: &Config) -> Cli
     // Get network listen address from config
     let listen_addr = format!(
         "/ip4/{}/tcp/{}",
-        config.network.listen_address,
-        config.network.listen_port
+        config.network.listen_address, config.network.listen_port
     );
 
     let mut authorizer = AuthorizerWorkflow::new();
@@ -203,7 +205,8 @@ pub async fn remove(device_id: &str, config: &Config) -> CliResult<()> {
         .map_err(|_| CliError::ValidationError("Invalid device ID format".to_string()))?;
 
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     // Remove the device
     let rows_affected =
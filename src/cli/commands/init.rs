@@ -1,7 +1,7 @@
 use crate::cli::config::{Config, DeviceConfig, UserConfig};
 use crate::cli::errors::{CliError, CliResult};
 use crate::cli::output;
-use crate::db::operations::initialize_database;
+use crate::db::operations::initialize_database_with_config;
 use crate::logic;
 use std::fs;
 use uuid::Uuid;
@@ -32,7 +32,7 @@ pub async fn execute(
 
     // Initialize database
     output::step(&format!("Creating database at {}", db_path_expanded));
-    let conn = initialize_database(&db_path_expanded)
+    let conn = initialize_database_with_config(&db_path_expanded, &config.connection_config())
         .map_err(|e| CliError::DatabaseError(e.to_string()))?;
     output::success("Database initialized");
 
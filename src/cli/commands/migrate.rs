@@ -0,0 +1,190 @@
+use crate::cli::config::Config;
+use crate::cli::errors::{CliError, CliResult};
+use crate::cli::output;
+use crate::db::backup;
+use crate::db::connection::open_connection;
+use crate::db::migrations::{self, MigrationState, MigrationStatus, CORE_NAMESPACE};
+use rusqlite::Connection;
+
+/// Open the database without applying migrations, which these commands control
+fn open(config: &Config) -> CliResult<Connection> {
+    let db_path = config.db_path();
+    if !std::path::Path::new(&db_path).exists() {
+        return Err(CliError::NotFound(format!(
+            "Database {} (run 'ahenk-cli init' first)",
+            db_path
+        )));
+    }
+    open_connection(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))
+}
+
+fn state_label(state: &MigrationState) -> &'static str {
+    match state {
+        MigrationState::Pending => "pending",
+        MigrationState::Applied => "applied",
+        MigrationState::Modified { .. } => "modified",
+        MigrationState::Unknown => "unknown",
+        MigrationState::Unregistered => "unregistered",
+    }
+}
+
+fn status_json(status: &MigrationStatus) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "namespace": status.namespace,
+        "version": status.version,
+        "description": status.description,
+        "state": state_label(&status.state),
+        "applied_at": status.applied_at,
+        "reversible": status.reversible,
+    });
+    if let MigrationState::Modified { recorded, expected } = &status.state {
+        value["recorded_checksum"] = serde_json::json!(recorded);
+        value["expected_checksum"] = serde_json::json!(expected);
+    }
+    value
+}
+
+fn print_statuses(statuses: &[MigrationStatus]) {
+    let mut table = output::create_table(vec![
+        "Namespace",
+        "Version",
+        "State",
+        "Applied",
+        "Down",
+        "Description",
+    ]);
+    for status in statuses {
+        table.add_row(prettytable::Row::new(vec![
+            prettytable::Cell::new(&status.namespace),
+            prettytable::Cell::new(&status.version.to_string()),
+            prettytable::Cell::new(state_label(&status.state)),
+            prettytable::Cell::new(status.applied_at.as_deref().unwrap_or("-")),
+            prettytable::Cell::new(if status.reversible { "yes" } else { "no" }),
+            prettytable::Cell::new(&status.description),
+        ]));
+    }
+    table.printstd();
+}
+
+pub async fn status(json: bool, config: &Config) -> CliResult<()> {
+    let conn = open(config)?;
+    let statuses =
+        migrations::migration_status(&conn).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if json {
+        let current = migrations::get_current_version(&conn)
+            .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+        output::json(&serde_json::json!({
+            "current_version": current,
+            "migrations": statuses.iter().map(status_json).collect::<Vec<_>>(),
+        }));
+    } else {
+        print_statuses(&statuses);
+    }
+
+    Ok(())
+}
+
+pub async fn up(to: Option<i32>, dry_run: bool, json: bool, config: &Config) -> CliResult<()> {
+    let conn = open(config)?;
+    let pending: Vec<MigrationStatus> = migrations::pending_migrations(&conn)
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?
+        .into_iter()
+        .filter(|status| status.namespace == CORE_NAMESPACE)
+        .filter(|status| to.is_none_or(|to| status.version <= to))
+        .collect();
+
+    if dry_run || pending.is_empty() {
+        if json {
+            output::json(&serde_json::json!({
+                "dry_run": dry_run,
+                "pending": pending.iter().map(status_json).collect::<Vec<_>>(),
+            }));
+        } else if pending.is_empty() {
+            output::info("No pending migrations");
+        } else {
+            output::info(&format!("{} migration(s) would be applied", pending.len()));
+            print_statuses(&pending);
+        }
+        return Ok(());
+    }
+
+    let backups = config.connection_config().backups;
+    let applied = backup::with_backup(&conn, &backups, |conn| {
+        migrations::migrate_up(conn, CORE_NAMESPACE, to)
+    })
+    .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if json {
+        output::json(&serde_json::json!({ "applied": applied }));
+    } else {
+        output::success(&format!("Applied {} migration(s)", applied.len()));
+    }
+
+    Ok(())
+}
+
+pub async fn down(to: i32, dry_run: bool, json: bool, config: &Config) -> CliResult<()> {
+    let conn = open(config)?;
+    let to_revert = migrations::migrations_to_revert(&conn, CORE_NAMESPACE, to)
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if dry_run || to_revert.is_empty() {
+        if json {
+            output::json(&serde_json::json!({
+                "dry_run": dry_run,
+                "revert": to_revert.iter().map(status_json).collect::<Vec<_>>(),
+            }));
+        } else if to_revert.is_empty() {
+            output::info(&format!("Already at or below version {}", to));
+        } else {
+            output::info(&format!(
+                "{} migration(s) would be reverted",
+                to_revert.len()
+            ));
+            print_statuses(&to_revert);
+        }
+        return Ok(());
+    }
+
+    let backups = config.connection_config().backups;
+    let reverted = backup::with_backup(&conn, &backups, |conn| {
+        migrations::migrate_down(conn, CORE_NAMESPACE, to)
+    })
+    .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if json {
+        output::json(&serde_json::json!({ "reverted": reverted }));
+    } else {
+        output::success(&format!("Reverted {} migration(s)", reverted.len()));
+    }
+
+    Ok(())
+}
+
+pub async fn verify(json: bool, config: &Config) -> CliResult<()> {
+    let conn = open(config)?;
+    let drift =
+        migrations::verify_migrations(&conn).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if json {
+        output::json(&serde_json::json!({
+            "ok": drift.is_empty(),
+            "drift": drift.iter().map(status_json).collect::<Vec<_>>(),
+        }));
+    } else if drift.is_empty() {
+        output::success("Applied migrations match this version of Ahenk");
+    } else {
+        print_statuses(&drift);
+    }
+
+    if drift.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::ValidationError(format!(
+            "{} applied migration(s) differ from this version of Ahenk",
+            drift.len()
+        )))
+    }
+}
@@ -1,8 +1,11 @@
+pub mod bundle;
 pub mod config;
 pub mod daemon;
 pub mod device;
 pub mod init;
 pub mod logs;
+pub mod migrate;
 pub mod peer;
+pub mod relay;
 pub mod sync;
 pub mod utils;
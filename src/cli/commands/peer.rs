@@ -1,11 +1,15 @@
 use crate::cli::config::Config;
 use crate::cli::errors::{CliError, CliResult};
 use crate::cli::output;
-use crate::db::operations::{delete_peer, get_all_peers, initialize_database};
+use crate::db::operations::{
+    delete_peer, get_all_peers, get_network_status, get_peer_network_info_by_device,
+    initialize_database_with_config,
+};
 
 pub async fn list(json: bool, config: &Config) -> CliResult<()> {
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     let peers = get_all_peers(&conn).map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
@@ -79,7 +83,8 @@ pub async fn remove(peer_id: &str, config: &Config) -> CliResult<()> {
         .map_err(|_| CliError::ValidationError("Invalid peer ID format".to_string()))?;
 
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     // Remove the peer from database
     let rows_affected =
@@ -97,7 +102,8 @@ pub async fn remove(peer_id: &str, config: &Config) -> CliResult<()> {
 
 pub async fn info(peer_id: &str, json: bool, config: &Config) -> CliResult<()> {
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     let peer_uuid = uuid::Uuid::parse_str(peer_id)
         .map_err(|_| CliError::ValidationError("Invalid peer ID".to_string()))?;
@@ -105,6 +111,11 @@ pub async fn info(peer_id: &str, json: bool, config: &Config) -> CliResult<()> {
     let peer = crate::db::operations::get_peer(&conn, peer_uuid)
         .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
+    // Written by the running daemon from identify, ping and AutoNAT events
+    let connections = get_peer_network_info_by_device(&conn, peer.device_id)
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let network = get_network_status(&conn).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
     if json {
         output::json(&serde_json::json!({
             "peer_id": peer.peer_id.to_string(),
@@ -112,6 +123,8 @@ pub async fn info(peer_id: &str, json: bool, config: &Config) -> CliResult<()> {
             "device_id": peer.device_id.to_string(),
             "last_known_ip": peer.last_known_ip,
             "last_sync_time": peer.last_sync_time,
+            "connections": connections,
+            "local_network": network,
         }));
     } else {
         output::print_box(
@@ -133,6 +146,45 @@ pub async fn info(peer_id: &str, json: bool, config: &Config) -> CliResult<()> {
                 ),
             ],
         );
+
+        println!();
+        if connections.is_empty() {
+            output::info("No libp2p connection details recorded for this peer");
+        } else {
+            let mut table = output::create_table(vec!["libp2p Peer ID", "Agent", "RTT", "Updated"]);
+            for info in &connections {
+                table.add_row(prettytable::Row::new(vec![
+                    prettytable::Cell::new(&info.peer_id),
+                    prettytable::Cell::new(info.agent_version.as_deref().unwrap_or("N/A")),
+                    prettytable::Cell::new(
+                        &info
+                            .rtt_ms
+                            .map(|ms| format!("{} ms", ms))
+                            .unwrap_or_else(|| "N/A".to_string()),
+                    ),
+                    prettytable::Cell::new(&info.updated_at.to_rfc3339()),
+                ]));
+            }
+            table.printstd();
+        }
+
+        println!();
+        match network {
+            Some(status) => {
+                output::key_value("NAT Status", &status.nat_status);
+                output::key_value(
+                    "Public Address",
+                    status.public_address.as_deref().unwrap_or("N/A"),
+                );
+                let observed = if status.observed_addresses.is_empty() {
+                    "N/A".to_string()
+                } else {
+                    status.observed_addresses.join(", ")
+                };
+                output::key_value("Observed Addresses", &observed);
+            }
+            None => output::key_value("NAT Status", "unknown (daemon has not run yet)"),
+        }
     }
 
     Ok(())
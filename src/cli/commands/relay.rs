@@ -0,0 +1,81 @@
+use crate::cli::commands::daemon::wait_for_shutdown_signal;
+use crate::cli::config::{Config, RelayConfig};
+use crate::cli::errors::{CliError, CliResult};
+use crate::cli::output;
+use crate::logic::relay_server::{load_or_create_keypair, RelayServer, RelayServerConfig};
+use std::path::Path;
+use std::time::Duration;
+
+/// Build the server limits from the `[relay]` config section
+fn server_config(relay: &RelayConfig) -> CliResult<RelayServerConfig> {
+    let mut server_config = RelayServerConfig {
+        max_reservations: relay.max_reservations,
+        max_reservations_per_peer: relay.max_reservations_per_peer,
+        reservation_duration: Duration::from_secs(relay.reservation_duration_secs),
+        reservations_per_peer_per_minute: relay.reservations_per_peer_per_minute,
+        max_circuits: relay.max_circuits,
+        max_circuits_per_peer: relay.max_circuits_per_peer,
+        circuits_per_peer_per_minute: relay.circuits_per_peer_per_minute,
+        max_circuit_duration: Duration::from_secs(relay.max_circuit_duration_secs),
+        max_circuit_bytes: relay.max_circuit_bytes,
+        max_registration_ttl: Duration::from_secs(relay.max_registration_ttl_secs),
+        max_registrations_per_peer: relay.max_registrations_per_peer,
+        ..RelayServerConfig::default()
+    };
+
+    for addr in &relay.external_addresses {
+        let addr = addr.parse().map_err(|_| {
+            CliError::ConfigError(format!("Invalid relay external address: {}", addr))
+        })?;
+        server_config.external_addresses.push(addr);
+    }
+    for entry in &relay.allowed_namespaces {
+        server_config.allow_namespace(entry);
+    }
+
+    Ok(server_config)
+}
+
+/// Run the relay v2 server and rendezvous point in the foreground
+pub async fn serve(port: Option<u16>, config: &Config) -> CliResult<()> {
+    let server_config = server_config(&config.relay)?;
+    let port = port.unwrap_or(config.relay.listen_port);
+
+    let key_path = config.relay_key_path();
+    let keypair = load_or_create_keypair(Path::new(&key_path))
+        .map_err(|e| CliError::ConfigError(format!("Failed to load relay key: {}", e)))?;
+
+    let mut server = RelayServer::new(keypair, server_config)
+        .map_err(|e| CliError::SyncError(format!("Failed to create relay server: {}", e)))?;
+
+    let listen_addr = format!("/ip4/{}/tcp/{}", config.relay.listen_address, port);
+    let listen_multiaddr = listen_addr
+        .parse()
+        .map_err(|_| CliError::ConfigError(format!("Invalid listen address: {}", listen_addr)))?;
+    server
+        .listen(listen_multiaddr)
+        .map_err(|e| CliError::SyncError(format!("Failed to start listening: {}", e)))?;
+
+    output::success(&format!("Relay started with peer ID {}", server.peer_id()));
+    output::info(&format!("Listening on {}", listen_addr));
+    if config.relay.allowed_namespaces.is_empty() {
+        output::warning("No namespace allowlist configured; any user may register");
+    }
+    output::info("Add this relay to clients under [network.relay_servers] and");
+    output::info("[network.rendezvous_points] as <address>/p2p/<peer ID>");
+
+    let handle = server.shutdown_handle();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        log::info!("Shutdown signal received");
+        handle.shutdown();
+    });
+
+    server
+        .run()
+        .await
+        .map_err(|e| CliError::SyncError(format!("Relay failed: {}", e)))?;
+    output::info("Relay stopped");
+
+    Ok(())
+}
@@ -1,13 +1,19 @@
 use crate::cli::config::Config;
+use crate::cli::daemon as daemon_utils;
 use crate::cli::errors::{CliError, CliResult};
 use crate::cli::output;
-use crate::db::operations::initialize_database;
+use crate::db::archive::{self, ArchiveInfo, ArchiveOptions};
+use crate::db::compaction;
+use crate::db::connection::open_connection;
+use crate::db::operations::initialize_database_with_config;
 use rusqlite::params;
 use std::fs;
+use std::path::Path;
 
 pub async fn query(sql: &str, json: bool, config: &Config) -> CliResult<()> {
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     // Execute query
     let mut stmt = conn
@@ -76,7 +82,8 @@ pub async fn oplog(
     config: &Config,
 ) -> CliResult<()> {
     let db_path = config.db_path();
-    let conn = initialize_database(&db_path).map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
     let mut sql =
         "SELECT id, device_id, timestamp, table_name, op_type, data FROM oplog".to_string();
@@ -157,6 +164,45 @@ pub async fn oplog(
     Ok(())
 }
 
+pub async fn oplog_compact(before: Option<i64>, json: bool, config: &Config) -> CliResult<()> {
+    let db_path = config.db_path();
+    let mut conn = initialize_database_with_config(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    let horizon = match before {
+        Some(horizon) => horizon,
+        None => {
+            let device_config = config.device.as_ref().ok_or_else(|| {
+                CliError::ConfigError(
+                    "No device configured; pass --before to choose the horizon".to_string(),
+                )
+            })?;
+            let device_id = uuid::Uuid::parse_str(&device_config.id)
+                .map_err(|_| CliError::ConfigError("Invalid device ID".to_string()))?;
+            compaction::stable_horizon(&conn, device_id)
+                .map_err(|e| CliError::DatabaseError(e.to_string()))?
+        }
+    };
+
+    let report = compaction::compact_oplog(&mut conn, horizon)
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+
+    if json {
+        output::json(&serde_json::json!({
+            "horizon": report.horizon,
+            "entities": report.entities,
+            "removed": report.removed,
+        }));
+    } else {
+        output::success(&format!(
+            "Compacted {} entities through {}, removed {} oplog entries",
+            report.entities, report.horizon, report.removed
+        ));
+    }
+
+    Ok(())
+}
+
 pub async fn info(json: bool) -> CliResult<()> {
     let version = env!("CARGO_PKG_VERSION");
     let system = sysinfo::System::new_all();
@@ -214,7 +260,7 @@ pub async fn doctor(config: &Config) -> CliResult<()> {
         checks_passed += 1;
 
         // Try to connect
-        match initialize_database(&db_path) {
+        match initialize_database_with_config(&db_path, &config.connection_config()) {
             Ok(_) => {
                 output::success("Database connection successful");
                 checks_passed += 1;
@@ -267,37 +313,161 @@ pub async fn doctor(config: &Config) -> CliResult<()> {
     Ok(())
 }
 
-pub async fn export(path: &str, config: &Config) -> CliResult<()> {
+/// Environment variable read for archive passphrases before prompting
+const PASSPHRASE_ENV: &str = "AHENK_ARCHIVE_PASSPHRASE";
+
+/// Read a passphrase from the environment variable `env`, or prompt for it
+/// (twice if `confirm`)
+pub(crate) fn read_passphrase(env: &str, confirm: bool) -> CliResult<String> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(CliError::ValidationError(
+            "Passphrase cannot be empty".to_string(),
+        ));
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err(CliError::ValidationError(
+            "Passphrases do not match".to_string(),
+        ));
+    }
+    Ok(passphrase)
+}
+
+fn archive_json(path: &str, info: &ArchiveInfo) -> serde_json::Value {
+    serde_json::json!({
+        "path": path,
+        "schema_version": info.schema_version,
+        "created_at": info.created_at.map(|time| time.to_rfc3339()),
+        "compressed": info.compressed,
+        "encrypted": info.encrypted,
+    })
+}
+
+pub async fn export(
+    path: &str,
+    compress: bool,
+    encrypt: bool,
+    json: bool,
+    config: &Config,
+) -> CliResult<()> {
     let db_path = config.db_path();
+    if !Path::new(&db_path).exists() {
+        return Err(CliError::NotFound(format!("Database {}", db_path)));
+    }
 
-    output::step(&format!("Exporting database to {}", path));
+    let options = ArchiveOptions {
+        compress,
+        passphrase: if encrypt {
+            Some(read_passphrase(PASSPHRASE_ENV, true)?)
+        } else {
+            None
+        },
+    };
+
+    if !json {
+        output::step(&format!("Exporting database to {}", path));
+    }
 
-    fs::copy(&db_path, path)?;
+    let conn = open_connection(&db_path, &config.connection_config())
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
+    let info = archive::export_archive(&conn, Path::new(path), &options)
+        .map_err(|e| CliError::DatabaseError(e.to_string()))?;
 
-    output::success(&format!("Database exported to {}", path));
+    if json {
+        output::json(&archive_json(path, &info));
+    } else {
+        output::success(&format!(
+            "Database exported to {} (schema version {})",
+            path, info.schema_version
+        ));
+    }
 
     Ok(())
 }
 
-pub async fn import(path: &str, force: bool, config: &Config) -> CliResult<()> {
+pub async fn import(path: &str, force: bool, json: bool, config: &Config) -> CliResult<()> {
     let db_path = config.db_path();
+    let exists = Path::new(&db_path).exists();
 
-    if std::path::Path::new(&db_path).exists() && !force {
+    if exists && !force {
         return Err(CliError::ValidationError(
             "Database already exists. Use --force to overwrite".to_string(),
         ));
     }
 
-    output::step(&format!("Importing database from {}", path));
+    let info = archive::read_archive_info(Path::new(path))
+        .map_err(|e| CliError::ValidationError(e.to_string()))?;
+    let passphrase = if info.encrypted {
+        Some(read_passphrase(PASSPHRASE_ENV, false)?)
+    } else {
+        None
+    };
+
+    // The daemon holds the database open; stop it for the restore and start it
+    // again afterwards
+    let pid_file = Config::pid_file();
+    let daemon_was_running = daemon_utils::is_running(&pid_file);
+    let launch = daemon_utils::read_launch(&Config::daemon_launch_file())?.unwrap_or_default();
+    if daemon_was_running {
+        if !json {
+            output::step("Stopping daemon");
+        }
+        daemon_utils::stop_daemon(&pid_file)?;
+    }
+
+    if !json {
+        output::step(&format!("Importing database from {}", path));
+    }
 
     // Create parent directory if needed
-    if let Some(parent) = std::path::Path::new(&db_path).parent() {
+    if let Some(parent) = Path::new(&db_path).parent() {
         fs::create_dir_all(parent)?;
     }
 
-    fs::copy(path, &db_path)?;
+    let mut connection_config = config.connection_config();
+    if !exists {
+        // Nothing to back up
+        connection_config.backups.keep = 0;
+    }
+    let result = open_connection(&db_path, &connection_config).and_then(|conn| {
+        archive::import_archive(
+            &conn,
+            Path::new(path),
+            passphrase.as_deref(),
+            &connection_config.backups,
+        )
+    });
+
+    let info = match result {
+        Ok(info) => info,
+        Err(e) => {
+            if daemon_was_running {
+                output::warning(
+                    "The daemon was stopped; start it again with 'ahenk-cli start --daemon'",
+                );
+            }
+            return Err(CliError::DatabaseError(e.to_string()));
+        }
+    };
+
+    if json {
+        output::json(&archive_json(path, &info));
+    } else {
+        output::success(&format!(
+            "Database imported to {} (schema version {})",
+            db_path, info.schema_version
+        ));
+    }
 
-    output::success(&format!("Database imported to {}", db_path));
+    if daemon_was_running {
+        if !json {
+            output::step("Starting daemon");
+        }
+        daemon_utils::spawn_daemon(&pid_file, &launch)?;
+    }
 
     Ok(())
 }
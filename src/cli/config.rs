@@ -1,4 +1,6 @@
 use crate::cli::errors::{CliError, CliResult};
+use crate::db::backup::BackupConfig;
+use crate::db::connection::ConnectionConfig;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -11,12 +13,18 @@ pub struct Config {
     pub sync: SyncConfig,
     pub network: NetworkConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub relay: RelayConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseConfig {
     pub path: String,
     pub auto_migrate: bool,
+    /// Backups kept in the nexus directory's `backups` folder, taken before
+    /// migrations run (0 = none)
+    #[serde(default = "default_backup_count")]
+    pub backup_count: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +48,10 @@ pub struct SyncConfig {
     pub auto_start: bool,
     pub enable_mdns: bool,
     pub enable_relay: bool,
+    #[serde(default = "default_true")]
+    pub enable_kademlia: bool,
+    #[serde(default = "default_discovery_interval_secs")]
+    pub discovery_interval_secs: u64,
     pub heartbeat_interval_secs: u64,
     pub max_message_size: usize,
 }
@@ -50,6 +62,82 @@ pub struct NetworkConfig {
     pub listen_address: String,
     pub bootstrap_nodes: Vec<String>,
     pub relay_servers: Vec<String>,
+    #[serde(default)]
+    pub rendezvous_points: Vec<String>,
+    /// Additional multiaddrs to listen on, e.g. `/ip6/::/tcp/4001`
+    #[serde(default)]
+    pub listen_addresses: Vec<String>,
+    #[serde(default = "default_true")]
+    pub enable_quic: bool,
+    #[serde(default)]
+    pub enable_websocket: bool,
+    /// TCP port for the WebSocket listener (0 = random)
+    #[serde(default)]
+    pub websocket_port: u16,
+}
+
+fn default_backup_count() -> usize {
+    BackupConfig::default().keep
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_discovery_interval_secs() -> u64 {
+    300
+}
+
+/// Settings for `ahenk-cli relay`, the self-hosted relay and rendezvous server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub listen_port: u16,
+    pub listen_address: String,
+    /// Publicly reachable multiaddrs of the relay; learned from peers when empty
+    pub external_addresses: Vec<String>,
+    /// Where the relay's keypair is kept, so its peer ID survives restarts
+    pub key_file: String,
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration_secs: u64,
+    pub reservations_per_peer_per_minute: u32,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub circuits_per_peer_per_minute: u32,
+    pub max_circuit_duration_secs: u64,
+    /// Bytes, over both directions, after which a relayed circuit is closed
+    pub max_circuit_bytes: u64,
+    pub max_registration_ttl_secs: u64,
+    pub max_registrations_per_peer: usize,
+    /// User IDs or rendezvous namespaces allowed to register; empty allows all
+    pub allowed_namespaces: Vec<String>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        let limits = crate::logic::relay_server::RelayServerConfig::default();
+        let key_file = Config::nexus_dir().join("relay.key");
+
+        RelayConfig {
+            listen_port: 4001,
+            listen_address: "0.0.0.0".to_string(),
+            external_addresses: vec![],
+            key_file: key_file.to_string_lossy().to_string(),
+            max_reservations: limits.max_reservations,
+            max_reservations_per_peer: limits.max_reservations_per_peer,
+            reservation_duration_secs: limits.reservation_duration.as_secs(),
+            reservations_per_peer_per_minute: limits.reservations_per_peer_per_minute,
+            max_circuits: limits.max_circuits,
+            max_circuits_per_peer: limits.max_circuits_per_peer,
+            circuits_per_peer_per_minute: limits.circuits_per_peer_per_minute,
+            max_circuit_duration_secs: limits.max_circuit_duration.as_secs(),
+            max_circuit_bytes: limits.max_circuit_bytes,
+            max_registration_ttl_secs: limits.max_registration_ttl.as_secs(),
+            max_registrations_per_peer: limits.max_registrations_per_peer,
+            allowed_namespaces: vec![],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -72,6 +160,7 @@ impl Default for Config {
             database: DatabaseConfig {
                 path: db_path.to_string_lossy().to_string(),
                 auto_migrate: true,
+                backup_count: default_backup_count(),
             },
             user: None,
             device: None,
@@ -80,6 +169,8 @@ impl Default for Config {
                 auto_start: false,
                 enable_mdns: true,
                 enable_relay: true,
+                enable_kademlia: true,
+                discovery_interval_secs: default_discovery_interval_secs(),
                 heartbeat_interval_secs: 10,
                 max_message_size: 65536,
             },
@@ -88,6 +179,11 @@ impl Default for Config {
                 listen_address: "0.0.0.0".to_string(),
                 bootstrap_nodes: vec![],
                 relay_servers: vec![],
+                rendezvous_points: vec![],
+                listen_addresses: vec![],
+                enable_quic: true,
+                enable_websocket: false,
+                websocket_port: 0,
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
@@ -96,6 +192,7 @@ impl Default for Config {
                 max_size_mb: 100,
                 max_files: 5,
             },
+            relay: RelayConfig::default(),
         }
     }
 }
@@ -116,11 +213,22 @@ impl Config {
             .join(".nexus")
     }
 
+    /// Path of this device's keypair, which gives the daemon a stable peer ID and
+    /// signs sync bundles
+    pub fn device_key_path() -> PathBuf {
+        Self::nexus_dir().join("device.key")
+    }
+
     /// Get the PID file path
     pub fn pid_file() -> PathBuf {
         Self::nexus_dir().join("nexus.pid")
     }
 
+    /// Path of the record of how the daemon was started
+    pub fn daemon_launch_file() -> PathBuf {
+        Self::nexus_dir().join("nexus.launch.json")
+    }
+
     /// Load configuration from file
     pub fn load(path: Option<&str>) -> CliResult<Self> {
         let config_path = if let Some(p) = path {
@@ -173,6 +281,11 @@ impl Config {
                         CliError::ValidationError("Invalid boolean value".to_string())
                     })?
                 }
+                "backup_count" => {
+                    self.database.backup_count = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
                 _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
             },
             "sync" => match parts[1] {
@@ -196,6 +309,16 @@ impl Config {
                         CliError::ValidationError("Invalid boolean value".to_string())
                     })?
                 }
+                "enable_kademlia" => {
+                    self.sync.enable_kademlia = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid boolean value".to_string())
+                    })?
+                }
+                "discovery_interval_secs" => {
+                    self.sync.discovery_interval_secs = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
                 "heartbeat_interval_secs" => {
                     self.sync.heartbeat_interval_secs = value.parse().map_err(|_| {
                         CliError::ValidationError("Invalid number value".to_string())
@@ -215,6 +338,56 @@ impl Config {
                         .map_err(|_| CliError::ValidationError("Invalid port number".to_string()))?
                 }
                 "listen_address" => self.network.listen_address = value.to_string(),
+                "enable_quic" => {
+                    self.network.enable_quic = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid boolean value".to_string())
+                    })?
+                }
+                "enable_websocket" => {
+                    self.network.enable_websocket = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid boolean value".to_string())
+                    })?
+                }
+                "websocket_port" => {
+                    self.network.websocket_port = value
+                        .parse()
+                        .map_err(|_| CliError::ValidationError("Invalid port number".to_string()))?
+                }
+                _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
+            },
+            "relay" => match parts[1] {
+                "listen_port" => {
+                    self.relay.listen_port = value
+                        .parse()
+                        .map_err(|_| CliError::ValidationError("Invalid port number".to_string()))?
+                }
+                "listen_address" => self.relay.listen_address = value.to_string(),
+                "key_file" => self.relay.key_file = value.to_string(),
+                "max_reservations" => {
+                    self.relay.max_reservations = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
+                "max_reservations_per_peer" => {
+                    self.relay.max_reservations_per_peer = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
+                "max_circuits" => {
+                    self.relay.max_circuits = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
+                "max_circuits_per_peer" => {
+                    self.relay.max_circuits_per_peer = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
+                "max_circuit_bytes" => {
+                    self.relay.max_circuit_bytes = value.parse().map_err(|_| {
+                        CliError::ValidationError("Invalid number value".to_string())
+                    })?
+                }
                 _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
             },
             "logging" => match parts[1] {
@@ -252,6 +425,7 @@ impl Config {
             "database" => match parts[1] {
                 "path" => self.database.path.clone(),
                 "auto_migrate" => self.database.auto_migrate.to_string(),
+                "backup_count" => self.database.backup_count.to_string(),
                 _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
             },
             "sync" => match parts[1] {
@@ -259,6 +433,8 @@ impl Config {
                 "auto_start" => self.sync.auto_start.to_string(),
                 "enable_mdns" => self.sync.enable_mdns.to_string(),
                 "enable_relay" => self.sync.enable_relay.to_string(),
+                "enable_kademlia" => self.sync.enable_kademlia.to_string(),
+                "discovery_interval_secs" => self.sync.discovery_interval_secs.to_string(),
                 "heartbeat_interval_secs" => self.sync.heartbeat_interval_secs.to_string(),
                 "max_message_size" => self.sync.max_message_size.to_string(),
                 _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
@@ -266,6 +442,20 @@ impl Config {
             "network" => match parts[1] {
                 "listen_port" => self.network.listen_port.to_string(),
                 "listen_address" => self.network.listen_address.clone(),
+                "enable_quic" => self.network.enable_quic.to_string(),
+                "enable_websocket" => self.network.enable_websocket.to_string(),
+                "websocket_port" => self.network.websocket_port.to_string(),
+                _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
+            },
+            "relay" => match parts[1] {
+                "listen_port" => self.relay.listen_port.to_string(),
+                "listen_address" => self.relay.listen_address.clone(),
+                "key_file" => self.relay.key_file.clone(),
+                "max_reservations" => self.relay.max_reservations.to_string(),
+                "max_reservations_per_peer" => self.relay.max_reservations_per_peer.to_string(),
+                "max_circuits" => self.relay.max_circuits.to_string(),
+                "max_circuits_per_peer" => self.relay.max_circuits_per_peer.to_string(),
+                "max_circuit_bytes" => self.relay.max_circuit_bytes.to_string(),
                 _ => return Err(CliError::NotFound(format!("Unknown key: {}", key))),
             },
             "logging" => match parts[1] {
@@ -333,8 +523,24 @@ impl Config {
         Self::expand_path(&self.database.path)
     }
 
+    /// Connection settings for the database, including its pre-migration backups
+    pub fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            backups: BackupConfig {
+                keep: self.database.backup_count,
+                dir: None,
+            },
+            ..ConnectionConfig::default()
+        }
+    }
+
     /// Get the expanded log file path
     pub fn log_path(&self) -> String {
         Self::expand_path(&self.logging.file)
     }
+
+    /// Get the expanded relay key file path
+    pub fn relay_key_path(&self) -> String {
+        Self::expand_path(&self.relay.key_file)
+    }
 }
@@ -1,8 +1,81 @@
 use crate::cli::errors::{CliError, CliResult};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
+/// How long `stop_daemon` waits for a graceful shutdown to complete
+const STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// How long `spawn_daemon` waits for the new daemon to write its PID file
+const START_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How the daemon was started, so `restart` and `import` can start it again the
+/// same way
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DaemonLaunch {
+    /// Port given to `start --port`
+    pub port: u16,
+    /// Configuration file the daemon loaded, as an absolute path
+    pub config_path: Option<PathBuf>,
+}
+
+/// Record how the daemon was started
+pub fn write_launch(launch_file: &Path, launch: &DaemonLaunch) -> CliResult<()> {
+    if let Some(parent) = launch_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = serde_json::to_string(launch)
+        .map_err(|e| CliError::DaemonError(format!("Failed to record daemon launch: {}", e)))?;
+    fs::write(launch_file, contents)?;
+    Ok(())
+}
+
+/// How the daemon was last started, if it recorded it
+pub fn read_launch(launch_file: &Path) -> CliResult<Option<DaemonLaunch>> {
+    if !launch_file.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(launch_file)?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| CliError::DaemonError(format!("Invalid daemon launch record: {}", e)))
+}
+
+/// Start the daemon in the background from a new `ahenk-cli start --daemon`
+/// process, so the calling command does not turn into the daemon itself, and
+/// wait until it is running
+pub fn spawn_daemon(pid_file: &Path, launch: &DaemonLaunch) -> CliResult<()> {
+    let mut command = Command::new(std::env::current_exe()?);
+    if let Some(config_path) = &launch.config_path {
+        command.arg("--config").arg(config_path);
+    }
+    // The started process exits once it has forked the daemon
+    let status = command
+        .args(["start", "--daemon", "--port", &launch.port.to_string()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(CliError::DaemonError(format!(
+            "Failed to start daemon ({})",
+            status
+        )));
+    }
+
+    let deadline = std::time::Instant::now() + START_TIMEOUT;
+    while !is_running(pid_file) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    if !is_running(pid_file) {
+        return Err(CliError::DaemonError(format!(
+            "Daemon did not start within {} seconds",
+            START_TIMEOUT.as_secs()
+        )));
+    }
+    Ok(())
+}
+
 /// Check if the daemon is running
 pub fn is_running(pid_file: &Path) -> bool {
     if !pid_file.exists() {
@@ -95,6 +168,39 @@ pub fn remove_pid_file(pid_file: &Path) -> CliResult<()> {
     Ok(())
 }
 
+/// Removes the PID file written for this process when dropped, so it is cleaned
+/// up on every exit path, including errors
+pub struct PidFileGuard {
+    pid_file: PathBuf,
+    pid: i32,
+}
+
+impl PidFileGuard {
+    /// Guard the PID file of the current process
+    pub fn new(pid_file: &Path) -> Self {
+        Self {
+            pid_file: pid_file.to_path_buf(),
+            pid: std::process::id() as i32,
+        }
+    }
+}
+
+impl Drop for PidFileGuard {
+    fn drop(&mut self) {
+        // Another process may have taken the file over since
+        if get_pid(&self.pid_file).ok() != Some(self.pid) {
+            return;
+        }
+        if let Err(e) = remove_pid_file(&self.pid_file) {
+            log::warn!(
+                "Failed to remove PID file {}: {}",
+                self.pid_file.display(),
+                e
+            );
+        }
+    }
+}
+
 /// Stop the daemon process
 pub fn stop_daemon(pid_file: &Path) -> CliResult<()> {
     if !is_running(pid_file) {
@@ -131,10 +237,22 @@ pub fn stop_daemon(pid_file: &Path) -> CliResult<()> {
         }
     }
 
-    // Wait a moment for the process to stop
-    std::thread::sleep(std::time::Duration::from_millis(500));
+    // Give the daemon time to finish in-flight transfers and checkpoint its state.
+    // It removes the PID file itself once shutdown completes.
+    let deadline = std::time::Instant::now() + STOP_TIMEOUT;
+    while is_running(pid_file) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    if is_running(pid_file) {
+        return Err(CliError::DaemonError(format!(
+            "Daemon (PID: {}) did not stop within {} seconds",
+            pid,
+            STOP_TIMEOUT.as_secs()
+        )));
+    }
 
-    // Remove PID file
+    // Remove PID file in case the daemon could not
     remove_pid_file(pid_file)?;
 
     Ok(())
@@ -4,23 +4,139 @@
 //! - Hybrid Logical Clock for causal ordering
 //! - Operation log management
 //! - Conflict resolution primitives
+//! - Entity operations: full state, JSON merge patch and JSON Patch, replayed in
+//!   HLC order from periodic snapshots
 //!
 //! Apps using ahenk should implement their own table-specific merge logic
 //! using the HLC and oplog primitives provided here.
 
+use crate::db::{capture, documents, operations, schema_registry};
+use crate::error::AhenkError;
 use crate::OplogEntry;
 use chrono::{DateTime, Utc};
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension, Transaction};
+use serde_json::Value;
 use std::cmp::Ordering;
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Source of physical time for [`HybridLogicalClock`].
+///
+/// [`SystemClock`] is used by default; tests inject their own clock to make
+/// timestamps deterministic.
+pub trait Clock: Send + Sync {
+    /// Current physical time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Physical time from the system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// How far ahead of local physical time a remote timestamp may be by default
+pub const DEFAULT_MAX_DRIFT: Duration = Duration::from_secs(60);
+
+/// What to do with a remote timestamp further ahead than the tolerated drift
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DriftPolicy {
+    /// Refuse the remote timestamp and leave the clock unchanged
+    #[default]
+    Reject,
+    /// Treat the remote timestamp as if it were exactly `max_drift` ahead.
+    /// Merged operations beyond the bound are still recorded with their own
+    /// timestamp, so they keep winning last-write-wins.
+    Clamp,
+}
+
+/// Bound on how far remote timestamps may run ahead of local physical time.
+///
+/// Without a bound, a single peer whose clock is set years ahead would drag every
+/// HLC it syncs with into the future.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriftBound {
+    /// Maximum tolerated drift
+    pub max_drift: Duration,
+    /// How timestamps beyond `max_drift` are handled
+    pub policy: DriftPolicy,
+}
+
+impl Default for DriftBound {
+    fn default() -> Self {
+        Self {
+            max_drift: DEFAULT_MAX_DRIFT,
+            policy: DriftPolicy::default(),
+        }
+    }
+}
+
+impl DriftBound {
+    /// Check that `remote` is at most `max_drift` ahead of the clock's physical time
+    pub fn check(
+        &self,
+        clock: &dyn Clock,
+        remote: HybridLogicalClock,
+    ) -> Result<(), ClockDriftError> {
+        self.check_at(physical_millis(clock.now()), remote)
+    }
+
+    fn check_at(
+        &self,
+        physical_now: u64,
+        remote: HybridLogicalClock,
+    ) -> Result<(), ClockDriftError> {
+        let limit = physical_now.saturating_add(self.max_drift.as_millis() as u64);
+        if remote.physical_time() > limit {
+            return Err(ClockDriftError {
+                remote,
+                drift: Duration::from_millis(remote.physical_time() - physical_now),
+                max_drift: self.max_drift,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A remote timestamp was further ahead of local physical time than allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockDriftError {
+    /// The rejected remote timestamp
+    pub remote: HybridLogicalClock,
+    /// How far the remote timestamp was ahead of local physical time
+    pub drift: Duration,
+    /// The tolerated drift
+    pub max_drift: Duration,
+}
+
+impl fmt::Display for ClockDriftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Remote timestamp {} is {:?} ahead of local time (max drift {:?})",
+            self.remote.to_timestamp(),
+            self.drift,
+            self.max_drift
+        )
+    }
+}
+
+impl std::error::Error for ClockDriftError {}
 
 /// Hybrid Logical Clock for maintaining causal ordering of operations.
 ///
 /// HLC combines physical time (system clock) with a logical counter to ensure:
 /// - Events on the same device are totally ordered
 /// - Events across devices can be causally ordered
-/// - Clock drift is bounded
+/// - Clock drift is bounded (see [`DriftBound`])
 ///
-/// Format: 48 bits physical time (microseconds) + 16 bits counter
+/// Format: 48 bits physical time (milliseconds) + 16 bits counter. A counter that
+/// runs out borrows the next millisecond, so the clock never stalls or wraps.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HybridLogicalClock {
     timestamp: u64,
@@ -29,10 +145,7 @@ pub struct HybridLogicalClock {
 impl HybridLogicalClock {
     /// Create a new HLC from physical time and counter
     pub fn new(physical_time: DateTime<Utc>, counter: u16) -> Self {
-        let physical_micros = physical_time.timestamp_micros() as u64;
-        // 48 bits for physical time, 16 bits for counter
-        let timestamp = (physical_micros << 16) | (counter as u64);
-        Self { timestamp }
+        Self::from_parts(physical_millis(physical_time), counter as u32)
     }
 
     /// Create HLC from a raw timestamp value
@@ -47,7 +160,7 @@ impl HybridLogicalClock {
         self.timestamp as i64
     }
 
-    /// Extract physical time component (microseconds since epoch)
+    /// Extract physical time component (milliseconds since epoch)
     pub fn physical_time(&self) -> u64 {
         self.timestamp >> 16
     }
@@ -59,8 +172,12 @@ impl HybridLogicalClock {
 
     /// Create HLC with current physical time and zero counter
     pub fn now() -> Self {
-        let now = Utc::now();
-        Self::new(now, 0)
+        Self::now_with(&SystemClock)
+    }
+
+    /// Create HLC with the given clock's current time and zero counter
+    pub fn now_with(clock: &dyn Clock) -> Self {
+        Self::new(clock.now(), 0)
     }
 
     /// Increment HLC, optionally synchronizing with remote time
@@ -68,49 +185,109 @@ impl HybridLogicalClock {
     /// This implements the HLC update rule:
     /// - Advance to max of local and remote physical time
     /// - Increment counter if physical times are equal
+    ///
+    /// Remote timestamps more than [`DEFAULT_MAX_DRIFT`] ahead are clamped; use
+    /// [`HybridLogicalClock::observe`] to choose the bound and policy.
     pub fn increment(&mut self, remote_time: Option<Self>) {
-        let physical_now = Utc::now().timestamp_micros() as u64;
+        self.increment_with(&SystemClock, remote_time);
+    }
+
+    /// Increment HLC like [`HybridLogicalClock::increment`], reading physical time from `clock`
+    pub fn increment_with(&mut self, clock: &dyn Clock, remote_time: Option<Self>) {
+        let bound = DriftBound {
+            policy: DriftPolicy::Clamp,
+            ..DriftBound::default()
+        };
+        // Clamping never fails
+        let _ = self.advance(physical_millis(clock.now()), remote_time, &bound);
+    }
+
+    /// Synchronize with a remote timestamp, enforcing `bound` on how far ahead it may be.
+    ///
+    /// With [`DriftPolicy::Reject`] the clock is left unchanged and an error returned
+    /// when the remote timestamp is too far ahead.
+    pub fn observe(
+        &mut self,
+        clock: &dyn Clock,
+        remote_time: Self,
+        bound: &DriftBound,
+    ) -> Result<(), ClockDriftError> {
+        self.advance(physical_millis(clock.now()), Some(remote_time), bound)
+    }
+
+    fn advance(
+        &mut self,
+        physical_now: u64,
+        remote_time: Option<Self>,
+        bound: &DriftBound,
+    ) -> Result<(), ClockDriftError> {
+        let local_physical = self.physical_time();
+        let local_counter = self.counter() as u32;
 
-        match remote_time {
+        let (physical, counter) = match remote_time {
             None => {
                 // Local operation: increment based on current time
-                let new_physical = physical_now.max(self.physical_time());
+                let new_physical = physical_now.max(local_physical);
 
-                if new_physical == self.physical_time() {
+                if new_physical == local_physical {
                     // Same physical time, increment counter
-                    let new_counter = self.counter() + 1;
-                    self.timestamp = (new_physical << 16) | (new_counter as u64);
+                    (new_physical, local_counter + 1)
                 } else {
                     // Time advanced, reset counter
-                    self.timestamp = new_physical << 16;
+                    (new_physical, 0)
                 }
             }
             Some(remote) => {
-                // Remote synchronization
-                let remote_physical = remote.physical_time();
-                let new_physical = physical_now.max(self.physical_time()).max(remote_physical);
+                // Remote synchronization, bounded by the tolerated drift
+                let (remote_physical, remote_counter) = match bound.check_at(physical_now, remote) {
+                    Ok(()) => (remote.physical_time(), remote.counter() as u32),
+                    Err(e) => match bound.policy {
+                        DriftPolicy::Reject => return Err(e),
+                        DriftPolicy::Clamp => (physical_now + e.max_drift.as_millis() as u64, 0),
+                    },
+                };
 
-                if new_physical == self.physical_time() && new_physical == remote_physical {
+                let new_physical = physical_now.max(local_physical).max(remote_physical);
+
+                if new_physical == local_physical && new_physical == remote_physical {
                     // All times equal, take max counter and increment
-                    let new_counter = self.counter().max(remote.counter()) + 1;
-                    self.timestamp = (new_physical << 16) | (new_counter as u64);
-                } else if new_physical == self.physical_time() {
+                    (new_physical, local_counter.max(remote_counter) + 1)
+                } else if new_physical == local_physical {
                     // Local time matches, increment local counter
-                    let new_counter = self.counter() + 1;
-                    self.timestamp = (new_physical << 16) | (new_counter as u64);
+                    (new_physical, local_counter + 1)
                 } else if new_physical == remote_physical {
                     // Remote time matches, increment remote counter
-                    let new_counter = remote.counter() + 1;
-                    self.timestamp = (new_physical << 16) | (new_counter as u64);
+                    (new_physical, remote_counter + 1)
                 } else {
                     // Time advanced, reset counter
-                    self.timestamp = new_physical << 16;
+                    (new_physical, 0)
                 }
             }
+        };
+
+        *self = Self::from_parts(physical, counter);
+        Ok(())
+    }
+
+    /// Pack physical time and counter, borrowing the next millisecond when the
+    /// counter no longer fits in 16 bits
+    fn from_parts(physical: u64, counter: u32) -> Self {
+        let (physical, counter) = if counter > u16::MAX as u32 {
+            (physical + 1, 0)
+        } else {
+            (physical, counter as u64)
+        };
+        Self {
+            timestamp: (physical << 16) | counter,
         }
     }
 }
 
+/// Milliseconds since the Unix epoch; times before it count as the epoch
+fn physical_millis(time: DateTime<Utc>) -> u64 {
+    time.timestamp_millis().max(0) as u64
+}
+
 impl PartialOrd for HybridLogicalClock {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -123,6 +300,59 @@ impl Ord for HybridLogicalClock {
     }
 }
 
+// ============================================================================
+// Persisted Clock
+// ============================================================================
+
+/// Advance the database's HLC for a local write and return the new timestamp.
+///
+/// The clock lives in the `hlc_state` table, so every connection to the database
+/// stamps from the same clock and it survives restarts.
+pub fn next_timestamp(conn: &Connection) -> Result<HybridLogicalClock, rusqlite::Error> {
+    next_timestamp_with(conn, &SystemClock)
+}
+
+/// Advance the database's HLC like [`next_timestamp`], reading physical time from `clock`
+pub fn next_timestamp_with(
+    conn: &Connection,
+    clock: &dyn Clock,
+) -> Result<HybridLogicalClock, rusqlite::Error> {
+    update_persisted_clock(conn, |hlc| hlc.increment_with(clock, None))
+}
+
+/// Advance the database's HLC past a remote timestamp, so later local writes sort
+/// after it. Timestamps more than `bound.max_drift` ahead are clamped.
+pub fn observe_timestamp(
+    conn: &Connection,
+    clock: &dyn Clock,
+    remote: HybridLogicalClock,
+    bound: &DriftBound,
+) -> Result<HybridLogicalClock, rusqlite::Error> {
+    let clamp = DriftBound {
+        policy: DriftPolicy::Clamp,
+        ..*bound
+    };
+    // Clamping never fails
+    update_persisted_clock(conn, |hlc| {
+        let _ = hlc.observe(clock, remote, &clamp);
+    })
+}
+
+/// Read-modify-write the persisted clock, retrying if another writer moved it
+fn update_persisted_clock(
+    conn: &Connection,
+    advance: impl Fn(&mut HybridLogicalClock),
+) -> Result<HybridLogicalClock, rusqlite::Error> {
+    loop {
+        let current = operations::get_hlc_timestamp(conn)?;
+        let mut hlc = HybridLogicalClock::from_timestamp(current);
+        advance(&mut hlc);
+        if operations::compare_and_set_hlc_timestamp(conn, current, hlc.to_timestamp())? {
+            return Ok(hlc);
+        }
+    }
+}
+
 // ============================================================================
 // Operation Application
 // ============================================================================
@@ -130,7 +360,11 @@ impl Ord for HybridLogicalClock {
 /// Apply a local operation and record it in the oplog.
 ///
 /// This function records the operation in the oplog for later synchronization.
-/// Apps should implement their own table-specific logic before calling this.
+/// Apps should implement their own table-specific logic before calling this;
+/// use [`local_apply_in`] to commit both in one transaction.
+/// Operations that violate the schema registered for their table (see
+/// `db::schema_registry`) are rejected with [`AhenkError::Validation`] and not
+/// recorded.
 ///
 /// # Example
 /// ```rust,no_run
@@ -147,6 +381,7 @@ impl Ord for HybridLogicalClock {
 ///
 /// // Record operation in oplog for sync
 /// let entry = build_oplog_entry(
+///     &conn,
 ///     device_id,
 ///     "my_app_data",
 ///     "create",
@@ -156,38 +391,86 @@ impl Ord for HybridLogicalClock {
 /// # Ok(())
 /// # }
 /// ```
-pub fn local_apply(conn: &mut Connection, op: &OplogEntry) -> Result<(), rusqlite::Error> {
-    // Check if operation already exists (idempotency)
-    let mut stmt = conn.prepare("SELECT 1 FROM oplog WHERE id = ?")?;
-    let exists = stmt.exists([op.id.to_string()])?;
+pub fn local_apply(conn: &mut Connection, op: &OplogEntry) -> crate::error::Result<()> {
+    local_apply_batch(conn, std::slice::from_ref(op)).map(|_| ())
+}
 
-    if !exists {
-        // Record operation in oplog
-        conn.execute(
-            "INSERT INTO oplog (id, device_id, timestamp, table_name, op_type, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            rusqlite::params![
-                op.id.to_string(),
-                op.device_id.to_string(),
-                op.timestamp,
-                op.table,
-                op.op_type,
-                serde_json::to_string(&op.data).unwrap(),
-            ],
-        )?;
-    }
+/// Apply a batch of local operations like [`local_apply`], in one transaction.
+///
+/// If any operation is invalid none of the batch is recorded. Operations
+/// already in the oplog are skipped. Returns how many were recorded.
+pub fn local_apply_batch(conn: &mut Connection, ops: &[OplogEntry]) -> crate::error::Result<usize> {
+    let tx = conn.transaction()?;
+    let recorded = local_apply_in(&tx, ops)?;
+    tx.commit()?;
+    Ok(recorded)
+}
 
-    Ok(())
+/// Record local operations inside the caller's transaction, so they commit
+/// atomically with the app's own table writes. Returns how many were recorded.
+///
+/// # Example
+/// ```rust,no_run
+/// use ahenk::{build_oplog_entry, local_apply_in};
+/// # use rusqlite::Connection;
+/// # use uuid::Uuid;
+///
+/// # fn example(mut conn: Connection, device_id: Uuid) -> Result<(), Box<dyn std::error::Error>> {
+/// let tx = conn.transaction()?;
+/// tx.execute(
+///     "INSERT INTO my_app_data (id, value) VALUES (?1, ?2)",
+///     rusqlite::params!["id1", "value1"],
+/// )?;
+/// let entry = build_oplog_entry(
+///     &tx,
+///     device_id,
+///     "my_app_data",
+///     "create",
+///     &serde_json::json!({"id": "id1", "value": "value1"}),
+/// )?;
+/// local_apply_in(&tx, &[entry])?;
+/// tx.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn local_apply_in(tx: &Transaction, ops: &[OplogEntry]) -> crate::error::Result<usize> {
+    let mut recorded = 0;
+    for op in ops {
+        // Idempotency: operations already recorded are skipped
+        if operations::oplog_entry_exists(tx, op.id)? {
+            continue;
+        }
+        if let Some(reason) = schema_registry::validate_op(tx, op)? {
+            return Err(AhenkError::Validation(format!(
+                "Invalid operation {}: {}",
+                op.id, reason
+            )));
+        }
+        if operations::insert_oplog_entry(tx, op)? {
+            invalidate_snapshots(tx, op)?;
+            recorded += 1;
+        }
+    }
+    Ok(recorded)
 }
 
 /// Merge remote operations into the local database.
 ///
 /// This function merges operations from remote peers, recording them in the oplog.
 /// Apps should implement their own conflict resolution logic and table updates.
+/// All writes for the batch are committed in one transaction; use [`merge_in`]
+/// to include the app's own table updates in it.
 ///
 /// The function:
 /// 1. Checks if each operation already exists (idempotency)
-/// 2. Records new operations in the oplog
-/// 3. Apps must handle actual table updates based on their conflict resolution strategy
+/// 2. Records new operations in the oplog. Operations with a newer payload version
+///    than the local schema are held back, and operations that violate the schema
+///    registered for their table are quarantined (see `db::schema_registry`), as are
+///    operations stamped further ahead of local time than [`DriftBound`] allows
+/// 3. Applies new operations on tables registered with `db::capture::register_table`
+///    (last-write-wins per row, without capturing them again) and on collections
+/// 4. Advances the database's HLC past the newest merged operation
+/// 5. Apps must handle table updates for other tables based on their conflict resolution strategy
 ///
 /// # Example
 /// ```rust,no_run
@@ -207,34 +490,498 @@ pub fn local_apply(conn: &mut Connection, op: &OplogEntry) -> Result<(), rusqlit
 /// # }
 /// ```
 pub fn merge(conn: &mut Connection, remote_ops: &[OplogEntry]) -> Result<(), rusqlite::Error> {
+    merge_with(conn, remote_ops, &SystemClock, &DriftBound::default())
+}
+
+/// Merge remote operations like [`merge`], reading physical time from `clock` and
+/// handling operations too far ahead of it according to `bound`
+pub fn merge_with(
+    conn: &mut Connection,
+    remote_ops: &[OplogEntry],
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<(), rusqlite::Error> {
     let tx = conn.transaction()?;
+    merge_in(&tx, remote_ops, clock, bound)?;
+    tx.commit()
+}
+
+/// Merge remote operations like [`merge_with`] inside the caller's transaction,
+/// so the app's own table updates for the batch commit atomically with it
+pub fn merge_in(
+    tx: &Transaction,
+    remote_ops: &[OplogEntry],
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<(), rusqlite::Error> {
+    merge_recorded(tx, remote_ops, clock, bound).map(|_| ())
+}
+
+/// [`merge_in`], returning the operations recorded in the oplog
+fn merge_recorded<'a>(
+    tx: &Transaction,
+    remote_ops: &'a [OplogEntry],
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<Vec<&'a OplogEntry>, rusqlite::Error> {
+    let mut new_ops = Vec::new();
+    let mut newest = None;
 
     for op in remote_ops {
-        let mut stmt = tx.prepare("SELECT 1 FROM oplog WHERE id = ?")?;
-        let exists = stmt.exists([op.id.to_string()])?;
-
-        if !exists {
-            // Record operation in oplog
-            tx.execute(
-                "INSERT INTO oplog (id, device_id, timestamp, table_name, op_type, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-                rusqlite::params![
-                    op.id.to_string(),
-                    op.device_id.to_string(),
-                    op.timestamp,
-                    op.table,
-                    op.op_type,
-                    serde_json::to_string(&op.data).unwrap(),
-                ],
-            )?;
+        let drift = match bound.policy {
+            DriftPolicy::Reject => bound
+                .check(clock, HybridLogicalClock::from_timestamp(op.timestamp))
+                .err(),
+            DriftPolicy::Clamp => None,
+        };
+        if drift.is_none() {
+            newest = newest.max(Some(op.timestamp));
+        }
+        if operations::oplog_entry_exists(tx, op.id)? {
+            continue;
+        }
+        if op.schema_version > schema_registry::current_version(tx, &op.table)? {
+            eprintln!(
+                "Holding back operation {} on {}: payload version {} is not known yet",
+                op.id, op.table, op.schema_version
+            );
+            schema_registry::hold_back(tx, op)?;
+            continue;
+        }
+        schema_registry::release_held(tx, op.id)?;
+        // Stored, a far-future operation would win every conflict on its row;
+        // quarantined, it is merged by `retry_quarantined` once local time catches up
+        if let Some(e) = drift {
+            eprintln!(
+                "Quarantining operation {} on {} from device {}: {}",
+                op.id, op.table, op.device_id, e
+            );
+            schema_registry::quarantine(tx, op, &e.to_string())?;
+            continue;
+        }
+        if let Some(reason) = schema_registry::validate_op(tx, op)? {
+            eprintln!(
+                "Quarantining operation {} on {} from device {}: {}",
+                op.id, op.table, op.device_id, reason
+            );
+            schema_registry::quarantine(tx, op, &reason)?;
+            continue;
+        }
+        schema_registry::release_quarantined(tx, op.id)?;
+        // Record operation in oplog; a duplicate within the batch is ignored
+        if operations::insert_oplog_entry(tx, op)? {
+            invalidate_snapshots(tx, op)?;
+            new_ops.push(op);
         }
     }
 
-    tx.commit()
+    // Tables registered for change capture and collections are updated in place
+    capture::apply_remote_ops(tx, &new_ops)?;
+    documents::apply_remote_ops(tx, &new_ops)?;
+
+    // Observing the newest operation is enough to order later local writes after all of them
+    if let Some(newest) = newest {
+        observe_timestamp(tx, clock, HybridLogicalClock::from_timestamp(newest), bound)?;
+    }
+    Ok(new_ops)
+}
+
+/// Merge quarantined operations again like [`merge_with`], e.g. after their
+/// table's schema changed or local time caught up with them.
+///
+/// Operations that are valid now are recorded and leave quarantine; the others
+/// stay with an updated reason. Returns the operations that were recorded.
+pub fn retry_quarantined(
+    conn: &mut Connection,
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<Vec<OplogEntry>, rusqlite::Error> {
+    let quarantined: Vec<OplogEntry> = schema_registry::quarantined_ops(conn)?
+        .into_iter()
+        .map(|q| q.entry)
+        .collect();
+    merge_retried(conn, &quarantined, clock, bound)
+}
+
+/// Merge held-back operations whose payload version is known now like
+/// [`merge_with`], e.g. after the app was upgraded and registered a newer schema.
+///
+/// Returns the operations that were recorded; ones that are still too new stay
+/// held back, and ones that turn out invalid are quarantined.
+pub fn retry_held_ops(
+    conn: &mut Connection,
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<Vec<OplogEntry>, rusqlite::Error> {
+    let held = schema_registry::held_ops(conn)?;
+    merge_retried(conn, &held, clock, bound)
+}
+
+fn merge_retried(
+    conn: &mut Connection,
+    ops: &[OplogEntry],
+    clock: &dyn Clock,
+    bound: &DriftBound,
+) -> Result<Vec<OplogEntry>, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let recorded = merge_recorded(&tx, ops, clock, bound)?
+        .into_iter()
+        .cloned()
+        .collect();
+    tx.commit()?;
+    Ok(recorded)
+}
+
+// ============================================================================
+// Entity Operations
+// ============================================================================
+
+/// Full state: `{"id": ..., "value": <entity>}`
+pub const OP_INSERT: &str = "insert";
+/// Full state: `{"id": ..., "value": <entity>}`
+pub const OP_UPDATE: &str = "update";
+/// `{"id": ...}`; the entity no longer exists
+pub const OP_DELETE: &str = "delete";
+/// JSON merge patch (RFC 7386): `{"id": ..., "patch": {...}}`
+pub const OP_MERGE_PATCH: &str = "merge_patch";
+/// JSON Patch (RFC 6902): `{"id": ..., "patch": [{"op": ...}, ...]}`
+pub const OP_JSON_PATCH: &str = "json_patch";
+
+/// Operations replayed past an entity's snapshot before a new one is taken
+pub const SNAPSHOT_INTERVAL: usize = 64;
+
+/// The state of an entity after applying `op` to `state`.
+///
+/// The payload is first upcast to its table's current version. Patches to an
+/// entity that does not exist are ignored, as are JSON Patches that fail
+/// (including a failing `test`), so every device reaches the same state from the
+/// same operations.
+pub fn apply_entity_op(state: Option<Value>, op: &OplogEntry) -> Option<Value> {
+    let op = match schema_registry::upcast(op) {
+        Ok(op) => op,
+        Err(reason) => {
+            eprintln!("Skipping {} operation {}: {}", op.table, op.id, reason);
+            return state;
+        }
+    };
+    match op.op_type.as_str() {
+        OP_INSERT | OP_UPDATE => op.data.get("value").cloned(),
+        OP_DELETE => None,
+        OP_MERGE_PATCH => state.map(|mut entity| {
+            if let Some(patch) = op.data.get("patch") {
+                merge_patch(&mut entity, patch);
+            }
+            entity
+        }),
+        OP_JSON_PATCH => state.map(|entity| {
+            let patch = op.data.get("patch").unwrap_or(&Value::Null);
+            json_patch(&entity, patch).unwrap_or_else(|e| {
+                eprintln!("Skipping JSON Patch {} on {}: {}", op.id, op.table, e);
+                entity
+            })
+        }),
+        other => {
+            eprintln!("Ignoring unknown {} operation type '{}'", op.table, other);
+            state
+        }
+    }
+}
+
+/// Apply a JSON merge patch (RFC 7386) to `target`
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("made an object above");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// A merge patch turning `from` into `to`, or `None` if `to` holds `null` values,
+/// which a merge patch cannot express
+pub fn merge_patch_diff(from: &Value, to: &Value) -> Option<Value> {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            let mut patch = serde_json::Map::new();
+            for key in from.keys().filter(|key| !to.contains_key(*key)) {
+                patch.insert(key.clone(), Value::Null);
+            }
+            for (key, value) in to {
+                match from.get(key) {
+                    Some(old) if old == value => {}
+                    Some(old) => {
+                        patch.insert(key.clone(), merge_patch_diff(old, value)?);
+                    }
+                    None => {
+                        patch.insert(key.clone(), merge_patch_diff(&Value::Null, value)?);
+                    }
+                }
+            }
+            Some(Value::Object(patch))
+        }
+        (_, Value::Null) => None,
+        (_, Value::Object(to)) => {
+            // Replacing a non-object: every nested value is sent as a patch on nothing
+            let mut patch = serde_json::Map::new();
+            for (key, value) in to {
+                patch.insert(key.clone(), merge_patch_diff(&Value::Null, value)?);
+            }
+            Some(Value::Object(patch))
+        }
+        (_, to) if contains_null(to) => None,
+        (_, to) => Some(to.clone()),
+    }
+}
+
+fn contains_null(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(items) => items.iter().any(contains_null),
+        Value::Object(fields) => fields.values().any(contains_null),
+        _ => false,
+    }
+}
+
+/// Apply a JSON Patch (RFC 6902) to `target`, returning the patched value.
+///
+/// The patch is applied atomically: if any operation fails, an error is returned
+/// and `target` is left as it was.
+pub fn json_patch(target: &Value, patch: &Value) -> Result<Value, String> {
+    let ops = patch
+        .as_array()
+        .ok_or_else(|| "JSON Patch must be an array of operations".to_string())?;
+    let mut doc = target.clone();
+
+    for (index, op) in ops.iter().enumerate() {
+        let field = |name: &str| {
+            op.get(name)
+                .ok_or_else(|| format!("operation {} has no '{}'", index, name))
+        };
+        let path = field("path")?
+            .as_str()
+            .ok_or_else(|| format!("operation {} has a non-string path", index))?;
+        let from = || {
+            field("from")?
+                .as_str()
+                .ok_or_else(|| format!("operation {} has a non-string from", index))
+        };
+
+        match field("op")?.as_str() {
+            Some("add") => pointer_add(&mut doc, path, field("value")?.clone())?,
+            Some("remove") => {
+                pointer_remove(&mut doc, path)?;
+            }
+            Some("replace") => {
+                let target = doc
+                    .pointer_mut(path)
+                    .ok_or_else(|| format!("path {} does not exist", path))?;
+                *target = field("value")?.clone();
+            }
+            Some("move") => {
+                let from = from()?;
+                if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                    return Err(format!("cannot move {} into its own child {}", from, path));
+                }
+                let value = pointer_remove(&mut doc, from)?;
+                pointer_add(&mut doc, path, value)?;
+            }
+            Some("copy") => {
+                let value = doc
+                    .pointer(from()?)
+                    .cloned()
+                    .ok_or_else(|| format!("path {} does not exist", from().unwrap_or_default()))?;
+                pointer_add(&mut doc, path, value)?;
+            }
+            Some("test") => {
+                if doc.pointer(path) != Some(field("value")?) {
+                    return Err(format!("test failed at {}", path));
+                }
+            }
+            other => return Err(format!("unknown operation {:?}", other)),
+        }
+    }
+
+    Ok(doc)
+}
+
+/// Split a JSON pointer into its parent pointer and unescaped last token
+fn split_pointer(path: &str) -> Result<(&str, String), String> {
+    let slash = path
+        .rfind('/')
+        .ok_or_else(|| format!("invalid JSON pointer {:?}", path))?;
+    let token = path[slash + 1..].replace("~1", "/").replace("~0", "~");
+    Ok((&path[..slash], token))
+}
+
+fn pointer_add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(fields)) => {
+            fields.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if token == "-" {
+                items.len()
+            } else {
+                token
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&i| i <= items.len())
+                    .ok_or_else(|| format!("invalid array index in {}", path))?
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(format!("parent of {} does not exist", path)),
+    }
+}
+
+fn pointer_remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = split_pointer(path)?;
+    let removed = match doc.pointer_mut(parent) {
+        Some(Value::Object(fields)) => fields.remove(&token),
+        Some(Value::Array(items)) => token
+            .parse::<usize>()
+            .ok()
+            .filter(|&i| i < items.len())
+            .map(|i| items.remove(i)),
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("path {} does not exist", path))
+}
+
+/// Rebuild an entity from its operations in HLC order, ties broken by operation ID.
+///
+/// Replay starts from the entity's latest snapshot and never writes, so this is
+/// safe on a read-only connection; [`snapshot_entity`] records new snapshots.
+pub fn entity_state(
+    conn: &Connection,
+    table: &str,
+    entity_id: &str,
+) -> Result<Option<Value>, rusqlite::Error> {
+    Ok(replay_entity(conn, table, entity_id)?.state)
+}
+
+/// Rebuild an entity like [`entity_state`], taking a new snapshot when more than
+/// [`SNAPSHOT_INTERVAL`] operations had to be replayed.
+///
+/// Call this on the write path, so replay stays bounded however many patches
+/// accumulate.
+pub fn snapshot_entity(
+    conn: &Connection,
+    table: &str,
+    entity_id: &str,
+) -> Result<Option<Value>, rusqlite::Error> {
+    let replay = replay_entity(conn, table, entity_id)?;
+    if let Some((timestamp, op_id)) = replay.last.filter(|_| replay.replayed >= SNAPSHOT_INTERVAL) {
+        conn.execute(
+            "INSERT OR REPLACE INTO entity_snapshots
+                 (table_name, entity_id, timestamp, op_id, data, op_count, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                table,
+                entity_id,
+                timestamp,
+                op_id.to_string(),
+                replay.state.as_ref().map(Value::to_string),
+                replay.op_count,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+    }
+    Ok(replay.state)
+}
+
+/// An entity replayed from its latest snapshot
+struct EntityReplay {
+    state: Option<Value>,
+    /// Timestamp and ID of the last operation replayed past the snapshot
+    last: Option<(i64, Uuid)>,
+    /// Operations replayed past the snapshot
+    replayed: usize,
+    /// Operations folded into `state`, including those behind the snapshot
+    op_count: i64,
+}
+
+fn replay_entity(
+    conn: &Connection,
+    table: &str,
+    entity_id: &str,
+) -> Result<EntityReplay, rusqlite::Error> {
+    let snapshot: Option<(i64, String, Option<String>, i64)> = conn
+        .query_row(
+            "SELECT timestamp, op_id, data, op_count FROM entity_snapshots
+             WHERE table_name = ?1 AND entity_id = ?2",
+            rusqlite::params![table, entity_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+    let (since_timestamp, since_op, mut state, op_count) = match snapshot {
+        Some((timestamp, op_id, data, op_count)) => {
+            let data = data
+                .map(|raw| serde_json::from_str(&raw))
+                .transpose()
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        2,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })?;
+            (timestamp, op_id, data, op_count)
+        }
+        None => (i64::MIN, String::new(), None, 0),
+    };
+
+    let ops =
+        operations::get_entity_oplog_entries(conn, table, entity_id, since_timestamp, &since_op)?;
+    for op in &ops {
+        state = apply_entity_op(state, op);
+    }
+    Ok(EntityReplay {
+        state,
+        last: ops.last().map(|op| (op.timestamp, op.id)),
+        replayed: ops.len(),
+        op_count: op_count + ops.len() as i64,
+    })
+}
+
+/// Drop snapshots that an operation older than them would change
+fn invalidate_snapshots(conn: &Connection, op: &OplogEntry) -> Result<(), rusqlite::Error> {
+    let Some(entity_id) = op.data.get("id").and_then(Value::as_str) else {
+        return Ok(());
+    };
+    let mut stmt = conn.prepare_cached(
+        "DELETE FROM entity_snapshots
+         WHERE table_name = ?1 AND entity_id = ?2 AND (timestamp, op_id) > (?3, ?4)",
+    )?;
+    stmt.execute(rusqlite::params![
+        op.table,
+        entity_id,
+        op.timestamp,
+        op.id.to_string()
+    ])?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{oplog_entry, ManualClock};
 
     #[test]
     fn test_hlc_ordering() {
@@ -286,6 +1033,493 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hlc_counter_orders_writes_within_a_millisecond() {
+        let clock = ManualClock::default();
+        let mut hlc = HybridLogicalClock::now_with(&clock);
+        let first = hlc;
+
+        hlc.increment_with(&clock, None);
+        assert_eq!(hlc.physical_time(), first.physical_time());
+        assert_eq!(hlc.counter(), 1);
+        assert!(hlc > first);
+    }
+
+    #[test]
+    fn test_hlc_counter_overflow_borrows_physical_time() {
+        let clock = ManualClock::default();
+        let mut hlc = HybridLogicalClock::new(clock.now(), u16::MAX);
+        let full = hlc;
+
+        hlc.increment_with(&clock, None);
+        assert!(hlc > full);
+        assert_eq!(hlc.physical_time(), full.physical_time() + 1);
+        assert_eq!(hlc.counter(), 0);
+
+        // A remote clock with a full counter at the same time is handled the same way
+        let mut local = HybridLogicalClock::now_with(&clock);
+        local.increment_with(&clock, Some(full));
+        assert!(local > full);
+        assert_eq!(local.counter(), 0);
+    }
+
+    #[test]
+    fn test_hlc_drift_bound() {
+        let clock = ManualClock::default();
+        let start = HybridLogicalClock::now_with(&clock);
+        let far_ahead = HybridLogicalClock::new(clock.now() + chrono::Duration::days(365 * 10), 0);
+        let near = HybridLogicalClock::new(clock.now() + chrono::Duration::seconds(5), 3);
+
+        // Rejecting leaves the clock untouched
+        let mut hlc = start;
+        let err = hlc
+            .observe(&clock, far_ahead, &DriftBound::default())
+            .unwrap_err();
+        assert_eq!(hlc, start);
+        assert_eq!(err.max_drift, DEFAULT_MAX_DRIFT);
+        assert!(err.drift > Duration::from_secs(365 * 24 * 60 * 60));
+
+        // Timestamps within the bound are accepted as usual
+        hlc.observe(&clock, near, &DriftBound::default()).unwrap();
+        assert_eq!(hlc.physical_time(), near.physical_time());
+        assert_eq!(hlc.counter(), 4);
+
+        // Clamping caps the clock at local time plus the tolerated drift
+        let bound = DriftBound {
+            max_drift: Duration::from_secs(30),
+            policy: DriftPolicy::Clamp,
+        };
+        let mut hlc = start;
+        hlc.observe(&clock, far_ahead, &bound).unwrap();
+        assert_eq!(hlc.physical_time(), start.physical_time() + 30_000);
+
+        // The default update rule clamps as well
+        let mut hlc = start;
+        hlc.increment_with(&clock, Some(far_ahead));
+        assert_eq!(
+            hlc.physical_time(),
+            start.physical_time() + DEFAULT_MAX_DRIFT.as_millis() as u64
+        );
+    }
+
+    #[test]
+    fn test_persisted_clock_is_shared_by_all_writers() {
+        let path = std::env::temp_dir().join(format!("ahenk-hlc-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let clock = ManualClock::default();
+
+        // Two connections to the same database never hand out the same timestamp
+        let first = crate::db::operations::initialize_database(path).unwrap();
+        let second = crate::db::operations::initialize_database(path).unwrap();
+        let a = next_timestamp_with(&first, &clock).unwrap();
+        let b = next_timestamp_with(&second, &clock).unwrap();
+        let c = next_timestamp_with(&first, &clock).unwrap();
+        assert!(a < b && b < c);
+        assert_eq!(c.counter(), 2);
+
+        // The clock survives reopening the database
+        drop((first, second));
+        let reopened = crate::db::operations::initialize_database(path).unwrap();
+        assert!(next_timestamp_with(&reopened, &clock).unwrap() > c);
+
+        drop(reopened);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_advances_persisted_clock() {
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        let clock = ManualClock::default();
+        let local = next_timestamp_with(&conn, &clock).unwrap();
+
+        let remote = HybridLogicalClock::new(clock.now() + chrono::Duration::seconds(10), 7);
+        let entry = OplogEntry {
+            id: uuid::Uuid::new_v4(),
+            device_id: uuid::Uuid::new_v4(),
+            timestamp: remote.to_timestamp(),
+            table: "notes".to_string(),
+            op_type: "create".to_string(),
+            data: serde_json::json!({"id": "n1"}),
+            schema_version: 0,
+        };
+        merge_with(&mut conn, &[entry], &clock, &DriftBound::default()).unwrap();
+
+        // Local writes after the merge sort after the remote entry
+        let next = next_timestamp_with(&conn, &clock).unwrap();
+        assert!(next > remote);
+        assert!(next > local);
+    }
+
+    #[test]
+    fn test_merge_quarantines_entries_beyond_drift_bound() {
+        let clock = ManualClock::default();
+        let far_ahead = HybridLogicalClock::new(clock.now() + chrono::Duration::days(365), 0);
+        let entry = OplogEntry {
+            id: uuid::Uuid::new_v4(),
+            device_id: uuid::Uuid::new_v4(),
+            timestamp: far_ahead.to_timestamp(),
+            table: "notes".to_string(),
+            op_type: "create".to_string(),
+            data: serde_json::json!({"id": "n1"}),
+            schema_version: 0,
+        };
+
+        // Rejected: the entry is held in quarantine and the clock stays put
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        merge_with(
+            &mut conn,
+            std::slice::from_ref(&entry),
+            &clock,
+            &DriftBound::default(),
+        )
+        .unwrap();
+        assert!(!operations::oplog_entry_exists(&conn, entry.id).unwrap());
+        let quarantined = schema_registry::quarantined_ops(&conn).unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].reason.contains("ahead of local time"));
+        assert!(next_timestamp_with(&conn, &clock).unwrap() < far_ahead);
+
+        // Retried against the same clock and bound it stays quarantined, and is
+        // only merged once local time catches up
+        assert!(retry_quarantined(&mut conn, &clock, &DriftBound::default())
+            .unwrap()
+            .is_empty());
+        assert_eq!(schema_registry::quarantined_ops(&conn).unwrap().len(), 1);
+        let later = ManualClock::new(clock.now() + chrono::Duration::days(365));
+        let merged = retry_quarantined(&mut conn, &later, &DriftBound::default()).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].id, entry.id);
+        assert!(operations::oplog_entry_exists(&conn, entry.id).unwrap());
+        assert!(schema_registry::quarantined_ops(&conn).unwrap().is_empty());
+
+        // A wider bound accepts it
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        let wide = DriftBound {
+            max_drift: Duration::from_secs(2 * 365 * 24 * 60 * 60),
+            policy: DriftPolicy::Reject,
+        };
+        merge_with(&mut conn, std::slice::from_ref(&entry), &clock, &wide).unwrap();
+        assert!(operations::oplog_entry_exists(&conn, entry.id).unwrap());
+
+        // Clamp records the entry but only moves the clock by the bound
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        let clamp = DriftBound {
+            policy: DriftPolicy::Clamp,
+            ..DriftBound::default()
+        };
+        merge_with(&mut conn, std::slice::from_ref(&entry), &clock, &clamp).unwrap();
+        assert!(operations::oplog_entry_exists(&conn, entry.id).unwrap());
+        let next = next_timestamp_with(&conn, &clock).unwrap();
+        assert!(next < far_ahead);
+        assert_eq!(
+            next.physical_time(),
+            HybridLogicalClock::now_with(&clock).physical_time()
+                + DEFAULT_MAX_DRIFT.as_millis() as u64
+        );
+    }
+
+    fn entity_op(timestamp: i64, op_type: &str, data: Value) -> OplogEntry {
+        oplog_entry(timestamp, "notes", op_type, data)
+    }
+
+    #[test]
+    fn test_merge_patch_and_diff() {
+        use serde_json::json;
+
+        let mut doc =
+            json!({"title": "a", "tags": ["x"], "meta": {"pinned": true, "color": "red"}});
+        merge_patch(&mut doc, &json!({"title": "b", "meta": {"color": null}}));
+        assert_eq!(
+            doc,
+            json!({"title": "b", "tags": ["x"], "meta": {"pinned": true}})
+        );
+
+        let from = json!({"title": "a", "meta": {"pinned": true, "color": "red"}, "old": 1});
+        let to = json!({"title": "a", "meta": {"pinned": false, "color": "red"}, "new": 2});
+        let diff = merge_patch_diff(&from, &to).unwrap();
+        assert_eq!(
+            diff,
+            json!({"meta": {"pinned": false}, "old": null, "new": 2})
+        );
+        let mut patched = from.clone();
+        merge_patch(&mut patched, &diff);
+        assert_eq!(patched, to);
+
+        // Nulls cannot be expressed in a merge patch
+        assert_eq!(merge_patch_diff(&from, &json!({"title": null})), None);
+    }
+
+    #[test]
+    fn test_json_patch() {
+        use serde_json::json;
+
+        let doc = json!({"title": "a", "tags": ["x", "y"], "meta": {}});
+        let patched = json_patch(
+            &doc,
+            &json!([
+                {"op": "test", "path": "/title", "value": "a"},
+                {"op": "replace", "path": "/title", "value": "b"},
+                {"op": "add", "path": "/tags/1", "value": "z"},
+                {"op": "add", "path": "/tags/-", "value": "w"},
+                {"op": "remove", "path": "/tags/0"},
+                {"op": "copy", "from": "/title", "path": "/meta/title"},
+                {"op": "move", "from": "/meta/title", "path": "/subtitle"}
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            patched,
+            json!({"title": "b", "subtitle": "b", "tags": ["z", "y", "w"], "meta": {}})
+        );
+
+        // A failing operation rejects the whole patch
+        let err = json_patch(
+            &doc,
+            &json!([
+                {"op": "replace", "path": "/title", "value": "b"},
+                {"op": "test", "path": "/title", "value": "a"}
+            ]),
+        );
+        assert!(err.is_err());
+        assert!(json_patch(&doc, &json!([{"op": "remove", "path": "/missing"}])).is_err());
+
+        // Applied as an operation, a failing patch leaves the entity unchanged
+        let op = entity_op(
+            1,
+            OP_JSON_PATCH,
+            json!({"id": "n1", "patch": [{"op": "remove", "path": "/missing"}]}),
+        );
+        assert_eq!(apply_entity_op(Some(doc.clone()), &op), Some(doc));
+    }
+
+    #[test]
+    fn test_entity_state_snapshots() {
+        use serde_json::json;
+
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        local_apply(
+            &mut conn,
+            &entity_op(10, OP_INSERT, json!({"id": "n1", "value": {"count": 0}})),
+        )
+        .unwrap();
+        for i in 1..=SNAPSHOT_INTERVAL as i64 {
+            let patch = json!({"id": "n1", "patch": {"count": i}});
+            local_apply(&mut conn, &entity_op(10 + i * 10, OP_MERGE_PATCH, patch)).unwrap();
+        }
+        let snapshots = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM entity_snapshots", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        let expected = json!({"count": SNAPSHOT_INTERVAL});
+        // Reads never write, so they work on a read-only connection
+        conn.pragma_update(None, "query_only", true).unwrap();
+        assert_eq!(
+            entity_state(&conn, "notes", "n1").unwrap(),
+            Some(expected.clone())
+        );
+        assert_eq!(snapshots(&conn), 0);
+        conn.pragma_update(None, "query_only", false).unwrap();
+
+        assert_eq!(
+            snapshot_entity(&conn, "notes", "n1").unwrap(),
+            Some(expected.clone())
+        );
+        assert_eq!(snapshots(&conn), 1);
+        // Served from the snapshot
+        assert_eq!(entity_state(&conn, "notes", "n1").unwrap(), Some(expected));
+
+        // A late operation older than the snapshot invalidates it
+        let late = json!({"id": "n1", "patch": {"late": true}});
+        merge(&mut conn, &[entity_op(15, OP_MERGE_PATCH, late)]).unwrap();
+        assert_eq!(snapshots(&conn), 0);
+        assert_eq!(
+            entity_state(&conn, "notes", "n1").unwrap(),
+            Some(json!({"count": SNAPSHOT_INTERVAL, "late": true}))
+        );
+    }
+
+    #[test]
+    fn test_invalid_operations_are_rejected_or_quarantined() {
+        use crate::db::schema_registry;
+        use serde_json::json;
+
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        let schema = json!({"type": "object", "required": ["id"]});
+        schema_registry::register_schema(&conn, "notes", 0, &["create"], Some(&schema)).unwrap();
+
+        let bad_local = entity_op(1, "create", json!({"title": "no id"}));
+        assert!(matches!(
+            local_apply(&mut conn, &bad_local),
+            Err(AhenkError::Validation(_))
+        ));
+
+        let good = entity_op(2, "create", json!({"id": "n1"}));
+        let bad_type = entity_op(3, "purge", json!({"id": "n1"}));
+        merge(&mut conn, &[good.clone(), bad_type.clone()]).unwrap();
+        let stored = crate::db::operations::get_oplog_entries_since(&conn, 0).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, good.id);
+        let quarantined = schema_registry::quarantined_ops(&conn).unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].entry.id, bad_type.id);
+        assert!(quarantined[0].reason.contains("'purge' is not allowed"));
+
+        // Accepted once the schema allows it
+        let retry = |conn: &mut Connection| {
+            retry_quarantined(conn, &SystemClock, &DriftBound::default()).unwrap()
+        };
+        assert!(retry(&mut conn).is_empty());
+        schema_registry::register_schema(&conn, "notes", 0, &["create", "purge"], Some(&schema))
+            .unwrap();
+        let accepted = retry(&mut conn);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].id, bad_type.id);
+        assert!(schema_registry::quarantined_ops(&conn).unwrap().is_empty());
+        assert_eq!(
+            crate::db::operations::get_oplog_entries_since(&conn, 0)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_batches_commit_atomically() {
+        use crate::db::{operations, schema_registry};
+        use serde_json::json;
+
+        let mut conn = operations::initialize_database(":memory:").unwrap();
+        let schema = json!({"type": "object", "required": ["id"]});
+        schema_registry::register_schema(&conn, "notes", 0, &["create"], Some(&schema)).unwrap();
+
+        // One invalid operation rejects the whole local batch
+        let good = entity_op(1, "create", json!({"id": "n1"}));
+        let bad = entity_op(2, "create", json!({"title": "no id"}));
+        assert!(matches!(
+            local_apply_batch(&mut conn, &[good.clone(), bad]),
+            Err(AhenkError::Validation(_))
+        ));
+        assert!(operations::get_oplog_entries_since(&conn, 0)
+            .unwrap()
+            .is_empty());
+
+        // Duplicates, in the oplog or within the batch, are recorded once
+        let other = entity_op(3, "create", json!({"id": "n2"}));
+        assert_eq!(
+            local_apply_batch(&mut conn, std::slice::from_ref(&good)).unwrap(),
+            1
+        );
+        let batch = [good.clone(), other.clone(), other.clone()];
+        assert_eq!(local_apply_batch(&mut conn, &batch).unwrap(), 1);
+        assert_eq!(
+            operations::get_oplog_entries_since(&conn, 0).unwrap().len(),
+            2
+        );
+
+        // App writes in the caller's transaction roll back with the merged batch
+        conn.execute("CREATE TABLE app_notes (id TEXT PRIMARY KEY)", [])
+            .unwrap();
+        let remote = entity_op(4, "create", json!({"id": "n3"}));
+        {
+            let tx = conn.transaction().unwrap();
+            tx.execute("INSERT INTO app_notes (id) VALUES ('n3')", [])
+                .unwrap();
+            merge_in(
+                &tx,
+                std::slice::from_ref(&remote),
+                &SystemClock,
+                &DriftBound::default(),
+            )
+            .unwrap();
+            // Dropped without committing
+        }
+        let app_rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM app_notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(app_rows, 0);
+        assert!(!operations::oplog_entry_exists(&conn, remote.id).unwrap());
+
+        let tx = conn.transaction().unwrap();
+        tx.execute("INSERT INTO app_notes (id) VALUES ('n3')", [])
+            .unwrap();
+        merge_in(
+            &tx,
+            std::slice::from_ref(&remote),
+            &SystemClock,
+            &DriftBound::default(),
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        assert!(operations::oplog_entry_exists(&conn, remote.id).unwrap());
+    }
+
+    #[test]
+    fn test_versioned_payloads_are_upcast_or_held_back() {
+        use crate::db::schema_registry;
+        use serde_json::json;
+
+        let mut conn = crate::db::operations::initialize_database(":memory:").unwrap();
+        let table = "versioned_people";
+        let schema = json!({"properties": {"value": {"required": ["name"]}}});
+        let op_types = [OP_INSERT, OP_UPDATE];
+        schema_registry::register_schema(&conn, table, 1, &op_types, Some(&schema)).unwrap();
+        schema_registry::register_upcaster(table, 0, |mut data| {
+            let value = data.get_mut("value").ok_or("no value")?;
+            let name = value["full_name"].take();
+            value["name"] = name;
+            value
+                .as_object_mut()
+                .ok_or("not an object")?
+                .remove("full_name");
+            Ok(data)
+        });
+
+        // A version 0 payload from an old device is upcast before validation and apply
+        let mut old = entity_op(
+            1,
+            OP_INSERT,
+            json!({"id": "p1", "value": {"full_name": "Ada"}}),
+        );
+        old.table = table.to_string();
+        merge(&mut conn, std::slice::from_ref(&old)).unwrap();
+        assert_eq!(
+            entity_state(&conn, table, "p1").unwrap(),
+            Some(json!({"name": "Ada"}))
+        );
+        let stored = crate::db::operations::get_table_oplog_entries(&conn, table).unwrap();
+        assert_eq!(stored[0].schema_version, 0);
+        assert_eq!(stored[0].data, old.data);
+
+        // A version 2 payload from a newer device waits for the upgrade
+        let mut newer = entity_op(
+            2,
+            OP_UPDATE,
+            json!({"id": "p1", "value": {"name": "Ada L"}}),
+        );
+        newer.table = table.to_string();
+        newer.schema_version = 2;
+        merge(&mut conn, std::slice::from_ref(&newer)).unwrap();
+        assert_eq!(schema_registry::held_ops(&conn).unwrap().len(), 1);
+        let retry = |conn: &mut Connection| {
+            retry_held_ops(conn, &SystemClock, &DriftBound::default()).unwrap()
+        };
+        assert!(retry(&mut conn).is_empty());
+
+        schema_registry::register_schema(&conn, table, 2, &op_types, Some(&schema)).unwrap();
+        schema_registry::register_upcaster(table, 1, Ok);
+        let merged = retry(&mut conn);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].id, newer.id);
+        assert!(schema_registry::held_ops(&conn).unwrap().is_empty());
+        assert_eq!(
+            entity_state(&conn, table, "p1").unwrap(),
+            Some(json!({"name": "Ada L"}))
+        );
+    }
+
     #[test]
     fn test_hlc_roundtrip() {
         let hlc = HybridLogicalClock::now();
@@ -0,0 +1,543 @@
+//! Database export archives.
+//!
+//! An archive is a copy of the database taken with the online backup API (see
+//! [`super::backup`]), so exporting is safe while the daemon writes and includes
+//! whatever is still in the WAL. It starts with a small header recording the
+//! schema version, which is checked before anything is restored, and can be
+//! gzip-compressed and encrypted with a passphrase:
+//!
+//! ```text
+//! magic "AHENKDB\0" | format u8 | flags u8 | schema version i32 | created at i64 (ms)
+//! [salt 16 bytes | nonce 24 bytes]   if encrypted
+//! payload                            SQLite database, optionally gzip, optionally sealed
+//! ```
+//!
+//! Encryption uses XChaCha20-Poly1305 with a key derived from the passphrase by
+//! Argon2id; the header is authenticated along with the payload. Plain SQLite
+//! files, as written by older exports, can be imported too.
+
+use super::backup::{self, BackupConfig};
+use super::migrations::{self, MigrationState, CORE_NAMESPACE};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{Connection, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"AHENKDB\0";
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const FORMAT: u8 = 1;
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 2;
+const HEADER_LEN: usize = 8 + 1 + 1 + 4 + 8;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// How an archive is written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveOptions {
+    /// gzip the database
+    pub compress: bool,
+    /// Encrypt the archive with a key derived from this passphrase
+    pub passphrase: Option<String>,
+}
+
+/// What an archive's header says about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveInfo {
+    /// Core schema version of the archived database
+    pub schema_version: i32,
+    /// When the archive was written; `None` for plain SQLite files
+    pub created_at: Option<DateTime<Utc>>,
+    pub compressed: bool,
+    pub encrypted: bool,
+}
+
+/// Write an archive of the database to `dest`.
+///
+/// The copy is integrity-checked before it is written out. It is taken in a
+/// temporary directory only the current user can read, so an encrypted export
+/// leaves no plaintext copy where others could pick it up.
+pub fn export_archive(
+    conn: &Connection,
+    dest: &Path,
+    options: &ArchiveOptions,
+) -> Result<ArchiveInfo> {
+    let dir = PrivateDir::create()?;
+    let snapshot = dir.0.join("snapshot.db");
+    (|| {
+        backup::backup_database(conn, &snapshot)?;
+        let schema_version = {
+            let copy = Connection::open(&snapshot)?;
+            backup::check_integrity(&copy)?;
+            migrations::get_current_version(&copy)?
+        };
+        let database = fs::read(&snapshot).map_err(|e| io_error(&snapshot, e))?;
+
+        let info = ArchiveInfo {
+            schema_version,
+            // The header stores milliseconds
+            created_at: Some(Utc::now().trunc_subsecs(3)),
+            compressed: options.compress,
+            encrypted: options.passphrase.is_some(),
+        };
+        let archive = pack(&info, &database, options.passphrase.as_deref())?;
+        fs::write(dest, archive).map_err(|e| io_error(dest, e))?;
+        Ok(info)
+    })()
+}
+
+/// Read an archive's header, without decrypting it
+pub fn read_archive_info(src: &Path) -> Result<ArchiveInfo> {
+    let mut file = fs::File::open(src).map_err(|e| io_error(src, e))?;
+    let mut header = [0u8; HEADER_LEN];
+    let read = read_up_to(&mut file, &mut header).map_err(|e| io_error(src, e))?;
+    if read >= SQLITE_MAGIC.len() && header.starts_with(SQLITE_MAGIC) {
+        drop(file);
+        let conn = Connection::open_with_flags(src, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        return Ok(ArchiveInfo {
+            schema_version: plain_schema_version(&conn)?,
+            created_at: None,
+            compressed: false,
+            encrypted: false,
+        });
+    }
+    parse_header(&header[..read])
+}
+
+/// Unpack the archive at `src` into a plain database at `dest`, checking its
+/// integrity and that this build of Ahenk can open it
+pub fn unpack_archive(src: &Path, passphrase: Option<&str>, dest: &Path) -> Result<ArchiveInfo> {
+    let archive = fs::read(src).map_err(|e| io_error(src, e))?;
+    let info = if archive.starts_with(SQLITE_MAGIC) {
+        fs::write(dest, &archive).map_err(|e| io_error(dest, e))?;
+        read_archive_info(dest)?
+    } else {
+        let (info, database) = unpack(&archive, passphrase)?;
+        fs::write(dest, database).map_err(|e| io_error(dest, e))?;
+        info
+    };
+
+    let conn = Connection::open(dest)?;
+    // Database files copied from a WAL database would leave `-wal` and `-shm`
+    // files behind once opened read-only for the restore
+    let _mode: String = conn.query_row("PRAGMA journal_mode = DELETE", [], |row| row.get(0))?;
+    backup::check_integrity(&conn)?;
+    check_compatible(&conn)?;
+    Ok(info)
+}
+
+/// Replace the database with the archive at `src` and bring it up to date.
+///
+/// The archive is unpacked next to the database and checked first; nothing
+/// changes if it is corrupt, encrypted with another passphrase, or written by a
+/// newer version of Ahenk. The current database is backed up as `backups`
+/// configures before it is overwritten, and restored if applying migrations to
+/// the imported copy fails.
+pub fn import_archive(
+    conn: &Connection,
+    src: &Path,
+    passphrase: Option<&str>,
+    backups: &BackupConfig,
+) -> Result<ArchiveInfo> {
+    let db_path = conn
+        .path()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| invalid("Archives can only be imported into a database file"))?;
+    let unpacked = temp_path(&db_path);
+    let result = (|| {
+        let info = unpack_archive(src, passphrase, &unpacked)?;
+        backup::with_backup(conn, backups, |conn| {
+            backup::restore_database(conn, &unpacked)?;
+            migrations::apply_pending_migrations(conn)
+        })?;
+        Ok(info)
+    })();
+    let _ = fs::remove_file(&unpacked);
+    result
+}
+
+/// Archived databases must be Ahenk databases no newer than this build, with
+/// unmodified core migrations
+fn check_compatible(conn: &Connection) -> Result<()> {
+    let version = plain_schema_version(conn)?;
+    if version == 0 {
+        return Err(invalid("The archive is not an Ahenk database"));
+    }
+    if version > migrations::latest_version() {
+        return Err(invalid(format!(
+            "The archive has schema version {}, newer than the {} this version of Ahenk supports",
+            version,
+            migrations::latest_version()
+        )));
+    }
+    let drift = migrations::verify_migrations(conn)?
+        .into_iter()
+        .find(|status| status.namespace == CORE_NAMESPACE);
+    if let Some(status) = drift {
+        let state = match status.state {
+            MigrationState::Modified { .. } => "was modified",
+            _ => "is unknown",
+        };
+        return Err(invalid(format!(
+            "Migration {} ({}) of the archive {}",
+            status.version, status.description, state
+        )));
+    }
+    Ok(())
+}
+
+/// Core schema version of a database that may lack the `schema_version` table
+fn plain_schema_version(conn: &Connection) -> Result<i32> {
+    let has_table: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_table {
+        return Ok(0);
+    }
+    let has_namespace: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info('schema_version') WHERE name = 'namespace')",
+        [],
+        |row| row.get(0),
+    )?;
+    let sql = if has_namespace {
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version WHERE namespace = 'ahenk'"
+    } else {
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version"
+    };
+    conn.query_row(sql, [], |row| row.get(0))
+}
+
+fn pack(info: &ArchiveInfo, database: &[u8], passphrase: Option<&str>) -> Result<Vec<u8>> {
+    let mut flags = 0;
+    if info.compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+    if info.encrypted {
+        flags |= FLAG_ENCRYPTED;
+    }
+    let mut header = Vec::with_capacity(HEADER_LEN + SALT_LEN + NONCE_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT);
+    header.push(flags);
+    header.extend_from_slice(&info.schema_version.to_le_bytes());
+    let created_at = info.created_at.map_or(0, |time| time.timestamp_millis());
+    header.extend_from_slice(&created_at.to_le_bytes());
+
+    let payload = if info.compressed {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(database)
+            .and_then(|()| encoder.finish())
+            .map_err(|e| invalid(format!("Compression failed: {}", e)))?
+    } else {
+        database.to_vec()
+    };
+
+    let mut archive = header;
+    match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            OsRng.fill_bytes(&mut salt);
+            OsRng.fill_bytes(&mut nonce);
+            archive.extend_from_slice(&salt);
+            archive.extend_from_slice(&nonce);
+            let sealed = seal(passphrase, &salt, &nonce, &archive, &payload)?;
+            archive.extend_from_slice(&sealed);
+        }
+        None => archive.extend_from_slice(&payload),
+    }
+    Ok(archive)
+}
+
+fn unpack(archive: &[u8], passphrase: Option<&str>) -> Result<(ArchiveInfo, Vec<u8>)> {
+    let info = parse_header(archive)?;
+    let mut body_start = HEADER_LEN;
+    let payload = if info.encrypted {
+        let passphrase = passphrase
+            .ok_or_else(|| invalid("The archive is encrypted; a passphrase is needed"))?;
+        body_start += SALT_LEN + NONCE_LEN;
+        if archive.len() < body_start {
+            return Err(invalid("The archive is truncated"));
+        }
+        let salt = &archive[HEADER_LEN..HEADER_LEN + SALT_LEN];
+        let nonce = &archive[HEADER_LEN + SALT_LEN..body_start];
+        open(
+            passphrase,
+            salt,
+            nonce,
+            &archive[..body_start],
+            &archive[body_start..],
+        )?
+    } else {
+        archive[body_start..].to_vec()
+    };
+
+    let database = if info.compressed {
+        let mut database = Vec::new();
+        GzDecoder::new(payload.as_slice())
+            .read_to_end(&mut database)
+            .map_err(|e| invalid(format!("The archive is corrupt: {}", e)))?;
+        database
+    } else {
+        payload
+    };
+    Ok((info, database))
+}
+
+fn parse_header(header: &[u8]) -> Result<ArchiveInfo> {
+    if header.len() < HEADER_LEN || &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not an Ahenk archive or SQLite database"));
+    }
+    if header[8] != FORMAT {
+        return Err(invalid(format!(
+            "Unsupported archive format {}; this version of Ahenk writes format {}",
+            header[8], FORMAT
+        )));
+    }
+    let flags = header[9];
+    let schema_version = i32::from_le_bytes(header[10..14].try_into().expect("4 bytes"));
+    let created_at = i64::from_le_bytes(header[14..22].try_into().expect("8 bytes"));
+    Ok(ArchiveInfo {
+        schema_version,
+        created_at: Utc.timestamp_millis_opt(created_at).single(),
+        compressed: flags & FLAG_COMPRESSED != 0,
+        encrypted: flags & FLAG_ENCRYPTED != 0,
+    })
+}
+
+/// Encrypt `plaintext` with a key derived from `passphrase` and `salt`,
+/// authenticating `aad` along with it
+pub(crate) fn seal(
+    passphrase: &str,
+    salt: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    cipher(passphrase, salt)?
+        .encrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| invalid("Encryption failed"))
+}
+
+/// Decrypt what [`seal`] produced
+pub(crate) fn open(
+    passphrase: &str,
+    salt: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    cipher(passphrase, salt)?
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| invalid("Wrong passphrase, or the data is corrupt"))
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| invalid(format!("Key derivation failed: {}", e)))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn read_up_to(file: &mut fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn temp_path(next_to: &Path) -> PathBuf {
+    let mut name = next_to.as_os_str().to_owned();
+    name.push(format!(".{}.tmp", uuid::Uuid::new_v4()));
+    PathBuf::from(name)
+}
+
+/// A temporary directory only the current user can access, removed on drop
+struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("ahenk-export-{}", uuid::Uuid::new_v4()));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&path).map_err(|e| io_error(&path, e))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> rusqlite::Error {
+    rusqlite::Error::InvalidParameterName(msg.into())
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> rusqlite::Error {
+    invalid(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::operations::initialize_database;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ahenk-archive-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn collections(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM collections ORDER BY name")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn test_encrypted_archive_round_trip() {
+        let dir = temp_dir();
+        let source = initialize_database(dir.join("source.db").to_str().unwrap()).unwrap();
+        source
+            .execute(
+                "INSERT INTO collections (name, created_at) VALUES ('todos', '')",
+                [],
+            )
+            .unwrap();
+
+        let archive = dir.join("export.ahenk");
+        let options = ArchiveOptions {
+            compress: true,
+            passphrase: Some("correct horse".to_string()),
+        };
+        let info = export_archive(&source, &archive, &options).unwrap();
+        assert_eq!(info.schema_version, migrations::latest_version());
+        assert_eq!(read_archive_info(&archive).unwrap(), info);
+        let bytes = fs::read(&archive).unwrap();
+        assert!(!bytes.windows(5).any(|window| window == b"todos"));
+
+        let target = initialize_database(dir.join("target.db").to_str().unwrap()).unwrap();
+        let backups = BackupConfig::default();
+        assert!(import_archive(&target, &archive, None, &backups).is_err());
+        assert!(import_archive(&target, &archive, Some("wrong"), &backups).is_err());
+        assert!(collections(&target).is_empty());
+
+        import_archive(&target, &archive, Some("correct horse"), &backups).unwrap();
+        assert_eq!(collections(&target), vec!["todos"]);
+
+        // Tampering with the header is detected too
+        let mut tampered = bytes.clone();
+        tampered[10] ^= 1;
+        fs::write(&archive, &tampered).unwrap();
+        let unpacked = dir.join("unpacked.db");
+        assert!(unpack_archive(&archive, Some("correct horse"), &unpacked).is_err());
+
+        drop((source, target));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incompatible_archives_are_rejected() {
+        let dir = temp_dir();
+        let source = initialize_database(dir.join("source.db").to_str().unwrap()).unwrap();
+        let archive = dir.join("export.ahenk");
+        let unpacked = dir.join("unpacked.db");
+
+        // Written by a newer Ahenk
+        source
+            .execute(
+                "INSERT INTO schema_version (version, applied_at, description) VALUES (?1, '', 'future')",
+                [migrations::latest_version() + 1],
+            )
+            .unwrap();
+        export_archive(&source, &archive, &ArchiveOptions::default()).unwrap();
+        assert!(unpack_archive(&archive, None, &unpacked).is_err());
+
+        // Not an Ahenk database
+        let other = Connection::open(dir.join("other.db")).unwrap();
+        other.execute_batch("CREATE TABLE t (x)").unwrap();
+        drop(other);
+        assert!(unpack_archive(&dir.join("other.db"), None, &unpacked).is_err());
+
+        drop(source);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plain_database_files_import() {
+        let dir = temp_dir();
+        let old = initialize_database(dir.join("old.db").to_str().unwrap()).unwrap();
+        old.execute(
+            "INSERT INTO collections (name, created_at) VALUES ('notes', '')",
+            [],
+        )
+        .unwrap();
+        // Older exports copied the WAL database file as it was
+        old.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+            .unwrap();
+        fs::copy(dir.join("old.db"), dir.join("export.db")).unwrap();
+
+        let info = read_archive_info(&dir.join("export.db")).unwrap();
+        assert_eq!(info.schema_version, migrations::latest_version());
+        assert!(!info.compressed && !info.encrypted && info.created_at.is_none());
+
+        let target = initialize_database(dir.join("target.db").to_str().unwrap()).unwrap();
+        import_archive(
+            &target,
+            &dir.join("export.db"),
+            None,
+            &BackupConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(collections(&target), vec!["notes"]);
+        let leftovers = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .to_string_lossy()
+                    .contains(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        drop((old, target));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
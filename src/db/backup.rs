@@ -0,0 +1,280 @@
+//! Database backups.
+//!
+//! Backups are taken with SQLite's online backup API, so they are consistent
+//! even while other connections (such as the daemon's) use the database.
+//! [`with_backup`] snapshots the database before a risky change and restores the
+//! snapshot if the change fails; migrations run under it. Backups are kept in a
+//! `backups` directory next to the database, and only the newest
+//! [`BackupConfig::keep`] of them are retained.
+
+use chrono::Utc;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where backups are written and how many are kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupConfig {
+    /// Backups to retain; 0 disables automatic backups
+    pub keep: usize,
+    /// Backup directory; `backups` next to the database if `None`
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self { keep: 3, dir: None }
+    }
+}
+
+/// Copy the database into a new file at `dest`.
+///
+/// The copy uses a rollback journal rather than WAL, so it is a single
+/// self-contained file.
+pub fn backup_database(conn: &Connection, dest: &Path) -> Result<()> {
+    let mut target = Connection::open(dest)?;
+    Backup::new(conn, &mut target)?.run_to_completion(256, Duration::from_millis(10), None)?;
+    let _mode: String = target.query_row("PRAGMA journal_mode = DELETE", [], |row| row.get(0))?;
+    Ok(())
+}
+
+/// Replace the contents of the database with the backup at `src`.
+///
+/// The copy goes through a second connection to the same file, so `conn` sees
+/// the restored contents afterwards. It must not be inside a transaction.
+pub fn restore_database(conn: &Connection, src: &Path) -> Result<()> {
+    let db_path = database_file(conn).ok_or_else(|| {
+        rusqlite::Error::InvalidParameterName(
+            "In-memory databases cannot be restored from a backup".to_string(),
+        )
+    })?;
+    let source = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut target = Connection::open(db_path)?;
+    target.busy_timeout(Duration::from_secs(5))?;
+    let backup = Backup::new(&source, &mut target)?;
+    backup.run_to_completion(256, Duration::from_millis(10), None)
+}
+
+/// Run SQLite's `integrity_check` and fail with the problems it reports
+pub fn check_integrity(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let problems: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    if problems.len() == 1 && problems[0] == "ok" {
+        return Ok(());
+    }
+    Err(rusqlite::Error::InvalidParameterName(format!(
+        "Integrity check failed: {}",
+        problems.join("; ")
+    )))
+}
+
+/// Write a timestamped backup of the database into the backup directory and
+/// delete the oldest ones beyond `config.keep`.
+///
+/// Returns `None` without writing anything for in-memory databases or when
+/// backups are disabled.
+pub fn create_backup(conn: &Connection, config: &BackupConfig) -> Result<Option<PathBuf>> {
+    let Some(db_path) = database_file(conn) else {
+        return Ok(None);
+    };
+    if config.keep == 0 {
+        return Ok(None);
+    }
+
+    let dir = backup_dir(&db_path, config);
+    fs::create_dir_all(&dir).map_err(|e| io_error(&dir, e))?;
+    let dest = dir.join(format!(
+        "{}-{}.db",
+        file_stem(&db_path),
+        Utc::now().format("%Y%m%dT%H%M%S%3fZ")
+    ));
+    backup_database(conn, &dest)?;
+
+    let backups = list_backups(&db_path, config)?;
+    for old in backups.iter().skip(config.keep) {
+        fs::remove_file(old).map_err(|e| io_error(old, e))?;
+    }
+    Ok(Some(dest))
+}
+
+/// Backups of the database at `db_path`, newest first
+pub fn list_backups(db_path: &str, config: &BackupConfig) -> Result<Vec<PathBuf>> {
+    let dir = backup_dir(db_path, config);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let prefix = format!("{}-", file_stem(db_path));
+    let mut backups: Vec<PathBuf> = fs::read_dir(&dir)
+        .map_err(|e| io_error(&dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".db"))
+        })
+        .collect();
+    // Timestamps in the names sort chronologically
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
+
+/// Run `f`, restoring the database from a backup taken just before if it fails.
+///
+/// The error from `f` is returned either way; if restoring fails too, that is
+/// reported on stderr and the backup is left in place. In-memory databases and
+/// disabled backups run `f` without a safety net.
+pub fn with_backup<T, E, F>(
+    conn: &Connection,
+    config: &BackupConfig,
+    f: F,
+) -> std::result::Result<T, E>
+where
+    F: FnOnce(&Connection) -> std::result::Result<T, E>,
+    E: From<rusqlite::Error> + std::fmt::Display,
+{
+    let backup = create_backup(conn, config)?;
+    if let Some(path) = &backup {
+        eprintln!("Backed up database to {}", path.display());
+    }
+
+    let err = match f(conn) {
+        Ok(value) => return Ok(value),
+        Err(err) => err,
+    };
+    if let Some(path) = backup {
+        match restore_database(conn, &path) {
+            Ok(()) => eprintln!("Restored database from {} after: {}", path.display(), err),
+            Err(restore_err) => eprintln!(
+                "Restoring the backup at {} after '{}' failed: {}",
+                path.display(),
+                err,
+                restore_err
+            ),
+        }
+    }
+    Err(err)
+}
+
+/// Path of the connection's main database file, or `None` if it is in memory
+fn database_file(conn: &Connection) -> Option<String> {
+    conn.path()
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+}
+
+fn backup_dir(db_path: &str, config: &BackupConfig) -> PathBuf {
+    config.dir.clone().unwrap_or_else(|| {
+        Path::new(db_path)
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("backups")
+    })
+}
+
+fn file_stem(db_path: &str) -> String {
+    Path::new(db_path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("database")
+        .to_string()
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> rusqlite::Error {
+    rusqlite::Error::InvalidParameterName(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connection::{open_connection, ConnectionConfig};
+
+    fn temp_db() -> (PathBuf, Connection) {
+        let dir = std::env::temp_dir().join(format!("ahenk-backup-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nexus.db");
+        let conn = open_connection(path.to_str().unwrap(), &ConnectionConfig::default()).unwrap();
+        conn.execute_batch("CREATE TABLE notes (body TEXT); INSERT INTO notes VALUES ('kept');")
+            .unwrap();
+        (dir, conn)
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_failed_change_is_rolled_back_from_backup() {
+        let (dir, conn) = temp_db();
+        let config = BackupConfig::default();
+
+        // Committed work before the failure is undone too
+        let result: Result<()> = with_backup(&conn, &config, |conn| {
+            conn.execute_batch("DELETE FROM notes; CREATE TABLE extra (x);")?;
+            conn.execute_batch("NOT SQL")
+        });
+        assert!(result.is_err());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM notes"), 1);
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'extra'"
+            ),
+            0
+        );
+
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        // Backups are single files
+        let backups: Vec<String> = fs::read_dir(dir.join("backups"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with("nexus-") && backups[0].ends_with(".db"));
+
+        drop(conn);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_only_the_newest_backups_are_kept() {
+        let (dir, conn) = temp_db();
+        let config = BackupConfig {
+            keep: 2,
+            dir: Some(dir.join("snapshots")),
+        };
+
+        let mut created = Vec::new();
+        for _ in 0..4 {
+            created.push(create_backup(&conn, &config).unwrap().unwrap());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let db_path = dir.join("nexus.db");
+        let kept = list_backups(db_path.to_str().unwrap(), &config).unwrap();
+        assert_eq!(kept, vec![created[3].clone(), created[2].clone()]);
+
+        let backup = Connection::open(&kept[0]).unwrap();
+        assert_eq!(count(&backup, "SELECT COUNT(*) FROM notes"), 1);
+
+        // Disabled backups and in-memory databases write nothing
+        let disabled = BackupConfig {
+            keep: 0,
+            ..config.clone()
+        };
+        assert_eq!(create_backup(&conn, &disabled).unwrap(), None);
+        let memory = Connection::open_in_memory().unwrap();
+        assert_eq!(create_backup(&memory, &config).unwrap(), None);
+
+        drop(backup);
+        drop(conn);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,693 @@
+//! Automatic change capture for application tables.
+//!
+//! Registering a table with [`register_table`] installs `AFTER INSERT/UPDATE/DELETE`
+//! triggers that append every change to `sync_capture`. Because the triggers live
+//! in the database, writes from any connection are captured, and nothing is lost
+//! if the app forgets a call.
+//!
+//! [`flush_captured_changes`] then moves captured changes into the oplog in the
+//! order they were made, stamping each from the database's HLC. The sync manager
+//! does this periodically; apps may call it directly to sync right away.
+//!
+//! Remote operations on registered tables are applied by `crdt::merge` through
+//! [`apply_remote_ops`] with capture suppressed, so they don't echo back. A remote
+//! operation only wins if no newer operation on the same row has been recorded
+//! and the row has no local change still waiting in `sync_capture`.
+
+use crate::crdt::{self, Clock, OP_DELETE, OP_INSERT, OP_UPDATE};
+use crate::db::{change_feed, operations, schema_registry};
+use crate::models::{OplogEntry, SyncedTable};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Result, Transaction};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Register an application table for sync and install its capture triggers.
+///
+/// `primary_key` must be a column with a PRIMARY KEY or UNIQUE constraint whose
+/// values identify a row on every device (e.g. UUIDs). All current columns are
+/// captured; register again after altering the table to pick up new columns.
+/// BLOB columns are not supported.
+pub fn register_table(conn: &Connection, table: &str, primary_key: &str) -> Result<SyncedTable> {
+    let columns = table_columns(conn, table)?;
+    if columns.is_empty() {
+        return Err(invalid(format!("Table '{}' does not exist", table)));
+    }
+    if let Some((name, _)) = columns
+        .iter()
+        .find(|(_, decl)| decl.to_uppercase().contains("BLOB"))
+    {
+        return Err(invalid(format!(
+            "Column '{}.{}' is a BLOB, which cannot be captured",
+            table, name
+        )));
+    }
+    if !columns.iter().any(|(name, _)| name == primary_key) {
+        return Err(invalid(format!(
+            "Table '{}' has no column '{}'",
+            table, primary_key
+        )));
+    }
+    if !is_unique_column(conn, table, primary_key)? {
+        return Err(invalid(format!(
+            "Column '{}.{}' is neither a PRIMARY KEY nor UNIQUE",
+            table, primary_key
+        )));
+    }
+
+    let synced = SyncedTable {
+        table_name: table.to_string(),
+        primary_key: primary_key.to_string(),
+        columns: columns.into_iter().map(|(name, _)| name).collect(),
+        registered_at: Utc::now(),
+    };
+
+    let columns_json =
+        serde_json::to_string(&synced.columns).map_err(|e| invalid(e.to_string()))?;
+    conn.execute(
+        "INSERT OR REPLACE INTO sync_tables (table_name, primary_key, columns, registered_at) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            synced.table_name,
+            synced.primary_key,
+            columns_json,
+            synced.registered_at.to_rfc3339()
+        ],
+    )?;
+    conn.execute_batch(&trigger_sql(&synced))?;
+    conn.execute_batch(&key_index_sql(&synced))?;
+    change_feed::install_table_triggers(conn, &synced)?;
+
+    Ok(synced)
+}
+
+/// Stop capturing changes to a table and remove its triggers.
+///
+/// Changes already captured are still flushed to the oplog.
+pub fn unregister_table(conn: &Connection, table: &str) -> Result<()> {
+    conn.execute_batch(&drop_trigger_sql(table))?;
+    conn.execute_batch(&format!("DROP INDEX IF EXISTS {};", key_index_name(table)))?;
+    change_feed::drop_table_triggers(conn, table)?;
+    conn.execute("DELETE FROM sync_tables WHERE table_name = ?1", [table])?;
+    Ok(())
+}
+
+/// All tables registered for sync
+pub fn registered_tables(conn: &Connection) -> Result<Vec<SyncedTable>> {
+    let mut stmt = conn.prepare(
+        "SELECT table_name, primary_key, columns, registered_at FROM sync_tables ORDER BY table_name",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let columns: String = row.get(2)?;
+        let registered_at: String = row.get(3)?;
+        Ok(SyncedTable {
+            table_name: row.get(0)?,
+            primary_key: row.get(1)?,
+            columns: serde_json::from_str(&columns).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    2,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?,
+            registered_at: DateTime::parse_from_rfc3339(&registered_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        3,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Number of captured changes not yet recorded in the oplog
+pub fn pending_capture_count(conn: &Connection) -> Result<usize> {
+    conn.query_row("SELECT COUNT(*) FROM sync_capture", [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|count| count as usize)
+}
+
+/// Record captured changes in the oplog, in capture order, stamped from the
+/// database's HLC. Returns the new oplog entries so they can be sent to peers.
+pub fn flush_captured_changes(
+    conn: &mut Connection,
+    clock: &dyn Clock,
+    device_id: Uuid,
+) -> Result<Vec<OplogEntry>> {
+    let tx = conn.transaction()?;
+
+    let captured: Vec<(i64, String, String, String)> = {
+        let mut stmt =
+            tx.prepare("SELECT seq, table_name, op_type, data FROM sync_capture ORDER BY seq")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+
+    let mut entries = Vec::with_capacity(captured.len());
+    for (_, table, op_type, data) in &captured {
+        let data: Value = serde_json::from_str(data).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        let timestamp = crdt::next_timestamp_with(&tx, clock)?;
+        let entry = OplogEntry {
+            id: Uuid::new_v4(),
+            device_id,
+            timestamp: timestamp.to_timestamp(),
+            table: table.clone(),
+            op_type: op_type.clone(),
+            data,
+            schema_version: schema_registry::current_version(&tx, table)?,
+        };
+        operations::create_oplog_entry(&tx, &entry)?;
+        entries.push(entry);
+    }
+
+    if let Some((last_seq, ..)) = captured.last() {
+        tx.execute("DELETE FROM sync_capture WHERE seq <= ?1", [last_seq])?;
+    }
+    tx.commit()?;
+
+    Ok(entries)
+}
+
+/// Run `f` in a transaction with change capture suppressed.
+///
+/// Use this when applying remote operations to registered tables by hand, so the
+/// writes are not captured and sent back out as local changes.
+pub fn with_capture_suppressed<T, F>(conn: &mut Connection, f: F) -> Result<T>
+where
+    F: FnOnce(&Transaction) -> Result<T>,
+{
+    let tx = conn.transaction()?;
+    set_suppressed(&tx, true)?;
+    let result = f(&tx)?;
+    set_suppressed(&tx, false)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Apply remote operations on registered tables, oldest first, with capture
+/// suppressed. Operations on other tables are left to the app.
+///
+/// Must be called inside a transaction, after the operations were recorded in
+/// the oplog. Returns the number of operations applied to a table.
+pub fn apply_remote_ops(conn: &Connection, ops: &[&OplogEntry]) -> Result<usize> {
+    let tables = registered_tables(conn)?;
+    let mut ops: Vec<&&OplogEntry> = ops
+        .iter()
+        .filter(|op| tables.iter().any(|t| t.table_name == op.table))
+        .collect();
+    if ops.is_empty() {
+        return Ok(0);
+    }
+    ops.sort_by_key(|op| (op.timestamp, op.id));
+    // Tables registered before key indexes existed get theirs on their first merge
+    for table in tables
+        .iter()
+        .filter(|t| ops.iter().any(|op| op.table == t.table_name))
+    {
+        conn.execute_batch(&key_index_sql(table))?;
+    }
+
+    set_suppressed(conn, true)?;
+    let mut applied = 0;
+    for op in ops {
+        let table = tables
+            .iter()
+            .find(|t| t.table_name == op.table)
+            .expect("filtered to registered tables");
+        let op = match schema_registry::upcast(op) {
+            Ok(op) => op,
+            Err(reason) => {
+                log::warn!("Skipping {} operation {}: {}", op.table, op.id, reason);
+                continue;
+            }
+        };
+        if apply_remote_op(conn, table, &op)? {
+            applied += 1;
+        }
+    }
+    set_suppressed(conn, false)?;
+
+    Ok(applied)
+}
+
+/// Apply one remote operation unless a newer or pending local change to the row exists
+fn apply_remote_op(conn: &Connection, table: &SyncedTable, op: &OplogEntry) -> Result<bool> {
+    let key_path = json_path(&table.primary_key);
+    let data = op.data.to_string();
+    let Some(key) = op.data.get(&table.primary_key).filter(|v| !v.is_null()) else {
+        log::warn!(
+            "Skipping {} operation {} without a '{}' value",
+            op.table,
+            op.id,
+            table.primary_key
+        );
+        return Ok(false);
+    };
+
+    let superseded: bool = conn.prepare_cached(&superseded_sql(table))?.query_row(
+        params![data, op.timestamp, op.id.to_string(), row_key(key)],
+        |row| row.get(0),
+    )?;
+    if superseded {
+        return Ok(false);
+    }
+
+    let quoted_table = quote_ident(&table.table_name);
+    let quoted_key = quote_ident(&table.primary_key);
+    match op.op_type.as_str() {
+        OP_DELETE => {
+            conn.execute(
+                &format!(
+                    "DELETE FROM {} WHERE {} = json_extract(?1, ?2)",
+                    quoted_table, quoted_key
+                ),
+                params![data, key_path],
+            )?;
+        }
+        _ => {
+            // Only columns present in the payload are written
+            let columns: Vec<&String> = table
+                .columns
+                .iter()
+                .filter(|c| op.data.get(c.as_str()).is_some())
+                .collect();
+            let names: Vec<String> = columns.iter().map(|c| quote_ident(c)).collect();
+            let values: Vec<String> = columns
+                .iter()
+                .map(|c| format!("json_extract(?1, '{}')", json_path(c).replace('\'', "''")))
+                .collect();
+            let updates: Vec<String> = names
+                .iter()
+                .filter(|n| **n != quoted_key)
+                .map(|n| format!("{} = excluded.{}", n, n))
+                .collect();
+            let on_conflict = if updates.is_empty() {
+                "DO NOTHING".to_string()
+            } else {
+                format!("DO UPDATE SET {}", updates.join(", "))
+            };
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} ({}) SELECT {} WHERE true ON CONFLICT({}) {}",
+                    quoted_table,
+                    names.join(", "),
+                    values.join(", "),
+                    quoted_key,
+                    on_conflict
+                ),
+                [data],
+            )?;
+        }
+    }
+
+    Ok(true)
+}
+
+fn set_suppressed(conn: &Connection, suppressed: bool) -> Result<()> {
+    conn.execute(
+        "UPDATE sync_capture_state SET suppressed = ?1 WHERE id = 1",
+        [suppressed as i64],
+    )?;
+    Ok(())
+}
+
+/// Column names and declared types of a table, in declaration order
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name, type FROM pragma_table_info(?1)")?;
+    let rows = stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Whether a single column is the table's primary key or covered by a UNIQUE index
+fn is_unique_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let sole_primary_key: bool = conn.query_row(
+        "SELECT COUNT(*) = 1 AND SUM(name = ?2) = 1 FROM pragma_table_info(?1) WHERE pk > 0",
+        params![table, column],
+        |row| row.get::<_, Option<bool>>(0).map(|v| v.unwrap_or(false)),
+    )?;
+    if sole_primary_key {
+        return Ok(true);
+    }
+
+    conn.query_row(
+        "SELECT EXISTS (
+             SELECT 1 FROM pragma_index_list(?1) AS il
+             WHERE il.\"unique\" = 1
+               AND (SELECT COUNT(*) FROM pragma_index_info(il.name)) = 1
+               AND (SELECT name FROM pragma_index_info(il.name)) = ?2
+         )",
+        params![table, column],
+        |row| row.get(0),
+    )
+}
+
+/// SQL creating the capture triggers of a table, replacing existing ones
+fn trigger_sql(table: &SyncedTable) -> String {
+    let quoted_table = quote_ident(&table.table_name);
+    let name = quote_literal(&table.table_name);
+    let row = |prefix: &str| {
+        let fields: Vec<String> = table
+            .columns
+            .iter()
+            .map(|c| format!("{}, {}.{}", quote_literal(c), prefix, quote_ident(c)))
+            .collect();
+        format!("json_object({})", fields.join(", "))
+    };
+    let key = |prefix: &str| format!("{}.{}", prefix, quote_ident(&table.primary_key));
+    let key_only = |prefix: &str| {
+        format!(
+            "json_object({}, {})",
+            quote_literal(&table.primary_key),
+            key(prefix)
+        )
+    };
+    let when = "WHEN (SELECT suppressed FROM sync_capture_state WHERE id = 1) = 0";
+    let insert = "INSERT INTO sync_capture (table_name, op_type, row_key, data)";
+
+    format!(
+        "{drop}
+         CREATE TRIGGER {insert_trigger} AFTER INSERT ON {table} {when}
+         BEGIN
+             {insert} VALUES ({name}, '{op_insert}', {new_key}, {new_row});
+         END;
+         CREATE TRIGGER {update_trigger} AFTER UPDATE ON {table} {when}
+         BEGIN
+             {insert} SELECT {name}, '{op_delete}', {old_key}, {old_key_only}
+                 WHERE {old_key} IS NOT {new_key};
+             {insert} VALUES ({name}, '{op_update}', {new_key}, {new_row});
+         END;
+         CREATE TRIGGER {delete_trigger} AFTER DELETE ON {table} {when}
+         BEGIN
+             {insert} VALUES ({name}, '{op_delete}', {old_key}, {old_key_only});
+         END;",
+        drop = drop_trigger_sql(&table.table_name),
+        insert_trigger = trigger_name(&table.table_name, OP_INSERT),
+        update_trigger = trigger_name(&table.table_name, OP_UPDATE),
+        delete_trigger = trigger_name(&table.table_name, OP_DELETE),
+        table = quoted_table,
+        when = when,
+        insert = insert,
+        name = name,
+        op_insert = OP_INSERT,
+        op_update = OP_UPDATE,
+        op_delete = OP_DELETE,
+        new_key = key("NEW"),
+        old_key = key("OLD"),
+        new_row = row("NEW"),
+        old_key_only = key_only("OLD"),
+    )
+}
+
+fn drop_trigger_sql(table: &str) -> String {
+    [OP_INSERT, OP_UPDATE, OP_DELETE]
+        .iter()
+        .map(|op| format!("DROP TRIGGER IF EXISTS {};", trigger_name(table, op)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether a newer operation on the row of `?1`'s key was recorded, or a local
+/// change to it is pending. Spelled exactly like the table's key index, which
+/// SQLite only uses when the key expression and table name match it literally.
+fn superseded_sql(table: &SyncedTable) -> String {
+    format!(
+        "SELECT EXISTS (
+             SELECT 1 FROM oplog
+             WHERE table_name = {name}
+               AND {key} = json_extract(?1, {path})
+               AND (timestamp > ?2 OR (timestamp = ?2 AND id > ?3))
+         ) OR EXISTS (
+             SELECT 1 FROM sync_capture WHERE table_name = {name} AND row_key = ?4
+         )",
+        name = quote_literal(&table.table_name),
+        key = key_expression(&table.primary_key),
+        path = quote_literal(&json_path(&table.primary_key)),
+    )
+}
+
+/// Partial index on the oplog entries of one table by primary key, so finding
+/// newer operations on a row doesn't scan the table's whole history
+fn key_index_sql(table: &SyncedTable) -> String {
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON oplog({}, timestamp, id) WHERE table_name = {};",
+        key_index_name(&table.table_name),
+        key_expression(&table.primary_key),
+        quote_literal(&table.table_name)
+    )
+}
+
+fn key_index_name(table: &str) -> String {
+    quote_ident(&format!("ahenk_oplog_key_{}", table))
+}
+
+/// The primary key of an oplog entry's payload
+fn key_expression(primary_key: &str) -> String {
+    format!(
+        "json_extract(data, {})",
+        quote_literal(&json_path(primary_key))
+    )
+}
+
+fn trigger_name(table: &str, op: &str) -> String {
+    quote_ident(&format!("ahenk_capture_{}_{}", table, op))
+}
+
+/// Text form of a primary key value, matching what the triggers store in `row_key`
+fn row_key(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn json_path(column: &str) -> String {
+    format!("$.\"{}\"", column.replace('"', "\\\""))
+}
+
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+pub(crate) fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn invalid(message: String) -> rusqlite::Error {
+    rusqlite::Error::InvalidParameterName(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::operations::initialize_database;
+    use crate::testing::{oplog_entry, ManualClock};
+    use serde_json::json;
+
+    fn setup() -> Connection {
+        let conn = initialize_database(":memory:").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notes (id TEXT PRIMARY KEY, title TEXT NOT NULL, pinned INTEGER)",
+        )
+        .unwrap();
+        register_table(&conn, "notes", "id").unwrap();
+        conn
+    }
+
+    fn note_title(conn: &Connection, id: &str) -> Option<String> {
+        conn.query_row("SELECT title FROM notes WHERE id = ?1", [id], |row| {
+            row.get(0)
+        })
+        .ok()
+    }
+
+    #[test]
+    fn test_register_table_validates() {
+        let conn = initialize_database(":memory:").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE plain (id TEXT, body TEXT);
+             CREATE TABLE files (id TEXT PRIMARY KEY, content BLOB);
+             CREATE TABLE tagged (rowid_key INTEGER PRIMARY KEY, tag TEXT UNIQUE);",
+        )
+        .unwrap();
+
+        assert!(register_table(&conn, "missing", "id").is_err());
+        assert!(register_table(&conn, "plain", "id").is_err());
+        assert!(register_table(&conn, "files", "id").is_err());
+        assert!(register_table(&conn, "tagged", "nope").is_err());
+
+        let tagged = register_table(&conn, "tagged", "tag").unwrap();
+        assert_eq!(tagged.columns, vec!["rowid_key", "tag"]);
+        assert_eq!(registered_tables(&conn).unwrap().len(), 1);
+
+        unregister_table(&conn, "tagged").unwrap();
+        assert!(registered_tables(&conn).unwrap().is_empty());
+        conn.execute("INSERT INTO tagged (tag) VALUES ('x')", [])
+            .unwrap();
+        assert_eq!(pending_capture_count(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_writes_are_captured_and_flushed_in_order() {
+        let mut conn = setup();
+        let clock = ManualClock::default();
+        let device_id = Uuid::new_v4();
+
+        conn.execute_batch(
+            "INSERT INTO notes (id, title) VALUES ('n1', 'Draft');
+             UPDATE notes SET title = 'Final', pinned = 1 WHERE id = 'n1';
+             INSERT INTO notes (id, title) VALUES ('n2', 'Other');
+             UPDATE notes SET id = 'n3' WHERE id = 'n2';
+             DELETE FROM notes WHERE id = 'n1';",
+        )
+        .unwrap();
+        assert_eq!(pending_capture_count(&conn).unwrap(), 6);
+
+        let entries = flush_captured_changes(&mut conn, &clock, device_id).unwrap();
+        let ops: Vec<(&str, &Value)> = entries
+            .iter()
+            .map(|e| (e.op_type.as_str(), &e.data))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (
+                    OP_INSERT,
+                    &json!({"id": "n1", "title": "Draft", "pinned": null})
+                ),
+                (
+                    OP_UPDATE,
+                    &json!({"id": "n1", "title": "Final", "pinned": 1})
+                ),
+                (
+                    OP_INSERT,
+                    &json!({"id": "n2", "title": "Other", "pinned": null})
+                ),
+                (OP_DELETE, &json!({"id": "n2"})),
+                (
+                    OP_UPDATE,
+                    &json!({"id": "n3", "title": "Other", "pinned": null})
+                ),
+                (OP_DELETE, &json!({"id": "n1"})),
+            ]
+        );
+        assert!(entries.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+        assert!(entries.iter().all(|e| e.device_id == device_id));
+
+        assert_eq!(pending_capture_count(&conn).unwrap(), 0);
+        assert_eq!(
+            operations::get_oplog_entries_since(&conn, 0).unwrap().len(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_merge_applies_remote_ops_without_echo() {
+        let mut conn = setup();
+        let clock = ManualClock::default();
+        let remote_device = Uuid::new_v4();
+        let op = |op_type: &str, data: Value, timestamp: i64| OplogEntry {
+            device_id: remote_device,
+            ..oplog_entry(timestamp, "notes", op_type, data)
+        };
+        let t0 = crdt::HybridLogicalClock::now_with(&clock).to_timestamp();
+
+        crdt::merge_with(
+            &mut conn,
+            &[
+                op(OP_UPDATE, json!({"id": "r1", "title": "Newer"}), t0 + 2),
+                op(OP_INSERT, json!({"id": "r1", "title": "Older"}), t0 + 1),
+                op(OP_INSERT, json!({"id": "r2", "title": "Gone"}), t0 + 1),
+                op(OP_DELETE, json!({"id": "r2"}), t0 + 3),
+            ],
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+
+        assert_eq!(note_title(&conn, "r1").as_deref(), Some("Newer"));
+        assert_eq!(note_title(&conn, "r2"), None);
+        assert_eq!(pending_capture_count(&conn).unwrap(), 0);
+
+        // An older remote op arriving late does not overwrite the row
+        crdt::merge_with(
+            &mut conn,
+            &[op(OP_UPDATE, json!({"id": "r1", "title": "Stale"}), t0)],
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+        assert_eq!(note_title(&conn, "r1").as_deref(), Some("Newer"));
+
+        // A local change that has not been flushed yet wins over remote ops
+        conn.execute("UPDATE notes SET title = 'Local' WHERE id = 'r1'", [])
+            .unwrap();
+        crdt::merge_with(
+            &mut conn,
+            &[op(
+                OP_UPDATE,
+                json!({"id": "r1", "title": "Remote"}),
+                t0 + 10,
+            )],
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+        assert_eq!(note_title(&conn, "r1").as_deref(), Some("Local"));
+        assert_eq!(pending_capture_count(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_superseded_check_uses_key_index() {
+        let conn = setup();
+        let table = &registered_tables(&conn).unwrap()[0];
+
+        let plan: Vec<String> = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", superseded_sql(table)))
+            .unwrap()
+            .query_map(params!["{}", 0, "", ""], |row| row.get(3))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(
+            plan.iter()
+                .any(|step| step.contains(&format!("ahenk_oplog_key_{}", table.table_name))),
+            "{:?}",
+            plan
+        );
+
+        unregister_table(&conn, &table.table_name).unwrap();
+        let indexes: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name LIKE 'ahenk_oplog_key_%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 0);
+    }
+
+    #[test]
+    fn test_with_capture_suppressed() {
+        let mut conn = setup();
+
+        with_capture_suppressed(&mut conn, |tx| {
+            tx.execute("INSERT INTO notes (id, title) VALUES ('s1', 'Quiet')", [])
+        })
+        .unwrap();
+        assert_eq!(note_title(&conn, "s1").as_deref(), Some("Quiet"));
+        assert_eq!(pending_capture_count(&conn).unwrap(), 0);
+
+        // Capture resumes afterwards
+        conn.execute("UPDATE notes SET title = 'Loud' WHERE id = 's1'", [])
+            .unwrap();
+        assert_eq!(pending_capture_count(&conn).unwrap(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+//! Row-level feed of changes to collections and synced app tables.
+//!
+//! Changes to collection documents are recorded by triggers from migration 008.
+//! Synced app tables get their own feed triggers from [`install_table_triggers`],
+//! which `capture::register_table` calls. Unlike the capture triggers these are
+//! never suppressed, so merged remote changes appear in the feed as well.
+
+use crate::db::capture::{self, quote_ident, quote_literal};
+use crate::db::documents;
+use crate::models::SyncedTable;
+use rusqlite::{Connection, OptionalExtension, Result};
+use serde_json::Value;
+
+/// Feed rows kept behind the newest one read, for readers that lag behind
+pub const FEED_RETENTION: i64 = 10_000;
+
+/// Kind of change recorded in the feed
+pub const CHANGE_INSERTED: &str = "inserted";
+pub const CHANGE_UPDATED: &str = "updated";
+pub const CHANGE_DELETED: &str = "deleted";
+
+/// One row of the change feed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedChange {
+    /// Position in the feed
+    pub seq: i64,
+    /// Collection or table name
+    pub source: String,
+    /// Document ID or primary key value
+    pub row_id: String,
+    /// `inserted`, `updated` or `deleted`
+    pub change: String,
+}
+
+/// Sequence number of the newest change, 0 if none
+pub fn latest_seq(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(seq), 0) FROM change_feed", [], |row| {
+        row.get(0)
+    })
+}
+
+/// Changes after `seq`, oldest first
+pub fn changes_since(conn: &Connection, seq: i64) -> Result<Vec<FeedChange>> {
+    let mut stmt = conn.prepare(
+        "SELECT seq, source, row_id, change FROM change_feed WHERE seq > ?1 ORDER BY seq",
+    )?;
+    let rows = stmt.query_map([seq], |row| {
+        Ok(FeedChange {
+            seq: row.get(0)?,
+            source: row.get(1)?,
+            row_id: row.get(2)?,
+            change: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Delete changes more than [`FEED_RETENTION`] rows behind `seq`
+pub fn prune(conn: &Connection, seq: i64) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM change_feed WHERE seq <= ?1",
+        [seq - FEED_RETENTION],
+    )
+}
+
+/// Install the feed triggers of a synced app table, replacing existing ones
+pub fn install_table_triggers(conn: &Connection, table: &SyncedTable) -> Result<()> {
+    let quoted_table = quote_ident(&table.table_name);
+    let name = quote_literal(&table.table_name);
+    let key = |prefix: &str| format!("{}.{}", prefix, quote_ident(&table.primary_key));
+    let insert = "INSERT INTO change_feed (source, row_id, change)";
+
+    conn.execute_batch(&format!(
+        "{drop}
+         CREATE TRIGGER {insert_trigger} AFTER INSERT ON {table}
+         BEGIN
+             {insert} VALUES ({name}, {new_key}, '{inserted}');
+         END;
+         CREATE TRIGGER {update_trigger} AFTER UPDATE ON {table}
+         BEGIN
+             {insert} SELECT {name}, {old_key}, '{deleted}' WHERE {old_key} IS NOT {new_key};
+             {insert} SELECT {name}, {new_key},
+                 CASE WHEN {old_key} IS NOT {new_key} THEN '{inserted}' ELSE '{updated}' END;
+         END;
+         CREATE TRIGGER {delete_trigger} AFTER DELETE ON {table}
+         BEGIN
+             {insert} VALUES ({name}, {old_key}, '{deleted}');
+         END;",
+        drop = drop_table_triggers_sql(&table.table_name),
+        insert_trigger = trigger_name(&table.table_name, "insert"),
+        update_trigger = trigger_name(&table.table_name, "update"),
+        delete_trigger = trigger_name(&table.table_name, "delete"),
+        table = quoted_table,
+        insert = insert,
+        name = name,
+        new_key = key("NEW"),
+        old_key = key("OLD"),
+        inserted = CHANGE_INSERTED,
+        updated = CHANGE_UPDATED,
+        deleted = CHANGE_DELETED,
+    ))
+}
+
+/// Remove the feed triggers of a synced app table
+pub fn drop_table_triggers(conn: &Connection, table: &str) -> Result<()> {
+    conn.execute_batch(&drop_table_triggers_sql(table))
+}
+
+/// Current state of a row as JSON: the document of a collection or the columns of
+/// a synced table. `None` if the row does not exist (or is deleted).
+pub fn row_state(conn: &Connection, source: &str, row_id: &str) -> Result<Option<Value>> {
+    if documents::is_collection(conn, source)? {
+        return Ok(documents::get_document(conn, source, row_id)?.and_then(|doc| doc.data));
+    }
+    let Some(table) = synced_table(conn, source)? else {
+        return Ok(None);
+    };
+
+    let raw: Option<String> = conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM {} WHERE {} = ?1",
+                json_object_sql(&table),
+                quote_ident(&table.table_name),
+                quote_ident(&table.primary_key)
+            ),
+            [row_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    raw.map(|raw| parse(&raw)).transpose()
+}
+
+/// All current rows of a collection or synced table, as IDs and JSON
+pub fn all_rows(conn: &Connection, source: &str) -> Result<Vec<(String, Value)>> {
+    if documents::is_collection(conn, source)? {
+        return Ok(documents::list_documents(conn, source)?
+            .into_iter()
+            .filter_map(|doc| doc.data.map(|data| (doc.id, data)))
+            .collect());
+    }
+    let Some(table) = synced_table(conn, source)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT CAST({} AS TEXT), {} FROM {}",
+        quote_ident(&table.primary_key),
+        json_object_sql(&table),
+        quote_ident(&table.table_name)
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        let raw: String = row.get(1)?;
+        Ok((row.get(0)?, parse(&raw)?))
+    })?;
+    rows.collect()
+}
+
+/// Whether `source` is a collection or a synced table
+pub fn is_source(conn: &Connection, source: &str) -> Result<bool> {
+    Ok(documents::is_collection(conn, source)? || synced_table(conn, source)?.is_some())
+}
+
+/// The registration of a synced table, if `name` is one
+pub fn synced_table(conn: &Connection, name: &str) -> Result<Option<SyncedTable>> {
+    Ok(capture::registered_tables(conn)?
+        .into_iter()
+        .find(|t| t.table_name == name))
+}
+
+fn json_object_sql(table: &SyncedTable) -> String {
+    let fields: Vec<String> = table
+        .columns
+        .iter()
+        .map(|c| format!("{}, {}", quote_literal(c), quote_ident(c)))
+        .collect();
+    format!("json_object({})", fields.join(", "))
+}
+
+fn drop_table_triggers_sql(table: &str) -> String {
+    ["insert", "update", "delete"]
+        .iter()
+        .map(|op| format!("DROP TRIGGER IF EXISTS {};", trigger_name(table, op)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn trigger_name(table: &str, op: &str) -> String {
+    quote_ident(&format!("ahenk_feed_{}_{}", table, op))
+}
+
+fn parse(raw: &str) -> Result<Value> {
+    serde_json::from_str(raw).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
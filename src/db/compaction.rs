@@ -0,0 +1,429 @@
+//! Oplog compaction.
+//!
+//! Without compaction the oplog keeps every operation forever, and a device that
+//! syncs from the start receives the whole history. [`compact_oplog`] collapses
+//! the operations on each entity up to a horizon into one snapshot entry:
+//!
+//! - In a collection, an entity's operations become a single `insert` of its
+//!   merged state (or a `delete` tombstone).
+//! - In a table registered for change capture, every operation carries the whole
+//!   row, so all but the newest operation on a row are dropped.
+//!
+//! Other tables are left alone, since only the app knows what their operations
+//! mean. A snapshot entry keeps the ID and timestamp of the newest operation it
+//! replaces, so devices that already have that operation skip it, and a device
+//! syncing from any point still converges.
+//!
+//! Compaction is only safe for operations every device has seen. [`stable_horizon`]
+//! picks the horizon from the acknowledgements in `device_sync_acks`, a version
+//! vector per device: how far it holds every operation each other device wrote.
+//! A device establishes one entry when it has merged the whole of a catch-up
+//! response sent by that device, and tells the others with a `SyncMessage::Ack`.
+//! The newest entry a device merged says nothing about older ones it may have
+//! missed, so it is not used. A device asking for entries before the horizon is
+//! answered with a `SyncMessage::Snapshot`.
+
+use crate::crdt;
+use crate::db::{capture, operations, schema_registry};
+use crate::models::OplogEntry;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Outcome of a compaction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionReport {
+    /// Horizon the oplog is compacted through
+    pub horizon: i64,
+    /// Entities whose operations were collapsed
+    pub entities: usize,
+    /// Oplog entries removed
+    pub removed: usize,
+}
+
+/// Collapse superseded operations at or before `horizon` on collections and
+/// registered tables, in one transaction.
+///
+/// Pass a horizon from [`stable_horizon`] unless every device is known to have
+/// synced past it.
+pub fn compact_oplog(conn: &mut Connection, horizon: i64) -> Result<CompactionReport> {
+    let tx = conn.transaction()?;
+    let mut report = CompactionReport {
+        horizon,
+        ..CompactionReport::default()
+    };
+
+    let collections: Vec<String> = {
+        let mut stmt = tx.prepare("SELECT name FROM collections ORDER BY name")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    for collection in &collections {
+        for (id, ops) in entity_groups(&tx, collection, horizon, |op| {
+            op.data
+                .get("id")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        })? {
+            let state = ops.iter().fold(None, crdt::apply_entity_op);
+            let last = ops.last().expect("groups are not empty");
+            let snapshot = OplogEntry {
+                op_type: if state.is_some() {
+                    crdt::OP_INSERT
+                } else {
+                    crdt::OP_DELETE
+                }
+                .to_string(),
+                data: match state {
+                    Some(value) => json!({"id": id, "value": value}),
+                    None => json!({"id": id}),
+                },
+                schema_version: schema_registry::current_version(&tx, collection)?,
+                ..last.clone()
+            };
+            replace_entries(&tx, &ops, Some(&snapshot))?;
+            report.entities += 1;
+            report.removed += ops.len() - 1;
+        }
+    }
+
+    for table in capture::registered_tables(&tx)? {
+        for (_, ops) in entity_groups(&tx, &table.table_name, horizon, |op| {
+            op.data
+                .get(&table.primary_key)
+                .filter(|key| !key.is_null())
+                .map(Value::to_string)
+        })? {
+            // The newest operation holds the whole row (or deletes it)
+            let superseded = &ops[..ops.len() - 1];
+            replace_entries(&tx, superseded, None)?;
+            report.entities += 1;
+            report.removed += superseded.len();
+        }
+    }
+
+    set_horizon(&tx, horizon)?;
+    tx.commit()?;
+    Ok(report)
+}
+
+/// The horizon the oplog has been compacted through, 0 if never compacted
+pub fn compaction_horizon(conn: &Connection) -> Result<i64> {
+    Ok(conn
+        .query_row(
+            "SELECT horizon FROM oplog_compaction WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Raise the compaction horizon, e.g. after merging a snapshot from a peer
+pub fn set_horizon(conn: &Connection, horizon: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO oplog_compaction (id, horizon, compacted_at) VALUES (1, ?1, ?2)
+         ON CONFLICT(id) DO UPDATE SET
+             horizon = MAX(horizon, excluded.horizon), compacted_at = excluded.compacted_at",
+        params![horizon, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Record that `device_id` holds every entry `origin_device_id` wrote up to `timestamp`
+pub fn record_device_ack(
+    conn: &Connection,
+    device_id: Uuid,
+    origin_device_id: Uuid,
+    timestamp: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO device_sync_acks (device_id, origin_device_id, acked_timestamp, updated_at)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(device_id, origin_device_id) DO UPDATE SET
+             acked_timestamp = MAX(acked_timestamp, excluded.acked_timestamp),
+             updated_at = excluded.updated_at",
+        params![
+            device_id.to_string(),
+            origin_device_id.to_string(),
+            timestamp,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// The newest timestamp up to which every other known device holds the
+/// operations of every device.
+///
+/// Known devices are the local one and those in `devices`, `device_sync_acks` or
+/// the oplog. For each other device and each origin, its acknowledgement of that
+/// origin counts, 0 if it never sent one. With no other devices, everything up
+/// to the local clock is stable.
+pub fn stable_horizon(conn: &Connection, local_device_id: Uuid) -> Result<i64> {
+    let (others, horizon): (i64, Option<i64>) = conn.query_row(
+        "WITH known AS (
+             SELECT ?1 AS device_id
+             UNION SELECT device_id FROM devices
+             UNION SELECT device_id FROM device_sync_acks
+             UNION SELECT origin_device_id FROM device_sync_acks
+             UNION SELECT DISTINCT device_id FROM oplog
+         )
+         SELECT COUNT(*), MIN(COALESCE(acks.acked_timestamp, 0))
+         FROM known holder
+         JOIN known origin ON origin.device_id != holder.device_id
+         LEFT JOIN device_sync_acks acks
+             ON acks.device_id = holder.device_id AND acks.origin_device_id = origin.device_id
+         WHERE holder.device_id != ?1",
+        [local_device_id.to_string()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if others == 0 {
+        return operations::get_hlc_timestamp(conn);
+    }
+    Ok(horizon.unwrap_or(0))
+}
+
+/// Operations at or before `horizon` grouped by entity key, for entities with
+/// more than one, each group in HLC order
+fn entity_groups<F>(
+    conn: &Connection,
+    table: &str,
+    horizon: i64,
+    key: F,
+) -> Result<Vec<(String, Vec<OplogEntry>)>>
+where
+    F: Fn(&OplogEntry) -> Option<String>,
+{
+    let mut groups: BTreeMap<String, Vec<OplogEntry>> = BTreeMap::new();
+    for op in operations::get_table_oplog_entries(conn, table)? {
+        if op.timestamp > horizon {
+            break;
+        }
+        if let Some(key) = key(&op) {
+            groups.entry(key).or_default().push(op);
+        }
+    }
+    Ok(groups
+        .into_iter()
+        .filter(|(_, ops)| ops.len() > 1)
+        .collect())
+}
+
+/// Delete `ops` from the oplog and record `snapshot` in their place
+fn replace_entries(
+    conn: &Connection,
+    ops: &[OplogEntry],
+    snapshot: Option<&OplogEntry>,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached("DELETE FROM oplog WHERE id = ?1")?;
+    for op in ops {
+        stmt.execute([op.id.to_string()])?;
+    }
+    if let Some(snapshot) = snapshot {
+        operations::create_oplog_entry(conn, snapshot)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::documents;
+    use crate::db::operations::initialize_database;
+    use crate::testing::oplog_entry as op;
+
+    #[test]
+    fn test_compact_collection() {
+        let mut conn = initialize_database(":memory:").unwrap();
+        documents::register_collection(&conn, "todos").unwrap();
+        let ops = vec![
+            op(
+                10,
+                "todos",
+                crdt::OP_INSERT,
+                json!({"id": "a", "value": {"n": 1}}),
+            ),
+            op(
+                20,
+                "todos",
+                crdt::OP_MERGE_PATCH,
+                json!({"id": "a", "patch": {"n": 2}}),
+            ),
+            op(
+                30,
+                "todos",
+                crdt::OP_INSERT,
+                json!({"id": "b", "value": {"n": 1}}),
+            ),
+            op(40, "todos", crdt::OP_DELETE, json!({"id": "b"})),
+            op(
+                50,
+                "todos",
+                crdt::OP_MERGE_PATCH,
+                json!({"id": "a", "patch": {"m": 1}}),
+            ),
+            op(60, "notes", "create", json!({"id": "x"})),
+            op(70, "notes", "update", json!({"id": "x"})),
+        ];
+        crdt::merge(&mut conn, &ops).unwrap();
+
+        let report = compact_oplog(&mut conn, 45).unwrap();
+        assert_eq!(report.entities, 2);
+        assert_eq!(report.removed, 2);
+        assert_eq!(compaction_horizon(&conn).unwrap(), 45);
+
+        let remaining = operations::get_table_oplog_entries(&conn, "todos").unwrap();
+        assert_eq!(remaining.len(), 3);
+        assert_eq!(remaining[0].id, ops[1].id);
+        assert_eq!(remaining[0].op_type, crdt::OP_INSERT);
+        assert_eq!(remaining[0].data, json!({"id": "a", "value": {"n": 2}}));
+        assert_eq!(remaining[1].id, ops[3].id);
+        assert_eq!(remaining[1].op_type, crdt::OP_DELETE);
+        // Tables that are neither collections nor registered are left alone
+        assert_eq!(
+            operations::get_table_oplog_entries(&conn, "notes")
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // A fresh device syncing the compacted oplog reaches the same state
+        let mut fresh = initialize_database(":memory:").unwrap();
+        documents::register_collection(&fresh, "todos").unwrap();
+        crdt::merge(
+            &mut fresh,
+            &operations::get_oplog_entries_since(&conn, 0).unwrap(),
+        )
+        .unwrap();
+        for id in ["a", "b"] {
+            assert_eq!(
+                documents::get_document(&fresh, "todos", id)
+                    .unwrap()
+                    .unwrap()
+                    .data,
+                documents::get_document(&conn, "todos", id)
+                    .unwrap()
+                    .unwrap()
+                    .data
+            );
+        }
+        assert_eq!(
+            crdt::entity_state(&fresh, "todos", "a").unwrap(),
+            Some(json!({"n": 2, "m": 1}))
+        );
+    }
+
+    #[test]
+    fn test_compact_registered_table() {
+        let mut conn = initialize_database(":memory:").unwrap();
+        conn.execute_batch("CREATE TABLE notes (id TEXT PRIMARY KEY, body TEXT)")
+            .unwrap();
+        capture::register_table(&conn, "notes", "id").unwrap();
+        let ops = vec![
+            op(
+                10,
+                "notes",
+                crdt::OP_INSERT,
+                json!({"id": "n1", "body": "a"}),
+            ),
+            op(
+                20,
+                "notes",
+                crdt::OP_UPDATE,
+                json!({"id": "n1", "body": "b"}),
+            ),
+            op(
+                30,
+                "notes",
+                crdt::OP_UPDATE,
+                json!({"id": "n1", "body": "c"}),
+            ),
+        ];
+        crdt::merge(&mut conn, &ops).unwrap();
+
+        let report = compact_oplog(&mut conn, 100).unwrap();
+        assert_eq!(report.removed, 2);
+        let remaining = operations::get_table_oplog_entries(&conn, "notes").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, ops[2].id);
+    }
+
+    #[test]
+    fn test_stable_horizon() {
+        let conn = initialize_database(":memory:").unwrap();
+        let local = Uuid::new_v4();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Alone, everything the local clock issued is stable
+        crdt::next_timestamp(&conn).unwrap();
+        assert_eq!(
+            stable_horizon(&conn, local).unwrap(),
+            operations::get_hlc_timestamp(&conn).unwrap()
+        );
+
+        record_device_ack(&conn, a, local, 50).unwrap();
+        record_device_ack(&conn, a, local, 40).unwrap();
+        record_device_ack(&conn, a, b, 60).unwrap();
+        record_device_ack(&conn, b, local, 30).unwrap();
+        // b never caught up with a
+        assert_eq!(stable_horizon(&conn, local).unwrap(), 0);
+
+        record_device_ack(&conn, b, a, 70).unwrap();
+        record_device_ack(&conn, local, a, 10).unwrap();
+        assert_eq!(stable_horizon(&conn, local).unwrap(), 30);
+    }
+
+    #[test]
+    fn test_horizon_stops_below_an_op_a_device_is_missing() {
+        let mut conn = initialize_database(":memory:").unwrap();
+        documents::register_collection(&conn, "todos").unwrap();
+        let local = Uuid::new_v4();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let ops = [
+            OplogEntry {
+                device_id: a,
+                ..op(
+                    10,
+                    "todos",
+                    crdt::OP_INSERT,
+                    json!({"id": "x", "value": {"n": 1}}),
+                )
+            },
+            OplogEntry {
+                device_id: a,
+                ..op(
+                    30,
+                    "todos",
+                    crdt::OP_MERGE_PATCH,
+                    json!({"id": "x", "patch": {"n": 2}}),
+                )
+            },
+            OplogEntry {
+                device_id: local,
+                ..op(
+                    40,
+                    "todos",
+                    crdt::OP_MERGE_PATCH,
+                    json!({"id": "x", "patch": {"m": 1}}),
+                )
+            },
+        ];
+        crdt::merge(&mut conn, &ops).unwrap();
+
+        // b received the local op at 40 but missed a's op at 30: it only caught up
+        // with a through 10
+        record_device_ack(&conn, a, local, 40).unwrap();
+        record_device_ack(&conn, a, b, 40).unwrap();
+        record_device_ack(&conn, b, local, 40).unwrap();
+        record_device_ack(&conn, b, a, 10).unwrap();
+
+        let horizon = stable_horizon(&conn, local).unwrap();
+        assert_eq!(horizon, 10);
+        assert_eq!(compact_oplog(&mut conn, horizon).unwrap().removed, 0);
+        assert!(operations::oplog_entry_exists(&conn, ops[1].id).unwrap());
+    }
+}
@@ -0,0 +1,385 @@
+//! Connection management.
+//!
+//! Every connection ahenk opens is configured by [`configure_connection`]:
+//!
+//! - WAL journaling, so readers never block the writer and the CLI can read the
+//!   database while the daemon writes to it
+//! - a busy timeout, so a connection waits for another process's write lock
+//!   instead of failing with `SQLITE_BUSY`
+//! - foreign key enforcement
+//!
+//! [`Database`] owns one writer connection on a dedicated thread and a pool of
+//! reader connections on their own threads. Work is submitted as closures and
+//! completes through a [`DbFuture`], so async code (such as the sync manager's
+//! event loop) never blocks on SQLite. All writes are serialized through the
+//! single writer, which is the only concurrency SQLite supports anyway.
+
+use super::backup::BackupConfig;
+use super::migrations;
+use futures::channel::oneshot;
+use rusqlite::{Connection, OpenFlags, Result};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Connection settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionConfig {
+    /// How long a connection waits for a lock held by another connection
+    pub busy_timeout: Duration,
+    /// Reader connections in a [`Database`]'s pool
+    pub readers: usize,
+    /// Backups taken before pending migrations are applied
+    pub backups: BackupConfig,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::from_secs(5),
+            readers: 4,
+            backups: BackupConfig::default(),
+        }
+    }
+}
+
+/// Enable WAL journaling, the busy timeout and foreign keys on a connection.
+///
+/// In-memory databases keep their `memory` journal, as WAL needs a file.
+pub fn configure_connection(conn: &Connection, config: &ConnectionConfig) -> Result<()> {
+    conn.busy_timeout(config.busy_timeout)?;
+    // Reports the resulting mode rather than failing when WAL is unavailable
+    let _mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    conn.execute_batch(
+        "PRAGMA synchronous = NORMAL;
+         PRAGMA foreign_keys = ON;",
+    )?;
+    Ok(())
+}
+
+/// Open a configured connection
+pub fn open_connection(db_path: &str, config: &ConnectionConfig) -> Result<Connection> {
+    let conn = Connection::open(db_path)?;
+    configure_connection(&conn, config)?;
+    Ok(conn)
+}
+
+/// Whether `db_path` names a private in-memory database, which other
+/// connections cannot open
+fn is_in_memory(db_path: &str) -> bool {
+    db_path.is_empty() || db_path == ":memory:" || db_path.contains("mode=memory")
+}
+
+/// Error from opening a [`Database`] or from work submitted to it
+#[derive(Debug)]
+pub enum DbError {
+    /// SQLite failed
+    Sqlite(rusqlite::Error),
+    /// The worker thread is gone, so the work never ran
+    WorkerStopped,
+    /// A worker thread could not be started
+    WorkerSpawn(std::io::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "{}", e),
+            DbError::WorkerStopped => write!(f, "database worker has stopped"),
+            DbError::WorkerSpawn(e) => write!(f, "failed to start database worker: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Sqlite(e) => Some(e),
+            DbError::WorkerSpawn(e) => Some(e),
+            DbError::WorkerStopped => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(err: rusqlite::Error) -> Self {
+        DbError::Sqlite(err)
+    }
+}
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+/// Result of work submitted to a [`Database`]; resolves once a worker ran it
+pub struct DbFuture<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> DbFuture<T> {
+    /// Block the current thread until the work completes. Don't call this from
+    /// async code; `.await` the future instead.
+    pub fn wait(self) -> std::result::Result<T, DbError> {
+        futures::executor::block_on(self)
+    }
+}
+
+impl<T> Future for DbFuture<T> {
+    type Output = std::result::Result<T, DbError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|result| match result {
+                Ok(result) => result.map_err(DbError::Sqlite),
+                Err(_) => Err(DbError::WorkerStopped),
+            })
+    }
+}
+
+/// A database with a single writer thread and a pool of reader threads.
+///
+/// Migrations are applied when it is opened. A private in-memory database has
+/// no separate readers (other connections would see a different database), so
+/// reads run on the writer thread instead.
+pub struct Database {
+    path: String,
+    writer: Option<Sender<Job>>,
+    readers: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Database {
+    /// Open the database with the default [`ConnectionConfig`]
+    pub fn open(db_path: &str) -> std::result::Result<Self, DbError> {
+        Self::open_with_config(db_path, &ConnectionConfig::default())
+    }
+
+    /// Open the database, apply pending migrations and start the worker threads
+    pub fn open_with_config(
+        db_path: &str,
+        config: &ConnectionConfig,
+    ) -> std::result::Result<Self, DbError> {
+        let writer_conn = open_connection(db_path, config)?;
+        migrations::apply_migrations_with_backups(&writer_conn, &config.backups)?;
+
+        let mut threads = Vec::new();
+        let (writer, jobs) = mpsc::channel::<Job>();
+        threads.push(spawn_worker(
+            "ahenk-db-writer",
+            writer_conn,
+            Arc::new(Mutex::new(jobs)),
+        )?);
+
+        let readers = if is_in_memory(db_path) || config.readers == 0 {
+            None
+        } else {
+            let (readers, jobs) = mpsc::channel::<Job>();
+            let jobs = Arc::new(Mutex::new(jobs));
+            for i in 0..config.readers {
+                let conn = Connection::open_with_flags(
+                    db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+                )?;
+                configure_reader(&conn, config)?;
+                let name = format!("ahenk-db-reader-{}", i);
+                threads.push(spawn_worker(&name, conn, Arc::clone(&jobs))?);
+            }
+            Some(readers)
+        };
+
+        Ok(Self {
+            path: db_path.to_string(),
+            writer: Some(writer),
+            readers,
+            threads,
+        })
+    }
+
+    /// Path the database was opened from
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Run `f` on a reader connection
+    pub fn read<T, F>(&self, f: F) -> DbFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let sender = self.readers.as_ref().or(self.writer.as_ref());
+        submit(sender, move |conn| f(conn))
+    }
+
+    /// Run `f` on the writer connection. Writes run one at a time, in the order
+    /// they were submitted.
+    pub fn write<T, F>(&self, f: F) -> DbFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        submit(self.writer.as_ref(), f)
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // Closing the channels lets each worker finish its queued jobs and exit
+        self.writer.take();
+        self.readers.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Readers are opened read-only; they only need the busy timeout
+fn configure_reader(conn: &Connection, config: &ConnectionConfig) -> Result<()> {
+    conn.busy_timeout(config.busy_timeout)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")
+}
+
+fn submit<T, F>(sender: Option<&Sender<Job>>, f: F) -> DbFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+{
+    let (tx, receiver) = oneshot::channel();
+    let job: Job = Box::new(move |conn| {
+        // The caller may have stopped waiting for the result
+        let _ = tx.send(f(conn));
+    });
+    // A failed send drops the job and with it `tx`, which resolves the future
+    // with a "worker stopped" error
+    if let Some(sender) = sender {
+        let _ = sender.send(job);
+    }
+    DbFuture { receiver }
+}
+
+fn spawn_worker(
+    name: &str,
+    mut conn: Connection,
+    jobs: Arc<Mutex<Receiver<Job>>>,
+) -> std::result::Result<JoinHandle<()>, DbError> {
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            // Hold the lock only while taking a job, so pooled workers run in parallel
+            let job = match jobs.lock() {
+                Ok(jobs) => jobs.recv(),
+                Err(_) => return,
+            };
+            match job {
+                Ok(job) => job(&mut conn),
+                Err(_) => return,
+            }
+        })
+        .map_err(DbError::WorkerSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::operations;
+
+    #[test]
+    fn test_connections_use_wal_and_foreign_keys() {
+        let dir = std::env::temp_dir().join(format!("ahenk-conn-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nexus.db");
+        let conn = operations::initialize_database(path.to_str().unwrap()).unwrap();
+
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_database_reads_see_committed_writes() {
+        let dir = std::env::temp_dir().join(format!("ahenk-db-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nexus.db");
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+
+        let written: Vec<DbFuture<()>> = (0..20)
+            .map(|i| {
+                db.write(move |conn| {
+                    conn.execute(
+                        "INSERT INTO collections (name, created_at) VALUES (?1, '')",
+                        [format!("c{}", i)],
+                    )?;
+                    Ok(())
+                })
+            })
+            .collect();
+        for write in written {
+            write.wait().unwrap();
+        }
+
+        let count = futures::executor::block_on(db.read(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM collections", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        }))
+        .unwrap();
+        assert_eq!(count, 20);
+
+        // Readers are read-only
+        assert!(db
+            .read(|conn| conn.execute("DELETE FROM collections", []))
+            .wait()
+            .is_err());
+
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_database_reads_through_the_writer() {
+        let db = Database::open(":memory:").unwrap();
+        db.write(|conn| {
+            conn.execute(
+                "INSERT INTO collections (name, created_at) VALUES ('todos', '')",
+                [],
+            )
+        })
+        .wait()
+        .unwrap();
+        let exists = db
+            .read(|conn| crate::db::documents::is_collection(conn, "todos"))
+            .wait()
+            .unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn test_stopped_worker_is_told_apart_from_sql_errors() {
+        let db = Database::open(":memory:").unwrap();
+        assert!(matches!(
+            db.write(|conn| conn.execute("NOT SQL", [])).wait(),
+            Err(DbError::Sqlite(_))
+        ));
+
+        // A panicking job takes the writer thread down with it
+        assert!(matches!(
+            db.write(|_| -> Result<()> { panic!("job failed") }).wait(),
+            Err(DbError::WorkerStopped)
+        ));
+        assert!(matches!(
+            db.read(|_| Ok(())).wait(),
+            Err(DbError::WorkerStopped)
+        ));
+    }
+}
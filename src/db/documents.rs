@@ -0,0 +1,213 @@
+//! Merged state of document collections.
+//!
+//! Every write to a collection is an oplog entry with the collection as its table
+//! and one of these payloads:
+//!
+//! - `insert`/`update`: `{"id": "...", "value": {...}}` replaces the document
+//! - `merge_patch`: `{"id": "...", "patch": {...}}` applies a JSON merge patch (RFC 7386)
+//! - `json_patch`: `{"id": "...", "patch": [...]}` applies a JSON Patch (RFC 6902)
+//! - `delete`: `{"id": "..."}` removes the document
+//!
+//! A document's state is the result of applying its operations in HLC order, ties
+//! broken by operation ID (see `crdt::apply_entity_op`). Operations arriving in
+//! order are applied on top of the stored state; an operation older than the last
+//! applied one makes the document be replayed from its latest snapshot.
+
+use crate::crdt;
+use crate::db::operations;
+use crate::models::OplogEntry;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_json::Value;
+
+/// Merged state of one document
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredDocument {
+    /// Document ID
+    pub id: String,
+    /// The document, `None` once deleted
+    pub data: Option<Value>,
+    /// HLC timestamp of the last applied operation
+    pub timestamp: i64,
+}
+
+/// Register a collection so merged operations on it are applied.
+///
+/// Returns `true` if it was not registered before, in which case operations
+/// already in the oplog are applied.
+pub fn register_collection(conn: &Connection, name: &str) -> Result<bool> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO collections (name, created_at) VALUES (?1, ?2)",
+        params![name, Utc::now().to_rfc3339()],
+    )? > 0;
+    if inserted {
+        rebuild_collection(conn, name)?;
+    }
+    Ok(inserted)
+}
+
+/// Whether `name` is a registered collection
+pub fn is_collection(conn: &Connection, name: &str) -> Result<bool> {
+    let mut stmt =
+        conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM collections WHERE name = ?1)")?;
+    stmt.query_row([name], |row| row.get(0))
+}
+
+/// Get a document's merged state, including tombstones
+pub fn get_document(
+    conn: &Connection,
+    collection: &str,
+    id: &str,
+) -> Result<Option<StoredDocument>> {
+    conn.query_row(
+        "SELECT id, data, timestamp FROM collection_documents WHERE collection = ?1 AND id = ?2",
+        params![collection, id],
+        read_document,
+    )
+    .optional()
+}
+
+/// Get all live documents of a collection, ordered by ID
+pub fn list_documents(conn: &Connection, collection: &str) -> Result<Vec<StoredDocument>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, data, timestamp FROM collection_documents
+         WHERE collection = ?1 AND data IS NOT NULL ORDER BY id",
+    )?;
+    let rows = stmt.query_map([collection], read_document)?;
+    rows.collect()
+}
+
+/// Apply an operation that was just recorded in the oplog to its document
+pub fn apply_op(conn: &Connection, op: &OplogEntry) -> Result<()> {
+    let Some(id) = document_id(op) else {
+        eprintln!(
+            "Skipping {} operation {} without a document id",
+            op.table, op.id
+        );
+        return Ok(());
+    };
+
+    let current: Option<(Option<String>, i64, String)> = conn
+        .prepare_cached(
+            "SELECT data, timestamp, op_id FROM collection_documents WHERE collection = ?1 AND id = ?2",
+        )?
+        .query_row(params![op.table, id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()?;
+
+    match current {
+        None => store(
+            conn,
+            &op.table,
+            id,
+            crdt::apply_entity_op(None, op).as_ref(),
+            op.timestamp,
+            &op.id.to_string(),
+        ),
+        Some((data, timestamp, op_id))
+            if (op.timestamp, op.id.to_string().as_str()) > (timestamp, op_id.as_str()) =>
+        {
+            let data = data.map(|d| parse(&d)).transpose()?;
+            store(
+                conn,
+                &op.table,
+                id,
+                crdt::apply_entity_op(data, op).as_ref(),
+                op.timestamp,
+                &op.id.to_string(),
+            )
+        }
+        // Older than the last applied operation: replay the document in order
+        Some(_) => replay_document(conn, &op.table, id),
+    }
+}
+
+/// Apply newly merged operations on registered collections, oldest first.
+///
+/// Must be called inside a transaction, after the operations were recorded in the oplog.
+pub fn apply_remote_ops(conn: &Connection, ops: &[&OplogEntry]) -> Result<usize> {
+    let mut applied = 0;
+    let mut ops: Vec<&&OplogEntry> = ops.iter().collect();
+    ops.sort_by_key(|op| (op.timestamp, op.id));
+    for op in ops {
+        if is_collection(conn, &op.table)? {
+            apply_op(conn, op)?;
+            applied += 1;
+        }
+    }
+    Ok(applied)
+}
+
+/// Recompute every document of a collection from the oplog
+pub fn rebuild_collection(conn: &Connection, collection: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM collection_documents WHERE collection = ?1",
+        [collection],
+    )?;
+    for op in operations::get_table_oplog_entries(conn, collection)? {
+        apply_op(conn, &op)?;
+    }
+    Ok(())
+}
+
+/// Recompute one document from its operations in the oplog
+fn replay_document(conn: &Connection, collection: &str, id: &str) -> Result<()> {
+    let last: Option<(i64, String)> = conn
+        .query_row(
+            "SELECT timestamp, id FROM oplog
+             WHERE table_name = ?1 AND json_extract(data, '$.id') = ?2
+             ORDER BY timestamp DESC, id DESC LIMIT 1",
+            params![collection, id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let Some((timestamp, op_id)) = last else {
+        return Ok(());
+    };
+    let data = crdt::snapshot_entity(conn, collection, id)?;
+    store(conn, collection, id, data.as_ref(), timestamp, &op_id)
+}
+
+fn store(
+    conn: &Connection,
+    collection: &str,
+    id: &str,
+    data: Option<&Value>,
+    timestamp: i64,
+    op_id: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO collection_documents (collection, id, data, timestamp, op_id)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(collection, id) DO UPDATE SET
+             data = excluded.data, timestamp = excluded.timestamp, op_id = excluded.op_id",
+    )?;
+    stmt.execute(params![
+        collection,
+        id,
+        data.map(Value::to_string),
+        timestamp,
+        op_id
+    ])?;
+    Ok(())
+}
+
+fn document_id(op: &OplogEntry) -> Option<&str> {
+    op.data.get("id").and_then(Value::as_str)
+}
+
+fn read_document(row: &rusqlite::Row) -> Result<StoredDocument> {
+    let data: Option<String> = row.get(1)?;
+    Ok(StoredDocument {
+        id: row.get(0)?,
+        data: data.map(|d| parse(&d)).transpose()?,
+        timestamp: row.get(2)?,
+    })
+}
+
+fn parse(raw: &str) -> Result<Value> {
+    serde_json::from_str(raw).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
@@ -1,87 +1,739 @@
+//! Schema migrations.
+//!
+//! Migrations are numbered scripts applied in order. Each one runs in its own
+//! transaction together with its `schema_version` row, which also records a
+//! SHA-256 checksum of the script, so a database whose applied migrations no
+//! longer match the shipped ones is detected ([`verify_migrations`]). Migrations
+//! with a down script can be reverted ([`migrate_down`]).
+//!
+//! Apps keep their own tables in step the same way: [`register_migrations`]
+//! registers SQL or Rust-code migrations under a namespace, versioned
+//! independently of Ahenk's own ([`CORE_NAMESPACE`]). [`apply_migrations`], and
+//! with it `initialize_database`, applies the core migrations first and then each
+//! registered namespace in name order.
+//!
+//! Before pending migrations are applied to an existing database it is backed
+//! up (see [`super::backup`]), and restored if any of them fails.
+
+use super::backup::{self, BackupConfig};
+use crate::error::AhenkError;
 use chrono::Utc;
-use rusqlite::{Connection, Result};
+use rusqlite::{params, Connection, Result};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Namespace of Ahenk's own migrations in `schema_version`
+pub const CORE_NAMESPACE: &str = "ahenk";
 
 /// Represents a single database migration
 pub struct Migration {
     pub version: i32,
     pub description: &'static str,
     pub sql: &'static str,
+    /// Script reverting the migration, if it can be reverted
+    pub down: Option<&'static str>,
+}
+
+impl Migration {
+    /// Hex SHA-256 of the migration's SQL, recorded when it is applied
+    pub fn checksum(&self) -> String {
+        checksum(self.sql)
+    }
+}
+
+type MigrationFn = dyn Fn(&Connection) -> Result<()> + Send + Sync;
+
+/// What a migration (or its down step) runs
+#[derive(Clone)]
+pub enum MigrationStep {
+    /// An SQL script, run with `execute_batch`
+    Sql(Cow<'static, str>),
+    /// Rust code, run inside the migration's transaction
+    Code(Arc<MigrationFn>),
+}
+
+impl MigrationStep {
+    fn run(&self, conn: &Connection) -> Result<()> {
+        match self {
+            MigrationStep::Sql(sql) => conn.execute_batch(sql),
+            MigrationStep::Code(f) => f(conn),
+        }
+    }
+}
+
+/// A migration registered by an app with [`register_migrations`]
+#[derive(Clone)]
+pub struct AppMigration {
+    pub version: i32,
+    pub description: String,
+    pub up: MigrationStep,
+    /// Step reverting the migration, if it can be reverted
+    pub down: Option<MigrationStep>,
+}
+
+impl AppMigration {
+    /// A migration running an SQL script
+    pub fn sql(
+        version: i32,
+        description: impl Into<String>,
+        sql: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        Self {
+            version,
+            description: description.into(),
+            up: MigrationStep::Sql(sql.into()),
+            down: None,
+        }
+    }
+
+    /// A migration running Rust code, for changes SQL can't express (such as
+    /// rewriting JSON documents)
+    pub fn code<F>(version: i32, description: impl Into<String>, f: F) -> Self
+    where
+        F: Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    {
+        Self {
+            version,
+            description: description.into(),
+            up: MigrationStep::Code(Arc::new(f)),
+            down: None,
+        }
+    }
+
+    /// Revert the migration with an SQL script
+    pub fn with_down_sql(mut self, sql: impl Into<Cow<'static, str>>) -> Self {
+        self.down = Some(MigrationStep::Sql(sql.into()));
+        self
+    }
+
+    /// Revert the migration with Rust code
+    pub fn with_down_code<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    {
+        self.down = Some(MigrationStep::Code(Arc::new(f)));
+        self
+    }
+
+    /// Hex SHA-256 of the migration's SQL. Code migrations have none and are
+    /// not checked for drift.
+    pub fn checksum(&self) -> Option<String> {
+        match &self.up {
+            MigrationStep::Sql(sql) => Some(checksum(sql)),
+            MigrationStep::Code(_) => None,
+        }
+    }
+}
+
+impl From<&Migration> for AppMigration {
+    fn from(migration: &Migration) -> Self {
+        Self {
+            version: migration.version,
+            description: migration.description.to_string(),
+            up: MigrationStep::Sql(Cow::Borrowed(migration.sql)),
+            down: migration
+                .down
+                .map(|down| MigrationStep::Sql(Cow::Borrowed(down))),
+        }
+    }
+}
+
+/// Where a migration stands in a database
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationState {
+    /// Shipped but not applied yet
+    Pending,
+    /// Applied, and its SQL matches the shipped migration
+    Applied,
+    /// Applied, but the recorded checksum differs from the shipped migration's
+    Modified { recorded: String, expected: String },
+    /// Applied, but not shipped with this version of Ahenk or the app
+    Unknown,
+    /// Applied under an app namespace that is not registered in this process,
+    /// so it can't be checked
+    Unregistered,
+}
+
+/// A migration and its state, as reported by [`migration_status`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    /// [`CORE_NAMESPACE`] or the namespace an app registered it under
+    pub namespace: String,
+    pub version: i32,
+    pub description: String,
+    pub state: MigrationState,
+    /// RFC3339 timestamp; `None` while pending
+    pub applied_at: Option<String>,
+    /// Whether the migration has a down script
+    pub reversible: bool,
 }
 
 /// List of all migrations in order
 /// Each migration should be numbered sequentially starting from 1
-const MIGRATIONS: &[Migration] = &[Migration {
-    version: 1,
-    description: "Initial schema - database synchronization infrastructure",
-    sql: include_str!("migrations/001_initial_schema.sql"),
-}];
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema - database synchronization infrastructure",
+        sql: include_str!("migrations/001_initial_schema.sql"),
+        down: None,
+    },
+    Migration {
+        version: 2,
+        description: "Sync checkpoint - persistent outbox for graceful shutdown",
+        sql: include_str!("migrations/002_sync_checkpoint.sql"),
+        down: Some(include_str!("migrations/002_sync_checkpoint.down.sql")),
+    },
+    Migration {
+        version: 3,
+        description: "Peer address book - known multiaddrs with dial history",
+        sql: include_str!("migrations/003_peer_address_book.sql"),
+        down: Some(include_str!("migrations/003_peer_address_book.down.sql")),
+    },
+    Migration {
+        version: 4,
+        description: "Peer network info - identify, ping and AutoNAT results",
+        sql: include_str!("migrations/004_peer_network_info.sql"),
+        down: Some(include_str!("migrations/004_peer_network_info.down.sql")),
+    },
+    Migration {
+        version: 5,
+        description: "HLC state - persisted hybrid logical clock shared by all writers",
+        sql: include_str!("migrations/005_hlc_state.sql"),
+        down: Some(include_str!("migrations/005_hlc_state.down.sql")),
+    },
+    Migration {
+        version: 6,
+        description: "Change capture - triggers recording writes to synced app tables",
+        sql: include_str!("migrations/006_change_capture.sql"),
+        down: None,
+    },
+    Migration {
+        version: 7,
+        description: "Collections - merged state of synced document collections",
+        sql: include_str!("migrations/007_collections.sql"),
+        down: Some(include_str!("migrations/007_collections.down.sql")),
+    },
+    Migration {
+        version: 8,
+        description: "Change feed - row-level changes read by live queries",
+        sql: include_str!("migrations/008_change_feed.sql"),
+        down: Some(include_str!("migrations/008_change_feed.down.sql")),
+    },
+    Migration {
+        version: 9,
+        description: "Entity snapshots - bound replay of patch operations",
+        sql: include_str!("migrations/009_entity_snapshots.sql"),
+        down: Some(include_str!("migrations/009_entity_snapshots.down.sql")),
+    },
+    Migration {
+        version: 10,
+        description: "Schema registry - declared payload schemas and quarantine",
+        sql: include_str!("migrations/010_schema_registry.sql"),
+        down: Some(include_str!("migrations/010_schema_registry.down.sql")),
+    },
+    Migration {
+        version: 11,
+        description: "Schema versions - payload versions and held-back operations",
+        sql: include_str!("migrations/011_schema_versions.sql"),
+        down: Some(include_str!("migrations/011_schema_versions.down.sql")),
+    },
+    Migration {
+        version: 12,
+        description: "Oplog compaction - compaction horizon and device sync acknowledgements",
+        sql: include_str!("migrations/012_oplog_compaction.sql"),
+        down: Some(include_str!("migrations/012_oplog_compaction.down.sql")),
+    },
+    Migration {
+        version: 13,
+        description: "Oplog indexes - timestamp, device and table indexes for paged reads",
+        sql: include_str!("migrations/013_oplog_indexes.sql"),
+        down: Some(include_str!("migrations/013_oplog_indexes.down.sql")),
+    },
+    Migration {
+        version: 14,
+        description: "Device foreign keys - oplog and peers may refer to unregistered devices",
+        sql: include_str!("migrations/014_device_foreign_keys.sql"),
+        down: None,
+    },
+    Migration {
+        version: 15,
+        description: "HLC clamp - bring a clock seeded from legacy timestamps back to now",
+        sql: include_str!("migrations/015_hlc_clamp.sql"),
+        down: None,
+    },
+    Migration {
+        version: 16,
+        description: "Device sync versions - acknowledgements per origin device",
+        sql: include_str!("migrations/016_device_sync_versions.sql"),
+        down: None,
+    },
+];
+
+/// Register an app's migrations under `namespace`, replacing any previously
+/// registered there.
+///
+/// Versions start at 1 and are tracked in `schema_version` separately from
+/// Ahenk's own, so they only have to be unique within the namespace. Like
+/// upcasters, registrations are not stored in the database: register them on
+/// each start, before the database is opened.
+pub fn register_migrations(
+    namespace: &str,
+    mut migrations: Vec<AppMigration>,
+) -> crate::error::Result<()> {
+    if namespace.is_empty() || namespace == CORE_NAMESPACE {
+        return Err(AhenkError::Validation(format!(
+            "'{}' cannot be used as a migration namespace",
+            namespace
+        )));
+    }
+    migrations.sort_by_key(|migration| migration.version);
+    if let Some(migration) = migrations.iter().find(|m| m.version < 1) {
+        return Err(AhenkError::Validation(format!(
+            "Migration versions start at 1, got {} in '{}'",
+            migration.version, namespace
+        )));
+    }
+    if let Some(pair) = migrations
+        .windows(2)
+        .find(|pair| pair[0].version == pair[1].version)
+    {
+        return Err(AhenkError::Validation(format!(
+            "Migration {} is registered twice in '{}'",
+            pair[0].version, namespace
+        )));
+    }
+
+    registry()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(namespace.to_string(), migrations);
+    Ok(())
+}
+
+/// Forget the migrations registered under `namespace`. Applied migrations stay
+/// applied.
+pub fn unregister_migrations(namespace: &str) -> bool {
+    registry()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(namespace)
+        .is_some()
+}
+
+/// Namespaces with registered migrations, in the order they are applied
+pub fn registered_namespaces() -> Vec<String> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .keys()
+        .cloned()
+        .collect()
+}
+
+fn registry() -> &'static RwLock<BTreeMap<String, Vec<AppMigration>>> {
+    static REGISTRY: OnceLock<RwLock<BTreeMap<String, Vec<AppMigration>>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Migrations of `namespace`, or `None` if no app registered it
+fn namespace_migrations(namespace: &str) -> Option<Vec<AppMigration>> {
+    if namespace == CORE_NAMESPACE {
+        return Some(MIGRATIONS.iter().map(AppMigration::from).collect());
+    }
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(namespace)
+        .cloned()
+}
+
+/// Core namespace first, then registered namespaces in name order
+fn namespaces_in_order() -> Vec<String> {
+    let mut namespaces = vec![CORE_NAMESPACE.to_string()];
+    namespaces.extend(registered_namespaces());
+    namespaces
+}
+
+fn checksum(sql: &str) -> String {
+    hex::encode(Sha256::digest(sql.as_bytes()))
+}
 
 /// Initialize the schema_version table if it doesn't exist
 fn ensure_schema_version_table(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS schema_version (
-            version INTEGER PRIMARY KEY,
+            namespace TEXT NOT NULL DEFAULT 'ahenk',
+            version INTEGER NOT NULL,
             applied_at TEXT NOT NULL,
-            description TEXT NOT NULL
+            description TEXT NOT NULL,
+            checksum TEXT,
+            PRIMARY KEY (namespace, version)
         )",
         [],
     )?;
+
+    let has_column = |name: &str| -> Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info('schema_version') WHERE name = ?1)",
+            [name],
+            |row| row.get(0),
+        )
+    };
+    // Tables created before checksums were recorded lack the column
+    if !has_column("checksum")? {
+        conn.execute("ALTER TABLE schema_version ADD COLUMN checksum TEXT", [])?;
+    }
+    // Tables created before app namespaces were keyed by version alone
+    if !has_column("namespace")? {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(
+            "CREATE TABLE schema_version_namespaced (
+                namespace TEXT NOT NULL DEFAULT 'ahenk',
+                version INTEGER NOT NULL,
+                applied_at TEXT NOT NULL,
+                description TEXT NOT NULL,
+                checksum TEXT,
+                PRIMARY KEY (namespace, version)
+            );
+            INSERT INTO schema_version_namespaced (namespace, version, applied_at, description, checksum)
+                SELECT 'ahenk', version, applied_at, description, checksum FROM schema_version;
+            DROP TABLE schema_version;
+            ALTER TABLE schema_version_namespaced RENAME TO schema_version;",
+        )?;
+        tx.commit()?;
+    }
     Ok(())
 }
 
 /// Get the current schema version
 /// Returns 0 if no migrations have been applied yet
 pub fn get_current_version(conn: &Connection) -> Result<i32> {
-    ensure_schema_version_table(conn)?;
-
-    let version: Result<Option<i32>> =
-        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
-            row.get(0)
-        });
+    get_namespace_version(conn, CORE_NAMESPACE)
+}
 
-    match version {
-        Ok(Some(v)) => Ok(v),
-        Ok(None) => Ok(0), // Table is empty, no migrations applied yet
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
-        Err(e) => Err(e),
-    }
+/// Newest core schema version this build of Ahenk knows
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
 }
 
-/// Apply a single migration
-fn apply_migration(conn: &Connection, migration: &Migration) -> Result<()> {
-    // Execute the migration SQL
-    conn.execute_batch(migration.sql)?;
+/// Latest applied migration of `namespace`, or 0 if none has been applied
+pub fn get_namespace_version(conn: &Connection, namespace: &str) -> Result<i32> {
+    ensure_schema_version_table(conn)?;
 
-    // Record the migration in schema_version table
-    conn.execute(
-        "INSERT INTO schema_version (version, applied_at, description) VALUES (?1, ?2, ?3)",
-        rusqlite::params![
+    let version: Option<i32> = conn.query_row(
+        "SELECT MAX(version) FROM schema_version WHERE namespace = ?1",
+        [namespace],
+        |row| row.get(0),
+    )?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Apply a single migration and record it, in one transaction
+fn apply_migration(conn: &Connection, namespace: &str, migration: &AppMigration) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    migration.up.run(&tx)?;
+    tx.execute(
+        "INSERT INTO schema_version (namespace, version, applied_at, description, checksum)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            namespace,
             migration.version,
             Utc::now().to_rfc3339(),
-            migration.description
+            migration.description,
+            migration.checksum()
         ],
     )?;
+    tx.commit()
+}
 
-    Ok(())
+/// Revert a single migration and remove its record, in one transaction
+fn revert_migration(
+    conn: &Connection,
+    namespace: &str,
+    migration: &AppMigration,
+    down: &MigrationStep,
+) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    down.run(&tx)?;
+    tx.execute(
+        "DELETE FROM schema_version WHERE namespace = ?1 AND version = ?2",
+        params![namespace, migration.version],
+    )?;
+    tx.commit()
 }
 
-/// Apply all pending migrations
+/// Apply all pending migrations: Ahenk's own first, then those of each
+/// registered namespace in name order.
+///
+/// An existing database is backed up with the default [`BackupConfig`] first and
+/// restored if a migration fails. Applied migrations whose SQL changed since are
+/// reported on stderr; run [`verify_migrations`] to act on them.
 pub fn apply_migrations(conn: &Connection) -> Result<()> {
+    apply_migrations_with_backups(conn, &BackupConfig::default())
+}
+
+/// Apply all pending migrations like [`apply_migrations`], keeping backups as
+/// `backups` configures
+pub fn apply_migrations_with_backups(conn: &Connection, backups: &BackupConfig) -> Result<()> {
+    // A new database has nothing to lose
+    if pending_migrations(conn)?.is_empty() || applied_rows(conn)?.is_empty() {
+        apply_pending_migrations(conn)?;
+    } else {
+        backup::with_backup(conn, backups, apply_pending_migrations)?;
+    }
+
+    for drift in verify_migrations(conn)? {
+        eprintln!(
+            "Warning: applied migration {}/{} ({}) does not match this version: {:?}",
+            drift.namespace, drift.version, drift.description, drift.state
+        );
+    }
+    Ok(())
+}
+
+/// Apply the pending migrations of every namespace, in order, without a backup
+pub(crate) fn apply_pending_migrations(conn: &Connection) -> Result<()> {
+    for namespace in namespaces_in_order() {
+        if let Some(migrations) = namespace_migrations(&namespace) {
+            apply_up(conn, &namespace, &migrations, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply pending migrations of `namespace` up to and including `target` (all
+/// of them if `None`). Returns the versions applied.
+pub fn migrate_up(
+    conn: &Connection,
+    namespace: &str,
+    target: Option<i32>,
+) -> crate::error::Result<Vec<i32>> {
+    let migrations = namespace_migrations(namespace).ok_or_else(|| {
+        AhenkError::Validation(format!("No migrations registered under '{}'", namespace))
+    })?;
+    Ok(apply_up(conn, namespace, &migrations, target)?)
+}
+
+fn apply_up(
+    conn: &Connection,
+    namespace: &str,
+    migrations: &[AppMigration],
+    target: Option<i32>,
+) -> Result<Vec<i32>> {
+    record_missing_checksums(conn)?;
+    let applied_versions: Vec<i32> = applied_rows(conn)?
+        .into_iter()
+        .filter(|row| row.namespace == namespace)
+        .map(|row| row.version)
+        .collect();
+
+    let mut applied = Vec::new();
+    for migration in migrations {
+        if target.is_some_and(|target| migration.version > target) {
+            break;
+        }
+        if applied_versions.contains(&migration.version) {
+            continue;
+        }
+        eprintln!(
+            "Applying migration {}/{}: {}",
+            namespace, migration.version, migration.description
+        );
+        apply_migration(conn, namespace, migration)?;
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+/// Revert applied migrations of `namespace` newer than `target`, newest first.
+/// Returns the versions reverted.
+///
+/// Nothing is reverted if any of them has no down step or is not shipped with
+/// this version of Ahenk or the app.
+pub fn migrate_down(
+    conn: &Connection,
+    namespace: &str,
+    target: i32,
+) -> crate::error::Result<Vec<i32>> {
+    let migrations = namespace_migrations(namespace).unwrap_or_default();
+    let to_revert = migrations_to_revert(conn, namespace, target)?;
+    let mut steps = Vec::new();
+    for status in &to_revert {
+        let step = migrations
+            .iter()
+            .find(|m| m.version == status.version)
+            .and_then(|m| m.down.as_ref().map(|down| (m, down)));
+        match step {
+            Some(step) => steps.push(step),
+            None => {
+                return Err(AhenkError::Validation(format!(
+                    "Migration {}/{} ({}) cannot be reverted",
+                    namespace, status.version, status.description
+                )))
+            }
+        }
+    }
+
+    let mut reverted = Vec::new();
+    for (migration, down) in steps {
+        eprintln!(
+            "Reverting migration {}/{}: {}",
+            namespace, migration.version, migration.description
+        );
+        revert_migration(conn, namespace, migration, down)?;
+        reverted.push(migration.version);
+    }
+    Ok(reverted)
+}
+
+struct AppliedRow {
+    namespace: String,
+    version: i32,
+    applied_at: String,
+    description: String,
+    checksum: Option<String>,
+}
+
+fn applied_rows(conn: &Connection) -> Result<Vec<AppliedRow>> {
     ensure_schema_version_table(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT namespace, version, applied_at, description, checksum FROM schema_version",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(AppliedRow {
+            namespace: row.get(0)?,
+            version: row.get(1)?,
+            applied_at: row.get(2)?,
+            description: row.get(3)?,
+            checksum: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
 
-    let current_version = get_current_version(conn)?;
+/// Every known migration, plus applied migrations that are not, with their
+/// state. Ordered as they apply: core first, then namespaces by name, each by
+/// version.
+pub fn migration_status(conn: &Connection) -> Result<Vec<MigrationStatus>> {
+    let mut applied: HashMap<(String, i32), AppliedRow> = applied_rows(conn)?
+        .into_iter()
+        .map(|row| ((row.namespace.clone(), row.version), row))
+        .collect();
 
-    for migration in MIGRATIONS {
-        if migration.version > current_version {
-            println!(
-                "Applying migration {}: {}",
-                migration.version, migration.description
-            );
-            apply_migration(conn, migration)?;
-            println!("Migration {} applied successfully", migration.version);
+    let mut statuses = Vec::new();
+    for namespace in namespaces_in_order() {
+        for migration in namespace_migrations(&namespace).unwrap_or_default() {
+            let row = applied.remove(&(namespace.clone(), migration.version));
+            let state = match (&row, migration.checksum()) {
+                (None, _) => MigrationState::Pending,
+                (Some(row), Some(expected)) => match &row.checksum {
+                    Some(recorded) if *recorded != expected => MigrationState::Modified {
+                        recorded: recorded.clone(),
+                        expected,
+                    },
+                    // Matching, or applied before checksums were recorded
+                    _ => MigrationState::Applied,
+                },
+                (Some(_), None) => MigrationState::Applied,
+            };
+            statuses.push(MigrationStatus {
+                namespace: namespace.clone(),
+                version: migration.version,
+                description: migration.description,
+                state,
+                applied_at: row.map(|row| row.applied_at),
+                reversible: migration.down.is_some(),
+            });
         }
     }
 
+    let registered = namespaces_in_order();
+    let mut unknown: Vec<MigrationStatus> = applied
+        .into_values()
+        .map(|row| MigrationStatus {
+            state: if registered.contains(&row.namespace) {
+                MigrationState::Unknown
+            } else {
+                MigrationState::Unregistered
+            },
+            namespace: row.namespace,
+            version: row.version,
+            description: row.description,
+            applied_at: Some(row.applied_at),
+            reversible: false,
+        })
+        .collect();
+    statuses.append(&mut unknown);
+    statuses.sort_by_key(|status| {
+        (
+            status.namespace != CORE_NAMESPACE,
+            status.namespace.clone(),
+            status.version,
+        )
+    });
+    Ok(statuses)
+}
+
+/// Migrations [`apply_migrations`] would apply, in order, without applying them
+pub fn pending_migrations(conn: &Connection) -> Result<Vec<MigrationStatus>> {
+    Ok(migration_status(conn)?
+        .into_iter()
+        .filter(|status| status.state == MigrationState::Pending)
+        .collect())
+}
+
+/// Migrations [`migrate_down`] would revert to bring `namespace` to `target`,
+/// newest first, without reverting them
+pub fn migrations_to_revert(
+    conn: &Connection,
+    namespace: &str,
+    target: i32,
+) -> Result<Vec<MigrationStatus>> {
+    let mut statuses: Vec<MigrationStatus> = migration_status(conn)?
+        .into_iter()
+        .filter(|status| {
+            status.namespace == namespace
+                && status.version > target
+                && status.state != MigrationState::Pending
+        })
+        .collect();
+    statuses.reverse();
+    Ok(statuses)
+}
+
+/// Applied migrations that differ from the known ones (modified or unknown).
+/// An empty result means the schema matches this version of Ahenk and of the
+/// registered apps. Namespaces not registered in this process are not checked.
+pub fn verify_migrations(conn: &Connection) -> Result<Vec<MigrationStatus>> {
+    Ok(migration_status(conn)?
+        .into_iter()
+        .filter(|status| {
+            matches!(
+                status.state,
+                MigrationState::Modified { .. } | MigrationState::Unknown
+            )
+        })
+        .collect())
+}
+
+/// Record checksums for migrations applied before checksums were recorded
+fn record_missing_checksums(conn: &Connection) -> Result<()> {
+    for row in applied_rows(conn)? {
+        if row.checksum.is_some() {
+            continue;
+        }
+        let expected = namespace_migrations(&row.namespace)
+            .and_then(|migrations| migrations.into_iter().find(|m| m.version == row.version))
+            .and_then(|migration| migration.checksum());
+        if let Some(expected) = expected {
+            conn.execute(
+                "UPDATE schema_version SET checksum = ?1 WHERE namespace = ?2 AND version = ?3",
+                params![expected, row.namespace, row.version],
+            )?;
+        }
+    }
     Ok(())
 }
 
@@ -90,10 +742,13 @@ pub fn get_migration_history(conn: &Connection) -> Result<Vec<(i32, String, Stri
     ensure_schema_version_table(conn)?;
 
     let mut stmt = conn.prepare(
-        "SELECT version, applied_at, description FROM schema_version ORDER BY version ASC",
+        "SELECT version, applied_at, description FROM schema_version
+         WHERE namespace = ?1 ORDER BY version ASC",
     )?;
 
-    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let rows = stmt.query_map([CORE_NAMESPACE], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
 
     let mut history = Vec::new();
     for row in rows {
@@ -150,4 +805,249 @@ mod tests {
             assert_eq!(*version, (i + 1) as i32);
         }
     }
+
+    #[test]
+    fn test_up_down_and_dry_run() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(
+            migrate_up(&conn, CORE_NAMESPACE, Some(9)).unwrap(),
+            (1..=9).collect::<Vec<_>>()
+        );
+        let pending: Vec<MigrationStatus> = pending_migrations(&conn)
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.namespace == CORE_NAMESPACE)
+            .collect();
+        assert_eq!(pending.first().map(|m| m.version), Some(10));
+        assert_eq!(pending.len(), MIGRATIONS.len() - 9);
+
+        apply_migrations(&conn).unwrap();
+        // 014 has no down script, so nothing is reverted
+        assert!(matches!(
+            migrate_down(&conn, CORE_NAMESPACE, 12),
+            Err(AhenkError::Validation(_))
+        ));
+        assert_eq!(get_current_version(&conn).unwrap(), MIGRATIONS.len() as i32);
+
+        // Down scripts exactly undo their migrations
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_up(&conn, CORE_NAMESPACE, Some(6)).unwrap();
+        let schema = |conn: &Connection| -> Vec<String> {
+            let mut stmt = conn
+                .prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name")
+                .unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<_>>().unwrap()
+        };
+        let before = schema(&conn);
+        migrate_up(&conn, CORE_NAMESPACE, Some(13)).unwrap();
+        let to_revert = migrations_to_revert(&conn, CORE_NAMESPACE, 6).unwrap();
+        assert_eq!(
+            to_revert.iter().map(|m| m.version).collect::<Vec<_>>(),
+            (7..=13).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            migrate_down(&conn, CORE_NAMESPACE, 6).unwrap(),
+            (7..=13).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(get_current_version(&conn).unwrap(), 6);
+        assert_eq!(schema(&conn), before);
+    }
+
+    #[test]
+    fn test_checksum_drift_is_detected() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply_migrations(&conn).unwrap();
+        assert!(verify_migrations(&conn).unwrap().is_empty());
+
+        conn.execute(
+            "UPDATE schema_version SET checksum = 'edited' WHERE version = 3",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, applied_at, description) VALUES (99, '', 'future')",
+            [],
+        )
+        .unwrap();
+        let drift = verify_migrations(&conn).unwrap();
+        assert_eq!(drift.len(), 2);
+        assert!(matches!(
+            &drift[0].state,
+            MigrationState::Modified { recorded, .. } if recorded == "edited"
+        ));
+        assert_eq!(drift[1].state, MigrationState::Unknown);
+
+        // Rows from before checksums were recorded are adopted
+        conn.execute("DELETE FROM schema_version WHERE version = 99", [])
+            .unwrap();
+        conn.execute("UPDATE schema_version SET checksum = NULL", [])
+            .unwrap();
+        assert!(verify_migrations(&conn).unwrap().is_empty());
+        migrate_up(&conn, CORE_NAMESPACE, None).unwrap();
+        let unrecorded: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM schema_version WHERE checksum IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unrecorded, 0);
+    }
+
+    #[test]
+    fn test_app_migrations_are_namespaced() {
+        let namespace = "test_app_notes";
+        register_migrations(
+            namespace,
+            vec![
+                AppMigration::code(2, "Seed notes", |conn| {
+                    conn.execute("INSERT INTO app_notes (body) VALUES ('hello')", [])?;
+                    Ok(())
+                })
+                .with_down_code(|conn| {
+                    conn.execute("DELETE FROM app_notes", [])?;
+                    Ok(())
+                }),
+                AppMigration::sql(1, "Notes table", "CREATE TABLE app_notes (body TEXT)")
+                    .with_down_sql("DROP TABLE app_notes"),
+            ],
+        )
+        .unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let pending = pending_migrations(&conn).unwrap();
+        let order: Vec<(&str, i32)> = pending
+            .iter()
+            .filter(|m| m.namespace == CORE_NAMESPACE || m.namespace == namespace)
+            .map(|m| (m.namespace.as_str(), m.version))
+            .collect();
+        let mut expected: Vec<(&str, i32)> = MIGRATIONS
+            .iter()
+            .map(|m| (CORE_NAMESPACE, m.version))
+            .collect();
+        expected.extend([(namespace, 1), (namespace, 2)]);
+        assert_eq!(order, expected);
+
+        apply_migrations(&conn).unwrap();
+        assert_eq!(get_current_version(&conn).unwrap(), MIGRATIONS.len() as i32);
+        assert_eq!(get_namespace_version(&conn, namespace).unwrap(), 2);
+        let notes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM app_notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(notes, 1);
+
+        assert_eq!(migrate_down(&conn, namespace, 0).unwrap(), vec![2, 1]);
+        assert_eq!(get_namespace_version(&conn, namespace).unwrap(), 0);
+        assert_eq!(get_current_version(&conn).unwrap(), MIGRATIONS.len() as i32);
+
+        // Unregistered namespaces are reported but not treated as drift
+        migrate_up(&conn, namespace, None).unwrap();
+        assert!(unregister_migrations(namespace));
+        assert!(verify_migrations(&conn).unwrap().is_empty());
+        let unregistered = migration_status(&conn)
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.namespace == namespace)
+            .filter(|m| m.state == MigrationState::Unregistered)
+            .count();
+        assert_eq!(unregistered, 2);
+        assert!(matches!(
+            migrate_up(&conn, namespace, None),
+            Err(AhenkError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_register_migrations_validates_versions() {
+        let create = |version| AppMigration::sql(version, "Create", "SELECT 1");
+        let rejected = |namespace, migrations| {
+            matches!(
+                register_migrations(namespace, migrations),
+                Err(AhenkError::Validation(_))
+            )
+        };
+        assert!(rejected(CORE_NAMESPACE, vec![create(1)]));
+        assert!(rejected("", vec![create(1)]));
+        assert!(rejected("test_invalid", vec![create(0)]));
+        assert!(rejected("test_invalid", vec![create(1), create(1)]));
+        assert!(!registered_namespaces().contains(&"test_invalid".to_string()));
+    }
+
+    #[test]
+    fn test_schema_version_table_is_upgraded() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE schema_version (
+                version INTEGER PRIMARY KEY,
+                applied_at TEXT NOT NULL,
+                description TEXT NOT NULL
+            );
+            INSERT INTO schema_version VALUES (1, '2024-01-01T00:00:00Z', 'Initial');",
+        )
+        .unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+
+        migrate_up(&conn, CORE_NAMESPACE, None).unwrap();
+        let (namespace, applied_at, checksum): (String, String, Option<String>) = conn
+            .query_row(
+                "SELECT namespace, applied_at, checksum FROM schema_version WHERE version = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(namespace, CORE_NAMESPACE);
+        assert_eq!(applied_at, "2024-01-01T00:00:00Z");
+        assert_eq!(checksum, Some(MIGRATIONS[0].checksum()));
+        assert_eq!(get_current_version(&conn).unwrap(), MIGRATIONS.len() as i32);
+    }
+
+    #[test]
+    fn test_failed_migration_restores_backup() {
+        let dir = std::env::temp_dir().join(format!("ahenk-migrate-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nexus.db");
+        let conn = crate::db::connection::open_connection(
+            path.to_str().unwrap(),
+            &crate::db::connection::ConnectionConfig::default(),
+        )
+        .unwrap();
+        migrate_up(&conn, CORE_NAMESPACE, Some(12)).unwrap();
+        conn.execute(
+            "INSERT INTO collections (name, created_at) VALUES ('todos', '')",
+            [],
+        )
+        .unwrap();
+        // Migration 13 applies and commits, then 014 trips over this table
+        conn.execute("CREATE TABLE peers_rebuilt (x)", []).unwrap();
+
+        let backups = BackupConfig::default();
+        assert!(apply_migrations_with_backups(&conn, &backups).is_err());
+        assert_eq!(get_current_version(&conn).unwrap(), 12);
+        let indexes: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'idx_oplog_timestamp'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 0);
+        assert!(crate::db::documents::is_collection(&conn, "todos").unwrap());
+        let saved = backup::list_backups(path.to_str().unwrap(), &backups).unwrap();
+        assert_eq!(saved.len(), 1);
+
+        // Once the obstacle is gone the migrations apply, after another backup
+        conn.execute("DROP TABLE peers_rebuilt", []).unwrap();
+        apply_migrations_with_backups(&conn, &backups).unwrap();
+        assert_eq!(get_current_version(&conn).unwrap(), MIGRATIONS.len() as i32);
+        assert_eq!(
+            backup::list_backups(path.to_str().unwrap(), &backups)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,2 @@
+-- Revert migration 002: Sync Checkpoint
+DROP TABLE IF EXISTS sync_outbox;
@@ -0,0 +1,10 @@
+-- Migration 002: Sync Checkpoint
+-- Description: Persists the sync manager's outbox so pending changes survive a
+-- daemon restart. Entries are written on graceful shutdown and restored on start.
+
+-- Sync Outbox Table: Oplog entries queued for delivery that have not yet reached a peer.
+CREATE TABLE IF NOT EXISTS sync_outbox (
+    id TEXT PRIMARY KEY,              -- Oplog entry UUID
+    entry TEXT NOT NULL,              -- JSON-encoded OplogEntry
+    queued_at TEXT NOT NULL           -- RFC3339 timestamp of when it was checkpointed
+);
@@ -0,0 +1,3 @@
+-- Revert migration 003: Peer Address Book
+DROP INDEX IF EXISTS idx_peer_addresses_device;
+DROP TABLE IF EXISTS peer_addresses;
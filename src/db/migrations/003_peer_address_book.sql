@@ -0,0 +1,19 @@
+-- Migration 003: Peer Address Book
+-- Description: Tracks every multiaddr a libp2p peer has been reachable at, with
+-- dial success/failure history, so dropped connections can be redialed with the
+-- most reliable address first.
+
+-- Peer Addresses Table: Known multiaddrs per libp2p peer.
+CREATE TABLE IF NOT EXISTS peer_addresses (
+    peer_id TEXT NOT NULL,            -- libp2p PeerId (base58)
+    multiaddr TEXT NOT NULL,          -- Dialable multiaddr without the /p2p suffix
+    device_id TEXT,                   -- Device that announced this peer, if known
+    success_count INTEGER NOT NULL DEFAULT 0,
+    failure_count INTEGER NOT NULL DEFAULT 0,
+    last_success TEXT,                -- RFC3339 timestamp of the last successful dial
+    last_failure TEXT,                -- RFC3339 timestamp of the last failed dial
+    last_seen TEXT NOT NULL,          -- RFC3339 timestamp of the last time the address was observed
+    PRIMARY KEY (peer_id, multiaddr)
+);
+
+CREATE INDEX IF NOT EXISTS idx_peer_addresses_device ON peer_addresses(device_id);
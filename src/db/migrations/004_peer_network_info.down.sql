@@ -0,0 +1,4 @@
+-- Revert migration 004: Peer Network Info
+DROP TABLE IF EXISTS local_network_status;
+DROP INDEX IF EXISTS idx_peer_network_info_device;
+DROP TABLE IF EXISTS peer_network_info;
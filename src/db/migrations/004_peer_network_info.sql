@@ -0,0 +1,26 @@
+-- Migration 004: Peer Network Info
+-- Description: Connection details learned through libp2p identify, ping and
+-- AutoNAT: per-peer agent and round-trip time, and this node's NAT status and
+-- observed addresses. Written by the running sync manager so the CLI can show
+-- them without talking to the daemon.
+
+-- Peer Network Info Table: Latest identify/ping results per libp2p peer.
+CREATE TABLE IF NOT EXISTS peer_network_info (
+    peer_id TEXT PRIMARY KEY,         -- libp2p PeerId (base58)
+    device_id TEXT,                   -- Device behind the peer, if known
+    agent_version TEXT,               -- Agent reported via identify
+    protocol_version TEXT,            -- Protocol version reported via identify
+    rtt_ms INTEGER,                   -- Last measured ping round-trip time
+    updated_at TEXT NOT NULL          -- RFC3339 timestamp of the last update
+);
+
+CREATE INDEX IF NOT EXISTS idx_peer_network_info_device ON peer_network_info(device_id);
+
+-- Local Network Status Table: Single row describing this node's reachability.
+CREATE TABLE IF NOT EXISTS local_network_status (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    nat_status TEXT NOT NULL,                     -- 'unknown', 'private' or 'public'
+    public_address TEXT,                          -- Confirmed public multiaddr, if public
+    observed_addresses TEXT NOT NULL DEFAULT '[]', -- JSON array of multiaddrs peers saw us at
+    updated_at TEXT NOT NULL                      -- RFC3339 timestamp of the last update
+);
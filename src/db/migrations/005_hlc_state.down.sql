@@ -0,0 +1,2 @@
+-- Revert migration 005: HLC State
+DROP TABLE IF EXISTS hlc_state;
@@ -0,0 +1,16 @@
+-- Migration 005: HLC State
+-- Description: One hybrid logical clock per database, persisted so that every
+-- writer (daemon, app, CLI) stamps oplog entries from the same clock and the
+-- clock survives restarts. Advanced on every local write and every merged
+-- remote operation.
+
+-- HLC State Table: Single row holding the latest HLC timestamp.
+CREATE TABLE IF NOT EXISTS hlc_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    timestamp INTEGER NOT NULL,       -- Latest HLC timestamp (48-bit ms | 16-bit counter)
+    updated_at TEXT NOT NULL          -- RFC3339 timestamp of the last update
+);
+
+-- Start at the newest entry already in the oplog, so new entries sort after it
+INSERT OR IGNORE INTO hlc_state (id, timestamp, updated_at)
+SELECT 1, COALESCE(MAX(timestamp), 0), strftime('%Y-%m-%dT%H:%M:%SZ', 'now') FROM oplog;
@@ -0,0 +1,34 @@
+-- Migration 006: Change Capture
+-- Description: Automatic capture of INSERT/UPDATE/DELETE on application tables
+-- registered for sync. Triggers installed per table append changes to
+-- sync_capture; they are moved into the oplog, stamped by the database's HLC,
+-- by crate::db::capture::flush_captured_changes. Capture is suppressed while
+-- remote operations are applied, so they do not echo back.
+
+-- Synced Tables: Application tables whose changes are captured.
+CREATE TABLE IF NOT EXISTS sync_tables (
+    table_name TEXT PRIMARY KEY,      -- Application table
+    primary_key TEXT NOT NULL,        -- Column identifying a row across devices
+    columns TEXT NOT NULL,            -- JSON array of captured columns
+    registered_at TEXT NOT NULL       -- RFC3339 timestamp of the (re-)registration
+);
+
+-- Sync Capture: Changes captured by triggers, not yet recorded in the oplog.
+CREATE TABLE IF NOT EXISTS sync_capture (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT, -- Capture order
+    table_name TEXT NOT NULL,              -- Table the change was made to
+    op_type TEXT NOT NULL,                 -- 'insert', 'update' or 'delete'
+    row_key TEXT NOT NULL,                 -- Primary key value of the changed row
+    data TEXT NOT NULL                     -- JSON object of the row (primary key only for deletes)
+);
+
+CREATE INDEX IF NOT EXISTS idx_sync_capture_row ON sync_capture(table_name, row_key);
+
+-- Capture State: Single row; capture is off while `suppressed` is 1. Only ever set
+-- inside the write transaction applying remote operations.
+CREATE TABLE IF NOT EXISTS sync_capture_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    suppressed INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT OR IGNORE INTO sync_capture_state (id, suppressed) VALUES (1, 0);
@@ -0,0 +1,3 @@
+-- Revert migration 007: Collections
+DROP TABLE IF EXISTS collection_documents;
+DROP TABLE IF EXISTS collections;
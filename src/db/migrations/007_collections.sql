@@ -0,0 +1,23 @@
+-- Migration 007: Collections
+-- Description: Document collections synced through the oplog. Each write to a
+-- collection is an oplog entry whose table_name is the collection; the merged
+-- state of every document is kept in collection_documents so reads don't replay
+-- the oplog. Deleted documents stay as tombstones so late, older operations
+-- cannot bring them back.
+
+-- Collections Table: Collections opened on this database.
+CREATE TABLE IF NOT EXISTS collections (
+    name TEXT PRIMARY KEY,            -- Collection name (oplog table_name)
+    created_at TEXT NOT NULL          -- RFC3339 timestamp of the first open
+);
+
+-- Collection Documents Table: Merged state of each document.
+CREATE TABLE IF NOT EXISTS collection_documents (
+    collection TEXT NOT NULL,         -- Collection name
+    id TEXT NOT NULL,                 -- Document ID
+    data TEXT,                        -- JSON document, NULL once deleted
+    timestamp INTEGER NOT NULL,       -- HLC timestamp of the last applied operation
+    op_id TEXT NOT NULL,              -- Oplog entry ID of the last applied operation
+    PRIMARY KEY (collection, id),
+    FOREIGN KEY (collection) REFERENCES collections(name) ON DELETE CASCADE
+);
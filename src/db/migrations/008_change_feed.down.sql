@@ -0,0 +1,5 @@
+-- Revert migration 008: Change Feed
+DROP TRIGGER IF EXISTS ahenk_feed_collection_documents_insert;
+DROP TRIGGER IF EXISTS ahenk_feed_collection_documents_update;
+DROP TRIGGER IF EXISTS ahenk_feed_collection_documents_delete;
+DROP TABLE IF EXISTS change_feed;
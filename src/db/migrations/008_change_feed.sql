@@ -0,0 +1,41 @@
+-- Migration 008: Change Feed
+-- Description: Row-level feed of changes to collections and synced app tables,
+-- from local writes and merges alike, read by live query subscriptions
+-- (crate::logic::live_query). Collection changes are recorded by the triggers
+-- below; synced tables get feed triggers when they are registered. Old rows are
+-- pruned by the readers.
+
+-- Change Feed Table: One row per changed document or table row.
+CREATE TABLE IF NOT EXISTS change_feed (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT, -- Change order
+    source TEXT NOT NULL,                  -- Collection or table name
+    row_id TEXT NOT NULL,                  -- Document ID or primary key value
+    change TEXT NOT NULL                   -- 'inserted', 'updated' or 'deleted'
+);
+
+-- Collection documents: tombstones (NULL data) count as deleted
+CREATE TRIGGER IF NOT EXISTS ahenk_feed_collection_documents_insert
+AFTER INSERT ON collection_documents WHEN NEW.data IS NOT NULL
+BEGIN
+    INSERT INTO change_feed (source, row_id, change) VALUES (NEW.collection, NEW.id, 'inserted');
+END;
+
+CREATE TRIGGER IF NOT EXISTS ahenk_feed_collection_documents_update
+AFTER UPDATE ON collection_documents WHEN OLD.data IS NOT NEW.data
+BEGIN
+    INSERT INTO change_feed (source, row_id, change) VALUES (
+        NEW.collection,
+        NEW.id,
+        CASE
+            WHEN OLD.data IS NULL THEN 'inserted'
+            WHEN NEW.data IS NULL THEN 'deleted'
+            ELSE 'updated'
+        END
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS ahenk_feed_collection_documents_delete
+AFTER DELETE ON collection_documents WHEN OLD.data IS NOT NULL
+BEGIN
+    INSERT INTO change_feed (source, row_id, change) VALUES (OLD.collection, OLD.id, 'deleted');
+END;
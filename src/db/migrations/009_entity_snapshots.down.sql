@@ -0,0 +1,2 @@
+-- Revert migration 009: Entity Snapshots
+DROP TABLE IF EXISTS entity_snapshots;
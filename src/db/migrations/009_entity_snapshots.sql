@@ -0,0 +1,18 @@
+-- Migration 009: Entity Snapshots
+-- Description: Periodic snapshots of entity state so rebuilding an entity from
+-- patch operations only replays the operations after its latest snapshot. A
+-- snapshot records the state after every operation up to (timestamp, op_id); it
+-- is dropped when an older operation on the entity is merged later. Snapshots
+-- are local and never synced.
+
+-- Entity Snapshots Table: Latest snapshot per entity.
+CREATE TABLE IF NOT EXISTS entity_snapshots (
+    table_name TEXT NOT NULL,         -- Table or collection of the entity
+    entity_id TEXT NOT NULL,          -- Entity ID (the "id" of its operations)
+    timestamp INTEGER NOT NULL,       -- HLC timestamp of the last operation included
+    op_id TEXT NOT NULL,              -- ID of the last operation included
+    data TEXT,                        -- JSON state, NULL if deleted
+    op_count INTEGER NOT NULL,        -- Operations included, for diagnostics
+    created_at TEXT NOT NULL,         -- RFC3339 timestamp
+    PRIMARY KEY (table_name, entity_id)
+);
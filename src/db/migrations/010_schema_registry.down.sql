@@ -0,0 +1,4 @@
+-- Revert migration 010: Schema Registry
+DROP INDEX IF EXISTS idx_quarantined_ops_table;
+DROP TABLE IF EXISTS quarantined_ops;
+DROP TABLE IF EXISTS table_schemas;
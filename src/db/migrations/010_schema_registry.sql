@@ -0,0 +1,28 @@
+-- Migration 010: Schema Registry
+-- Description: Declared schemas of synced tables and quarantine for remote
+-- operations that violate them. A table with a registered schema only accepts
+-- operations of its listed types whose payload matches its JSON Schema; invalid
+-- remote operations are kept in quarantined_ops instead of the oplog, so a buggy
+-- client cannot spread bad data to every device.
+
+-- Table Schemas Table: One declared schema per table or collection.
+CREATE TABLE IF NOT EXISTS table_schemas (
+    table_name TEXT PRIMARY KEY,      -- Table or collection name used in the oplog
+    op_types TEXT NOT NULL,           -- JSON array of allowed operation types
+    payload_schema TEXT,              -- JSON Schema for operation payloads, NULL for any
+    registered_at TEXT NOT NULL       -- RFC3339 timestamp
+);
+
+-- Quarantined Operations Table: Remote operations rejected by validation.
+CREATE TABLE IF NOT EXISTS quarantined_ops (
+    id TEXT PRIMARY KEY,              -- Operation ID
+    device_id TEXT NOT NULL,          -- Device that created the operation
+    timestamp INTEGER NOT NULL,       -- HLC timestamp
+    table_name TEXT NOT NULL,
+    op_type TEXT NOT NULL,
+    data TEXT NOT NULL,               -- JSON payload as received
+    reason TEXT NOT NULL,             -- Why validation failed
+    quarantined_at TEXT NOT NULL      -- RFC3339 timestamp
+);
+
+CREATE INDEX IF NOT EXISTS idx_quarantined_ops_table ON quarantined_ops(table_name);
@@ -0,0 +1,6 @@
+-- Revert migration 011: Schema Versions
+DROP INDEX IF EXISTS idx_held_ops_table;
+DROP TABLE IF EXISTS held_ops;
+ALTER TABLE table_schemas DROP COLUMN version;
+ALTER TABLE quarantined_ops DROP COLUMN schema_version;
+ALTER TABLE oplog DROP COLUMN schema_version;
@@ -0,0 +1,24 @@
+-- Migration 011: Schema Versions
+-- Description: Payload schema versions. Every operation records the version of
+-- its table's payload shape it was written with; 0 marks operations from before
+-- versioning. Older payloads are upcast to the current shape when applied.
+-- Remote operations written with a newer version than this device knows are held
+-- back in held_ops until the app is upgraded, instead of being dropped.
+
+ALTER TABLE oplog ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE quarantined_ops ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE table_schemas ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+-- Held Operations Table: Remote operations with a payload version not known yet.
+CREATE TABLE IF NOT EXISTS held_ops (
+    id TEXT PRIMARY KEY,              -- Operation ID
+    device_id TEXT NOT NULL,          -- Device that created the operation
+    timestamp INTEGER NOT NULL,       -- HLC timestamp
+    table_name TEXT NOT NULL,
+    op_type TEXT NOT NULL,
+    data TEXT NOT NULL,               -- JSON payload as received
+    schema_version INTEGER NOT NULL,  -- Payload version, newer than the local one
+    held_at TEXT NOT NULL             -- RFC3339 timestamp
+);
+
+CREATE INDEX IF NOT EXISTS idx_held_ops_table ON held_ops(table_name, schema_version);
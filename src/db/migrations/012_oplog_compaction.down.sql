@@ -0,0 +1,3 @@
+-- Revert migration 012: Oplog Compaction
+DROP TABLE IF EXISTS device_sync_acks;
+DROP TABLE IF EXISTS oplog_compaction;
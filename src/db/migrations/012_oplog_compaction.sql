@@ -0,0 +1,20 @@
+-- Migration 012: Oplog Compaction
+-- Description: Bookkeeping for oplog compaction. Operations at or before the
+-- compaction horizon that are superseded by a later one on the same entity are
+-- collapsed into a single snapshot entry. The horizon is chosen from the sync
+-- acknowledgements of the user's other devices, so only causally stable
+-- operations (seen by every device) are compacted.
+
+-- Oplog Compaction Table: Horizon the oplog has been compacted through (one row).
+CREATE TABLE IF NOT EXISTS oplog_compaction (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    horizon INTEGER NOT NULL,         -- HLC timestamp; history before it may be collapsed
+    compacted_at TEXT NOT NULL        -- RFC3339 timestamp
+);
+
+-- Device Sync Acknowledgements Table: How far each other device has synced.
+CREATE TABLE IF NOT EXISTS device_sync_acks (
+    device_id TEXT PRIMARY KEY,       -- One of the user's other devices
+    acked_timestamp INTEGER NOT NULL, -- HLC timestamp through which it has all entries
+    updated_at TEXT NOT NULL          -- RFC3339 timestamp
+);
@@ -0,0 +1,4 @@
+-- Revert migration 013: Oplog Indexes
+DROP INDEX IF EXISTS idx_oplog_table;
+DROP INDEX IF EXISTS idx_oplog_device;
+DROP INDEX IF EXISTS idx_oplog_timestamp;
@@ -0,0 +1,8 @@
+-- Migration 013: Oplog Indexes
+-- Description: Indexes backing the paginated and filtered oplog reads used by
+-- sync. Entries are paged in (timestamp, id) order, optionally restricted to
+-- one table or one originating device.
+
+CREATE INDEX IF NOT EXISTS idx_oplog_timestamp ON oplog(timestamp, id);
+CREATE INDEX IF NOT EXISTS idx_oplog_device ON oplog(device_id, timestamp, id);
+CREATE INDEX IF NOT EXISTS idx_oplog_table ON oplog(table_name, timestamp, id);
@@ -0,0 +1,36 @@
+-- Migration 014: Drop Device Foreign Keys
+-- Description: Connections now enforce foreign keys. Oplog entries and peer
+-- records refer to the user's other devices, which usually have no row in the
+-- local devices table (they are only registered on the device itself), so
+-- those references are not foreign keys. Both tables are rebuilt without them.
+
+CREATE TABLE oplog_rebuilt (
+    id TEXT PRIMARY KEY,              -- UUID as text
+    device_id TEXT NOT NULL,          -- Device that created this operation
+    timestamp INTEGER NOT NULL,       -- HLC timestamp (64-bit)
+    table_name TEXT NOT NULL,         -- Table this operation affects
+    op_type TEXT NOT NULL,            -- Operation type
+    data TEXT NOT NULL,               -- JSON-encoded operation data
+    schema_version INTEGER NOT NULL DEFAULT 0 -- Payload version (migration 011)
+);
+INSERT INTO oplog_rebuilt (id, device_id, timestamp, table_name, op_type, data, schema_version)
+    SELECT id, device_id, timestamp, table_name, op_type, data, schema_version FROM oplog;
+DROP TABLE oplog;
+ALTER TABLE oplog_rebuilt RENAME TO oplog;
+
+CREATE INDEX IF NOT EXISTS idx_oplog_timestamp ON oplog(timestamp, id);
+CREATE INDEX IF NOT EXISTS idx_oplog_device ON oplog(device_id, timestamp, id);
+CREATE INDEX IF NOT EXISTS idx_oplog_table ON oplog(table_name, timestamp, id);
+
+CREATE TABLE peers_rebuilt (
+    peer_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    device_id UUID NOT NULL,
+    last_known_ip VARCHAR(255),
+    last_sync_time TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+INSERT INTO peers_rebuilt (peer_id, user_id, device_id, last_known_ip, last_sync_time)
+    SELECT peer_id, user_id, device_id, last_known_ip, last_sync_time FROM peers;
+DROP TABLE peers;
+ALTER TABLE peers_rebuilt RENAME TO peers;
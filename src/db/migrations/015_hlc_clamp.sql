@@ -0,0 +1,12 @@
+-- Migration 015: Clamp HLC State
+-- Description: Migration 005 seeded hlc_state from the newest oplog entry. On a
+-- database upgraded from before the clock was persisted, oplog timestamps were
+-- stamped in microseconds, which read as far in the future now that the
+-- physical part is milliseconds. Peers rejecting clock drift would then
+-- quarantine every write from this device, so a clock more than a minute (the
+-- default drift bound) ahead of the current time is brought back to it.
+
+UPDATE hlc_state
+SET timestamp = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER) << 16,
+    updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+WHERE timestamp > (CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER) + 60000) << 16;
@@ -0,0 +1,19 @@
+-- Migration 016: Per-Origin Sync Acknowledgements
+-- Description: device_sync_acks held one timestamp per device, the newest
+-- entry it had merged. That does not mean the device has every older entry:
+-- an op gossiped live can arrive before an older one it never received, and
+-- compacting through that timestamp folded ops the device was missing.
+-- Acknowledgements are now kept per origin device (a version vector): a row
+-- says device_id has every entry origin_device_id wrote up to acked_timestamp.
+-- Existing rows cannot be split by origin, so they are dropped; devices
+-- acknowledge again on their next catch-up sync.
+
+DROP TABLE IF EXISTS device_sync_acks;
+
+CREATE TABLE device_sync_acks (
+    device_id TEXT NOT NULL,
+    origin_device_id TEXT NOT NULL,
+    acked_timestamp INTEGER NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (device_id, origin_device_id)
+);
@@ -1,2 +1,10 @@
+pub mod archive;
+pub mod backup;
+pub mod capture;
+pub mod change_feed;
+pub mod compaction;
+pub mod connection;
+pub mod documents;
 pub mod migrations;
 pub mod operations;
+pub mod schema_registry;
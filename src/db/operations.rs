@@ -5,20 +5,41 @@
 //! - Devices: Device registration and tracking
 //! - OplogEntry: Operation log for CRDT synchronization
 //! - Peer: P2P network peer management
-
-use crate::models::{Device, OplogEntry, Peer, User};
+//! - Sync outbox: Pending changes checkpointed across restarts and local writes awaiting delivery
+//! - PeerAddress: Persistent address book of known peer multiaddrs
+//! - PeerNetworkInfo / NetworkStatus: Identify, ping and AutoNAT results
+//! - HLC state: The database's persisted hybrid logical clock
+
+use crate::models::{
+    Device, NetworkStatus, OplogCursor, OplogEntry, OplogFilter, OplogPage, Peer, PeerAddress,
+    PeerNetworkInfo, User,
+};
 use chrono::{DateTime, Utc};
-use rusqlite::{params, types::Type, Connection, Result, Row};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, params_from_iter, Connection, Result, Row};
 use uuid::Uuid;
 
-/// Initialize the database with migrations
+/// Initialize the database with migrations.
+///
+/// The connection uses WAL journaling, a busy timeout and foreign keys (see
+/// [`super::connection::configure_connection`]).
 pub fn initialize_database(db_path: &str) -> Result<Connection> {
-    let conn = Connection::open(db_path)?;
+    initialize_database_with_config(db_path, &Default::default())
+}
+
+/// Initialize the database like [`initialize_database`], with custom connection
+/// and pre-migration backup settings
+pub fn initialize_database_with_config(
+    db_path: &str,
+    config: &super::connection::ConnectionConfig,
+) -> Result<Connection> {
+    let conn = super::connection::open_connection(db_path, config)?;
 
     // Apply all pending migrations
     // This will create tables if they don't exist (new database)
-    // or upgrade the schema to the latest version (existing database)
-    super::migrations::apply_migrations(&conn)?;
+    // or upgrade the schema to the latest version (existing database).
+    // Existing databases are backed up first and restored if a migration fails
+    super::migrations::apply_migrations_with_backups(&conn, &config.backups)?;
 
     Ok(conn)
 }
@@ -27,7 +48,7 @@ pub fn initialize_database(db_path: &str) -> Result<Connection> {
 // Helper Functions
 // ============================================================================
 
-fn conversion_failure<E>(column_index: usize, err: E) -> rusqlite::Error
+pub(crate) fn conversion_failure<E>(column_index: usize, err: E) -> rusqlite::Error
 where
     E: std::error::Error + Send + Sync + 'static,
 {
@@ -39,7 +60,7 @@ fn parse_uuid_column(row: &Row, idx: usize) -> rusqlite::Result<Uuid> {
     Uuid::parse_str(&value).map_err(|e| conversion_failure(idx, e))
 }
 
-fn parse_datetime_column(row: &Row, idx: usize) -> rusqlite::Result<DateTime<Utc>> {
+pub(crate) fn parse_datetime_column(row: &Row, idx: usize) -> rusqlite::Result<DateTime<Utc>> {
     let value: String = row.get(idx)?;
     DateTime::parse_from_rfc3339(&value)
         .map(|dt| dt.with_timezone(&Utc))
@@ -84,7 +105,7 @@ fn row_to_device(row: &Row) -> rusqlite::Result<Device> {
     })
 }
 
-fn row_to_oplog_entry(row: &Row) -> rusqlite::Result<OplogEntry> {
+pub(crate) fn row_to_oplog_entry(row: &Row) -> rusqlite::Result<OplogEntry> {
     let data_raw: String = row.get(5)?;
     let data = serde_json::from_str(&data_raw).map_err(|e| conversion_failure(5, e))?;
 
@@ -95,6 +116,7 @@ fn row_to_oplog_entry(row: &Row) -> rusqlite::Result<OplogEntry> {
         table: row.get(3)?,
         op_type: row.get(4)?,
         data,
+        schema_version: row.get(6)?,
     })
 }
 
@@ -108,6 +130,40 @@ fn row_to_peer(row: &Row) -> rusqlite::Result<Peer> {
     })
 }
 
+fn row_to_peer_address(row: &Row) -> rusqlite::Result<PeerAddress> {
+    let device_id: Option<String> = row.get(2)?;
+    let device_id = device_id
+        .map(|raw| Uuid::parse_str(&raw).map_err(|e| conversion_failure(2, e)))
+        .transpose()?;
+
+    Ok(PeerAddress {
+        peer_id: row.get(0)?,
+        multiaddr: row.get(1)?,
+        device_id,
+        success_count: row.get(3)?,
+        failure_count: row.get(4)?,
+        last_success: parse_optional_datetime_column(row, 5)?,
+        last_failure: parse_optional_datetime_column(row, 6)?,
+        last_seen: parse_datetime_column(row, 7)?,
+    })
+}
+
+fn row_to_peer_network_info(row: &Row) -> rusqlite::Result<PeerNetworkInfo> {
+    let device_id: Option<String> = row.get(1)?;
+    let device_id = device_id
+        .map(|raw| Uuid::parse_str(&raw).map_err(|e| conversion_failure(1, e)))
+        .transpose()?;
+
+    Ok(PeerNetworkInfo {
+        peer_id: row.get(0)?,
+        device_id,
+        agent_version: row.get(2)?,
+        protocol_version: row.get(3)?,
+        rtt_ms: row.get(4)?,
+        updated_at: parse_datetime_column(row, 5)?,
+    })
+}
+
 // ============================================================================
 // User Operations
 // ============================================================================
@@ -225,24 +281,60 @@ pub fn delete_device(conn: &Connection, device_id: Uuid) -> Result<usize> {
 pub fn create_oplog_entry(conn: &Connection, entry: &OplogEntry) -> Result<()> {
     let data = serde_json::to_string(&entry.data).map_err(|e| conversion_failure(5, e))?;
 
-    conn.execute(
-        "INSERT INTO oplog (id, device_id, timestamp, table_name, op_type, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![
-            &entry.id.to_string(),
-            &entry.device_id.to_string(),
-            entry.timestamp,
-            &entry.table,
-            &entry.op_type,
-            &data,
-        ],
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO oplog (id, device_id, timestamp, table_name, op_type, data, schema_version)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
     )?;
+    stmt.execute(params![
+        &entry.id.to_string(),
+        &entry.device_id.to_string(),
+        entry.timestamp,
+        &entry.table,
+        &entry.op_type,
+        &data,
+        entry.schema_version,
+    ])?;
     Ok(())
 }
 
+/// Record an operation log entry unless one with the same ID exists.
+/// Returns whether it was inserted.
+pub fn insert_oplog_entry(conn: &Connection, entry: &OplogEntry) -> Result<bool> {
+    let data = serde_json::to_string(&entry.data).map_err(|e| conversion_failure(5, e))?;
+
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO oplog (id, device_id, timestamp, table_name, op_type, data, schema_version)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    let inserted = stmt.execute(params![
+        &entry.id.to_string(),
+        &entry.device_id.to_string(),
+        entry.timestamp,
+        &entry.table,
+        &entry.op_type,
+        &data,
+        entry.schema_version,
+    ])?;
+    Ok(inserted > 0)
+}
+
+/// Whether the oplog has an entry with this ID
+pub fn oplog_entry_exists(conn: &Connection, id: Uuid) -> Result<bool> {
+    let mut stmt = conn.prepare_cached("SELECT 1 FROM oplog WHERE id = ?1")?;
+    stmt.exists([id.to_string()])
+}
+
+/// Timestamp of the newest oplog entry, or 0 for an empty oplog
+pub fn latest_oplog_timestamp(conn: &Connection) -> Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(timestamp), 0) FROM oplog", [], |row| {
+        row.get(0)
+    })
+}
+
 /// Get all oplog entries since a timestamp
 pub fn get_oplog_entries_since(conn: &Connection, since: i64) -> Result<Vec<OplogEntry>> {
     let mut stmt = conn.prepare(
-        "SELECT id, device_id, timestamp, table_name, op_type, data FROM oplog WHERE timestamp > ?1 ORDER BY timestamp ASC",
+        "SELECT id, device_id, timestamp, table_name, op_type, data, schema_version FROM oplog WHERE timestamp > ?1 ORDER BY timestamp ASC, id ASC",
     )?;
     let rows = stmt.query_map(params![since], row_to_oplog_entry)?;
 
@@ -254,6 +346,184 @@ pub fn get_oplog_entries_since(conn: &Connection, since: i64) -> Result<Vec<Oplo
     Ok(entries)
 }
 
+const OPLOG_COLUMNS: &str = "id, device_id, timestamp, table_name, op_type, data, schema_version";
+
+/// Build a `SELECT` over the oplog entries after `since` (and after `after`,
+/// when resuming a paged read) matching `filter`, in (timestamp, id) order
+fn filtered_oplog_query(
+    since: i64,
+    filter: &OplogFilter,
+    after: Option<OplogCursor>,
+) -> (String, Vec<Value>) {
+    let mut sql = format!("SELECT {} FROM oplog WHERE timestamp > ?", OPLOG_COLUMNS);
+    let mut values = vec![Value::Integer(since)];
+    if let Some(cursor) = after {
+        sql.push_str(" AND (timestamp, id) > (?, ?)");
+        values.push(Value::Integer(cursor.timestamp));
+        values.push(Value::Text(cursor.id.to_string()));
+    }
+    if let Some(table) = &filter.table {
+        sql.push_str(" AND table_name = ?");
+        values.push(Value::Text(table.clone()));
+    }
+    if let Some(device_id) = filter.device_id {
+        sql.push_str(" AND device_id = ?");
+        values.push(Value::Text(device_id.to_string()));
+    }
+    if let Some(op_type) = &filter.op_type {
+        sql.push_str(" AND op_type = ?");
+        values.push(Value::Text(op_type.clone()));
+    }
+    sql.push_str(" ORDER BY timestamp ASC, id ASC");
+    (sql, values)
+}
+
+/// Get at most `limit` oplog entries since a timestamp matching `filter`,
+/// resuming after `after`. The returned page's `next` cursor continues the read.
+pub fn get_oplog_page(
+    conn: &Connection,
+    since: i64,
+    filter: &OplogFilter,
+    after: Option<OplogCursor>,
+    limit: usize,
+) -> Result<OplogPage> {
+    let limit = limit.max(1);
+    let (mut sql, mut values) = filtered_oplog_query(since, filter, after);
+    // One extra row tells whether another page follows
+    sql.push_str(" LIMIT ?");
+    values.push(Value::Integer(limit as i64 + 1));
+
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let mut entries = stmt
+        .query_map(params_from_iter(values), row_to_oplog_entry)?
+        .collect::<Result<Vec<_>>>()?;
+    let next = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| OplogCursor {
+            timestamp: entry.timestamp,
+            id: entry.id,
+        })
+    } else {
+        None
+    };
+    Ok(OplogPage { entries, next })
+}
+
+/// Call `f` with each oplog entry since a timestamp matching `filter`, one row
+/// at a time, in (timestamp, id) order. Returns the number of entries visited.
+pub fn for_each_oplog_entry<F>(
+    conn: &Connection,
+    since: i64,
+    filter: &OplogFilter,
+    mut f: F,
+) -> Result<usize>
+where
+    F: FnMut(OplogEntry) -> Result<()>,
+{
+    let (sql, values) = filtered_oplog_query(since, filter, None);
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    let mut visited = 0;
+    while let Some(row) = rows.next()? {
+        f(row_to_oplog_entry(row)?)?;
+        visited += 1;
+    }
+    Ok(visited)
+}
+
+/// Iterator over the oplog in pages of at most `page_size` entries, see
+/// [`oplog_pages`]
+pub struct OplogPages<'a> {
+    conn: &'a Connection,
+    since: i64,
+    filter: OplogFilter,
+    page_size: usize,
+    cursor: Option<OplogCursor>,
+    done: bool,
+}
+
+impl Iterator for OplogPages<'_> {
+    type Item = Result<Vec<OplogEntry>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match get_oplog_page(
+            self.conn,
+            self.since,
+            &self.filter,
+            self.cursor,
+            self.page_size,
+        ) {
+            Ok(page) => {
+                self.cursor = page.next;
+                self.done = page.next.is_none();
+                if page.entries.is_empty() {
+                    None
+                } else {
+                    Some(Ok(page.entries))
+                }
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Page through the oplog entries since a timestamp matching `filter`, so only
+/// `page_size` entries are held in memory at once. Each page is a separate
+/// query, so the connection is free between pages.
+pub fn oplog_pages(
+    conn: &Connection,
+    since: i64,
+    filter: OplogFilter,
+    page_size: usize,
+) -> OplogPages<'_> {
+    OplogPages {
+        conn,
+        since,
+        filter,
+        page_size,
+        cursor: None,
+        done: false,
+    }
+}
+
+/// Get all oplog entries of a table in HLC order, ties broken by entry ID
+pub fn get_table_oplog_entries(conn: &Connection, table: &str) -> Result<Vec<OplogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, device_id, timestamp, table_name, op_type, data, schema_version FROM oplog
+         WHERE table_name = ?1 ORDER BY timestamp ASC, id ASC",
+    )?;
+    let rows = stmt.query_map(params![table], row_to_oplog_entry)?;
+    rows.collect()
+}
+
+/// Get the oplog entries on one entity (entries whose data has that `id`) after
+/// `(timestamp, op_id)`, in HLC order with ties broken by entry ID
+pub fn get_entity_oplog_entries(
+    conn: &Connection,
+    table: &str,
+    entity_id: &str,
+    after_timestamp: i64,
+    after_op_id: &str,
+) -> Result<Vec<OplogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, device_id, timestamp, table_name, op_type, data, schema_version FROM oplog
+         WHERE table_name = ?1 AND json_extract(data, '$.id') = ?2
+           AND (timestamp, id) > (?3, ?4)
+         ORDER BY timestamp ASC, id ASC",
+    )?;
+    let rows = stmt.query_map(
+        params![table, entity_id, after_timestamp, after_op_id],
+        row_to_oplog_entry,
+    )?;
+    rows.collect()
+}
+
 // ============================================================================
 // Peer Operations
 // ============================================================================
@@ -318,3 +588,348 @@ pub fn delete_peer(conn: &Connection, peer_id: Uuid) -> Result<usize> {
         params![peer_id.to_string()],
     )
 }
+
+/// Update the last known IP address of the peer record for a device
+pub fn update_peer_last_known_ip(conn: &Connection, device_id: Uuid, ip: &str) -> Result<usize> {
+    conn.execute(
+        "UPDATE peers SET last_known_ip = ?1 WHERE device_id = ?2",
+        params![ip, device_id.to_string()],
+    )
+}
+
+/// Update the last sync time of the peer record for a device
+pub fn update_peer_last_sync_time(
+    conn: &Connection,
+    device_id: Uuid,
+    last_sync_time: i64,
+) -> Result<usize> {
+    conn.execute(
+        "UPDATE peers SET last_sync_time = ?1 WHERE device_id = ?2",
+        params![last_sync_time, device_id.to_string()],
+    )
+}
+
+// ============================================================================
+// Peer Address Book Operations
+// ============================================================================
+
+const PEER_ADDRESS_COLUMNS: &str = "peer_id, multiaddr, device_id, success_count, failure_count, last_success, last_failure, last_seen";
+
+// Most reliable addresses first: recently successful, then fewest failures
+const PEER_ADDRESS_ORDER: &str =
+    "ORDER BY last_success IS NULL, last_success DESC, failure_count ASC, last_seen DESC";
+
+/// Record that a peer was observed at an address.
+///
+/// Creates the address book entry if needed and refreshes `last_seen`. A known
+/// `device_id` is kept when the new observation does not carry one.
+pub fn upsert_peer_address(
+    conn: &Connection,
+    peer_id: &str,
+    multiaddr: &str,
+    device_id: Option<Uuid>,
+    seen_at: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO peer_addresses (peer_id, multiaddr, device_id, last_seen) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(peer_id, multiaddr) DO UPDATE SET
+             device_id = COALESCE(excluded.device_id, peer_addresses.device_id),
+             last_seen = excluded.last_seen",
+        params![
+            peer_id,
+            multiaddr,
+            device_id.map(|id| id.to_string()),
+            seen_at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Record a successful dial to a peer address
+pub fn record_peer_address_success(
+    conn: &Connection,
+    peer_id: &str,
+    multiaddr: &str,
+    at: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO peer_addresses (peer_id, multiaddr, success_count, last_success, last_seen) VALUES (?1, ?2, 1, ?3, ?3)
+         ON CONFLICT(peer_id, multiaddr) DO UPDATE SET
+             success_count = peer_addresses.success_count + 1,
+             last_success = excluded.last_success,
+             last_seen = excluded.last_seen",
+        params![peer_id, multiaddr, at.to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Record a failed dial to a peer address
+pub fn record_peer_address_failure(
+    conn: &Connection,
+    peer_id: &str,
+    multiaddr: &str,
+    at: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO peer_addresses (peer_id, multiaddr, failure_count, last_failure, last_seen) VALUES (?1, ?2, 1, ?3, ?3)
+         ON CONFLICT(peer_id, multiaddr) DO UPDATE SET
+             failure_count = peer_addresses.failure_count + 1,
+             last_failure = excluded.last_failure",
+        params![peer_id, multiaddr, at.to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Get all known addresses for a peer, most reliable first
+pub fn get_peer_addresses(conn: &Connection, peer_id: &str) -> Result<Vec<PeerAddress>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM peer_addresses WHERE peer_id = ?1 {}",
+        PEER_ADDRESS_COLUMNS, PEER_ADDRESS_ORDER
+    ))?;
+    let rows = stmt.query_map(params![peer_id], row_to_peer_address)?;
+
+    let mut addresses = Vec::new();
+    for row in rows {
+        addresses.push(row?);
+    }
+
+    Ok(addresses)
+}
+
+/// Get all known addresses of the peers a device has announced, most reliable first
+pub fn get_peer_addresses_by_device(
+    conn: &Connection,
+    device_id: Uuid,
+) -> Result<Vec<PeerAddress>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM peer_addresses WHERE device_id = ?1 {}",
+        PEER_ADDRESS_COLUMNS, PEER_ADDRESS_ORDER
+    ))?;
+    let rows = stmt.query_map(params![device_id.to_string()], row_to_peer_address)?;
+
+    let mut addresses = Vec::new();
+    for row in rows {
+        addresses.push(row?);
+    }
+
+    Ok(addresses)
+}
+
+/// Get the whole address book
+pub fn get_all_peer_addresses(conn: &Connection) -> Result<Vec<PeerAddress>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM peer_addresses {}",
+        PEER_ADDRESS_COLUMNS, PEER_ADDRESS_ORDER
+    ))?;
+    let rows = stmt.query_map(params![], row_to_peer_address)?;
+
+    let mut addresses = Vec::new();
+    for row in rows {
+        addresses.push(row?);
+    }
+
+    Ok(addresses)
+}
+
+// ============================================================================
+// Peer Network Info Operations
+// ============================================================================
+
+const PEER_NETWORK_INFO_COLUMNS: &str =
+    "peer_id, device_id, agent_version, protocol_version, rtt_ms, updated_at";
+
+/// Record what a peer reported about itself via identify.
+///
+/// A known `device_id` is kept when the new report does not carry one.
+pub fn upsert_peer_identity(
+    conn: &Connection,
+    peer_id: &str,
+    device_id: Option<Uuid>,
+    agent_version: &str,
+    protocol_version: &str,
+    at: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO peer_network_info (peer_id, device_id, agent_version, protocol_version, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(peer_id) DO UPDATE SET
+             device_id = COALESCE(excluded.device_id, peer_network_info.device_id),
+             agent_version = excluded.agent_version,
+             protocol_version = excluded.protocol_version,
+             updated_at = excluded.updated_at",
+        params![
+            peer_id,
+            device_id.map(|id| id.to_string()),
+            agent_version,
+            protocol_version,
+            at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Record the latest ping round-trip time to a peer
+pub fn record_peer_rtt(
+    conn: &Connection,
+    peer_id: &str,
+    rtt_ms: u64,
+    at: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO peer_network_info (peer_id, rtt_ms, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT(peer_id) DO UPDATE SET
+             rtt_ms = excluded.rtt_ms,
+             updated_at = excluded.updated_at",
+        params![peer_id, rtt_ms, at.to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Get the network info of a libp2p peer
+pub fn get_peer_network_info(conn: &Connection, peer_id: &str) -> Result<Option<PeerNetworkInfo>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM peer_network_info WHERE peer_id = ?1",
+        PEER_NETWORK_INFO_COLUMNS
+    ))?;
+    let mut rows = stmt.query_map(params![peer_id], row_to_peer_network_info)?;
+
+    rows.next().transpose()
+}
+
+/// Get the network info of every libp2p peer known to belong to a device,
+/// either directly or through the address book
+pub fn get_peer_network_info_by_device(
+    conn: &Connection,
+    device_id: Uuid,
+) -> Result<Vec<PeerNetworkInfo>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM peer_network_info
+         WHERE device_id = ?1
+            OR peer_id IN (SELECT peer_id FROM peer_addresses WHERE device_id = ?1)
+         ORDER BY updated_at DESC",
+        PEER_NETWORK_INFO_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![device_id.to_string()], row_to_peer_network_info)?;
+
+    let mut infos = Vec::new();
+    for row in rows {
+        infos.push(row?);
+    }
+
+    Ok(infos)
+}
+
+/// Store the local node's network status, replacing the previous one
+pub fn save_network_status(conn: &Connection, status: &NetworkStatus) -> Result<()> {
+    let observed =
+        serde_json::to_string(&status.observed_addresses).map_err(|e| conversion_failure(3, e))?;
+    conn.execute(
+        "INSERT OR REPLACE INTO local_network_status (id, nat_status, public_address, observed_addresses, updated_at)
+         VALUES (1, ?1, ?2, ?3, ?4)",
+        params![
+            status.nat_status,
+            status.public_address,
+            observed,
+            status.updated_at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Get the local node's last saved network status
+pub fn get_network_status(conn: &Connection) -> Result<Option<NetworkStatus>> {
+    let mut stmt = conn.prepare(
+        "SELECT nat_status, public_address, observed_addresses, updated_at FROM local_network_status WHERE id = 1",
+    )?;
+    let mut rows = stmt.query_map(params![], |row| {
+        let observed: String = row.get(2)?;
+        Ok(NetworkStatus {
+            nat_status: row.get(0)?,
+            public_address: row.get(1)?,
+            observed_addresses: serde_json::from_str(&observed)
+                .map_err(|e| conversion_failure(2, e))?,
+            updated_at: parse_datetime_column(row, 3)?,
+        })
+    })?;
+
+    rows.next().transpose()
+}
+
+// ============================================================================
+// HLC State Operations
+// ============================================================================
+
+/// Get the database's persisted HLC timestamp (0 if none was stored yet)
+pub fn get_hlc_timestamp(conn: &Connection) -> Result<i64> {
+    let mut stmt = conn.prepare("SELECT timestamp FROM hlc_state WHERE id = 1")?;
+    let mut rows = stmt.query_map(params![], |row| row.get(0))?;
+    rows.next().transpose().map(|ts| ts.unwrap_or(0))
+}
+
+/// Replace the persisted HLC timestamp, but only if it still equals `expected`.
+///
+/// Returns whether the update happened; a concurrent writer may have moved the
+/// clock in between, in which case the caller should read it again and retry.
+pub fn compare_and_set_hlc_timestamp(conn: &Connection, expected: i64, new: i64) -> Result<bool> {
+    let now = Utc::now().to_rfc3339();
+    let updated = conn.execute(
+        "UPDATE hlc_state SET timestamp = ?1, updated_at = ?2 WHERE id = 1 AND timestamp = ?3",
+        params![new, now, expected],
+    )?;
+    if updated == 1 {
+        return Ok(true);
+    }
+
+    // The row only goes missing if the table was emptied by hand
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO hlc_state (id, timestamp, updated_at) SELECT 1, ?1, ?2
+         WHERE ?3 = 0",
+        params![new, now, expected],
+    )?;
+    Ok(inserted == 1)
+}
+
+// ============================================================================
+// Sync Outbox Operations
+// ============================================================================
+
+/// Queue entries for delivery by adding them to the persisted sync outbox.
+///
+/// Entries already in the outbox are left as they are.
+pub fn enqueue_sync_outbox(conn: &Connection, entries: &[OplogEntry]) -> Result<()> {
+    let mut stmt = conn
+        .prepare("INSERT OR IGNORE INTO sync_outbox (id, entry, queued_at) VALUES (?1, ?2, ?3)")?;
+    let queued_at = Utc::now().to_rfc3339();
+    for entry in entries {
+        let raw = serde_json::to_string(entry).map_err(|e| conversion_failure(1, e))?;
+        stmt.execute(params![entry.id.to_string(), raw, queued_at])?;
+    }
+
+    Ok(())
+}
+
+/// Remove and return all entries in the persisted sync outbox, oldest first
+pub fn take_sync_outbox(conn: &mut Connection) -> Result<Vec<OplogEntry>> {
+    let tx = conn.transaction()?;
+    let entries = get_sync_outbox(&tx)?;
+    tx.execute("DELETE FROM sync_outbox", [])?;
+    tx.commit()?;
+
+    Ok(entries)
+}
+
+/// Get all entries in the persisted sync outbox, oldest first
+pub fn get_sync_outbox(conn: &Connection) -> Result<Vec<OplogEntry>> {
+    let mut stmt =
+        conn.prepare("SELECT entry FROM sync_outbox ORDER BY queued_at ASC, rowid ASC")?;
+    let rows = stmt.query_map(params![], |row| {
+        let raw: String = row.get(0)?;
+        serde_json::from_str(&raw).map_err(|e| conversion_failure(0, e))
+    })?;
+
+    let mut entries = Vec::new();
+    for row in rows {
+        entries.push(row?);
+    }
+
+    Ok(entries)
+}
@@ -33,11 +33,14 @@ CREATE TABLE oplog (
     table_name TEXT NOT NULL,            -- Which table this operation affects
     op_type TEXT NOT NULL,               -- Operation type: "create", "update", "delete"
     data TEXT NOT NULL,                  -- JSON-serialized operation data
-    FOREIGN KEY (device_id) REFERENCES devices(device_id)
+    schema_version INTEGER NOT NULL DEFAULT 0 -- Payload version (migration 011)
+    -- device_id is not a foreign key: entries come from other devices (migration 014)
 );
 
--- Index for efficient oplog queries (sync since timestamp)
-CREATE INDEX idx_oplog_timestamp ON oplog(timestamp);
+-- Indexes for paged oplog queries (sync since timestamp, filtered by table or device)
+CREATE INDEX idx_oplog_timestamp ON oplog(timestamp, id);
+CREATE INDEX idx_oplog_device ON oplog(device_id, timestamp, id);
+CREATE INDEX idx_oplog_table ON oplog(table_name, timestamp, id);
 
 -- Peers Table: P2P network peer information
 CREATE TABLE peers (
@@ -46,8 +49,166 @@ CREATE TABLE peers (
     device_id TEXT NOT NULL,             -- Device identifier
     last_known_ip TEXT,                  -- Last known IP address (optional)
     last_sync_time TEXT,                 -- RFC3339 timestamp of last sync
-    FOREIGN KEY (user_id) REFERENCES users(user_id),
-    FOREIGN KEY (device_id) REFERENCES devices(device_id)
+    FOREIGN KEY (user_id) REFERENCES users(user_id)
+    -- device_id is not a foreign key: peers are other devices (migration 014)
+);
+
+-- Sync Outbox Table: Pending changes checkpointed on graceful shutdown (migration 002)
+CREATE TABLE sync_outbox (
+    id TEXT PRIMARY KEY,                 -- Oplog entry UUID
+    entry TEXT NOT NULL,                 -- JSON-serialized OplogEntry
+    queued_at TEXT NOT NULL              -- RFC3339 timestamp of the checkpoint
+);
+
+-- Peer Addresses Table: Address book of known multiaddrs per libp2p peer (migration 003)
+CREATE TABLE peer_addresses (
+    peer_id TEXT NOT NULL,               -- libp2p PeerId
+    multiaddr TEXT NOT NULL,             -- Dialable multiaddr
+    device_id TEXT,                      -- Announcing device, if known
+    success_count INTEGER NOT NULL DEFAULT 0,
+    failure_count INTEGER NOT NULL DEFAULT 0,
+    last_success TEXT,                   -- RFC3339 timestamp
+    last_failure TEXT,                   -- RFC3339 timestamp
+    last_seen TEXT NOT NULL,             -- RFC3339 timestamp
+    PRIMARY KEY (peer_id, multiaddr)
+);
+
+-- Peer Network Info Table: Identify and ping results per libp2p peer (migration 004)
+CREATE TABLE peer_network_info (
+    peer_id TEXT PRIMARY KEY,            -- libp2p PeerId
+    device_id TEXT,                      -- Device behind the peer, if known
+    agent_version TEXT,
+    protocol_version TEXT,
+    rtt_ms INTEGER,                      -- Last ping round-trip time
+    updated_at TEXT NOT NULL             -- RFC3339 timestamp
+);
+
+-- Local Network Status Table: This node's NAT status and observed addresses (migration 004)
+CREATE TABLE local_network_status (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    nat_status TEXT NOT NULL,            -- 'unknown', 'private' or 'public'
+    public_address TEXT,
+    observed_addresses TEXT NOT NULL DEFAULT '[]', -- JSON array
+    updated_at TEXT NOT NULL             -- RFC3339 timestamp
+);
+
+-- HLC State Table: The database's hybrid logical clock (migration 005)
+CREATE TABLE hlc_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    timestamp INTEGER NOT NULL,          -- Latest HLC timestamp
+    updated_at TEXT NOT NULL             -- RFC3339 timestamp
+);
+
+-- Synced Tables: App tables registered for automatic change capture (migration 006)
+CREATE TABLE sync_tables (
+    table_name TEXT PRIMARY KEY,
+    primary_key TEXT NOT NULL,
+    columns TEXT NOT NULL,               -- JSON array
+    registered_at TEXT NOT NULL          -- RFC3339 timestamp
+);
+
+-- Sync Capture: Trigger-captured changes awaiting an HLC stamp (migration 006)
+CREATE TABLE sync_capture (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    table_name TEXT NOT NULL,
+    op_type TEXT NOT NULL,               -- 'insert', 'update' or 'delete'
+    row_key TEXT NOT NULL,
+    data TEXT NOT NULL                   -- JSON object
+);
+
+-- Capture State: Whether capture is suppressed (migration 006)
+CREATE TABLE sync_capture_state (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    suppressed INTEGER NOT NULL DEFAULT 0
+);
+
+-- Collections: Synced document collections (migration 007)
+CREATE TABLE collections (
+    name TEXT PRIMARY KEY,
+    created_at TEXT NOT NULL             -- RFC3339 timestamp
+);
+
+-- Collection Documents: Merged state of each document, tombstones for deletes (migration 007)
+CREATE TABLE collection_documents (
+    collection TEXT NOT NULL,
+    id TEXT NOT NULL,
+    data TEXT,                           -- JSON document, NULL once deleted
+    timestamp INTEGER NOT NULL,          -- HLC timestamp of the last applied operation
+    op_id TEXT NOT NULL,
+    PRIMARY KEY (collection, id),
+    FOREIGN KEY (collection) REFERENCES collections(name) ON DELETE CASCADE
+);
+
+-- Change Feed: Changed documents and synced table rows, read by live queries (migration 008)
+CREATE TABLE change_feed (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    source TEXT NOT NULL,                -- Collection or table name
+    row_id TEXT NOT NULL,
+    change TEXT NOT NULL                 -- 'inserted', 'updated' or 'deleted'
+);
+
+-- Entity Snapshots: Latest local snapshot of each entity's state (migration 009)
+CREATE TABLE entity_snapshots (
+    table_name TEXT NOT NULL,
+    entity_id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,          -- HLC timestamp of the last operation included
+    op_id TEXT NOT NULL,
+    data TEXT,                           -- JSON state, NULL if deleted
+    op_count INTEGER NOT NULL,
+    created_at TEXT NOT NULL,            -- RFC3339 timestamp
+    PRIMARY KEY (table_name, entity_id)
+);
+
+-- Table Schemas: Declared op types and payload schemas of synced tables (migration 010)
+CREATE TABLE table_schemas (
+    table_name TEXT PRIMARY KEY,
+    op_types TEXT NOT NULL,              -- JSON array of allowed operation types
+    payload_schema TEXT,                 -- JSON Schema, NULL to accept any payload
+    registered_at TEXT NOT NULL,         -- RFC3339 timestamp
+    version INTEGER NOT NULL DEFAULT 0   -- Current payload version (migration 011)
+);
+
+-- Quarantined Operations: Remote operations rejected by validation (migration 010)
+CREATE TABLE quarantined_ops (
+    id TEXT PRIMARY KEY,
+    device_id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    table_name TEXT NOT NULL,
+    op_type TEXT NOT NULL,
+    data TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    quarantined_at TEXT NOT NULL,        -- RFC3339 timestamp
+    schema_version INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX idx_quarantined_ops_table ON quarantined_ops(table_name);
+
+-- Held Operations: Remote operations with a newer payload version (migration 011)
+CREATE TABLE held_ops (
+    id TEXT PRIMARY KEY,
+    device_id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    table_name TEXT NOT NULL,
+    op_type TEXT NOT NULL,
+    data TEXT NOT NULL,
+    schema_version INTEGER NOT NULL,
+    held_at TEXT NOT NULL                -- RFC3339 timestamp
+);
+
+CREATE INDEX idx_held_ops_table ON held_ops(table_name, schema_version);
+
+-- Oplog Compaction: Horizon the oplog has been compacted through (migration 012)
+CREATE TABLE oplog_compaction (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    horizon INTEGER NOT NULL,            -- HLC timestamp
+    compacted_at TEXT NOT NULL           -- RFC3339 timestamp
+);
+
+-- Device Sync Acknowledgements: How far each other device has synced (migration 012)
+CREATE TABLE device_sync_acks (
+    device_id TEXT PRIMARY KEY,
+    acked_timestamp INTEGER NOT NULL,    -- HLC timestamp through which it has all entries
+    updated_at TEXT NOT NULL             -- RFC3339 timestamp
 );
 
 -- ============================================================================
@@ -81,7 +242,7 @@ CREATE TABLE peers (
 -- conn.execute("INSERT INTO tasks (...) VALUES (...)", params![...])?;
 --
 -- // Create oplog entry for sync
--- let oplog = build_oplog_entry(device_id, "tasks", "create", &task)?;
+-- let oplog = build_oplog_entry(&conn, device_id, "tasks", "create", &task)?;
 -- local_apply(&mut conn, &oplog)?;
 -- ```
 --
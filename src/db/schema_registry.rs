@@ -0,0 +1,716 @@
+//! Declared schemas of synced tables and validation of their operations.
+//!
+//! Apps register a [`TableSchema`] for each table or collection they sync: the
+//! operation types it accepts and, optionally, a JSON Schema for their payloads.
+//! A Rust type can stand in for the JSON Schema with [`register_payload_type`].
+//! Tables without a registered schema accept any operation.
+//!
+//! A schema also has a version. Local writes record the current version in
+//! `OplogEntry::schema_version`; operations written with an older version are
+//! brought to the current shape by the upcasters registered with
+//! [`register_upcaster`], one version step at a time, before they are validated
+//! and applied. The oplog keeps them as written. Remote operations with a newer
+//! version than the local schema are held back in `held_ops` until the app is
+//! upgraded and `crdt::retry_held_ops` merges them.
+//!
+//! `crdt::local_apply` rejects invalid operations. `crdt::merge` records invalid
+//! remote operations in `quarantined_ops` instead of the oplog, so one buggy
+//! client cannot spread bad data to every device; `crdt::retry_quarantined` merges
+//! them again after the schema changed.
+//!
+//! Supported JSON Schema keywords: `type`, `enum`, `const`, `properties`,
+//! `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+//! `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`,
+//! `exclusiveMaximum`, `allOf`, `anyOf`, `oneOf` and `not`, plus the annotations
+//! `$schema`, `$id`, `$comment`, `title`, `description`, `default` and
+//! `examples`. [`register_schema`] rejects schemas using any other keyword
+//! rather than silently not enforcing it.
+
+use crate::db::operations::{conversion_failure, parse_datetime_column, row_to_oplog_entry};
+use crate::error::AhenkError;
+use crate::models::{OplogEntry, QuarantinedOp, TableSchema};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Result, Row};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use uuid::Uuid;
+
+type PayloadCheck = fn(&Value) -> std::result::Result<(), String>;
+type Upcaster = dyn Fn(Value) -> std::result::Result<Value, String> + Send + Sync;
+type Upcasters = HashMap<(String, u32), Arc<Upcaster>>;
+
+/// JSON Schema keywords [`check`] enforces
+const VALIDATION_KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+];
+
+/// JSON Schema keywords that only annotate and never affect validation
+const ANNOTATION_KEYWORDS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// Register the schema of a table or collection, replacing any previous one.
+///
+/// `version` is the current payload version, stamped on local writes. `op_types`
+/// must not be empty. `payload_schema`, if given, must be a JSON Schema object (or
+/// boolean) using only the supported keywords (see the module documentation) and
+/// applies to the `data` of every operation, after upcasting.
+pub fn register_schema(
+    conn: &Connection,
+    table: &str,
+    version: u32,
+    op_types: &[&str],
+    payload_schema: Option<&Value>,
+) -> crate::error::Result<TableSchema> {
+    if op_types.is_empty() {
+        return Err(AhenkError::Validation(format!(
+            "Schema of '{}' allows no operation types",
+            table
+        )));
+    }
+    if let Some(schema) = payload_schema {
+        check_supported(schema, "").map_err(|reason| {
+            AhenkError::Validation(format!("Payload schema of '{}': {}", table, reason))
+        })?;
+    }
+
+    let schema = TableSchema {
+        table_name: table.to_string(),
+        version,
+        op_types: op_types.iter().map(|op| op.to_string()).collect(),
+        payload_schema: payload_schema.cloned(),
+        registered_at: Utc::now(),
+    };
+    let op_types_json = serde_json::to_string(&schema.op_types)
+        .map_err(|e| AhenkError::Serialization(e.to_string()))?;
+    conn.execute(
+        "INSERT OR REPLACE INTO table_schemas
+             (table_name, op_types, payload_schema, registered_at, version)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            schema.table_name,
+            op_types_json,
+            schema.payload_schema.as_ref().map(Value::to_string),
+            schema.registered_at.to_rfc3339(),
+            schema.version
+        ],
+    )?;
+    Ok(schema)
+}
+
+/// Remove the schema of a table; its operations are no longer validated
+pub fn unregister_schema(conn: &Connection, table: &str) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM table_schemas WHERE table_name = ?1", [table])? > 0)
+}
+
+/// The registered schema of a table, if any
+pub fn get_schema(conn: &Connection, table: &str) -> Result<Option<TableSchema>> {
+    // Looked up for every merged operation, so the statement is cached
+    let mut stmt = conn.prepare_cached(
+        "SELECT table_name, op_types, payload_schema, registered_at, version FROM table_schemas
+         WHERE table_name = ?1",
+    )?;
+    stmt.query_row([table], read_schema).optional()
+}
+
+/// Current payload version of a table, 0 if it has no registered schema
+pub fn current_version(conn: &Connection, table: &str) -> Result<u32> {
+    let mut stmt =
+        conn.prepare_cached("SELECT version FROM table_schemas WHERE table_name = ?1")?;
+    Ok(stmt
+        .query_row([table], |row| row.get(0))
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// All registered schemas
+pub fn registered_schemas(conn: &Connection) -> Result<Vec<TableSchema>> {
+    let mut stmt = conn.prepare(
+        "SELECT table_name, op_types, payload_schema, registered_at, version FROM table_schemas
+         ORDER BY table_name",
+    )?;
+    let rows = stmt.query_map([], read_schema)?;
+    rows.collect()
+}
+
+/// Require payloads of `table` to deserialize into `T`.
+///
+/// Unlike [`register_schema`] this is not stored in the database: it holds for
+/// every connection of this process and must be registered again on each start.
+/// It applies in addition to a registered JSON Schema.
+pub fn register_payload_type<T: DeserializeOwned>(table: &str) {
+    payload_types()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(table.to_string(), check_payload_type::<T>);
+}
+
+/// Stop checking payloads of `table` against a Rust type
+pub fn unregister_payload_type(table: &str) -> bool {
+    payload_types()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(table)
+        .is_some()
+}
+
+/// Register a function upcasting payloads of `table` from `from_version` to
+/// `from_version + 1`, replacing any previous one.
+///
+/// Like payload types, upcasters are not stored in the database and must be
+/// registered again on each start, before operations are merged or replayed.
+pub fn register_upcaster<F>(table: &str, from_version: u32, upcaster: F)
+where
+    F: Fn(Value) -> std::result::Result<Value, String> + Send + Sync + 'static,
+{
+    upcasters()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert((table.to_string(), from_version), Arc::new(upcaster));
+}
+
+/// Bring an operation's payload to the newest version its upcasters reach.
+///
+/// Operations without an upcaster for their version are returned unchanged.
+/// Fails if an upcaster fails.
+pub fn upcast(op: &OplogEntry) -> std::result::Result<Cow<'_, OplogEntry>, String> {
+    let mut op = Cow::Borrowed(op);
+    loop {
+        let upcaster = upcasters()
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&(op.table.clone(), op.schema_version))
+            .cloned();
+        let Some(upcaster) = upcaster else {
+            return Ok(op);
+        };
+        let data = upcaster(op.data.clone()).map_err(|e| {
+            format!(
+                "cannot upcast '{}' payload from version {}: {}",
+                op.table, op.schema_version, e
+            )
+        })?;
+        let upcast = op.to_mut();
+        upcast.data = data;
+        upcast.schema_version += 1;
+    }
+}
+
+/// Validate an operation against its table's schema and payload type.
+///
+/// The payload is upcast first; an operation that cannot be brought to the
+/// current version is invalid. Returns why the operation is invalid, or `None` if
+/// it is valid.
+pub fn validate_op(conn: &Connection, op: &OplogEntry) -> Result<Option<String>> {
+    let op = match upcast(op) {
+        Ok(op) => op,
+        Err(reason) => return Ok(Some(reason)),
+    };
+    if let Some(schema) = get_schema(conn, &op.table)? {
+        if op.schema_version > schema.version {
+            return Ok(Some(format!(
+                "'{}' payload version {} is newer than the current version {}",
+                op.table, op.schema_version, schema.version
+            )));
+        }
+        if op.schema_version < schema.version {
+            return Ok(Some(format!(
+                "no upcaster for '{}' payloads from version {} (current: {})",
+                op.table, op.schema_version, schema.version
+            )));
+        }
+        if !schema.op_types.contains(&op.op_type) {
+            return Ok(Some(format!(
+                "operation type '{}' is not allowed on '{}' (allowed: {})",
+                op.op_type,
+                op.table,
+                schema.op_types.join(", ")
+            )));
+        }
+        if let Some(payload_schema) = &schema.payload_schema {
+            if let Err(reason) = check(payload_schema, &op.data, "") {
+                return Ok(Some(format!("invalid '{}' payload: {}", op.table, reason)));
+            }
+        }
+    }
+
+    let payload_type = payload_types()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&op.table)
+        .copied();
+    if let Some(check_type) = payload_type {
+        if let Err(reason) = check_type(&op.data) {
+            return Ok(Some(format!("invalid '{}' payload: {}", op.table, reason)));
+        }
+    }
+    Ok(None)
+}
+
+/// Keep a rejected remote operation out of the oplog, replacing an earlier
+/// quarantine of the same operation
+pub fn quarantine(conn: &Connection, op: &OplogEntry, reason: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO quarantined_ops
+             (id, device_id, timestamp, table_name, op_type, data, schema_version, reason,
+              quarantined_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            op.id.to_string(),
+            op.device_id.to_string(),
+            op.timestamp,
+            op.table,
+            op.op_type,
+            op.data.to_string(),
+            op.schema_version,
+            reason,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Quarantined operations, oldest first
+pub fn quarantined_ops(conn: &Connection) -> Result<Vec<QuarantinedOp>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, device_id, timestamp, table_name, op_type, data, schema_version, reason,
+                quarantined_at
+         FROM quarantined_ops ORDER BY timestamp, id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(QuarantinedOp {
+            entry: row_to_oplog_entry(row)?,
+            reason: row.get(7)?,
+            quarantined_at: parse_datetime_column(row, 8)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Remove an operation from quarantine, e.g. once it was accepted or to discard it
+pub fn release_quarantined(conn: &Connection, op_id: Uuid) -> Result<bool> {
+    let mut stmt = conn.prepare_cached("DELETE FROM quarantined_ops WHERE id = ?1")?;
+    Ok(stmt.execute([op_id.to_string()])? > 0)
+}
+
+/// Keep a remote operation with a newer payload version than the local schema
+/// until this device is upgraded
+pub fn hold_back(conn: &Connection, op: &OplogEntry) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO held_ops
+             (id, device_id, timestamp, table_name, op_type, data, schema_version, held_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            op.id.to_string(),
+            op.device_id.to_string(),
+            op.timestamp,
+            op.table,
+            op.op_type,
+            op.data.to_string(),
+            op.schema_version,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Held-back operations, oldest first
+pub fn held_ops(conn: &Connection) -> Result<Vec<OplogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, device_id, timestamp, table_name, op_type, data, schema_version
+         FROM held_ops ORDER BY timestamp, id",
+    )?;
+    let rows = stmt.query_map([], row_to_oplog_entry)?;
+    rows.collect()
+}
+
+/// Remove an operation from the held-back operations
+pub fn release_held(conn: &Connection, op_id: Uuid) -> Result<bool> {
+    let mut stmt = conn.prepare_cached("DELETE FROM held_ops WHERE id = ?1")?;
+    Ok(stmt.execute([op_id.to_string()])? > 0)
+}
+
+fn read_schema(row: &Row) -> Result<TableSchema> {
+    let op_types: String = row.get(1)?;
+    let payload_schema: Option<String> = row.get(2)?;
+    Ok(TableSchema {
+        table_name: row.get(0)?,
+        op_types: serde_json::from_str(&op_types).map_err(|e| conversion_failure(1, e))?,
+        payload_schema: payload_schema
+            .map(|raw| serde_json::from_str(&raw))
+            .transpose()
+            .map_err(|e| conversion_failure(2, e))?,
+        registered_at: parse_datetime_column(row, 3)?,
+        version: row.get(4)?,
+    })
+}
+
+fn payload_types() -> &'static RwLock<HashMap<String, PayloadCheck>> {
+    static PAYLOAD_TYPES: OnceLock<RwLock<HashMap<String, PayloadCheck>>> = OnceLock::new();
+    PAYLOAD_TYPES.get_or_init(Default::default)
+}
+
+fn upcasters() -> &'static RwLock<Upcasters> {
+    static UPCASTERS: OnceLock<RwLock<Upcasters>> = OnceLock::new();
+    UPCASTERS.get_or_init(Default::default)
+}
+
+fn check_payload_type<T: DeserializeOwned>(value: &Value) -> std::result::Result<(), String> {
+    T::deserialize(value).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Make sure [`check`] enforces every keyword of the schema at JSON pointer
+/// `path` and its subschemas
+fn check_supported(schema: &Value, path: &str) -> std::result::Result<(), String> {
+    let at = if path.is_empty() { "/" } else { path };
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => return Err(format!("{}: a schema must be an object or a boolean", at)),
+    };
+    for (keyword, value) in schema {
+        let keyword_path = format!("{}/{}", path, keyword);
+        match keyword.as_str() {
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .ok_or_else(|| format!("{}: expected an object", keyword_path))?;
+                for (name, property) in properties {
+                    let name = name.replace('~', "~0").replace('/', "~1");
+                    check_supported(property, &format!("{}/{}", keyword_path, name))?;
+                }
+            }
+            "additionalProperties" | "items" | "not" => check_supported(value, &keyword_path)?,
+            "allOf" | "anyOf" | "oneOf" => {
+                let subschemas = value
+                    .as_array()
+                    .ok_or_else(|| format!("{}: expected an array", keyword_path))?;
+                for (i, subschema) in subschemas.iter().enumerate() {
+                    check_supported(subschema, &format!("{}/{}", keyword_path, i))?;
+                }
+            }
+            keyword
+                if VALIDATION_KEYWORDS.contains(&keyword)
+                    || ANNOTATION_KEYWORDS.contains(&keyword) => {}
+            keyword => return Err(format!("{}: unsupported keyword '{}'", at, keyword)),
+        }
+    }
+    Ok(())
+}
+
+/// Validate `value` at JSON pointer `path` against a JSON Schema
+fn check(schema: &Value, value: &Value, path: &str) -> std::result::Result<(), String> {
+    let at = if path.is_empty() { "/" } else { path };
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{}: no value is allowed", at)),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| has_type(value, name)) {
+            return Err(format!(
+                "{}: expected {}, got {}",
+                at,
+                allowed.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            return Err(format!(
+                "{}: {} is not one of {}",
+                at,
+                value,
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{}: expected {}", at, expected));
+        }
+    }
+
+    match value {
+        Value::Object(fields) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                if let Some(missing) = required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .find(|name| !fields.contains_key(*name))
+                {
+                    return Err(format!("{}: missing required field '{}'", at, missing));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_path = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+                match properties.and_then(|p| p.get(name)) {
+                    Some(field_schema) => check(field_schema, field, &field_path)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            return Err(format!("{}: unexpected field '{}'", at, name))
+                        }
+                        Some(additional) => check(additional, field, &field_path)?,
+                        None => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                if (items.len() as u64) < min {
+                    return Err(format!("{}: expected at least {} items", at, min));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                if items.len() as u64 > max {
+                    return Err(format!("{}: expected at most {} items", at, max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{}/{}", path, i))?;
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if len < min {
+                    return Err(format!("{}: expected at least {} characters", at, min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if len > max {
+                    return Err(format!("{}: expected at most {} characters", at, max));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+            if let Some(min) = bound("minimum").filter(|min| n < *min) {
+                return Err(format!("{}: {} is less than {}", at, n, min));
+            }
+            if let Some(max) = bound("maximum").filter(|max| n > *max) {
+                return Err(format!("{}: {} is greater than {}", at, n, max));
+            }
+            if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+                return Err(format!("{}: {} is not greater than {}", at, n, min));
+            }
+            if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+                return Err(format!("{}: {} is not less than {}", at, n, max));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for sub in all {
+            check(sub, value, path)?;
+        }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf") {
+        if !any.iter().any(|sub| check(sub, value, path).is_ok()) {
+            return Err(format!("{}: matches none of the allowed schemas", at));
+        }
+    }
+    if let Some(Value::Array(one)) = schema.get("oneOf") {
+        let matching = one
+            .iter()
+            .filter(|sub| check(sub, value, path).is_ok())
+            .count();
+        if matching != 1 {
+            return Err(format!(
+                "{}: must match exactly one schema, matches {}",
+                at, matching
+            ));
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if check(not, value, path).is_ok() {
+            return Err(format!("{}: matches a forbidden schema", at));
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => {
+            value.as_i64().is_some()
+                || value.as_u64().is_some()
+                || value.as_f64().is_some_and(|f| f.fract() == 0.0)
+        }
+        _ => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::operations::initialize_database;
+    use crate::testing::oplog_entry;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    fn op(table: &str, op_type: &str, data: Value) -> OplogEntry {
+        oplog_entry(1, table, op_type, data)
+    }
+
+    #[test]
+    fn test_validate_against_schema() {
+        let conn = initialize_database(":memory:").unwrap();
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "title"],
+            "properties": {
+                "id": {"type": "string", "minLength": 1},
+                "title": {"type": "string", "maxLength": 10},
+                "priority": {"type": "integer", "minimum": 0, "maximum": 3},
+                "tags": {"type": "array", "items": {"enum": ["home", "work"]}}
+            },
+            "additionalProperties": false
+        });
+        register_schema(&conn, "tasks", 0, &["create", "update"], Some(&schema)).unwrap();
+        assert_eq!(registered_schemas(&conn).unwrap().len(), 1);
+
+        let valid = json!({"id": "t1", "title": "Milk", "priority": 2, "tags": ["home"]});
+        assert_eq!(
+            validate_op(&conn, &op("tasks", "create", valid.clone())).unwrap(),
+            None
+        );
+
+        let reject = |op_type: &str, data: Value| {
+            validate_op(&conn, &op("tasks", op_type, data))
+                .unwrap()
+                .expect("should be rejected")
+        };
+        assert!(reject("drop", valid).contains("not allowed"));
+        assert!(reject("create", json!({"id": "t1"})).contains("missing required field 'title'"));
+        assert!(
+            reject("create", json!({"id": "t1", "title": 5})).contains("/title: expected string")
+        );
+        assert!(
+            reject("create", json!({"id": "t1", "title": "x", "priority": 1.5}))
+                .contains("/priority")
+        );
+        assert!(
+            reject("create", json!({"id": "t1", "title": "x", "tags": ["gym"]}))
+                .contains("/tags/0")
+        );
+        assert!(
+            reject("create", json!({"id": "t1", "title": "x", "extra": 1}))
+                .contains("unexpected field 'extra'")
+        );
+
+        // Tables without a schema accept anything
+        assert_eq!(
+            validate_op(&conn, &op("notes", "anything", json!(1))).unwrap(),
+            None
+        );
+        assert!(unregister_schema(&conn, "tasks").unwrap());
+        assert_eq!(
+            validate_op(&conn, &op("tasks", "drop", json!(null))).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unsupported_keywords_are_rejected() {
+        let conn = initialize_database(":memory:").unwrap();
+        let register =
+            |schema: Value| register_schema(&conn, "tasks", 0, &["create"], Some(&schema));
+
+        for schema in [
+            json!({"type": "string", "pattern": "^[a-z]+$"}),
+            json!({"properties": {"email": {"type": "string", "format": "email"}}}),
+            json!({"items": [{"type": "string"}]}),
+            json!({"anyOf": [{"type": "string"}, {"$ref": "#/definitions/task"}]}),
+            json!("object"),
+        ] {
+            match register(schema.clone()) {
+                Err(AhenkError::Validation(reason)) => assert!(reason.contains("'tasks'")),
+                other => panic!("{} should be rejected, got {:?}", schema, other),
+            }
+        }
+        assert!(registered_schemas(&conn).unwrap().is_empty());
+
+        register(json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "Task",
+            "oneOf": [{"type": "string"}, {"not": {"const": 0}, "description": "non-zero"}]
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_payload_type() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Note {
+            id: String,
+            body: String,
+        }
+
+        let conn = initialize_database(":memory:").unwrap();
+        register_payload_type::<Note>("typed_notes");
+        let valid = json!({"id": "n1", "body": "hello"});
+        assert_eq!(
+            validate_op(&conn, &op("typed_notes", "create", valid)).unwrap(),
+            None
+        );
+        let reason = validate_op(&conn, &op("typed_notes", "create", json!({"id": "n1"})))
+            .unwrap()
+            .unwrap();
+        assert!(reason.contains("body"));
+        assert!(unregister_payload_type("typed_notes"));
+    }
+}
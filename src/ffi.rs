@@ -1,10 +1,11 @@
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::ptr;
 
 use rusqlite::Connection;
 
 use crate::initialize_database;
+use crate::logic::live_query::{ChangeSet, LiveQueries, LiveQuery};
 
 /// Opaque pointer to a rusqlite Connection.
 pub type DbConnection = Connection;
@@ -128,3 +129,138 @@ pub unsafe extern "C" fn ahenk_login_user(
         Err(_) => ptr::null_mut(),
     }
 }
+
+/// Opaque pointer to a set of live query subscriptions.
+pub type LiveQueriesHandle = LiveQueries;
+
+/// Called with a JSON-encoded change set and the `user_data` given at subscription.
+///
+/// The string is only valid for the duration of the call.
+pub type LiveQueryCallback = extern "C" fn(changes_json: *const c_char, user_data: *mut c_void);
+
+/// `user_data` is owned by the caller, who must keep it valid until unsubscribing.
+struct CallbackTarget {
+    callback: LiveQueryCallback,
+    user_data: *mut c_void,
+}
+
+unsafe impl Send for CallbackTarget {}
+unsafe impl Sync for CallbackTarget {}
+
+impl CallbackTarget {
+    fn call(&self, changes: &ChangeSet) {
+        if let Ok(json) = serde_json::to_string(changes) {
+            if let Ok(json) = CString::new(json) {
+                (self.callback)(json.as_ptr(), self.user_data);
+            }
+        }
+    }
+}
+
+/// Creates an empty set of live query subscriptions.
+///
+/// The caller is responsible for calling `ahenk_live_queries_free` to free it.
+#[unsafe(no_mangle)]
+pub extern "C" fn ahenk_live_queries_new() -> *mut LiveQueriesHandle {
+    Box::into_raw(Box::new(LiveQueries::new()))
+}
+
+/// Frees a set of live query subscriptions.
+///
+/// # Safety
+///
+/// The `live_ptr` must be a valid pointer created by `ahenk_live_queries_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ahenk_live_queries_free(live_ptr: *mut LiveQueriesHandle) {
+    if !live_ptr.is_null() {
+        unsafe {
+            let _ = Box::from_raw(live_ptr);
+        }
+    }
+}
+
+/// Subscribes to changes of a collection or synced table.
+///
+/// `query_json` is a JSON `LiveQuery`, e.g. `{"table": "todos"}`. `callback` is
+/// called from `ahenk_live_queries_dispatch` with each change set.
+///
+/// Returns the subscription ID, or 0 on error.
+///
+/// # Safety
+///
+/// The `live_ptr` and `conn_ptr` must be valid pointers. The `query_json` must be a
+/// valid, null-terminated C string. `user_data` must stay valid until the
+/// subscription is removed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ahenk_live_query_subscribe(
+    live_ptr: *mut LiveQueriesHandle,
+    conn_ptr: *mut DbConnection,
+    query_json: *const c_char,
+    callback: LiveQueryCallback,
+    user_data: *mut c_void,
+) -> u64 {
+    if live_ptr.is_null() || conn_ptr.is_null() || query_json.is_null() {
+        return 0;
+    }
+    let live = &*live_ptr;
+    let conn = &*conn_ptr;
+
+    let Ok(query) = CStr::from_ptr(query_json).to_str() else {
+        return 0;
+    };
+    let Ok(query) = serde_json::from_str::<LiveQuery>(query) else {
+        return 0;
+    };
+
+    let target = CallbackTarget {
+        callback,
+        user_data,
+    };
+    match live.subscribe_with(conn, query, move |changes| target.call(&changes)) {
+        Ok((id, _)) => id,
+        Err(_) => 0,
+    }
+}
+
+/// Removes a live query subscription.
+///
+/// Returns 1 if it existed, 0 otherwise.
+///
+/// # Safety
+///
+/// The `live_ptr` must be a valid pointer created by `ahenk_live_queries_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ahenk_live_query_unsubscribe(
+    live_ptr: *mut LiveQueriesHandle,
+    subscription_id: u64,
+) -> i32 {
+    if live_ptr.is_null() {
+        return 0;
+    }
+    (&*live_ptr).unsubscribe(subscription_id) as i32
+}
+
+/// Delivers changes made since the last dispatch to the subscriptions' callbacks.
+///
+/// Call it after local writes and after merging remote operations. Returns the
+/// number of change sets delivered, or -1 on error.
+///
+/// # Safety
+///
+/// The `live_ptr` and `conn_ptr` must be valid pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ahenk_live_queries_dispatch(
+    live_ptr: *mut LiveQueriesHandle,
+    conn_ptr: *mut DbConnection,
+) -> i32 {
+    if live_ptr.is_null() || conn_ptr.is_null() {
+        return -1;
+    }
+    let live = &*live_ptr;
+    let conn = &*conn_ptr;
+
+    match live.dispatch(conn) {
+        Ok(delivered) => delivered as i32,
+        Err(_) => -1,
+    }
+}
@@ -41,6 +41,7 @@
 //! - `crdt`: CRDT implementation with hybrid logical clocks
 //! - `auth`: Device authorization workflows
 //! - `error`: Error types and result aliases
+//! - `testing`: Multi-device sync simulations (with the `test-support` feature)
 
 // Internal modules
 pub mod auth;
@@ -52,6 +53,10 @@ pub mod logic;
 pub mod models;
 pub mod tauri_api;
 
+// Simulated multi-device networks for tests (enabled with "test-support" feature)
+#[cfg(all(any(test, feature = "test-support"), not(feature = "tauri-api")))]
+pub mod testing;
+
 // CLI module (optional, enabled with "cli" feature)
 #[cfg(feature = "cli")]
 pub mod cli;
@@ -66,15 +71,40 @@ pub use error::{AhenkError, Result};
 // Core Models
 // ============================================================================
 
-pub use models::{Device, OplogEntry, Peer, User};
+pub use models::{
+    Device, OplogCursor, OplogEntry, OplogFilter, OplogPage, Peer, QuarantinedOp, SyncedTable,
+    TableSchema, User,
+};
 
 // ============================================================================
 // Database Operations
 // ============================================================================
 
 // Initialization and migrations
-pub use db::migrations::{apply_migrations, get_current_version, get_migration_history};
-pub use db::operations::initialize_database;
+pub use db::migrations::{
+    apply_migrations, apply_migrations_with_backups, get_current_version, get_migration_history,
+    get_namespace_version, migrate_down, migrate_up, migration_status, migrations_to_revert,
+    pending_migrations, register_migrations, registered_namespaces, unregister_migrations,
+    verify_migrations, AppMigration, Migration, MigrationState, MigrationStatus, MigrationStep,
+    CORE_NAMESPACE,
+};
+pub use db::operations::{initialize_database, initialize_database_with_config};
+
+// Backups with the online backup API, taken before migrations run
+pub use db::backup::{
+    backup_database, check_integrity, create_backup, list_backups, restore_database, with_backup,
+    BackupConfig,
+};
+
+// Export archives: integrity-checked, version-checked, optionally compressed and encrypted
+pub use db::archive::{
+    export_archive, import_archive, read_archive_info, unpack_archive, ArchiveInfo, ArchiveOptions,
+};
+
+// Connection layer: WAL, busy timeout, foreign keys, reader pool and writer thread
+pub use db::connection::{
+    configure_connection, open_connection, ConnectionConfig, Database, DbError, DbFuture,
+};
 
 // User operations
 pub use db::operations::{create_user, get_user, get_user_by_mail, get_user_by_name};
@@ -85,11 +115,32 @@ pub use db::operations::{
 };
 
 // OplogEntry operations
-pub use db::operations::{create_oplog_entry, get_oplog_entries_since};
+pub use db::operations::{
+    create_oplog_entry, for_each_oplog_entry, get_oplog_entries_since, get_oplog_page, oplog_pages,
+    OplogPages,
+};
 
 // Peer operations
 pub use db::operations::{create_peer, get_all_peers, get_peer, get_peers_by_user_id};
 
+// Change capture for app tables
+pub use db::capture::{
+    flush_captured_changes, register_table, registered_tables, unregister_table,
+    with_capture_suppressed,
+};
+
+// Oplog compaction
+pub use db::compaction::{
+    compact_oplog, compaction_horizon, record_device_ack, stable_horizon, CompactionReport,
+};
+
+// Schema registry and quarantine
+pub use db::schema_registry::{
+    current_version, held_ops, quarantined_ops, register_payload_type, register_schema,
+    register_upcaster, registered_schemas, release_quarantined, unregister_payload_type,
+    unregister_schema, upcast, validate_op,
+};
+
 // ============================================================================
 // Business Logic
 // ============================================================================
@@ -97,8 +148,14 @@ pub use db::operations::{create_peer, get_all_peers, get_peer, get_peers_by_user
 // User management
 pub use logic::{add_device_to_user, get_user_devices, login_user, register_user};
 
-// Oplog entry builder helper
-pub use logic::build_oplog_entry;
+// Oplog entry builder helpers
+pub use logic::{build_oplog_entry, build_oplog_entry_with};
+
+// Typed synced collections
+pub use logic::collection::{Collection, Document};
+
+// Live queries over collections and synced tables
+pub use logic::live_query::{ChangeSet, Filter, LiveQueries, LiveQuery, Subscription};
 
 // ============================================================================
 // P2P Synchronization
@@ -107,11 +164,18 @@ pub use logic::build_oplog_entry;
 pub use logic::sync::{
     connect_to_bootstrap_nodes, connect_to_relay_servers, create_swarm, create_swarm_default,
     decode_sync_message, encode_sync_message, generate_device_id, handle_sync_message,
-    parse_multiaddr_peer_id, update_peer_info, AhenkBehaviour, P2PConfig, SyncMessage,
+    parse_multiaddr_peer_id, respond_to_sync_request, split_by_encoded_size, update_peer_info,
+    AhenkBehaviour, P2PConfig, SyncLimits, SyncMessage, SYNC_BATCH_SIZE,
 };
 
 // Sync manager for orchestrating P2P operations
-pub use logic::sync_manager::SyncManager;
+pub use logic::sync_manager::{ShutdownHandle, SyncManager};
+
+// Signed, optionally encrypted oplog bundles for file-based sync
+pub use logic::bundle::{
+    apply_bundle, create_bundle, decode_bundle, encode_bundle, export_bundle, import_bundle,
+    is_bundle_encrypted, version_vector, Bundle, VerifiedBundle, VersionVector,
+};
 
 // ============================================================================
 // Device Authorization
@@ -126,7 +190,16 @@ pub use auth::{
 // CRDT Operations
 // ============================================================================
 
-pub use crdt::{local_apply, merge, HybridLogicalClock};
+pub use crdt::{
+    local_apply, local_apply_batch, local_apply_in, merge, merge_in, merge_with, next_timestamp,
+    next_timestamp_with, observe_timestamp, retry_held_ops, retry_quarantined, Clock,
+    ClockDriftError, DriftBound, DriftPolicy, HybridLogicalClock, SystemClock,
+};
+
+pub use crdt::{
+    apply_entity_op, entity_state, json_patch, merge_patch, merge_patch_diff, snapshot_entity,
+    OP_DELETE, OP_INSERT, OP_JSON_PATCH, OP_MERGE_PATCH, OP_UPDATE, SNAPSHOT_INTERVAL,
+};
 
 // ============================================================================
 // Tests
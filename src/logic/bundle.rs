@@ -0,0 +1,516 @@
+//! Sync bundles: oplog entries carried between devices as files.
+//!
+//! Devices that never share a network can still sync through any file
+//! transport — a USB stick, an email attachment. The receiving device reports
+//! what it has as a [`VersionVector`] (the newest timestamp it holds from each
+//! device); the sending device writes every entry the receiver lacks into a
+//! bundle with [`export_bundle`], and the receiver merges it through
+//! [`crdt::merge`] with [`import_bundle`]. Bundles are idempotent, so importing
+//! one twice or importing overlapping bundles is harmless.
+//!
+//! A bundle is signed with the exporting device's ed25519 key and can be
+//! encrypted with a passphrase, the same way database archives are (see
+//! `db::archive`):
+//!
+//! ```text
+//! magic "AHENKSB\0" | format u8 | flags u8
+//! [salt 16 bytes | nonce 24 bytes]   if encrypted
+//! payload                            optionally sealed
+//! ```
+//!
+//! The payload is the bundle as JSON (length-prefixed), the signer's public key
+//! and the signature over the JSON.
+
+use crate::crdt;
+use crate::db::{archive, compaction, operations};
+use crate::models::{OplogEntry, OplogFilter};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 8] = b"AHENKSB\0";
+const FORMAT: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 1;
+const HEADER_LEN: usize = 8 + 1 + 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// The newest HLC timestamp held from each device.
+///
+/// Written as `<device-id>:<timestamp>` pairs separated by commas; devices that
+/// are not listed count as 0, so an empty vector asks for everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<Uuid, i64>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Newest timestamp held from `device_id`, 0 if none
+    pub fn get(&self, device_id: Uuid) -> i64 {
+        self.0.get(&device_id).copied().unwrap_or(0)
+    }
+
+    /// Record that everything from `device_id` up to `timestamp` is held
+    pub fn observe(&mut self, device_id: Uuid, timestamp: i64) {
+        let newest = self.0.entry(device_id).or_insert(timestamp);
+        *newest = (*newest).max(timestamp);
+    }
+
+    /// Whether `entry` is newer than what this vector holds from its device
+    pub fn is_missing(&self, entry: &OplogEntry) -> bool {
+        entry.timestamp > self.get(entry.device_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Uuid, i64)> + '_ {
+        self.0
+            .iter()
+            .map(|(device_id, timestamp)| (*device_id, *timestamp))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for VersionVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (device_id, timestamp)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:{}", device_id, timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VersionVector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut vector = VersionVector::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (device_id, timestamp) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Expected <device-id>:<timestamp>, got '{}'", pair))?;
+            let device_id = Uuid::parse_str(device_id.trim())
+                .map_err(|e| format!("Invalid device ID '{}': {}", device_id, e))?;
+            let timestamp = timestamp
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid timestamp '{}': {}", timestamp, e))?;
+            vector.observe(device_id, timestamp);
+        }
+        Ok(vector)
+    }
+}
+
+/// Oplog entries exported for another device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    /// Device that exported the bundle
+    pub device_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// What the receiving device already had
+    pub since: VersionVector,
+    /// Compaction horizon of the exporting device, when the bundle carries its
+    /// compacted history (see `db::compaction`)
+    pub horizon: Option<i64>,
+    pub entries: Vec<OplogEntry>,
+}
+
+/// A bundle whose signature has been checked
+#[derive(Debug, Clone)]
+pub struct VerifiedBundle {
+    pub bundle: Bundle,
+    /// Peer ID of the key that signed the bundle
+    pub signer: PeerId,
+}
+
+/// The local oplog's version vector, to send to a device that exports a bundle
+/// for this one
+pub fn version_vector(conn: &Connection) -> Result<VersionVector, String> {
+    let mut stmt = conn
+        .prepare("SELECT device_id, MAX(timestamp) FROM oplog GROUP BY device_id")
+        .map_err(|e| format!("Database error: {}", e))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    let mut vector = VersionVector::new();
+    for row in rows {
+        let (device_id, timestamp) = row.map_err(|e| format!("Database error: {}", e))?;
+        let device_id = Uuid::parse_str(&device_id)
+            .map_err(|e| format!("Invalid device ID '{}' in oplog: {}", device_id, e))?;
+        vector.observe(device_id, timestamp);
+    }
+    Ok(vector)
+}
+
+/// Collect the oplog entries a device holding `since` is missing.
+///
+/// Entries at or before the compaction horizon may have been folded into
+/// snapshots stamped with another device's operation, so if the receiver lacks
+/// anything from before the horizon, the whole compacted history is included,
+/// as a `SyncMessage::Snapshot` would.
+pub fn create_bundle(
+    conn: &Connection,
+    device_id: Uuid,
+    since: &VersionVector,
+) -> Result<Bundle, String> {
+    let local = version_vector(conn)?;
+    let horizon = compaction::compaction_horizon(conn).map_err(|e| e.to_string())?;
+    let snapshot = local
+        .iter()
+        .any(|(device_id, newest)| since.get(device_id) < newest.min(horizon));
+
+    let start = if snapshot {
+        0
+    } else {
+        local
+            .iter()
+            .map(|(device_id, _)| since.get(device_id))
+            .min()
+            .unwrap_or(0)
+    };
+    let mut entries = Vec::new();
+    operations::for_each_oplog_entry(conn, start, &OplogFilter::default(), |entry| {
+        if since.is_missing(&entry) || (snapshot && entry.timestamp <= horizon) {
+            entries.push(entry);
+        }
+        Ok(())
+    })
+    .map_err(|e| format!("Failed to read oplog: {}", e))?;
+
+    Ok(Bundle {
+        device_id,
+        created_at: Utc::now(),
+        since: since.clone(),
+        horizon: snapshot.then_some(horizon),
+        entries,
+    })
+}
+
+/// Sign `bundle` with `keypair` and encode it, encrypted if a passphrase is given
+pub fn encode_bundle(
+    bundle: &Bundle,
+    keypair: &Keypair,
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    let contents =
+        serde_json::to_vec(bundle).map_err(|e| format!("Failed to encode bundle: {}", e))?;
+    let signature = keypair
+        .sign(&contents)
+        .map_err(|e| format!("Failed to sign bundle: {}", e))?;
+    let public_key = keypair.public().encode_protobuf();
+
+    let mut payload = Vec::with_capacity(contents.len() + public_key.len() + signature.len() + 6);
+    payload.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&contents);
+    payload.extend_from_slice(&(public_key.len() as u16).to_le_bytes());
+    payload.extend_from_slice(&public_key);
+    payload.extend_from_slice(&signature);
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN + SALT_LEN + NONCE_LEN + payload.len());
+    encoded.extend_from_slice(MAGIC);
+    encoded.push(FORMAT);
+    match passphrase {
+        Some(passphrase) => {
+            encoded.push(FLAG_ENCRYPTED);
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            OsRng.fill_bytes(&mut salt);
+            OsRng.fill_bytes(&mut nonce);
+            encoded.extend_from_slice(&salt);
+            encoded.extend_from_slice(&nonce);
+            let sealed = archive::seal(passphrase, &salt, &nonce, &encoded, &payload)
+                .map_err(|e| e.to_string())?;
+            encoded.extend_from_slice(&sealed);
+        }
+        None => {
+            encoded.push(0);
+            encoded.extend_from_slice(&payload);
+        }
+    }
+    Ok(encoded)
+}
+
+/// Whether an encoded bundle needs a passphrase to decode
+pub fn is_bundle_encrypted(bytes: &[u8]) -> Result<bool, String> {
+    Ok(parse_header(bytes)? & FLAG_ENCRYPTED != 0)
+}
+
+/// Decode a bundle and check its signature
+pub fn decode_bundle(bytes: &[u8], passphrase: Option<&str>) -> Result<VerifiedBundle, String> {
+    let flags = parse_header(bytes)?;
+    let payload = if flags & FLAG_ENCRYPTED != 0 {
+        let passphrase = passphrase
+            .ok_or_else(|| "The bundle is encrypted; a passphrase is needed".to_string())?;
+        let body_start = HEADER_LEN + SALT_LEN + NONCE_LEN;
+        if bytes.len() < body_start {
+            return Err("The bundle is truncated".to_string());
+        }
+        let salt = &bytes[HEADER_LEN..HEADER_LEN + SALT_LEN];
+        let nonce = &bytes[HEADER_LEN + SALT_LEN..body_start];
+        archive::open(
+            passphrase,
+            salt,
+            nonce,
+            &bytes[..body_start],
+            &bytes[body_start..],
+        )
+        .map_err(|e| e.to_string())?
+    } else {
+        bytes[HEADER_LEN..].to_vec()
+    };
+
+    let truncated = || "The bundle is truncated".to_string();
+    let (len, rest) = payload.split_at_checked(4).ok_or_else(truncated)?;
+    let len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+    let (contents, rest) = rest.split_at_checked(len).ok_or_else(truncated)?;
+    let (len, rest) = rest.split_at_checked(2).ok_or_else(truncated)?;
+    let len = u16::from_le_bytes(len.try_into().expect("2 bytes")) as usize;
+    let (public_key, signature) = rest.split_at_checked(len).ok_or_else(truncated)?;
+
+    let public_key = PublicKey::try_decode_protobuf(public_key)
+        .map_err(|e| format!("Invalid signing key: {}", e))?;
+    if !public_key.verify(contents, signature) {
+        return Err("The bundle's signature does not match its contents".to_string());
+    }
+    let bundle =
+        serde_json::from_slice(contents).map_err(|e| format!("Failed to decode bundle: {}", e))?;
+
+    Ok(VerifiedBundle {
+        bundle,
+        signer: public_key.to_peer_id(),
+    })
+}
+
+/// Merge a bundle's entries through [`crdt::merge`]. Returns the number of
+/// entries that were not in the oplog before.
+///
+/// A bundle carrying compacted history raises the local compaction horizon, as
+/// merging a `SyncMessage::Snapshot` does.
+pub fn apply_bundle(conn: &mut Connection, bundle: &Bundle) -> Result<usize, String> {
+    let mut new_entries = 0;
+    for entry in &bundle.entries {
+        if !operations::oplog_entry_exists(conn, entry.id).map_err(|e| e.to_string())? {
+            new_entries += 1;
+        }
+    }
+
+    crdt::merge(conn, &bundle.entries).map_err(|e| format!("Failed to merge bundle: {}", e))?;
+    if let Some(horizon) = bundle.horizon {
+        compaction::set_horizon(conn, horizon).map_err(|e| e.to_string())?;
+    }
+    Ok(new_entries)
+}
+
+/// Write a signed bundle of the entries a device holding `since` is missing to
+/// `path`, encrypted if a passphrase is given
+pub fn export_bundle(
+    conn: &Connection,
+    device_id: Uuid,
+    since: &VersionVector,
+    keypair: &Keypair,
+    passphrase: Option<&str>,
+    path: &Path,
+) -> Result<Bundle, String> {
+    let bundle = create_bundle(conn, device_id, since)?;
+    let encoded = encode_bundle(&bundle, keypair, passphrase)?;
+    std::fs::write(path, encoded)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(bundle)
+}
+
+/// Verify the bundle at `path` and merge it.
+///
+/// With `trusted` signers, bundles signed by any other key are rejected before
+/// anything is merged. Returns the verified bundle and the number of new entries.
+pub fn import_bundle(
+    conn: &mut Connection,
+    path: &Path,
+    passphrase: Option<&str>,
+    trusted: Option<&[PeerId]>,
+) -> Result<(VerifiedBundle, usize), String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let verified = decode_bundle(&bytes, passphrase)?;
+    if let Some(trusted) = trusted {
+        if !trusted.contains(&verified.signer) {
+            return Err(format!(
+                "The bundle is signed by {}, which is not trusted",
+                verified.signer
+            ));
+        }
+    }
+    let new_entries = apply_bundle(conn, &verified.bundle)?;
+    Ok((verified, new_entries))
+}
+
+fn parse_header(bytes: &[u8]) -> Result<u8, String> {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("Not an Ahenk sync bundle".to_string());
+    }
+    if bytes[8] != FORMAT {
+        return Err(format!(
+            "Unsupported bundle format {}; this version of Ahenk writes format {}",
+            bytes[8], FORMAT
+        ));
+    }
+    Ok(bytes[9])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::operations::initialize_database;
+    use crate::testing::oplog_entry;
+    use serde_json::json;
+
+    fn entry(device_id: Uuid, timestamp: i64) -> OplogEntry {
+        OplogEntry {
+            device_id,
+            ..oplog_entry(timestamp, "notes", "insert", json!({ "id": timestamp }))
+        }
+    }
+
+    fn memory_db() -> Connection {
+        initialize_database(":memory:").unwrap()
+    }
+
+    #[test]
+    fn test_version_vector_round_trips_through_text() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut vector = VersionVector::new();
+        vector.observe(a, 10);
+        vector.observe(a, 5);
+        vector.observe(b, 7);
+        assert_eq!(vector.get(a), 10);
+        assert_eq!(vector.get(Uuid::new_v4()), 0);
+
+        assert_eq!(vector.to_string().parse::<VersionVector>().unwrap(), vector);
+        assert_eq!("".parse::<VersionVector>().unwrap(), VersionVector::new());
+        assert!("not-a-vector".parse::<VersionVector>().is_err());
+        assert!(format!("{}:soon", a).parse::<VersionVector>().is_err());
+    }
+
+    #[test]
+    fn test_bundle_brings_a_device_up_to_date() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut sender = memory_db();
+        let mut receiver = memory_db();
+
+        let shared = vec![entry(a, 100), entry(b, 200)];
+        crdt::merge(&mut sender, &shared).unwrap();
+        crdt::merge(&mut receiver, &shared).unwrap();
+        let new = vec![entry(a, 300), entry(b, 250)];
+        crdt::merge(&mut sender, &new).unwrap();
+
+        let since = version_vector(&receiver).unwrap();
+        let bundle = create_bundle(&sender, a, &since).unwrap();
+        let mut ids: Vec<Uuid> = bundle.entries.iter().map(|entry| entry.id).collect();
+        ids.sort();
+        let mut expected: Vec<Uuid> = new.iter().map(|entry| entry.id).collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(bundle.horizon, None);
+
+        let keypair = Keypair::generate_ed25519();
+        let bytes = encode_bundle(&bundle, &keypair, Some("sneakernet")).unwrap();
+        assert!(is_bundle_encrypted(&bytes).unwrap());
+        assert!(decode_bundle(&bytes, None).is_err());
+        assert!(decode_bundle(&bytes, Some("wrong")).is_err());
+
+        let verified = decode_bundle(&bytes, Some("sneakernet")).unwrap();
+        assert_eq!(verified.signer, keypair.public().to_peer_id());
+        assert_eq!(apply_bundle(&mut receiver, &verified.bundle).unwrap(), 2);
+        assert_eq!(
+            version_vector(&receiver).unwrap(),
+            version_vector(&sender).unwrap()
+        );
+
+        // Importing again changes nothing
+        assert_eq!(apply_bundle(&mut receiver, &verified.bundle).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_receivers_behind_the_horizon_get_compacted_history() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut sender = memory_db();
+        let entries = vec![entry(a, 100), entry(b, 200), entry(a, 300)];
+        crdt::merge(&mut sender, &entries).unwrap();
+        compaction::set_horizon(&sender, 250).unwrap();
+
+        // Up to date with a but not b past the horizon: everything through it is resent
+        let mut since = VersionVector::new();
+        since.observe(a, 300);
+        since.observe(b, 150);
+        let bundle = create_bundle(&sender, a, &since).unwrap();
+        assert_eq!(bundle.horizon, Some(250));
+        assert_eq!(bundle.entries.len(), 2);
+
+        let mut receiver = memory_db();
+        apply_bundle(&mut receiver, &bundle).unwrap();
+        assert_eq!(compaction::compaction_horizon(&receiver).unwrap(), 250);
+
+        // Receivers past the horizon only get what they lack
+        since.observe(b, 200);
+        let bundle = create_bundle(&sender, a, &since).unwrap();
+        assert_eq!(bundle.horizon, None);
+        assert!(bundle.entries.is_empty());
+    }
+
+    #[test]
+    fn test_tampered_and_untrusted_bundles_are_rejected() {
+        let device_id = Uuid::new_v4();
+        let mut sender = memory_db();
+        crdt::merge(&mut sender, &[entry(device_id, 100)]).unwrap();
+        let mut receiver = memory_db();
+
+        let keypair = Keypair::generate_ed25519();
+        let path = std::env::temp_dir().join(format!("ahenk-bundle-{}.bundle", Uuid::new_v4()));
+        export_bundle(
+            &sender,
+            device_id,
+            &VersionVector::new(),
+            &keypair,
+            None,
+            &path,
+        )
+        .unwrap();
+
+        let stranger = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(import_bundle(&mut receiver, &path, None, Some(&[stranger])).is_err());
+        assert!(version_vector(&receiver).unwrap().is_empty());
+
+        let bytes = std::fs::read(&path).unwrap();
+        let mut tampered = bytes.clone();
+        let at = tampered
+            .windows(3)
+            .position(|window| window == b"100")
+            .unwrap();
+        tampered[at] = b'9';
+        assert!(decode_bundle(&tampered, None).is_err());
+
+        let signer = keypair.public().to_peer_id();
+        let (verified, new_entries) =
+            import_bundle(&mut receiver, &path, None, Some(&[signer])).unwrap();
+        assert_eq!(verified.signer, signer);
+        assert_eq!(new_entries, 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,490 @@
+//! Typed collections of synced documents.
+//!
+//! A [`Collection`] stores values of one type as JSON documents keyed by a string
+//! ID. Every write is stamped from the database's HLC, recorded in the oplog,
+//! applied to the collection's merged state and queued in the sync outbox, all in
+//! one transaction; the sync manager sends queued writes on its next tick. Remote
+//! writes are applied by `crdt::merge`, so reads always return the merged state.
+//!
+//! # Example
+//! ```rust,no_run
+//! use ahenk::{initialize_database, Collection};
+//! use serde::{Deserialize, Serialize};
+//! use uuid::Uuid;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Todo {
+//!     title: String,
+//!     done: bool,
+//! }
+//!
+//! # fn example() -> Result<(), String> {
+//! let mut conn = initialize_database("app.db").map_err(|e| e.to_string())?;
+//! let todos: Collection<Todo> = Collection::open(&conn, "todos", Uuid::new_v4())?;
+//!
+//! let id = todos.insert(&mut conn, &Todo { title: "Buy milk".into(), done: false })?;
+//! todos.patch(&mut conn, &id, &serde_json::json!({"done": true}))?;
+//! assert!(todos.get(&conn, &id)?.unwrap().done);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::crdt::{
+    self, Clock, SystemClock, OP_DELETE, OP_INSERT, OP_JSON_PATCH, OP_MERGE_PATCH, OP_UPDATE,
+};
+use crate::db::capture;
+use crate::db::documents;
+use crate::db::operations;
+use crate::db::schema_registry;
+use crate::logic::build_oplog_entry_with;
+use crate::logic::live_query::LiveQueries;
+use rusqlite::{Connection, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A document read from a collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document<T> {
+    /// Document ID
+    pub id: String,
+    /// HLC timestamp of the last write to the document
+    pub timestamp: i64,
+    /// The document
+    pub value: T,
+}
+
+/// A synced collection of `T` documents
+pub struct Collection<T> {
+    name: String,
+    device_id: Uuid,
+    clock: Arc<dyn Clock>,
+    live_queries: Option<LiveQueries>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Collection<T> {
+    /// Open a collection, creating it on first use.
+    ///
+    /// Writes are attributed to `device_id`. A collection cannot share its name
+    /// with a table registered for change capture.
+    pub fn open(conn: &Connection, name: &str, device_id: Uuid) -> Result<Self, String> {
+        if name.is_empty() {
+            return Err("Collection name cannot be empty".to_string());
+        }
+        let registered = capture::registered_tables(conn)
+            .map_err(|e| format!("Failed to read synced tables: {}", e))?;
+        if registered.iter().any(|t| t.table_name == name) {
+            return Err(format!(
+                "'{}' is already registered as a synced table",
+                name
+            ));
+        }
+        documents::register_collection(conn, name)
+            .map_err(|e| format!("Failed to open collection {}: {}", name, e))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            device_id,
+            clock: Arc::new(SystemClock),
+            live_queries: None,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Read physical time for HLC stamps from `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Dispatch live query changes after every write through this collection
+    pub fn with_live_queries(mut self, live_queries: LiveQueries) -> Self {
+        self.live_queries = Some(live_queries);
+        self
+    }
+
+    /// Name of the collection
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Insert a document under a new random ID and return the ID
+    pub fn insert(&self, conn: &mut Connection, value: &T) -> Result<String, String> {
+        let id = Uuid::new_v4().to_string();
+        self.insert_with_id(conn, &id, value)?;
+        Ok(id)
+    }
+
+    /// Insert a document under the given ID; fails if a document with that ID exists
+    pub fn insert_with_id(&self, conn: &mut Connection, id: &str, value: &T) -> Result<(), String> {
+        let value = self.encode(value)?;
+        self.write(conn, |tx| {
+            if self.current(tx, id)?.is_some() {
+                return Err(format!("{} document {} already exists", self.name, id));
+            }
+            Ok(Some((OP_INSERT, json!({"id": id, "value": value}))))
+        })
+    }
+
+    /// Replace a document; fails if it does not exist.
+    ///
+    /// Only the changed fields are sent, as a merge patch, unless the new document
+    /// holds `null` values; then the whole document is.
+    pub fn update(&self, conn: &mut Connection, id: &str, value: &T) -> Result<(), String> {
+        let value = self.encode(value)?;
+        self.write(conn, |tx| {
+            let current = self.require(tx, id)?;
+            Ok(match crdt::merge_patch_diff(&current, &value) {
+                Some(patch) if patch.as_object().is_some_and(|p| p.is_empty()) => None,
+                Some(patch) => Some((OP_MERGE_PATCH, json!({"id": id, "patch": patch}))),
+                None => Some((OP_UPDATE, json!({"id": id, "value": value}))),
+            })
+        })
+    }
+
+    /// Apply a JSON merge patch (RFC 7386) to a document and return the result.
+    ///
+    /// Only the patched fields are sent, so concurrent patches to different fields
+    /// both take effect. Fails if the document does not exist or the patched
+    /// document is not a valid `T`.
+    pub fn patch(&self, conn: &mut Connection, id: &str, patch: &Value) -> Result<T, String> {
+        let mut patched = None;
+        self.write(conn, |tx| {
+            let mut value = self.require(tx, id)?;
+            crdt::merge_patch(&mut value, patch);
+            patched = Some(self.decode(value)?);
+            Ok(Some((OP_MERGE_PATCH, json!({"id": id, "patch": patch}))))
+        })?;
+        Ok(patched.expect("set by a successful write"))
+    }
+
+    /// Apply a JSON Patch (RFC 6902) to a document and return the result.
+    ///
+    /// Fails if the document does not exist, an operation fails (including `test`)
+    /// or the patched document is not a valid `T`. Devices that receive the patch
+    /// after a conflicting change skip it if it no longer applies.
+    pub fn json_patch(&self, conn: &mut Connection, id: &str, patch: &Value) -> Result<T, String> {
+        let mut patched = None;
+        self.write(conn, |tx| {
+            let value = crdt::json_patch(&self.require(tx, id)?, patch)
+                .map_err(|e| format!("Cannot patch {} document {}: {}", self.name, id, e))?;
+            patched = Some(self.decode(value)?);
+            Ok(Some((OP_JSON_PATCH, json!({"id": id, "patch": patch}))))
+        })?;
+        Ok(patched.expect("set by a successful write"))
+    }
+
+    /// Delete a document; returns `false` if it did not exist
+    pub fn delete(&self, conn: &mut Connection, id: &str) -> Result<bool, String> {
+        let mut existed = false;
+        self.write(conn, |tx| {
+            existed = self.current(tx, id)?.is_some();
+            Ok(existed.then(|| (OP_DELETE, json!({ "id": id }))))
+        })?;
+        Ok(existed)
+    }
+
+    /// Get a document by ID
+    pub fn get(&self, conn: &Connection, id: &str) -> Result<Option<T>, String> {
+        self.current(conn, id)?
+            .map(|value| self.decode(value))
+            .transpose()
+    }
+
+    /// All documents in the collection, ordered by ID
+    pub fn list(&self, conn: &Connection) -> Result<Vec<Document<T>>, String> {
+        documents::list_documents(conn, &self.name)
+            .map_err(|e| format!("Failed to list {}: {}", self.name, e))?
+            .into_iter()
+            .filter_map(|doc| doc.data.map(|data| (doc.id, doc.timestamp, data)))
+            .map(|(id, timestamp, data)| {
+                Ok(Document {
+                    id,
+                    timestamp,
+                    value: self.decode(data)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Run `op` in a transaction and record the operation it returns, if any
+    fn write<F>(&self, conn: &mut Connection, op: F) -> Result<(), String>
+    where
+        F: FnOnce(&Transaction) -> Result<Option<(&'static str, Value)>, String>,
+    {
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
+        let Some((op_type, payload)) = op(&tx)? else {
+            return Ok(());
+        };
+
+        let entry = build_oplog_entry_with(
+            &tx,
+            self.clock.as_ref(),
+            self.device_id,
+            &self.name,
+            op_type,
+            &payload,
+        )?;
+        if let Some(reason) = schema_registry::validate_op(&tx, &entry)
+            .map_err(|e| format!("Failed to validate {} operation: {}", self.name, e))?
+        {
+            return Err(format!("Invalid {} operation: {}", self.name, reason));
+        }
+        operations::create_oplog_entry(&tx, &entry)
+            .and_then(|_| documents::apply_op(&tx, &entry))
+            .and_then(|_| operations::enqueue_sync_outbox(&tx, std::slice::from_ref(&entry)))
+            .and_then(|_| tx.commit())
+            .map_err(|e| format!("Failed to write {} document: {}", self.name, e))?;
+
+        if let Some(live_queries) = &self.live_queries {
+            if let Err(e) = live_queries.dispatch(conn) {
+                eprintln!("Failed to dispatch {} changes: {}", self.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The live document with this ID, if any
+    fn current(&self, conn: &Connection, id: &str) -> Result<Option<Value>, String> {
+        documents::get_document(conn, &self.name, id)
+            .map(|doc| doc.and_then(|doc| doc.data))
+            .map_err(|e| format!("Failed to read {} document {}: {}", self.name, id, e))
+    }
+
+    fn require(&self, conn: &Connection, id: &str) -> Result<Value, String> {
+        self.current(conn, id)?
+            .ok_or_else(|| format!("{} document {} not found", self.name, id))
+    }
+
+    fn encode(&self, value: &T) -> Result<Value, String> {
+        serde_json::to_value(value)
+            .map_err(|e| format!("Failed to serialize {} document: {}", self.name, e))
+    }
+
+    fn decode(&self, value: Value) -> Result<T, String> {
+        serde_json::from_value(value).map_err(|e| format!("Invalid {} document: {}", self.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt;
+    use crate::db::operations::initialize_database;
+    use crate::testing::ManualClock;
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Todo {
+        title: String,
+        done: bool,
+    }
+
+    fn todo(title: &str) -> Todo {
+        Todo {
+            title: title.to_string(),
+            done: false,
+        }
+    }
+
+    #[test]
+    fn test_crud() {
+        let mut conn = initialize_database(":memory:").unwrap();
+        let todos: Collection<Todo> = Collection::open(&conn, "todos", Uuid::new_v4()).unwrap();
+
+        let id = todos.insert(&mut conn, &todo("Buy milk")).unwrap();
+        assert_eq!(todos.get(&conn, &id).unwrap(), Some(todo("Buy milk")));
+        assert!(todos.insert_with_id(&mut conn, &id, &todo("Dup")).is_err());
+
+        todos.update(&mut conn, &id, &todo("Buy oat milk")).unwrap();
+        let patched = todos.patch(&mut conn, &id, &json!({"done": true})).unwrap();
+        assert_eq!(
+            patched,
+            Todo {
+                title: "Buy oat milk".to_string(),
+                done: true
+            }
+        );
+        assert!(todos
+            .patch(&mut conn, &id, &json!({"done": "yes"}))
+            .is_err());
+        assert!(todos.update(&mut conn, "missing", &todo("x")).is_err());
+
+        // "0" sorts before any UUID
+        todos
+            .insert_with_id(&mut conn, "0", &todo("First"))
+            .unwrap();
+        let listed = todos.list(&conn).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].id, "0");
+
+        assert!(todos.delete(&mut conn, &id).unwrap());
+        assert!(!todos.delete(&mut conn, &id).unwrap());
+        assert_eq!(todos.get(&conn, &id).unwrap(), None);
+
+        // Every write went to the oplog and the outbox, stamped in order
+        let entries = operations::get_oplog_entries_since(&conn, 0).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert!(entries.iter().all(|e| e.table == "todos"));
+        assert_eq!(operations::get_sync_outbox(&conn).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_merged_state_across_devices() {
+        let clock = ManualClock::default();
+        let mut a = initialize_database(":memory:").unwrap();
+        let mut b = initialize_database(":memory:").unwrap();
+        let on_a: Collection<Todo> = Collection::open(&a, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let on_b: Collection<Todo> = Collection::open(&b, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+
+        on_a.insert_with_id(&mut a, "t1", &todo("Shared")).unwrap();
+        crdt::merge_with(
+            &mut b,
+            &operations::take_sync_outbox(&mut a).unwrap(),
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+
+        // Concurrent patches to different fields both apply
+        clock.advance(Duration::from_millis(1));
+        on_a.patch(&mut a, "t1", &json!({"title": "Renamed"}))
+            .unwrap();
+        on_b.patch(&mut b, "t1", &json!({"done": true})).unwrap();
+        let from_a = operations::take_sync_outbox(&mut a).unwrap();
+        let from_b = operations::take_sync_outbox(&mut b).unwrap();
+        crdt::merge_with(&mut a, &from_b, &clock, &crdt::DriftBound::default()).unwrap();
+        crdt::merge_with(&mut b, &from_a, &clock, &crdt::DriftBound::default()).unwrap();
+
+        let expected = Todo {
+            title: "Renamed".to_string(),
+            done: true,
+        };
+        assert_eq!(on_a.get(&a, "t1").unwrap(), Some(expected.clone()));
+        assert_eq!(on_b.get(&b, "t1").unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn test_late_operation_replays_document() {
+        let clock = ManualClock::default();
+        let mut a = initialize_database(":memory:").unwrap();
+        let mut b = initialize_database(":memory:").unwrap();
+        let on_a: Collection<Todo> = Collection::open(&a, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let on_b: Collection<Todo> = Collection::open(&b, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+
+        on_a.insert_with_id(&mut a, "t1", &todo("Original"))
+            .unwrap();
+        crdt::merge_with(
+            &mut b,
+            &operations::take_sync_outbox(&mut a).unwrap(),
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+
+        clock.advance(Duration::from_millis(1));
+        on_a.patch(&mut a, "t1", &json!({"done": true})).unwrap();
+        clock.advance(Duration::from_millis(1));
+        on_b.patch(&mut b, "t1", &json!({"title": "Replaced", "done": false}))
+            .unwrap();
+
+        // The patch reaches b after its newer one and is overwritten by it on replay
+        let from_a = operations::take_sync_outbox(&mut a).unwrap();
+        let from_b = operations::take_sync_outbox(&mut b).unwrap();
+        crdt::merge_with(&mut b, &from_a, &clock, &crdt::DriftBound::default()).unwrap();
+        crdt::merge_with(&mut a, &from_b, &clock, &crdt::DriftBound::default()).unwrap();
+
+        assert_eq!(on_a.get(&a, "t1").unwrap(), Some(todo("Replaced")));
+        assert_eq!(on_b.get(&b, "t1").unwrap(), Some(todo("Replaced")));
+    }
+
+    #[test]
+    fn test_update_sends_changed_fields() {
+        let clock = ManualClock::default();
+        let mut a = initialize_database(":memory:").unwrap();
+        let mut b = initialize_database(":memory:").unwrap();
+        let on_a: Collection<Todo> = Collection::open(&a, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let on_b: Collection<Todo> = Collection::open(&b, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        on_a.insert_with_id(&mut a, "t1", &todo("Shared")).unwrap();
+        crdt::merge_with(
+            &mut b,
+            &operations::take_sync_outbox(&mut a).unwrap(),
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+
+        // Concurrent full updates touching different fields both survive
+        clock.advance(Duration::from_millis(1));
+        on_a.update(&mut a, "t1", &todo("Renamed")).unwrap();
+        let mut done = todo("Shared");
+        done.done = true;
+        on_b.update(&mut b, "t1", &done).unwrap();
+        let from_a = operations::take_sync_outbox(&mut a).unwrap();
+        let from_b = operations::take_sync_outbox(&mut b).unwrap();
+        assert_eq!(from_a[0].op_type, OP_MERGE_PATCH);
+        assert_eq!(
+            from_a[0].data,
+            json!({"id": "t1", "patch": {"title": "Renamed"}})
+        );
+        crdt::merge_with(&mut a, &from_b, &clock, &crdt::DriftBound::default()).unwrap();
+        crdt::merge_with(&mut b, &from_a, &clock, &crdt::DriftBound::default()).unwrap();
+
+        let expected = Todo {
+            title: "Renamed".to_string(),
+            done: true,
+        };
+        assert_eq!(on_a.get(&a, "t1").unwrap(), Some(expected.clone()));
+        assert_eq!(on_b.get(&b, "t1").unwrap(), Some(expected));
+
+        // Unchanged documents write nothing; JSON Patch ships as-is
+        let current = on_a.get(&a, "t1").unwrap().unwrap();
+        on_a.update(&mut a, "t1", &current).unwrap();
+        assert!(operations::take_sync_outbox(&mut a).unwrap().is_empty());
+        let patch = json!([{"op": "replace", "path": "/title", "value": "Final"}]);
+        on_a.json_patch(&mut a, "t1", &patch).unwrap();
+        assert!(on_a
+            .json_patch(&mut a, "t1", &json!([{"op": "remove", "path": "/missing"}]))
+            .is_err());
+        crdt::merge_with(
+            &mut b,
+            &operations::take_sync_outbox(&mut a).unwrap(),
+            &clock,
+            &crdt::DriftBound::default(),
+        )
+        .unwrap();
+        assert_eq!(on_b.get(&b, "t1").unwrap().unwrap().title, "Final");
+    }
+
+    #[test]
+    fn test_open_applies_existing_operations() {
+        let mut a = initialize_database(":memory:").unwrap();
+        let mut b = initialize_database(":memory:").unwrap();
+        let on_a: Collection<Todo> = Collection::open(&a, "todos", Uuid::new_v4()).unwrap();
+        on_a.insert_with_id(&mut a, "t1", &todo("Early")).unwrap();
+
+        // b receives the write before it ever opens the collection
+        crdt::merge(&mut b, &operations::take_sync_outbox(&mut a).unwrap()).unwrap();
+        let on_b: Collection<Todo> = Collection::open(&b, "todos", Uuid::new_v4()).unwrap();
+        assert_eq!(on_b.get(&b, "t1").unwrap(), Some(todo("Early")));
+    }
+}
@@ -0,0 +1,61 @@
+//! Wide-area discovery of a user's devices.
+//!
+//! mDNS only finds peers on the local network. To let devices of the same user
+//! find each other across networks, every device registers under a user-scoped
+//! key in two places:
+//! - the Kademlia DHT, as a provider of the key, and
+//! - rendezvous points, under a namespace derived from the same key.
+//!
+//! The key is a domain-separated SHA-256 hash of the user ID, so the DHT and
+//! rendezvous servers only ever see an opaque identifier rather than the user ID.
+
+use libp2p::{kad, rendezvous, StreamProtocol};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Protocol name of Ahenk's Kademlia DHT, kept separate from the public IPFS DHT
+pub const KADEMLIA_PROTOCOL: StreamProtocol = StreamProtocol::new("/ahenk/kad/1.0.0");
+
+/// How long rendezvous registrations stay valid before they must be renewed
+pub const RENDEZVOUS_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Domain separator mixed into the discovery hash
+const DISCOVERY_DOMAIN: &[u8] = b"ahenk/discovery/v1/";
+
+/// Opaque, user-scoped discovery identifier (64 hex characters)
+pub fn discovery_namespace(user_id: Uuid) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(DISCOVERY_DOMAIN);
+    hasher.update(user_id.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Kademlia provider key for a user's devices
+pub fn discovery_key(user_id: Uuid) -> kad::RecordKey {
+    kad::RecordKey::new(&discovery_namespace(user_id))
+}
+
+/// Rendezvous namespace for a user's devices
+pub fn rendezvous_namespace(user_id: Uuid) -> rendezvous::Namespace {
+    // 64 hex characters are always within the rendezvous namespace limit
+    rendezvous::Namespace::new(discovery_namespace(user_id))
+        .expect("discovery namespace fits the rendezvous limit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_namespace_is_stable_and_opaque() {
+        let user_id = Uuid::new_v4();
+        let namespace = discovery_namespace(user_id);
+
+        assert_eq!(namespace, discovery_namespace(user_id));
+        assert_ne!(namespace, discovery_namespace(Uuid::new_v4()));
+        assert_eq!(namespace.len(), 64);
+        assert!(!namespace.contains(&user_id.simple().to_string()));
+        assert_eq!(rendezvous_namespace(user_id).to_string(), namespace);
+    }
+}
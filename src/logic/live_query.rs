@@ -0,0 +1,510 @@
+//! Live queries over collections and synced app tables.
+//!
+//! A subscription names a collection or synced table and optionally a [`Filter`]
+//! on the JSON state of its rows. [`LiveQueries::dispatch`] reads the change feed
+//! and delivers a [`ChangeSet`] to every subscription whose rows changed, whether
+//! by a local write or a merge. With a filter, rows entering the result are
+//! reported as inserted and rows leaving it as deleted.
+//!
+//! The sync manager dispatches after every merge and capture tick; collections
+//! opened with [`Collection::with_live_queries`](crate::logic::collection::Collection::with_live_queries)
+//! dispatch after each write.
+
+use crate::db::change_feed::{self, CHANGE_DELETED, CHANGE_INSERTED};
+use futures::channel::mpsc;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Predicate on the JSON state of a row.
+///
+/// Paths are JSON pointers (RFC 6901), e.g. `/author/name`. Ordering comparisons
+/// apply to two numbers or two strings and are false otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Eq { path: String, value: Value },
+    Ne { path: String, value: Value },
+    Gt { path: String, value: Value },
+    Gte { path: String, value: Value },
+    Lt { path: String, value: Value },
+    Lte { path: String, value: Value },
+    Exists { path: String },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    /// Whether `row` satisfies the filter
+    pub fn matches(&self, row: &Value) -> bool {
+        match self {
+            Filter::Eq { path, value } => row.pointer(path) == Some(value),
+            Filter::Ne { path, value } => row.pointer(path) != Some(value),
+            Filter::Gt { path, value } => {
+                compare(row.pointer(path), value) == Some(Ordering::Greater)
+            }
+            Filter::Gte { path, value } => matches!(
+                compare(row.pointer(path), value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Filter::Lt { path, value } => compare(row.pointer(path), value) == Some(Ordering::Less),
+            Filter::Lte { path, value } => matches!(
+                compare(row.pointer(path), value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Filter::Exists { path } => row.pointer(path).is_some_and(|v| !v.is_null()),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(row)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(row)),
+            Filter::Not(filter) => !filter.matches(row),
+        }
+    }
+}
+
+fn compare(actual: Option<&Value>, expected: &Value) -> Option<Ordering> {
+    match (actual?, expected) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// What a subscription watches
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LiveQuery {
+    /// Collection or synced table name
+    pub table: String,
+    /// Only rows matching this filter; every row if `None`
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+impl LiveQuery {
+    /// Watch every row of a collection or synced table
+    pub fn table(table: &str) -> Self {
+        Self {
+            table: table.to_string(),
+            filter: None,
+        }
+    }
+
+    /// Only watch rows matching `filter`
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+/// Rows of a subscription that changed since the last delivery, by ID
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChangeSet {
+    /// Subscription the changes are for
+    pub subscription_id: u64,
+    /// Collection or table name
+    pub table: String,
+    pub inserted: Vec<String>,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl ChangeSet {
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// A subscription created by [`LiveQueries::subscribe`]
+pub struct Subscription {
+    /// ID to unsubscribe with
+    pub id: u64,
+    /// IDs of the rows matching the query when it was created
+    pub initial: Vec<String>,
+    /// Change sets, in order
+    pub changes: mpsc::UnboundedReceiver<ChangeSet>,
+}
+
+type Callback = Arc<dyn Fn(ChangeSet) + Send + Sync>;
+
+struct Watcher {
+    query: LiveQuery,
+    /// Rows currently matching a filtered query
+    members: HashSet<String>,
+    /// Feed position the subscription started at
+    since: i64,
+    callback: Callback,
+}
+
+#[derive(Default)]
+struct Registry {
+    cursor: i64,
+    next_id: u64,
+    watchers: HashMap<u64, Watcher>,
+}
+
+/// Live query subscriptions on one database; cheap to clone and share
+#[derive(Clone, Default)]
+pub struct LiveQueries {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl LiveQueries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to changes, receiving change sets on a channel
+    pub fn subscribe(&self, conn: &Connection, query: LiveQuery) -> Result<Subscription, String> {
+        let (sender, changes) = mpsc::unbounded();
+        let (id, initial) = self.subscribe_with(conn, query, move |changes| {
+            let _ = sender.unbounded_send(changes);
+        })?;
+        Ok(Subscription {
+            id,
+            initial,
+            changes,
+        })
+    }
+
+    /// Subscribe to changes, calling `callback` with each change set.
+    ///
+    /// Returns the subscription ID and the IDs of the rows matching the query now.
+    /// The callback runs on the thread calling [`LiveQueries::dispatch`].
+    pub fn subscribe_with<F>(
+        &self,
+        conn: &Connection,
+        query: LiveQuery,
+        callback: F,
+    ) -> Result<(u64, Vec<String>), String>
+    where
+        F: Fn(ChangeSet) + Send + Sync + 'static,
+    {
+        if !change_feed::is_source(conn, &query.table).map_err(|e| e.to_string())? {
+            return Err(format!(
+                "'{}' is not a collection or synced table",
+                query.table
+            ));
+        }
+        // Tables registered before the change feed existed get their triggers now
+        if let Some(table) =
+            change_feed::synced_table(conn, &query.table).map_err(|e| e.to_string())?
+        {
+            change_feed::install_table_triggers(conn, &table)
+                .map_err(|e| format!("Failed to watch {}: {}", query.table, e))?;
+        }
+
+        let since = change_feed::latest_seq(conn).map_err(|e| e.to_string())?;
+        let initial: Vec<String> = change_feed::all_rows(conn, &query.table)
+            .map_err(|e| format!("Failed to read {}: {}", query.table, e))?
+            .into_iter()
+            .filter(|(_, row)| query.filter.as_ref().is_none_or(|f| f.matches(row)))
+            .map(|(id, _)| id)
+            .collect();
+
+        let mut registry = self.registry.lock().map_err(|e| e.to_string())?;
+        if registry.watchers.is_empty() {
+            registry.cursor = since;
+        }
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.watchers.insert(
+            id,
+            Watcher {
+                members: if query.filter.is_some() {
+                    initial.iter().cloned().collect()
+                } else {
+                    HashSet::new()
+                },
+                query,
+                since,
+                callback: Arc::new(callback),
+            },
+        );
+
+        Ok((id, initial))
+    }
+
+    /// Stop a subscription; returns `false` if it did not exist
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.registry
+            .lock()
+            .map(|mut registry| registry.watchers.remove(&id).is_some())
+            .unwrap_or(false)
+    }
+
+    /// Number of active subscriptions
+    pub fn len(&self) -> usize {
+        self.registry.lock().map(|r| r.watchers.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Deliver changes recorded since the last dispatch to matching subscriptions.
+    ///
+    /// Returns the number of change sets delivered.
+    pub fn dispatch(&self, conn: &Connection) -> Result<usize, String> {
+        let mut deliveries: Vec<(Callback, ChangeSet)> = Vec::new();
+        {
+            let mut registry = self.registry.lock().map_err(|e| e.to_string())?;
+            if registry.watchers.is_empty() {
+                return Ok(0);
+            }
+            let changes =
+                change_feed::changes_since(conn, registry.cursor).map_err(|e| e.to_string())?;
+            let Some(last) = changes.last().map(|c| c.seq) else {
+                return Ok(0);
+            };
+
+            for (id, watcher) in registry.watchers.iter_mut() {
+                let touched = net_changes(
+                    changes
+                        .iter()
+                        .filter(|c| c.seq > watcher.since && c.source == watcher.query.table),
+                );
+                if touched.is_empty() {
+                    continue;
+                }
+                let change_set = watcher.change_set(*id, conn, touched)?;
+                if !change_set.is_empty() {
+                    deliveries.push((watcher.callback.clone(), change_set));
+                }
+            }
+
+            registry.cursor = last;
+            if let Err(e) = change_feed::prune(conn, last) {
+                eprintln!("Failed to prune change feed: {}", e);
+            }
+        }
+
+        // Callbacks run without the lock held, so they may (un)subscribe
+        let delivered = deliveries.len();
+        for (callback, change_set) in deliveries {
+            callback(change_set);
+        }
+        Ok(delivered)
+    }
+}
+
+impl Watcher {
+    fn change_set(
+        &mut self,
+        id: u64,
+        conn: &Connection,
+        touched: BTreeMap<String, Option<&'static str>>,
+    ) -> Result<ChangeSet, String> {
+        let mut change_set = ChangeSet {
+            subscription_id: id,
+            table: self.query.table.clone(),
+            ..ChangeSet::default()
+        };
+
+        for (row_id, change) in touched {
+            let change = match &self.query.filter {
+                None => change,
+                Some(filter) => {
+                    let state = change_feed::row_state(conn, &self.query.table, &row_id)
+                        .map_err(|e| format!("Failed to read {}: {}", self.query.table, e))?;
+                    let now = state.as_ref().is_some_and(|row| filter.matches(row));
+                    let was = self.members.contains(&row_id);
+                    if now {
+                        self.members.insert(row_id.clone());
+                    } else {
+                        self.members.remove(&row_id);
+                    }
+                    match (was, now) {
+                        (false, true) => Some(CHANGE_INSERTED),
+                        (true, true) => Some(change_feed::CHANGE_UPDATED),
+                        (true, false) => Some(CHANGE_DELETED),
+                        (false, false) => None,
+                    }
+                }
+            };
+            match change {
+                Some(CHANGE_INSERTED) => change_set.inserted.push(row_id),
+                Some(CHANGE_DELETED) => change_set.deleted.push(row_id),
+                Some(_) => change_set.updated.push(row_id),
+                None => {}
+            }
+        }
+
+        Ok(change_set)
+    }
+}
+
+/// Net effect of a sequence of feed changes per row; `None` if they cancel out
+fn net_changes<'a>(
+    changes: impl Iterator<Item = &'a change_feed::FeedChange>,
+) -> BTreeMap<String, Option<&'static str>> {
+    let mut first_last: BTreeMap<String, (&str, &str)> = BTreeMap::new();
+    for change in changes {
+        first_last
+            .entry(change.row_id.clone())
+            .and_modify(|(_, last)| *last = change.change.as_str())
+            .or_insert((change.change.as_str(), change.change.as_str()));
+    }
+
+    first_last
+        .into_iter()
+        .map(|(row_id, (first, last))| {
+            let existed_before = first != CHANGE_INSERTED;
+            let exists_after = last != CHANGE_DELETED;
+            let change = match (existed_before, exists_after) {
+                (false, true) => Some(CHANGE_INSERTED),
+                (true, true) => Some(change_feed::CHANGE_UPDATED),
+                (true, false) => Some(CHANGE_DELETED),
+                (false, false) => None,
+            };
+            (row_id, change)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt;
+    use crate::db::operations::{self, initialize_database};
+    use crate::logic::collection::Collection;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Todo {
+        title: String,
+        done: bool,
+    }
+
+    fn next(subscription: &mut Subscription) -> Option<ChangeSet> {
+        subscription.changes.try_recv().ok()
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let row = json!({"title": "Milk", "priority": 2, "tags": {"home": true}});
+        let eq = |path: &str, value: Value| Filter::Eq {
+            path: path.to_string(),
+            value,
+        };
+
+        assert!(eq("/title", json!("Milk")).matches(&row));
+        assert!(eq("/tags/home", json!(true)).matches(&row));
+        assert!(Filter::Gte {
+            path: "/priority".to_string(),
+            value: json!(2)
+        }
+        .matches(&row));
+        assert!(!Filter::Lt {
+            path: "/title".to_string(),
+            value: json!(3)
+        }
+        .matches(&row));
+        assert!(Filter::And(vec![
+            Filter::Exists {
+                path: "/title".to_string()
+            },
+            Filter::Not(Box::new(eq("/priority", json!(1)))),
+        ])
+        .matches(&row));
+
+        // Filters round-trip through JSON for the Tauri and FFI layers
+        let filter: Filter =
+            serde_json::from_value(json!({"or": [{"eq": {"path": "/done", "value": false}}]}))
+                .unwrap();
+        assert!(filter.matches(&json!({"done": false})));
+    }
+
+    #[test]
+    fn test_collection_changes_from_local_writes_and_merges() {
+        let mut conn = initialize_database(":memory:").unwrap();
+        let mut remote = initialize_database(":memory:").unwrap();
+        let live = LiveQueries::new();
+        let todos: Collection<Todo> = Collection::open(&conn, "todos", Uuid::new_v4())
+            .unwrap()
+            .with_live_queries(live.clone());
+        let remote_todos: Collection<Todo> =
+            Collection::open(&remote, "todos", Uuid::new_v4()).unwrap();
+
+        let todo = |title: &str| Todo {
+            title: title.to_string(),
+            done: false,
+        };
+        todos.insert_with_id(&mut conn, "a", &todo("A")).unwrap();
+
+        let mut all = live.subscribe(&conn, LiveQuery::table("todos")).unwrap();
+        let mut open = live
+            .subscribe(
+                &conn,
+                LiveQuery::table("todos").filter(Filter::Eq {
+                    path: "/done".to_string(),
+                    value: json!(false),
+                }),
+            )
+            .unwrap();
+        assert_eq!(all.initial, vec!["a"]);
+        assert_eq!(open.initial, vec!["a"]);
+
+        // Local writes are delivered right away
+        todos.insert_with_id(&mut conn, "b", &todo("B")).unwrap();
+        let changes = next(&mut all).unwrap();
+        assert_eq!(changes.inserted, vec!["b"]);
+        assert_eq!(next(&mut open).unwrap().inserted, vec!["b"]);
+
+        // Completing a todo updates it, but removes it from the filtered query
+        todos.patch(&mut conn, "a", &json!({"done": true})).unwrap();
+        assert_eq!(next(&mut all).unwrap().updated, vec!["a"]);
+        assert_eq!(next(&mut open).unwrap().deleted, vec!["a"]);
+
+        // Merged remote writes are delivered on dispatch, coalesced per row
+        remote_todos
+            .insert_with_id(&mut remote, "c", &todo("C"))
+            .unwrap();
+        remote_todos.delete(&mut remote, "c").unwrap();
+        remote_todos
+            .insert_with_id(&mut remote, "d", &todo("D"))
+            .unwrap();
+        let entries = operations::take_sync_outbox(&mut remote).unwrap();
+        crdt::merge(&mut conn, &entries).unwrap();
+        assert_eq!(live.dispatch(&conn).unwrap(), 2);
+        let changes = next(&mut all).unwrap();
+        assert_eq!(changes.inserted, vec!["d"]);
+        assert!(changes.updated.is_empty() && changes.deleted.is_empty());
+        assert_eq!(next(&mut open).unwrap().inserted, vec!["d"]);
+
+        assert!(live.unsubscribe(all.id));
+        todos.delete(&mut conn, "d").unwrap();
+        assert_eq!(next(&mut open).unwrap().deleted, vec!["d"]);
+        assert_eq!(live.len(), 1);
+    }
+
+    #[test]
+    fn test_synced_table_changes() {
+        let conn = initialize_database(":memory:").unwrap();
+        conn.execute_batch("CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT)")
+            .unwrap();
+        crate::db::capture::register_table(&conn, "notes", "id").unwrap();
+        let live = LiveQueries::new();
+        let mut notes = live.subscribe(&conn, LiveQuery::table("notes")).unwrap();
+        assert!(live.subscribe(&conn, LiveQuery::table("missing")).is_err());
+
+        conn.execute_batch(
+            "INSERT INTO notes (id, title) VALUES (1, 'One'), (2, 'Two');
+             UPDATE notes SET title = 'Uno' WHERE id = 1;
+             UPDATE notes SET id = 3 WHERE id = 2;",
+        )
+        .unwrap();
+        assert_eq!(live.dispatch(&conn).unwrap(), 1);
+        let changes = next(&mut notes).unwrap();
+        assert_eq!(changes.inserted, vec!["1", "3"]);
+        assert!(changes.updated.is_empty() && changes.deleted.is_empty());
+
+        conn.execute("DELETE FROM notes WHERE id = 1", []).unwrap();
+        live.dispatch(&conn).unwrap();
+        assert_eq!(next(&mut notes).unwrap().deleted, vec!["1"]);
+        assert_eq!(live.dispatch(&conn).unwrap(), 0);
+    }
+}
@@ -3,19 +3,31 @@
 //! This module provides high-level functions for:
 //! - User registration and authentication
 //! - Device management and authorization
+//! - Typed synced document collections (see collection module)
+//! - Live queries over synced data (see live_query module)
 //! - P2P synchronization (see sync module)
 //! - Sync orchestration (see sync_manager module)
+//! - Reconnection backoff (see reconnect module)
+//! - Wide-area device discovery (see discovery module)
+//! - Self-hosted relay and rendezvous server (see relay_server module)
+//! - Sync bundles for file-based transfer (see bundle module)
 //!
 //! # TODO: Error Handling Migration
 //! Currently this module uses `Result<T, String>` for error handling.
 //! Should be migrated to `Result<T, AhenkError>` for better error categorization
 //! and consistent error handling across the crate.
 
+pub mod bundle;
+pub mod collection;
+pub mod discovery;
+pub mod live_query;
+pub mod reconnect;
+pub mod relay_server;
 pub mod sync;
 pub mod sync_manager;
 
 use crate::crdt;
-use crate::db::operations;
+use crate::db::{operations, schema_registry};
 use crate::models::{Device, OplogEntry, User};
 use argon2::password_hash::{rand_core::OsRng, SaltString};
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
@@ -25,7 +37,24 @@ use serde::Serialize;
 use uuid::Uuid;
 
 /// Helper function to build an oplog entry for CRDT synchronization
+///
+/// The entry is stamped by advancing the database's persisted HLC, so entries
+/// from every writer to the same database are strictly ordered, and carries the
+/// table's current payload version from the schema registry.
 pub fn build_oplog_entry<T: Serialize>(
+    conn: &Connection,
+    device_id: Uuid,
+    table: &str,
+    op_type: &str,
+    value: &T,
+) -> Result<OplogEntry, String> {
+    build_oplog_entry_with(conn, &crdt::SystemClock, device_id, table, op_type, value)
+}
+
+/// Build an oplog entry like [`build_oplog_entry`], reading physical time from `clock`
+pub fn build_oplog_entry_with<T: Serialize>(
+    conn: &Connection,
+    clock: &dyn crdt::Clock,
     device_id: Uuid,
     table: &str,
     op_type: &str,
@@ -33,14 +62,19 @@ pub fn build_oplog_entry<T: Serialize>(
 ) -> Result<OplogEntry, String> {
     let data = serde_json::to_value(value)
         .map_err(|e| format!("Failed to serialize {} payload: {}", table, e))?;
+    let timestamp = crdt::next_timestamp_with(conn, clock)
+        .map_err(|e| format!("Failed to advance clock: {}", e))?;
+    let schema_version = schema_registry::current_version(conn, table)
+        .map_err(|e| format!("Failed to read {} schema version: {}", table, e))?;
 
     Ok(OplogEntry {
         id: Uuid::new_v4(),
         device_id,
-        timestamp: crdt::HybridLogicalClock::now().to_timestamp(),
+        timestamp: timestamp.to_timestamp(),
         table: table.to_string(),
         op_type: op_type.to_string(),
         data,
+        schema_version,
     })
 }
 
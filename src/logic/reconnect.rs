@@ -0,0 +1,135 @@
+//! Reconnection scheduling with exponential backoff.
+//!
+//! The sync manager registers peers it wants to stay connected to (bootstrap
+//! nodes, relay servers and devices of the same user). When a connection to one
+//! of them drops or a dial fails, the peer is scheduled for a redial after a
+//! backoff that doubles with every consecutive failure, capped at a maximum.
+
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Redial state of a single peer
+#[derive(Debug, Clone)]
+struct ReconnectState {
+    /// Consecutive failed attempts since the last successful connection
+    attempts: u32,
+    /// When the next dial is due; `None` while a dial is in flight
+    next_attempt: Option<Instant>,
+}
+
+/// Tracks which peers need redialing and when
+#[derive(Debug, Clone)]
+pub struct ReconnectScheduler {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    peers: HashMap<PeerId, ReconnectState>,
+}
+
+impl ReconnectScheduler {
+    /// Create a scheduler with the given backoff bounds
+    pub fn new(initial_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            initial_backoff,
+            max_backoff: max_backoff.max(initial_backoff),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Backoff before the given attempt (1-based): `initial * 2^(attempt - 1)`, capped
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .checked_mul(1u32 << exponent)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Schedule a redial after a dropped connection or a failed dial
+    pub fn schedule(&mut self, peer_id: PeerId, now: Instant) {
+        let attempts = self.attempts(&peer_id).saturating_add(1);
+        let next_attempt = Some(now + self.backoff_for(attempts));
+        self.peers.insert(
+            peer_id,
+            ReconnectState {
+                attempts,
+                next_attempt,
+            },
+        );
+    }
+
+    /// Forget a peer's backoff once it is connected again
+    pub fn reset(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Whether a redial is pending or in flight for the peer
+    pub fn is_scheduled(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    /// Number of consecutive failed attempts for the peer
+    pub fn attempts(&self, peer_id: &PeerId) -> u32 {
+        self.peers.get(peer_id).map(|s| s.attempts).unwrap_or(0)
+    }
+
+    /// Take all peers whose redial is due, marking their dials as in flight
+    pub fn take_due(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut due = Vec::new();
+        for (peer_id, state) in self.peers.iter_mut() {
+            if state.next_attempt.is_some_and(|at| at <= now) {
+                state.next_attempt = None;
+                due.push(*peer_id);
+            }
+        }
+        due
+    }
+
+    /// Time until the earliest pending redial, if any
+    pub fn next_due_in(&self, now: Instant) -> Option<Duration> {
+        self.peers
+            .values()
+            .filter_map(|s| s.next_attempt)
+            .min()
+            .map(|at| at.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        let scheduler = ReconnectScheduler::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(scheduler.backoff_for(1), Duration::from_secs(1));
+        assert_eq!(scheduler.backoff_for(2), Duration::from_secs(2));
+        assert_eq!(scheduler.backoff_for(4), Duration::from_secs(8));
+        assert_eq!(scheduler.backoff_for(5), Duration::from_secs(10));
+        assert_eq!(scheduler.backoff_for(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_take_due_and_reset() {
+        let mut scheduler =
+            ReconnectScheduler::new(Duration::from_secs(1), Duration::from_secs(60));
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        scheduler.schedule(peer, start);
+        assert!(scheduler.take_due(start).is_empty());
+        assert_eq!(scheduler.next_due_in(start), Some(Duration::from_secs(1)));
+
+        let due = scheduler.take_due(start + Duration::from_secs(1));
+        assert_eq!(due, vec![peer]);
+        // In flight: not due again until it is rescheduled
+        assert!(scheduler.next_due_in(start).is_none());
+
+        scheduler.schedule(peer, start);
+        assert_eq!(scheduler.attempts(&peer), 2);
+        assert_eq!(scheduler.next_due_in(start), Some(Duration::from_secs(2)));
+
+        scheduler.reset(&peer);
+        assert!(!scheduler.is_scheduled(&peer));
+    }
+}
@@ -0,0 +1,689 @@
+//! Self-hosted relay and rendezvous server.
+//!
+//! Devices behind NAT need a publicly reachable node to relay their traffic
+//! (circuit relay v2) and to find each other (rendezvous). This module runs
+//! both on a single swarm so users can operate their own infrastructure
+//! instead of depending on public relays.
+//!
+//! Abuse is bounded by:
+//! - reservation limits (total, per peer, duration and request rate)
+//! - circuit limits: a circuit is closed once it has relayed more than
+//!   `max_circuit_bytes` (both directions together) or lived for
+//!   `max_circuit_duration`, and a peer may open at most `max_circuits_per_peer`
+//!   circuits at once and `circuits_per_peer_per_minute` new ones per minute.
+//!   There is no separate byte budget per peer; a peer's relayed traffic is
+//!   bounded only through these per-circuit limits.
+//! - an allowlist of user-scoped rendezvous namespaces (see [`discovery`](super::discovery)).
+//!   The rendezvous server cannot refuse a registration by namespace, so a peer
+//!   that registers outside the allowlist is disconnected and blocked; its
+//!   registration stays discoverable until it expires, after at most
+//!   `max_registration_ttl`. Peers that hold a reservation without an allowed
+//!   registration are disconnected as well.
+//! - external addresses learned from peers are only used once several distinct
+//!   peers report them, and only a few are kept
+
+use crate::logic::discovery::discovery_namespace;
+use crate::logic::sync_manager::ShutdownHandle;
+use futures::channel::mpsc;
+use libp2p::core::upgrade;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{
+    allow_block_list, identify, identity, noise, ping, relay, rendezvous, tcp, yamux, Multiaddr,
+    PeerId, Swarm, Transport,
+};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Protocol version advertised by the relay via identify
+const RELAY_PROTOCOL_VERSION: &str = "/ahenk/relay/1.0.0";
+
+/// Number of distinct peers that must observe an address before it is used
+const OBSERVED_ADDRESS_CONFIRMATIONS: usize = 3;
+
+/// Maximum number of external addresses learned from peers
+const MAX_OBSERVED_ADDRESSES: usize = 4;
+
+/// Maximum number of unconfirmed observed addresses tracked at once
+const MAX_ADDRESS_CANDIDATES: usize = 64;
+
+/// How long a peer may hold a reservation before it must have registered under
+/// an allowed namespace, when an allowlist is configured
+const UNREGISTERED_RESERVATION_GRACE: Duration = Duration::from_secs(30);
+
+/// Network behaviour of the relay server
+#[derive(NetworkBehaviour)]
+pub struct RelayServerBehaviour {
+    /// Circuit relay v2 server
+    pub relay: relay::Behaviour,
+    /// Rendezvous point for device discovery
+    pub rendezvous: rendezvous::server::Behaviour,
+    /// Identify, so clients learn the addresses they are observed at
+    pub identify: identify::Behaviour,
+    /// Ping for liveness of client connections
+    pub ping: ping::Behaviour,
+    /// Peers banned for registering outside the namespace allowlist
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+}
+
+/// Limits and policy of the relay server
+#[derive(Debug, Clone)]
+pub struct RelayServerConfig {
+    /// Maximum number of active reservations
+    pub max_reservations: usize,
+    /// Maximum number of active reservations per peer
+    pub max_reservations_per_peer: usize,
+    /// How long a reservation lasts before the client must renew it
+    pub reservation_duration: Duration,
+    /// Maximum number of reservation requests a peer may make per minute
+    pub reservations_per_peer_per_minute: u32,
+    /// Maximum number of concurrently relayed circuits
+    pub max_circuits: usize,
+    /// Maximum number of concurrently relayed circuits per source peer
+    pub max_circuits_per_peer: usize,
+    /// Maximum number of new circuits a source peer may open per minute
+    pub circuits_per_peer_per_minute: u32,
+    /// Maximum lifetime of a relayed circuit
+    pub max_circuit_duration: Duration,
+    /// Number of bytes, counted over both directions, after which a circuit is closed
+    pub max_circuit_bytes: u64,
+    /// Maximum lifetime of a rendezvous registration
+    pub max_registration_ttl: Duration,
+    /// Maximum number of rendezvous registrations per peer
+    pub max_registrations_per_peer: usize,
+    /// Rendezvous namespaces peers may register under; empty allows all
+    pub allowed_namespaces: HashSet<String>,
+    /// Publicly reachable addresses of this relay. When empty, an address
+    /// reported by connecting peers is used once enough distinct peers agree.
+    pub external_addresses: Vec<Multiaddr>,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(60 * 60), // 1 hour
+            reservations_per_peer_per_minute: 30,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            circuits_per_peer_per_minute: 30,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 17, // 128 KiB, enough for the DCUtR handshake
+            max_registration_ttl: Duration::from_secs(2 * 60 * 60),
+            max_registrations_per_peer: 16,
+            allowed_namespaces: HashSet::new(),
+            external_addresses: Vec::new(),
+        }
+    }
+}
+
+impl RelayServerConfig {
+    /// Allow a namespace given either as a user ID or as the namespace itself
+    pub fn allow_namespace(&mut self, entry: &str) {
+        let namespace = match Uuid::parse_str(entry) {
+            Ok(user_id) => discovery_namespace(user_id),
+            Err(_) => entry.to_string(),
+        };
+        self.allowed_namespaces.insert(namespace);
+    }
+
+    /// Whether peers may register under the namespace
+    pub fn is_namespace_allowed(&self, namespace: &str) -> bool {
+        self.allowed_namespaces.is_empty() || self.allowed_namespaces.contains(namespace)
+    }
+}
+
+/// Create the relay server swarm
+pub fn create_relay_swarm(
+    keypair: identity::Keypair,
+    config: &RelayServerConfig,
+) -> Result<Swarm<RelayServerBehaviour>, Box<dyn std::error::Error>> {
+    let peer_id = PeerId::from(keypair.public());
+    let per_minute = Duration::from_secs(60);
+
+    let mut relay_config = relay::Config {
+        max_reservations: config.max_reservations,
+        max_reservations_per_peer: config.max_reservations_per_peer,
+        reservation_duration: config.reservation_duration,
+        max_circuits: config.max_circuits,
+        max_circuits_per_peer: config.max_circuits_per_peer,
+        max_circuit_duration: config.max_circuit_duration,
+        max_circuit_bytes: config.max_circuit_bytes,
+        ..Default::default()
+    };
+    if let Some(limit) = NonZeroU32::new(config.reservations_per_peer_per_minute) {
+        relay_config = relay_config.reservation_rate_per_peer(limit, per_minute);
+    }
+    if let Some(limit) = NonZeroU32::new(config.circuits_per_peer_per_minute) {
+        relay_config = relay_config.circuit_src_per_peer(limit, per_minute);
+    }
+
+    let rendezvous_config = rendezvous::server::Config::default()
+        .with_max_ttl(config.max_registration_ttl.as_secs())
+        .with_max_registration_per_peer(config.max_registrations_per_peer);
+
+    let behaviour = RelayServerBehaviour {
+        relay: relay::Behaviour::new(peer_id, relay_config),
+        rendezvous: rendezvous::server::Behaviour::new(rendezvous_config),
+        identify: identify::Behaviour::new(identify::Config::new(
+            RELAY_PROTOCOL_VERSION.to_string(),
+            keypair.public(),
+        )),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        blocked: allow_block_list::Behaviour::default(),
+    };
+
+    let transport = tcp::tokio::Transport::default()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(&keypair)?)
+        .multiplex(yamux::Config::default())
+        .boxed();
+
+    let mut swarm = Swarm::new(
+        transport,
+        behaviour,
+        peer_id,
+        libp2p::swarm::Config::with_tokio_executor(),
+    );
+    for addr in &config.external_addresses {
+        swarm.add_external_address(addr.clone());
+    }
+
+    Ok(swarm)
+}
+
+/// Load the relay's keypair from a file, creating it on first start.
+///
+/// The relay's peer ID must stay stable, since clients configure it as
+/// `/…/p2p/<peer_id>` in their relay servers and rendezvous points.
+pub fn load_or_create_keypair(
+    path: &Path,
+) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+    if path.exists() {
+        let bytes = std::fs::read(path)?;
+        return Ok(identity::Keypair::from_protobuf_encoding(&bytes)?);
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, keypair.to_protobuf_encoding()?)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    Ok(keypair)
+}
+
+/// External addresses reported by peers through identify, which any peer can
+/// forge, so an address is only confirmed once several distinct peers report it
+#[derive(Debug, Default)]
+struct ObservedAddresses {
+    candidates: HashMap<Multiaddr, HashSet<PeerId>>,
+    confirmed: HashSet<Multiaddr>,
+}
+
+impl ObservedAddresses {
+    /// Record that `peer` observed us at `addr`; returns the address once it
+    /// is newly confirmed
+    fn observe(&mut self, peer: PeerId, addr: Multiaddr) -> Option<Multiaddr> {
+        if self.confirmed.contains(&addr) || self.confirmed.len() >= MAX_OBSERVED_ADDRESSES {
+            return None;
+        }
+        if !self.candidates.contains_key(&addr) && self.candidates.len() >= MAX_ADDRESS_CANDIDATES {
+            return None;
+        }
+
+        let observers = self.candidates.entry(addr.clone()).or_default();
+        observers.insert(peer);
+        if observers.len() < OBSERVED_ADDRESS_CONFIRMATIONS {
+            return None;
+        }
+
+        self.candidates.remove(&addr);
+        self.confirmed.insert(addr.clone());
+        Some(addr)
+    }
+}
+
+/// Relay v2 server and rendezvous point
+pub struct RelayServer {
+    swarm: Swarm<RelayServerBehaviour>,
+    config: RelayServerConfig,
+    /// Allowed namespaces each peer is currently registered under
+    registrations: HashMap<PeerId, HashSet<String>>,
+    observed_addresses: ObservedAddresses,
+    /// Peers holding a reservation without an allowed registration, and since when
+    unregistered_reservations: HashMap<PeerId, Instant>,
+    shutdown_tx: mpsc::UnboundedSender<()>,
+    shutdown_rx: mpsc::UnboundedReceiver<()>,
+    shutdown_requested: bool,
+}
+
+impl RelayServer {
+    pub fn new(
+        keypair: identity::Keypair,
+        config: RelayServerConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let swarm = create_relay_swarm(keypair, &config)?;
+        let (shutdown_tx, shutdown_rx) = mpsc::unbounded();
+        Ok(Self {
+            swarm,
+            config,
+            registrations: HashMap::new(),
+            observed_addresses: ObservedAddresses::default(),
+            unregistered_reservations: HashMap::new(),
+            shutdown_tx,
+            shutdown_rx,
+            shutdown_requested: false,
+        })
+    }
+
+    /// The relay's peer ID, to be configured by clients
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Start listening on an address
+    pub fn listen(&mut self, addr: Multiaddr) -> Result<(), Box<dyn std::error::Error>> {
+        self.swarm.listen_on(addr)?;
+        Ok(())
+    }
+
+    /// Get the addresses the relay listens on
+    pub fn get_listen_addresses(&self) -> Vec<String> {
+        self.swarm
+            .listeners()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    /// Get a handle that can stop [`RelayServer::run`] from another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.shutdown_tx.clone(),
+        }
+    }
+
+    /// Process a single network event, or a pending shutdown request
+    pub async fn process_event(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        use futures::StreamExt;
+
+        let event = futures::select_biased! {
+            _ = self.shutdown_rx.next() => {
+                self.shutdown_requested = true;
+                return Ok(());
+            }
+            event = self.swarm.select_next_some() => event,
+        };
+
+        self.handle_swarm_event(event);
+        // Ping keeps events flowing while clients are connected, so the grace
+        // period is checked often enough
+        self.enforce_reservation_allowlist();
+        Ok(())
+    }
+
+    /// Serve until a shutdown is requested
+    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        while !self.shutdown_requested {
+            self.process_event().await?;
+        }
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+
+        Ok(())
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<RelayServerBehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Relay listening on: {}/p2p/{}", address, self.peer_id());
+            }
+            SwarmEvent::Behaviour(RelayServerBehaviourEvent::Identify(
+                identify::Event::Received { peer_id, info, .. },
+            )) if self.config.external_addresses.is_empty() => {
+                // Without configured external addresses, use what enough peers observe
+                if let Some(addr) = self.observed_addresses.observe(peer_id, info.observed_addr) {
+                    println!("Confirmed external address: {}", addr);
+                    self.swarm.add_external_address(addr);
+                }
+            }
+            SwarmEvent::Behaviour(RelayServerBehaviourEvent::Rendezvous(event)) => {
+                self.handle_rendezvous_event(event)
+            }
+            SwarmEvent::Behaviour(RelayServerBehaviourEvent::Relay(event)) => match event {
+                relay::Event::ReservationReqAccepted {
+                    src_peer_id,
+                    renewed,
+                } => {
+                    if !renewed {
+                        println!("Reservation accepted for {}", src_peer_id);
+                    }
+                    if !self.config.allowed_namespaces.is_empty()
+                        && !self.registrations.contains_key(&src_peer_id)
+                    {
+                        self.unregistered_reservations
+                            .entry(src_peer_id)
+                            .or_insert_with(Instant::now);
+                    }
+                }
+                relay::Event::ReservationReqDenied { src_peer_id, .. } => {
+                    eprintln!("Reservation denied for {}", src_peer_id);
+                }
+                relay::Event::CircuitReqAccepted {
+                    src_peer_id,
+                    dst_peer_id,
+                } => {
+                    println!("Relaying circuit {} -> {}", src_peer_id, dst_peer_id);
+                }
+                relay::Event::CircuitReqDenied {
+                    src_peer_id,
+                    dst_peer_id,
+                    ..
+                } => {
+                    eprintln!("Circuit denied {} -> {}", src_peer_id, dst_peer_id);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn handle_rendezvous_event(&mut self, event: rendezvous::server::Event) {
+        match event {
+            rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                let namespace = registration.namespace.to_string();
+                if self.config.is_namespace_allowed(&namespace) {
+                    println!("Peer {} registered under {}", peer, namespace);
+                    self.registrations
+                        .entry(peer)
+                        .or_default()
+                        .insert(namespace);
+                    self.unregistered_reservations.remove(&peer);
+                } else {
+                    // The rendezvous server cannot refuse by namespace, so ban the
+                    // peer; its registration lapses after at most the maximum TTL.
+                    eprintln!(
+                        "Blocking peer {} for registering outside the allowlist: {}",
+                        peer, namespace
+                    );
+                    self.swarm.behaviour_mut().blocked.block_peer(peer);
+                }
+            }
+            rendezvous::server::Event::PeerUnregistered { peer, namespace } => {
+                self.forget_registration(peer, &namespace.to_string());
+            }
+            rendezvous::server::Event::RegistrationExpired(registration) => {
+                let peer = registration.record.peer_id();
+                self.forget_registration(peer, &registration.namespace.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn forget_registration(&mut self, peer: PeerId, namespace: &str) {
+        if let Some(namespaces) = self.registrations.get_mut(&peer) {
+            namespaces.remove(namespace);
+            if namespaces.is_empty() {
+                self.registrations.remove(&peer);
+            }
+        }
+    }
+
+    /// Disconnect peers that reserved a circuit address but did not register
+    /// under an allowed namespace within the grace period
+    fn enforce_reservation_allowlist(&mut self) {
+        let now = Instant::now();
+        let registrations = &self.registrations;
+        let mut expired = Vec::new();
+        self.unregistered_reservations.retain(|peer, since| {
+            if registrations.contains_key(peer) {
+                return false;
+            }
+            if now.duration_since(*since) >= UNREGISTERED_RESERVATION_GRACE {
+                expired.push(*peer);
+                return false;
+            }
+            true
+        });
+
+        for peer in expired {
+            eprintln!(
+                "Disconnecting {}: reservation without an allowed registration",
+                peer
+            );
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_allowlist() {
+        let mut config = RelayServerConfig::default();
+        let user_id = Uuid::new_v4();
+        assert!(config.is_namespace_allowed("anything"));
+
+        config.allow_namespace(&user_id.to_string());
+        config.allow_namespace("explicit-namespace");
+
+        assert!(config.is_namespace_allowed(&discovery_namespace(user_id)));
+        assert!(config.is_namespace_allowed("explicit-namespace"));
+        assert!(!config.is_namespace_allowed(&discovery_namespace(Uuid::new_v4())));
+    }
+
+    #[test]
+    fn test_observed_address_needs_distinct_peers() {
+        let mut observed = ObservedAddresses::default();
+        let addr: Multiaddr = "/ip4/198.51.100.7/tcp/4001".parse().unwrap();
+        let peer = PeerId::random();
+
+        // Repeated reports from one peer never confirm an address
+        for _ in 0..OBSERVED_ADDRESS_CONFIRMATIONS {
+            assert_eq!(observed.observe(peer, addr.clone()), None);
+        }
+        for _ in 1..OBSERVED_ADDRESS_CONFIRMATIONS - 1 {
+            assert_eq!(observed.observe(PeerId::random(), addr.clone()), None);
+        }
+        assert_eq!(observed.observe(PeerId::random(), addr.clone()), Some(addr));
+    }
+
+    #[test]
+    fn test_observed_addresses_are_capped() {
+        let mut observed = ObservedAddresses::default();
+        let peers: Vec<PeerId> = (0..OBSERVED_ADDRESS_CONFIRMATIONS)
+            .map(|_| PeerId::random())
+            .collect();
+        let mut confirmed = 0;
+
+        for port in 0..MAX_OBSERVED_ADDRESSES + 2 {
+            let addr: Multiaddr = format!("/ip4/198.51.100.7/tcp/{}", 4000 + port)
+                .parse()
+                .unwrap();
+            for peer in &peers {
+                if observed.observe(*peer, addr.clone()).is_some() {
+                    confirmed += 1;
+                }
+            }
+        }
+
+        assert_eq!(confirmed, MAX_OBSERVED_ADDRESSES);
+    }
+
+    #[test]
+    fn test_keypair_is_persisted() {
+        let path = std::env::temp_dir().join(format!("ahenk-relay-{}.key", Uuid::new_v4()));
+
+        let first = load_or_create_keypair(&path).unwrap();
+        let second = load_or_create_keypair(&path).unwrap();
+        assert_eq!(first.public(), second.public());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registration_outside_allowlist_blocks_peer() {
+        use futures::StreamExt;
+        use libp2p::rendezvous;
+
+        let mut config = RelayServerConfig::default();
+        config.allow_namespace("allowed");
+        let mut relay = RelayServer::new(identity::Keypair::generate_ed25519(), config).unwrap();
+        relay
+            .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        while relay.get_listen_addresses().is_empty() {
+            relay.process_event().await.unwrap();
+        }
+        let relay_addr: Multiaddr = relay.get_listen_addresses()[0].parse().unwrap();
+        let relay_peer = relay.peer_id();
+        let relay_handle = relay.shutdown_handle();
+        let relay_task = tokio::spawn(async move { relay.run().await.is_ok() });
+
+        let mut client = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|keypair| rendezvous::client::Behaviour::new(keypair.clone()))
+            .unwrap()
+            .build();
+        client.add_external_address("/ip4/192.0.2.1/tcp/4001".parse().unwrap());
+        client
+            .dial(relay_addr.with(libp2p::multiaddr::Protocol::P2p(relay_peer)))
+            .unwrap();
+
+        async fn register(
+            client: &mut Swarm<rendezvous::client::Behaviour>,
+            relay_peer: PeerId,
+            namespace: &str,
+        ) -> Result<(), rendezvous::ErrorCode> {
+            let namespace = rendezvous::Namespace::new(namespace.to_string()).unwrap();
+            client
+                .behaviour_mut()
+                .register(namespace, relay_peer, None)
+                .unwrap();
+            loop {
+                match client.select_next_some().await {
+                    SwarmEvent::Behaviour(rendezvous::client::Event::Registered { .. }) => {
+                        return Ok(())
+                    }
+                    SwarmEvent::Behaviour(rendezvous::client::Event::RegisterFailed {
+                        error,
+                        ..
+                    }) => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !client.is_connected(&relay_peer) {
+                client.select_next_some().await;
+            }
+            assert_eq!(register(&mut client, relay_peer, "allowed").await, Ok(()));
+            assert!(client.is_connected(&relay_peer));
+
+            // The server accepts the registration, then the relay bans the peer
+            client
+                .behaviour_mut()
+                .register(
+                    rendezvous::Namespace::from_static("foreign"),
+                    relay_peer,
+                    None,
+                )
+                .unwrap();
+            loop {
+                if let SwarmEvent::ConnectionClosed { peer_id, .. } =
+                    client.select_next_some().await
+                {
+                    if peer_id == relay_peer {
+                        break;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("relay should disconnect a peer registering outside the allowlist");
+
+        relay_handle.shutdown();
+        assert!(relay_task.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_client_reserves_circuit_address() {
+        use crate::db::connection::Database;
+        use crate::logic::sync::P2PConfig;
+        use crate::logic::sync_manager::SyncManager;
+
+        let listen_addr: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        let mut relay = RelayServer::new(
+            identity::Keypair::generate_ed25519(),
+            RelayServerConfig::default(),
+        )
+        .unwrap();
+        relay.listen(listen_addr).unwrap();
+        while relay.get_listen_addresses().is_empty() {
+            relay.process_event().await.unwrap();
+        }
+        let relay_addr: Multiaddr = relay.get_listen_addresses()[0].parse().unwrap();
+        // The relay hands out its external addresses in reservations
+        relay.swarm.add_external_address(relay_addr.clone());
+        let relay_peer = relay.peer_id();
+        let relay_handle = relay.shutdown_handle();
+        let relay_task = tokio::spawn(async move { relay.run().await.is_ok() });
+
+        let relay_server = format!("{}/p2p/{}", relay_addr, relay_peer);
+        let config = P2PConfig {
+            enable_mdns: false,
+            enable_kademlia: false,
+            relay_servers: vec![relay_server.clone()],
+            ..P2PConfig::default()
+        };
+        let mut client = SyncManager::new(
+            identity::Keypair::generate_ed25519(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Database::open(":memory:").unwrap(),
+            config,
+        )
+        .unwrap();
+        client.connect_to_network(&[], &[relay_server]).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while !client
+                .get_listen_addresses()
+                .iter()
+                .any(|addr| addr.contains("/p2p-circuit"))
+            {
+                client.process_event().await.unwrap();
+            }
+        })
+        .await
+        .expect("client should obtain a relay reservation");
+        assert_eq!(
+            client.get_relay_reservations(),
+            vec![relay_peer.to_string()]
+        );
+
+        relay_handle.shutdown();
+        assert!(relay_task.await.unwrap());
+    }
+}
@@ -1,27 +1,52 @@
 use crate::crdt;
-use crate::db::operations;
-use crate::models::{OplogEntry, Peer};
+use crate::db::{compaction, operations};
+use crate::logic::discovery::KADEMLIA_PROTOCOL;
+use crate::models::{OplogEntry, OplogFilter, Peer};
 use chrono::Utc;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{MemoryTransport, OptionalTransport};
 use libp2p::gossipsub::{MessageAuthenticity, ValidationMode};
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::{
-    core::upgrade, dcutr, gossipsub, identity, mdns, multiaddr::Protocol, noise, relay,
-    swarm::NetworkBehaviour, tcp, yamux, PeerId, Swarm, Transport,
+    allow_block_list, autonat, core::upgrade, dcutr, dns, gossipsub, identify, identity, kad, mdns,
+    multiaddr::Protocol, noise, ping, quic, relay, rendezvous, swarm::NetworkBehaviour, tcp,
+    websocket, yamux, Multiaddr, PeerId, Swarm, Transport,
 };
 use rusqlite::Connection;
+use std::net::IpAddr;
 use std::time::Duration;
 use uuid::Uuid;
 
-/// Network behavior combining mDNS, Gossipsub, Relay, and DCUtR
+/// Protocol version advertised via identify; peers on other versions still connect
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/ahenk/1.0.0";
+
+/// Agent string advertised via identify
+pub const AGENT_VERSION: &str = concat!("ahenk/", env!("CARGO_PKG_VERSION"));
+
+/// Network behavior combining mDNS, Gossipsub, Relay, DCUtR, Kademlia, Rendezvous,
+/// identify, ping, AutoNAT and a peer block list
 #[derive(NetworkBehaviour)]
 pub struct AhenkBehaviour {
-    /// mDNS for local network peer discovery
-    pub mdns: mdns::tokio::Behaviour,
+    /// mDNS for local network peer discovery (if enabled)
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     /// Gossipsub for message propagation
     pub gossipsub: gossipsub::Behaviour,
-    /// Relay client for NAT traversal
-    pub relay_client: relay::client::Behaviour,
-    /// Direct Connection Upgrade through Relay (DCUtR)
-    pub dcutr: dcutr::Behaviour,
+    /// Relay client for NAT traversal (if enabled)
+    pub relay_client: Toggle<relay::client::Behaviour>,
+    /// Direct Connection Upgrade through Relay (DCUtR), enabled together with the relay client
+    pub dcutr: Toggle<dcutr::Behaviour>,
+    /// Kademlia DHT for locating devices beyond the local network
+    pub kademlia: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    /// Rendezvous client for registering with and discovering through rendezvous points
+    pub rendezvous: Toggle<rendezvous::client::Behaviour>,
+    /// Identify for exchanging listen and observed addresses
+    pub identify: identify::Behaviour,
+    /// Ping for liveness and round-trip time
+    pub ping: ping::Behaviour,
+    /// AutoNAT for determining whether this node is publicly reachable
+    pub autonat: autonat::Behaviour,
+    /// Peers this node refuses to connect to
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
 }
 
 /// Configuration for P2P network
@@ -33,12 +58,44 @@ pub struct P2PConfig {
     pub enable_relay: bool,
     /// Bootstrap nodes to connect to
     pub bootstrap_nodes: Vec<String>,
-    /// Relay server addresses
+    /// Relay server addresses; those with `/p2p/<peer_id>` also get a reservation
     pub relay_servers: Vec<String>,
+    /// Enable the Kademlia DHT for wide-area discovery
+    pub enable_kademlia: bool,
+    /// Rendezvous point addresses (must include `/p2p/<peer_id>`)
+    pub rendezvous_points: Vec<String>,
+    /// How often DHT provider records and rendezvous registrations are refreshed
+    pub discovery_interval: Duration,
     /// Gossipsub heartbeat interval
     pub heartbeat_interval: Duration,
+    /// Interval between libp2p pings on each connection
+    pub ping_interval: Duration,
     /// Maximum message size for gossipsub
     pub max_message_size: usize,
+    /// IP address [`SyncManager::listen`](crate::logic::sync_manager::SyncManager::listen) binds to
+    pub listen_address: String,
+    /// Additional multiaddrs to listen on, e.g. `/ip6/::/tcp/4001`
+    pub listen_addresses: Vec<String>,
+    /// Enable QUIC (UDP) alongside TCP, for faster connection setup and hole punching
+    pub enable_quic: bool,
+    /// Enable WebSocket over TCP, for networks that only allow HTTP(S) traffic
+    pub enable_websocket: bool,
+    /// TCP port for the WebSocket listener (0 = random); must differ from the TCP port
+    pub websocket_port: u16,
+    /// Use libp2p's in-process memory transport instead of the network (for simulations);
+    /// listen addresses become `/memory/<port>`
+    pub memory_transport: bool,
+    /// How long a graceful shutdown keeps polling the swarm to finish in-flight transfers
+    pub shutdown_grace_period: Duration,
+    /// Delay before the first redial of a dropped peer; doubles on every failure
+    pub reconnect_initial_backoff: Duration,
+    /// Upper bound for the redial backoff
+    pub reconnect_max_backoff: Duration,
+    /// How often changes captured from registered app tables are moved into the oplog and sent
+    pub capture_interval: Duration,
+    /// How far ahead of local time received entries may be stamped; entries beyond
+    /// it are quarantined or only clamp the clock, per its policy
+    pub drift_bound: crdt::DriftBound,
 }
 
 impl Default for P2PConfig {
@@ -48,8 +105,23 @@ impl Default for P2PConfig {
             enable_relay: true,
             bootstrap_nodes: vec![],
             relay_servers: vec![],
+            enable_kademlia: true,
+            rendezvous_points: vec![],
+            discovery_interval: Duration::from_secs(5 * 60),
             heartbeat_interval: Duration::from_secs(10),
+            ping_interval: Duration::from_secs(15),
             max_message_size: 65536, // 64KB
+            listen_address: "0.0.0.0".to_string(),
+            listen_addresses: vec![],
+            enable_quic: true,
+            enable_websocket: false,
+            websocket_port: 0,
+            memory_transport: false,
+            shutdown_grace_period: Duration::from_secs(3),
+            reconnect_initial_backoff: Duration::from_secs(1),
+            reconnect_max_backoff: Duration::from_secs(300), // 5 minutes
+            capture_interval: Duration::from_secs(1),
+            drift_bound: crdt::DriftBound::default(),
         }
     }
 }
@@ -57,60 +129,284 @@ impl Default for P2PConfig {
 /// Message types for P2P communication
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum SyncMessage {
-    /// Request oplog entries since a specific timestamp
-    RequestSync { user_id: Uuid, since_timestamp: i64 },
+    /// Request oplog entries after an HLC timestamp
+    RequestSync {
+        user_id: Uuid,
+        since_timestamp: i64,
+        /// Tags the response pages and asks for a closing `SyncComplete`
+        #[serde(default)]
+        request_id: Option<Uuid>,
+    },
     /// Response with oplog entries
     SyncData {
         user_id: Uuid,
         entries: Vec<OplogEntry>,
+        /// The `RequestSync` this page answers, if any
+        #[serde(default)]
+        request_id: Option<Uuid>,
+    },
+    /// Response to a `RequestSync` from before the compaction horizon: the
+    /// compacted entries (one snapshot per entity up to `horizon`) and all later ones
+    Snapshot {
+        user_id: Uuid,
+        horizon: i64,
+        entries: Vec<OplogEntry>,
+        /// The `RequestSync` this page answers
+        #[serde(default)]
+        request_id: Option<Uuid>,
+    },
+    /// Ends the response to a tagged `RequestSync`: `device_id` sent `pages`
+    /// pages holding every entry in its oplog after the requested timestamp, up
+    /// to `through`
+    SyncComplete {
+        user_id: Uuid,
+        request_id: Uuid,
+        device_id: Uuid,
+        through: i64,
+        pages: usize,
     },
     /// Announce presence with device info
     Announce {
         user_id: Uuid,
         device_id: Uuid,
         peer_id: String,
+        /// Multiaddrs the announcing peer can be dialed at
+        #[serde(default)]
+        addresses: Vec<String>,
     },
-    /// Ping message for keepalive
+    /// Application-level ping; liveness and latency are measured by libp2p ping
     Ping { timestamp: i64 },
     /// Pong response to ping
     Pong { timestamp: i64 },
+    /// Sent after merging a complete catch-up response: `device_id` holds every
+    /// entry `origin_device_id` had up to `timestamp`, so history up to it may be
+    /// compacted once every device has acknowledged it from every other
+    Ack {
+        user_id: Uuid,
+        device_id: Uuid,
+        origin_device_id: Uuid,
+        timestamp: i64,
+    },
 }
 
-pub fn handle_sync_message(
+/// Apply a received message on the device `device_id`. A `RequestSync` is
+/// answered through `reply`, in pages within the default [`SyncLimits`].
+///
+/// A `SyncComplete` is not acted on: acknowledging a response needs to know
+/// which of its pages were merged, which the [`SyncManager`] tracks.
+///
+/// [`SyncManager`]: crate::logic::sync_manager::SyncManager
+pub fn handle_sync_message<F>(
     conn: &mut Connection,
+    device_id: Uuid,
     msg: SyncMessage,
-) -> Result<Option<SyncMessage>, String> {
+    reply: F,
+) -> Result<(), String>
+where
+    F: FnMut(SyncMessage) -> Result<(), String>,
+{
     match msg {
         SyncMessage::RequestSync {
             user_id,
             since_timestamp,
+            request_id,
         } => {
-            // Note: get_oplog_entries_since only takes (conn, since_timestamp)
-            // It returns all entries after the timestamp, regardless of user_id
-            let entries = operations::get_oplog_entries_since(conn, since_timestamp)
-                .map_err(|e| e.to_string())?;
-            Ok(Some(SyncMessage::SyncData { user_id, entries }))
+            // Answers with every entry after the timestamp, regardless of user_id
+            respond_to_sync_request(
+                conn,
+                user_id,
+                device_id,
+                request_id,
+                since_timestamp,
+                SyncLimits::default(),
+                reply,
+            )?;
+            Ok(())
         }
-        SyncMessage::SyncData {
-            user_id: _,
-            entries,
+        SyncMessage::SyncData { entries, .. } => {
+            crdt::merge(conn, &entries).map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        SyncMessage::Snapshot {
+            horizon, entries, ..
         } => {
             crdt::merge(conn, &entries).map_err(|e| e.to_string())?;
-            Ok(None)
+            // History before the horizon is now only held as snapshots here too
+            compaction::set_horizon(conn, horizon).map_err(|e| e.to_string())?;
+            Ok(())
         }
         SyncMessage::Announce {
             user_id,
             device_id,
             peer_id,
+            addresses,
         } => {
-            update_peer_info(conn, user_id, device_id, peer_id, None)?;
-            Ok(None)
+            let ip_address = addresses
+                .iter()
+                .filter_map(|addr| addr.parse::<Multiaddr>().ok())
+                .find_map(|addr| multiaddr_ip(&addr));
+            update_peer_info(conn, user_id, device_id, peer_id.clone(), ip_address)?;
+            record_peer_addresses(conn, &peer_id, Some(device_id), &addresses)?;
+            Ok(())
+        }
+        SyncMessage::Ping { .. } => Ok(()),
+        SyncMessage::Pong { .. } => Ok(()),
+        SyncMessage::SyncComplete { .. } => Ok(()),
+        SyncMessage::Ack {
+            device_id,
+            origin_device_id,
+            timestamp,
+            ..
+        } => {
+            compaction::record_device_ack(conn, device_id, origin_device_id, timestamp)
+                .map_err(|e| e.to_string())?;
+            Ok(())
         }
-        SyncMessage::Ping { .. } => Ok(None),
-        SyncMessage::Pong { .. } => Ok(None),
     }
 }
 
+/// Maximum number of oplog entries carried by one `SyncData` or `Snapshot` message
+pub const SYNC_BATCH_SIZE: usize = 256;
+
+/// Room left under the message size limit for gossipsub's own framing of a
+/// published message (source, sequence number, topic and signature)
+const GOSSIPSUB_FRAMING: usize = 512;
+
+/// How much a single `SyncData` or `Snapshot` message may carry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncLimits {
+    /// Maximum number of entries per message
+    pub max_entries: usize,
+    /// Maximum encoded size of a message, as configured for gossipsub
+    pub max_message_size: usize,
+}
+
+impl Default for SyncLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: SYNC_BATCH_SIZE,
+            max_message_size: P2PConfig::default().max_message_size,
+        }
+    }
+}
+
+impl P2PConfig {
+    /// The limits sync messages must stay within to be published with this config
+    pub fn sync_limits(&self) -> SyncLimits {
+        SyncLimits {
+            max_entries: SYNC_BATCH_SIZE,
+            max_message_size: self.max_message_size,
+        }
+    }
+}
+
+/// Split `entries` into runs whose `message` encodes to at most
+/// `max_message_size` bytes once gossipsub has framed it. An entry too large
+/// for any message gets a run of its own, which publishing will refuse.
+pub fn split_by_encoded_size<F>(
+    entries: Vec<OplogEntry>,
+    max_message_size: usize,
+    message: F,
+) -> Result<Vec<Vec<OplogEntry>>, String>
+where
+    F: Fn(Vec<OplogEntry>) -> SyncMessage,
+{
+    let budget = max_message_size.saturating_sub(GOSSIPSUB_FRAMING);
+    // The entries are encoded inside the message's array exactly as on their own
+    let envelope = encode_sync_message(&message(Vec::new()))?.len();
+    let mut runs = Vec::new();
+    let mut run = Vec::new();
+    let mut size = envelope;
+    for entry in entries {
+        let entry_size = serde_json::to_vec(&entry)
+            .map_err(|e| format!("Failed to encode entry: {}", e))?
+            .len();
+        if !run.is_empty() && size + 1 + entry_size > budget {
+            runs.push(std::mem::take(&mut run));
+            size = envelope;
+        }
+        // Every entry after the first is preceded by a comma
+        size += usize::from(!run.is_empty()) + entry_size;
+        run.push(entry);
+    }
+    if !run.is_empty() {
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+/// Answer a `RequestSync` in messages within `limits`, paging through the
+/// oplog so a large history is never loaded at once. Each message is passed to
+/// `send`; returns the number of pages sent.
+///
+/// Requests from before the compaction horizon are answered with `Snapshot`
+/// messages (at least one, so the requester adopts the horizon), later ones
+/// with `SyncData` (none if there is nothing new). A tagged request is closed
+/// with a `SyncComplete` naming `device_id` as the responder.
+pub fn respond_to_sync_request<F>(
+    conn: &Connection,
+    user_id: Uuid,
+    device_id: Uuid,
+    request_id: Option<Uuid>,
+    since_timestamp: i64,
+    limits: SyncLimits,
+    mut send: F,
+) -> Result<usize, String>
+where
+    F: FnMut(SyncMessage) -> Result<(), String>,
+{
+    // One read transaction, so `through` is the end of exactly what is sent
+    let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+    let horizon = compaction::compaction_horizon(&tx).map_err(|e| e.to_string())?;
+    let through = operations::latest_oplog_timestamp(&tx).map_err(|e| e.to_string())?;
+    let snapshot = since_timestamp < horizon;
+    let message = |entries| {
+        if snapshot {
+            SyncMessage::Snapshot {
+                user_id,
+                horizon,
+                entries,
+                request_id,
+            }
+        } else {
+            SyncMessage::SyncData {
+                user_id,
+                entries,
+                request_id,
+            }
+        }
+    };
+
+    let mut sent = 0;
+    let pages = operations::oplog_pages(
+        &tx,
+        since_timestamp,
+        OplogFilter::default(),
+        limits.max_entries,
+    );
+    for page in pages {
+        let page = page.map_err(|e| e.to_string())?;
+        for run in split_by_encoded_size(page, limits.max_message_size, message)? {
+            send(message(run))?;
+            sent += 1;
+        }
+    }
+    if sent == 0 && snapshot {
+        send(message(Vec::new()))?;
+        sent += 1;
+    }
+    if let Some(request_id) = request_id {
+        send(SyncMessage::SyncComplete {
+            user_id,
+            request_id,
+            device_id,
+            through,
+            pages: sent,
+        })?;
+    }
+    Ok(sent)
+}
+
 /// Generate a unique device ID and keypair for P2P communication
 pub fn generate_device_id() -> (PeerId, identity::Keypair) {
     let local_key = identity::Keypair::generate_ed25519();
@@ -147,37 +443,109 @@ pub fn create_swarm(
 
     // Create mDNS behaviour for local network discovery (if enabled)
     let mdns = if config.enable_mdns {
-        mdns::tokio::Behaviour::new(
+        Some(mdns::tokio::Behaviour::new(
             mdns::Config {
                 ttl: Duration::from_secs(60 * 6), // 6 minutes
                 query_interval: Duration::from_secs(60),
                 ..Default::default()
             },
             peer_id,
-        )?
+        )?)
     } else {
-        mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?
+        None
     };
 
-    // Create relay client for NAT traversal
-    let (_, relay_client) = relay::client::new(peer_id);
+    // Create relay client for NAT traversal (if enabled). Its transport must be part
+    // of the swarm's transport, or the behaviour cannot reach it.
+    let (relay_transport, relay_client) = if config.enable_relay {
+        let (transport, behaviour) = relay::client::new(peer_id);
+        (OptionalTransport::some(transport), Some(behaviour))
+    } else {
+        (OptionalTransport::none(), None)
+    };
+
+    // Create DCUtR behaviour for hole punching over relayed connections
+    let dcutr = config.enable_relay.then(|| dcutr::Behaviour::new(peer_id));
+
+    // Create Kademlia on Ahenk's own protocol so it forms a private DHT (if enabled)
+    let kademlia = config.enable_kademlia.then(|| {
+        let kad_config = kad::Config::new(KADEMLIA_PROTOCOL);
+        kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), kad_config)
+    });
 
-    // Create DCUtR behaviour for hole punching
-    let dcutr = dcutr::Behaviour::new(peer_id);
+    // Create rendezvous client when rendezvous points are configured
+    let rendezvous = (!config.rendezvous_points.is_empty())
+        .then(|| rendezvous::client::Behaviour::new(keypair.clone()));
+
+    // Create identify so peers learn our listen addresses and how they observe us
+    let identify = identify::Behaviour::new(
+        identify::Config::new(IDENTIFY_PROTOCOL_VERSION.to_string(), keypair.public())
+            .with_agent_version(AGENT_VERSION.to_string()),
+    );
+
+    // Create ping for liveness and round-trip time
+    let ping = ping::Behaviour::new(ping::Config::new().with_interval(config.ping_interval));
+
+    // Create AutoNAT, probing reachability through connected peers
+    let autonat = autonat::Behaviour::new(peer_id, autonat::Config::default());
 
     let behaviour = AhenkBehaviour {
-        mdns,
+        mdns: Toggle::from(mdns),
         gossipsub,
-        relay_client,
-        dcutr,
+        relay_client: Toggle::from(relay_client),
+        dcutr: Toggle::from(dcutr),
+        kademlia: Toggle::from(kademlia),
+        rendezvous: Toggle::from(rendezvous),
+        identify,
+        ping,
+        autonat,
+        blocked: allow_block_list::Behaviour::default(),
     };
 
-    // Build the transport using the tokio API
-    let tcp_transport = tcp::tokio::Transport::default();
-    let transport = tcp_transport
+    // Simulated networks stay in-process and never open sockets
+    if config.memory_transport {
+        let transport = relay_transport
+            .or_transport(MemoryTransport::default())
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(&keypair)?)
+            .multiplex(yamux::Config::default())
+            .boxed();
+        return Ok(Swarm::new(
+            transport,
+            behaviour,
+            peer_id,
+            libp2p::swarm::Config::with_tokio_executor(),
+        ));
+    }
+
+    // Build the stream transports using the tokio API: WebSocket (if enabled) and
+    // plain TCP, both resolving `/dns` addresses, plus relayed circuits. They are
+    // secured with noise and multiplexed with yamux.
+    let websocket_transport = if config.enable_websocket {
+        OptionalTransport::some(websocket::Config::new(tcp::tokio::Transport::default()))
+    } else {
+        OptionalTransport::none()
+    };
+    let stream_transport = dns::tokio::Transport::system(
+        websocket_transport.or_transport(tcp::tokio::Transport::default()),
+    )?;
+    let upgraded_transport = relay_transport
+        .or_transport(stream_transport)
         .upgrade(upgrade::Version::V1)
         .authenticate(noise::Config::new(&keypair)?)
         .multiplex(yamux::Config::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+
+    // QUIC brings its own encryption and multiplexing (if enabled)
+    let quic_transport = if config.enable_quic {
+        OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(&keypair)))
+    } else {
+        OptionalTransport::none()
+    };
+    let transport = quic_transport
+        .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+        .or_transport(upgraded_transport)
+        .map(|output, _| output.into_inner())
         .boxed();
 
     // Use the libp2p 0.56 API with tokio executor
@@ -191,6 +559,46 @@ pub fn create_swarm(
     Ok(swarm)
 }
 
+/// Addresses to listen on for a port: TCP, plus QUIC and WebSocket when enabled,
+/// on `listen_address`, followed by the configured extra `listen_addresses`.
+///
+/// With `memory_transport` this is just `/memory/<port>`.
+pub fn listen_multiaddrs(config: &P2PConfig, port: u16) -> Result<Vec<Multiaddr>, String> {
+    if config.memory_transport {
+        return Ok(vec![Multiaddr::empty().with(Protocol::Memory(port as u64))]);
+    }
+
+    let ip: IpAddr = config
+        .listen_address
+        .parse()
+        .map_err(|_| format!("Invalid listen address: {}", config.listen_address))?;
+
+    let mut addrs = vec![Multiaddr::from(ip).with(Protocol::Tcp(port))];
+    if config.enable_quic {
+        addrs.push(
+            Multiaddr::from(ip)
+                .with(Protocol::Udp(port))
+                .with(Protocol::QuicV1),
+        );
+    }
+    if config.enable_websocket {
+        addrs.push(
+            Multiaddr::from(ip)
+                .with(Protocol::Tcp(config.websocket_port))
+                .with(Protocol::Ws("/".into())),
+        );
+    }
+
+    for raw in &config.listen_addresses {
+        let addr = raw
+            .parse()
+            .map_err(|_| format!("Invalid listen multiaddr: {}", raw))?;
+        addrs.push(addr);
+    }
+
+    Ok(addrs)
+}
+
 /// Create a swarm with default configuration
 pub fn create_swarm_default(
     keypair: identity::Keypair,
@@ -212,6 +620,54 @@ pub fn parse_multiaddr_peer_id(addr: &str) -> Option<PeerId> {
     None
 }
 
+/// Split a multiaddr into its dialable part and the trailing `/p2p/<peer_id>`, if any
+pub fn split_peer_multiaddr(addr: &Multiaddr) -> (Multiaddr, Option<PeerId>) {
+    let mut transport = addr.clone();
+    match transport.pop() {
+        Some(Protocol::P2p(peer_id)) => (transport, Some(peer_id)),
+        _ => (addr.clone(), None),
+    }
+}
+
+/// Extract a routable IP address from a multiaddr, skipping loopback and unspecified ones
+pub fn multiaddr_ip(addr: &Multiaddr) -> Option<String> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip.to_string()),
+        Protocol::Ip6(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip.to_string()),
+        _ => None,
+    })
+}
+
+/// Short label for a NAT status: `unknown`, `private` or `public`
+pub fn nat_status_label(status: &autonat::NatStatus) -> &'static str {
+    match status {
+        autonat::NatStatus::Public(_) => "public",
+        autonat::NatStatus::Private => "private",
+        autonat::NatStatus::Unknown => "unknown",
+    }
+}
+
+/// Record addresses a peer was observed at in the persistent address book
+pub fn record_peer_addresses(
+    conn: &Connection,
+    peer_id: &str,
+    device_id: Option<Uuid>,
+    addresses: &[String],
+) -> Result<(), String> {
+    let now = Utc::now();
+
+    for raw in addresses {
+        let Ok(addr) = raw.parse::<Multiaddr>() else {
+            continue;
+        };
+        let (transport, _) = split_peer_multiaddr(&addr);
+        operations::upsert_peer_address(conn, peer_id, &transport.to_string(), device_id, now)
+            .map_err(|e| format!("Failed to record peer address: {}", e))?;
+    }
+
+    Ok(())
+}
+
 /// Connect to bootstrap nodes
 pub fn connect_to_bootstrap_nodes(
     swarm: &mut Swarm<AhenkBehaviour>,
@@ -227,6 +683,13 @@ pub fn connect_to_bootstrap_nodes(
                 Ok(_) => {
                     connected += 1;
                     println!("Dialing bootstrap node: {}", node_addr);
+
+                    // Bootstrap nodes seed the DHT routing table
+                    if let (transport, Some(peer_id)) = split_peer_multiaddr(&addr) {
+                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                            kademlia.add_address(&peer_id, transport);
+                        }
+                    }
                 }
                 Err(e) => {
                     eprintln!("Failed to dial bootstrap node {}: {:?}", node_addr, e);
@@ -241,6 +704,14 @@ pub fn connect_to_bootstrap_nodes(
     Ok(connected)
 }
 
+/// The `/p2p-circuit` address to listen on for a reservation on a relay server
+pub fn relay_circuit_address(relay_addr: &Multiaddr, relay_peer_id: PeerId) -> Multiaddr {
+    split_peer_multiaddr(relay_addr)
+        .0
+        .with(Protocol::P2p(relay_peer_id))
+        .with(Protocol::P2pCircuit)
+}
+
 /// Connect to relay servers for NAT traversal
 pub fn connect_to_relay_servers(
     swarm: &mut Swarm<AhenkBehaviour>,
@@ -294,6 +765,9 @@ pub fn update_peer_info(
         };
         operations::create_peer(conn, &new_peer)
             .map_err(|e| format!("Failed to create peer: {}", e))?;
+    } else if let Some(ip) = ip_address {
+        operations::update_peer_last_known_ip(conn, device_id, &ip)
+            .map_err(|e| format!("Failed to update peer: {}", e))?;
     }
 
     Ok(())
@@ -325,9 +799,218 @@ mod tests {
         let config = P2PConfig::default();
         assert!(config.enable_mdns);
         assert!(config.enable_relay);
+        assert!(config.enable_kademlia);
+        assert!(config.rendezvous_points.is_empty());
         assert_eq!(config.heartbeat_interval, Duration::from_secs(10));
     }
 
+    #[test]
+    fn test_request_before_compaction_horizon_gets_snapshot() {
+        use crate::db::{documents, operations::initialize_database};
+        use serde_json::json;
+
+        let mut conn = initialize_database(":memory:").unwrap();
+        documents::register_collection(&conn, "todos").unwrap();
+        let op = |timestamp: i64, op_type: &str, data: serde_json::Value| {
+            crate::testing::oplog_entry(timestamp, "todos", op_type, data)
+        };
+        let ops = [
+            op(10, crdt::OP_INSERT, json!({"id": "a", "value": {"n": 1}})),
+            op(
+                20,
+                crdt::OP_MERGE_PATCH,
+                json!({"id": "a", "patch": {"n": 2}}),
+            ),
+            op(
+                30,
+                crdt::OP_MERGE_PATCH,
+                json!({"id": "a", "patch": {"n": 3}}),
+            ),
+        ];
+        crdt::merge(&mut conn, &ops).unwrap();
+        compaction::compact_oplog(&mut conn, 25).unwrap();
+
+        let user_id = Uuid::new_v4();
+        let request = |since_timestamp| SyncMessage::RequestSync {
+            user_id,
+            since_timestamp,
+            request_id: None,
+        };
+        let respond = |conn: &mut Connection, since_timestamp| {
+            let mut replies = Vec::new();
+            handle_sync_message(conn, Uuid::new_v4(), request(since_timestamp), |reply| {
+                replies.push(reply);
+                Ok(())
+            })
+            .unwrap();
+            replies
+        };
+        let response = respond(&mut conn, 0);
+        let [snapshot @ SyncMessage::Snapshot { horizon: 25, .. }] = &response[..] else {
+            panic!("expected a snapshot, got {:?}", response);
+        };
+        assert!(matches!(
+            &respond(&mut conn, 25)[..],
+            [SyncMessage::SyncData { .. }]
+        ));
+
+        // A fresh device bootstraps from the snapshot and adopts its horizon
+        let mut fresh = initialize_database(":memory:").unwrap();
+        documents::register_collection(&fresh, "todos").unwrap();
+        handle_sync_message(&mut fresh, Uuid::new_v4(), snapshot.clone(), |_| Ok(())).unwrap();
+        assert_eq!(compaction::compaction_horizon(&fresh).unwrap(), 25);
+        assert_eq!(
+            documents::get_document(&fresh, "todos", "a")
+                .unwrap()
+                .unwrap()
+                .data,
+            Some(json!({"n": 3}))
+        );
+    }
+
+    #[test]
+    fn test_sync_request_answered_in_batches() {
+        use crate::db::operations::initialize_database;
+
+        let conn = initialize_database(":memory:").unwrap();
+        let device_id = Uuid::new_v4();
+        for timestamp in 1..=5 {
+            let entry = OplogEntry {
+                id: Uuid::new_v4(),
+                device_id,
+                timestamp,
+                table: "todos".to_string(),
+                op_type: crdt::OP_INSERT.to_string(),
+                data: serde_json::json!({"id": timestamp.to_string(), "value": {}}),
+                schema_version: 0,
+            };
+            operations::create_oplog_entry(&conn, &entry).unwrap();
+        }
+
+        let user_id = Uuid::new_v4();
+        let limits = SyncLimits {
+            max_entries: 2,
+            ..SyncLimits::default()
+        };
+        let mut batches = Vec::new();
+        let sent = respond_to_sync_request(&conn, user_id, device_id, None, 0, limits, |message| {
+            let SyncMessage::SyncData { entries, .. } = message else {
+                panic!("expected sync data, got {:?}", message);
+            };
+            batches.push(entries.iter().map(|e| e.timestamp).collect::<Vec<_>>());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent, 3);
+        assert_eq!(batches, vec![vec![1, 2], vec![3, 4], vec![5]]);
+
+        // Nothing new since the last entry
+        let sent = respond_to_sync_request(&conn, user_id, device_id, None, 5, limits, |_| Ok(()))
+            .unwrap();
+        assert_eq!(sent, 0);
+
+        // A tagged request is closed with how far the response reaches
+        let request_id = Uuid::new_v4();
+        let mut messages = Vec::new();
+        respond_to_sync_request(
+            &conn,
+            user_id,
+            device_id,
+            Some(request_id),
+            0,
+            limits,
+            |message| {
+                messages.push(message);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[..3].iter().all(|message| matches!(
+            message,
+            SyncMessage::SyncData { request_id: Some(id), .. } if *id == request_id
+        )));
+        assert!(matches!(
+            messages[3],
+            SyncMessage::SyncComplete { request_id: id, device_id: responder, through: 5, pages: 3, .. }
+                if id == request_id && responder == device_id
+        ));
+    }
+
+    #[test]
+    fn test_large_entries_split_to_fit_the_message_size() {
+        use crate::db::operations::initialize_database;
+
+        let conn = initialize_database(":memory:").unwrap();
+        let device_id = Uuid::new_v4();
+        // Ten 20KB payloads: well under the entry cap, far over one 64KB message
+        for timestamp in 1..=10 {
+            let entry = OplogEntry {
+                id: Uuid::new_v4(),
+                device_id,
+                timestamp,
+                table: "notes".to_string(),
+                op_type: crdt::OP_INSERT.to_string(),
+                data: serde_json::json!({
+                    "id": timestamp.to_string(),
+                    "value": {"body": "x".repeat(20_000)},
+                }),
+                schema_version: 0,
+            };
+            operations::create_oplog_entry(&conn, &entry).unwrap();
+        }
+
+        let limits = P2PConfig::default().sync_limits();
+        let mut messages = Vec::new();
+        let sent = respond_to_sync_request(
+            &conn,
+            Uuid::new_v4(),
+            device_id,
+            Some(Uuid::new_v4()),
+            0,
+            limits,
+            |message| {
+                messages.push(message);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert!(sent > 1);
+        assert_eq!(messages.len(), sent + 1);
+        let mut timestamps = Vec::new();
+        for message in &messages[..sent] {
+            let encoded = encode_sync_message(message).unwrap();
+            assert!(encoded.len() + GOSSIPSUB_FRAMING <= limits.max_message_size);
+            let SyncMessage::SyncData { entries, .. } = message else {
+                panic!("expected sync data, got {:?}", message);
+            };
+            timestamps.extend(entries.iter().map(|e| e.timestamp));
+        }
+        assert_eq!(timestamps, (1..=10).collect::<Vec<_>>());
+        assert!(matches!(
+            messages[sent],
+            SyncMessage::SyncComplete { pages, through: 10, .. } if pages == sent
+        ));
+
+        // An entry that fits no message is still sent, alone, for publishing to refuse
+        let oversized = messages[..sent]
+            .iter()
+            .flat_map(|message| match message {
+                SyncMessage::SyncData { entries, .. } => entries.clone(),
+                _ => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let runs = split_by_encoded_size(oversized, 10_000, |entries| SyncMessage::SyncData {
+            user_id: Uuid::nil(),
+            entries,
+            request_id: None,
+        })
+        .unwrap();
+        assert_eq!(runs.len(), 10);
+        assert!(runs.iter().all(|run| run.len() == 1));
+    }
+
     #[test]
     fn test_encode_decode_sync_message() {
         let msg = SyncMessage::Ping {
@@ -342,6 +1025,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_split_peer_multiaddr_and_ip() {
+        let addr: Multiaddr =
+            "/ip4/192.168.1.20/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"
+                .parse()
+                .unwrap();
+        let (transport, peer_id) = split_peer_multiaddr(&addr);
+        assert_eq!(transport.to_string(), "/ip4/192.168.1.20/tcp/4001");
+        assert!(peer_id.is_some());
+        assert_eq!(multiaddr_ip(&transport), Some("192.168.1.20".to_string()));
+
+        let loopback: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert_eq!(multiaddr_ip(&loopback), None);
+    }
+
+    #[test]
+    fn test_listen_multiaddrs() {
+        let config = P2PConfig {
+            listen_address: "127.0.0.1".to_string(),
+            enable_websocket: true,
+            websocket_port: 4002,
+            listen_addresses: vec!["/ip6/::1/tcp/4001".to_string()],
+            ..P2PConfig::default()
+        };
+        let addrs: Vec<String> = listen_multiaddrs(&config, 4001)
+            .unwrap()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "/ip4/127.0.0.1/tcp/4001",
+                "/ip4/127.0.0.1/udp/4001/quic-v1",
+                "/ip4/127.0.0.1/tcp/4002/ws",
+                "/ip6/::1/tcp/4001",
+            ]
+        );
+
+        let tcp_only = P2PConfig {
+            enable_quic: false,
+            ..P2PConfig::default()
+        };
+        let addrs = listen_multiaddrs(&tcp_only, 0).unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].to_string(), "/ip4/0.0.0.0/tcp/0");
+
+        let invalid = P2PConfig {
+            listen_address: "localhost".to_string(),
+            ..P2PConfig::default()
+        };
+        assert!(listen_multiaddrs(&invalid, 0).is_err());
+    }
+
     #[test]
     fn test_parse_multiaddr_peer_id() {
         let addr =
@@ -1,19 +1,93 @@
+use crate::crdt::{self, Clock, DriftBound, HybridLogicalClock, SystemClock};
+use crate::db::connection::Database;
+use crate::db::{capture, compaction, operations};
+use crate::logic::discovery::{
+    discovery_key, discovery_namespace, rendezvous_namespace, KADEMLIA_PROTOCOL, RENDEZVOUS_TTL,
+};
+use crate::logic::live_query::LiveQueries;
+use crate::logic::reconnect::ReconnectScheduler;
 use crate::logic::sync::{
     connect_to_bootstrap_nodes, connect_to_relay_servers, create_swarm, encode_sync_message,
-    AhenkBehaviour, AhenkBehaviourEvent, P2PConfig, SyncMessage,
+    handle_sync_message, listen_multiaddrs, nat_status_label, relay_circuit_address,
+    respond_to_sync_request, split_by_encoded_size, split_peer_multiaddr, AhenkBehaviour,
+    AhenkBehaviourEvent, P2PConfig, SyncMessage,
 };
-use crate::models::OplogEntry;
+use crate::models::{NetworkStatus, OplogEntry};
 use chrono::{DateTime, Utc};
-use libp2p::swarm::SwarmEvent;
-use libp2p::PeerId;
-use libp2p::{gossipsub, identity, mdns, Swarm};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use libp2p::core::transport::ListenerId;
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{DialError, SwarmEvent};
+use libp2p::{
+    autonat, dcutr, gossipsub, identify, identity, kad, mdns, ping, relay, rendezvous, Multiaddr,
+    PeerId, Swarm,
+};
 use rusqlite::Connection;
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 #[cfg(feature = "tauri-api")]
 use tauri::AppHandle;
 use uuid::Uuid;
 
+/// How many addresses other peers observed us at are remembered
+const MAX_OBSERVED_ADDRESSES: usize = 8;
+
+/// Sync responses and merge results queued between the database workers and the
+/// event loop; a full queue holds the workers back
+const DB_EVENT_QUEUE: usize = 4;
+
+/// Results of work handed to the database workers
+enum DbEvent {
+    /// A batch of a sync response, ready to publish
+    Publish(SyncMessage),
+    /// Local changes taken from the outbox and change capture, ready to send
+    Captured(Vec<OplogEntry>),
+    /// Address book entries of persistent peers whose redial is due
+    Redial(Vec<(PeerId, Vec<Multiaddr>)>),
+    /// Received entries, a page of the response to `request_id` if tagged, were
+    /// merged (or failed to)
+    Merged {
+        source: Option<PeerId>,
+        request_id: Option<Uuid>,
+        result: Result<(), String>,
+    },
+}
+
+/// Catch-up requests whose responses are tracked for acknowledgement
+const MAX_CATCH_UP_REQUESTS: usize = 8;
+
+/// How far a peer's response to a catch-up request has been merged
+#[derive(Debug, Default)]
+struct CatchUpResponse {
+    /// Pages merged so far
+    merged: usize,
+    /// Whether merging a page failed, so the response can't be acknowledged
+    failed: bool,
+    /// From the closing `SyncComplete`: the responding device, the timestamp
+    /// the response reaches and its number of pages
+    complete: Option<(Uuid, i64, usize)>,
+}
+
+/// Cloneable handle for requesting a graceful shutdown of a [`SyncManager`] or a
+/// [`RelayServer`](crate::logic::relay_server::RelayServer)
+/// from another task or a signal handler.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    pub(crate) sender: mpsc::UnboundedSender<()>,
+}
+
+impl ShutdownHandle {
+    /// Ask the manager to stop once the event it is currently waiting on resolves
+    pub fn shutdown(&self) {
+        // The receiver only goes away together with the manager, in which case
+        // there is nothing left to shut down.
+        let _ = self.sender.unbounded_send(());
+    }
+}
+
 /// Sync manager for handling P2P network events and synchronization
 pub struct SyncManager {
     /// The libp2p swarm
@@ -22,8 +96,6 @@ pub struct SyncManager {
     user_id: Uuid,
     /// Device ID for this device
     device_id: Uuid,
-    /// Database connection (thread-safe)
-    _conn: Arc<Mutex<Connection>>,
     /// Gossipsub topic for sync messages
     topic: gossipsub::IdentTopic,
     /// Is the manager currently actively syncing/connected to peers
@@ -39,27 +111,101 @@ pub struct SyncManager {
     pending_changes: VecDeque<OplogEntry>,
     /// Is the device currently online
     is_online: bool,
+    /// Device IDs of peers that announced themselves, keyed by libp2p peer ID
+    peer_devices: HashMap<PeerId, Uuid>,
+    /// Last time sync data was received from each known device
+    peer_sync_times: HashMap<Uuid, DateTime<Utc>>,
+    /// Listeners opened through [`SyncManager::listen`] and [`SyncManager::listen_on`]
+    listeners: Vec<ListenerId>,
+    /// Network configuration, kept to derive listen addresses from
+    config: P2PConfig,
+    /// Sender side of the shutdown channel, cloned into [`ShutdownHandle`]s
+    shutdown_tx: mpsc::UnboundedSender<()>,
+    /// Receiver side of the shutdown channel, polled alongside swarm events
+    shutdown_rx: mpsc::UnboundedReceiver<()>,
+    /// Whether a shutdown has been requested
+    shutdown_requested: bool,
+    /// How long to keep polling the swarm during shutdown
+    shutdown_grace_period: Duration,
+    /// Peers that are redialed when their connection drops
+    persistent_peers: HashSet<PeerId>,
+    /// Backoff schedule for redialing persistent peers
+    reconnect: ReconnectScheduler,
+    /// Configured relay servers that reservations are made on
+    relay_servers: HashMap<PeerId, Multiaddr>,
+    /// `/p2p-circuit` listeners with an active or pending reservation, per relay server
+    relay_listeners: HashMap<PeerId, ListenerId>,
+    /// Configured rendezvous points and their addresses
+    rendezvous_points: HashMap<PeerId, Multiaddr>,
+    /// Discovery cookies per rendezvous point, so repeated discovers only return new registrations
+    rendezvous_cookies: HashMap<PeerId, rendezvous::Cookie>,
+    /// When DHT provider records and rendezvous registrations are next refreshed
+    next_discovery: Option<Instant>,
+    /// Interval between discovery refreshes
+    discovery_interval: Duration,
+    /// When captured app table changes are next flushed to the oplog
+    next_capture: Instant,
+    /// Interval between capture flushes
+    capture_interval: Duration,
+    /// Reachability of this node as determined by AutoNAT
+    nat_status: autonat::NatStatus,
+    /// Addresses connected peers reported observing this node at, most recent last
+    observed_addresses: Vec<Multiaddr>,
+    /// Latest ping round-trip time per connected peer
+    peer_rtts: HashMap<PeerId, Duration>,
+    /// Physical time source for the database's HLC when merging remote entries
+    clock: Arc<dyn Clock>,
+    /// How far ahead of `clock` merged entries may be stamped
+    drift_bound: DriftBound,
+    /// Live query subscriptions notified after merges and capture ticks
+    live_queries: LiveQueries,
+    /// Recent requests for the whole oplog, oldest first
+    catch_up_requests: VecDeque<Uuid>,
+    /// Progress of each peer's response to a catch-up request
+    catch_up_responses: HashMap<(PeerId, Uuid), CatchUpResponse>,
+    /// Sender cloned into database worker jobs
+    db_events_tx: mpsc::Sender<DbEvent>,
+    /// Results of database worker jobs, polled alongside swarm events. Declared
+    /// before `database` so it is dropped first and workers blocked on a full
+    /// queue fail their send instead of keeping the database from shutting down.
+    db_events_rx: mpsc::Receiver<DbEvent>,
+    /// Worker threads all SQLite work runs on, so the event loop never waits on it
+    database: Database,
 }
 
 impl SyncManager {
-    /// Create a new sync manager
+    /// Create a new sync manager working on `database`
     #[cfg(feature = "tauri-api")]
     pub fn new(
         keypair: identity::Keypair,
         user_id: Uuid,
         device_id: Uuid,
-        conn: Arc<Mutex<Connection>>,
+        database: Database,
         config: P2PConfig,
         app_handle: AppHandle,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let swarm = create_swarm(keypair, config)?;
+        let shutdown_grace_period = config.shutdown_grace_period;
+        let reconnect = ReconnectScheduler::new(
+            config.reconnect_initial_backoff,
+            config.reconnect_max_backoff,
+        );
+        let relay_servers = parse_peer_addresses(&config.relay_servers);
+        let rendezvous_points = parse_peer_addresses(&config.rendezvous_points);
+        let discovery_interval = config.discovery_interval;
+        let capture_interval = config.capture_interval;
+        let drift_bound = config.drift_bound;
+        let swarm = create_swarm(keypair, config.clone())?;
+        let next_discovery = (swarm.behaviour().kademlia.is_enabled()
+            || swarm.behaviour().rendezvous.is_enabled())
+        .then(Instant::now);
         let topic = gossipsub::IdentTopic::new("nexus-sync");
+        let (shutdown_tx, shutdown_rx) = mpsc::unbounded();
+        let (db_events_tx, db_events_rx) = mpsc::channel(DB_EVENT_QUEUE);
 
         Ok(Self {
             swarm,
             user_id,
             device_id,
-            _conn: conn,
             topic,
             is_syncing: false,
             last_sync_time: None,
@@ -67,58 +213,527 @@ impl SyncManager {
             is_online: true,
             connected_peers: Vec::new(),
             app_handle,
+            peer_devices: HashMap::new(),
+            peer_sync_times: HashMap::new(),
+            listeners: Vec::new(),
+            config,
+            shutdown_tx,
+            shutdown_rx,
+            shutdown_requested: false,
+            shutdown_grace_period,
+            persistent_peers: HashSet::new(),
+            reconnect,
+            relay_servers,
+            relay_listeners: HashMap::new(),
+            rendezvous_points,
+            rendezvous_cookies: HashMap::new(),
+            next_discovery,
+            discovery_interval,
+            next_capture: Instant::now(),
+            capture_interval,
+            nat_status: autonat::NatStatus::Unknown,
+            observed_addresses: Vec::new(),
+            peer_rtts: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            drift_bound,
+            live_queries: LiveQueries::new(),
+            catch_up_requests: VecDeque::new(),
+            catch_up_responses: HashMap::new(),
+            db_events_tx,
+            db_events_rx,
+            database,
         })
     }
 
+    /// Create a new sync manager working on `database`
     #[cfg(not(feature = "tauri-api"))]
     pub fn new(
         keypair: identity::Keypair,
         user_id: Uuid,
         device_id: Uuid,
-        conn: Arc<Mutex<Connection>>,
+        database: Database,
         config: P2PConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let swarm = create_swarm(keypair, config)?;
+        let shutdown_grace_period = config.shutdown_grace_period;
+        let reconnect = ReconnectScheduler::new(
+            config.reconnect_initial_backoff,
+            config.reconnect_max_backoff,
+        );
+        let relay_servers = parse_peer_addresses(&config.relay_servers);
+        let rendezvous_points = parse_peer_addresses(&config.rendezvous_points);
+        let discovery_interval = config.discovery_interval;
+        let capture_interval = config.capture_interval;
+        let drift_bound = config.drift_bound;
+        let swarm = create_swarm(keypair, config.clone())?;
+        let next_discovery = (swarm.behaviour().kademlia.is_enabled()
+            || swarm.behaviour().rendezvous.is_enabled())
+        .then(Instant::now);
         let topic = gossipsub::IdentTopic::new("nexus-sync");
+        let (shutdown_tx, shutdown_rx) = mpsc::unbounded();
+        let (db_events_tx, db_events_rx) = mpsc::channel(DB_EVENT_QUEUE);
 
         Ok(Self {
             swarm,
             user_id,
             device_id,
-            _conn: conn,
             topic,
             is_syncing: false,
             last_sync_time: None,
             pending_changes: VecDeque::new(),
             is_online: true,
             connected_peers: Vec::new(),
+            peer_devices: HashMap::new(),
+            peer_sync_times: HashMap::new(),
+            listeners: Vec::new(),
+            config,
+            shutdown_tx,
+            shutdown_rx,
+            shutdown_requested: false,
+            shutdown_grace_period,
+            persistent_peers: HashSet::new(),
+            reconnect,
+            relay_servers,
+            relay_listeners: HashMap::new(),
+            rendezvous_points,
+            rendezvous_cookies: HashMap::new(),
+            next_discovery,
+            discovery_interval,
+            next_capture: Instant::now(),
+            capture_interval,
+            nat_status: autonat::NatStatus::Unknown,
+            observed_addresses: Vec::new(),
+            peer_rtts: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            drift_bound,
+            live_queries: LiveQueries::new(),
+            catch_up_requests: VecDeque::new(),
+            catch_up_responses: HashMap::new(),
+            db_events_tx,
+            db_events_rx,
+            database,
         })
     }
 
-    /// Start listening on all network interfaces
+    /// Start listening on the configured `listen_address`
+    ///
+    /// Listens on TCP, plus QUIC (on the same UDP port) and WebSocket when they are
+    /// enabled, and on every extra address in `listen_addresses`.
     pub fn listen(&mut self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        let listen_addr = format!("/ip4/0.0.0.0/tcp/{}", port);
-        self.swarm.listen_on(listen_addr.parse()?)?;
+        for addr in listen_multiaddrs(&self.config, port)? {
+            self.listen_on(addr)?;
+        }
         Ok(())
     }
 
-    /// Connect to bootstrap and relay nodes
+    /// Start listening on a single multiaddr
+    pub fn listen_on(&mut self, addr: Multiaddr) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = self.swarm.listen_on(addr)?;
+        self.listeners.push(listener);
+        Ok(())
+    }
+
+    /// Connect to bootstrap and relay nodes, and to the configured rendezvous points
+    ///
+    /// Nodes whose multiaddr ends in `/p2p/<peer_id>` are added to the address book
+    /// and redialed with backoff whenever their connection drops.
     pub fn connect_to_network(
         &mut self,
         bootstrap_nodes: &[String],
         relay_servers: &[String],
     ) -> Result<(), String> {
+        for raw in bootstrap_nodes.iter().chain(relay_servers) {
+            let Ok(addr) = raw.parse::<Multiaddr>() else {
+                continue;
+            };
+            if let (transport, Some(peer_id)) = split_peer_multiaddr(&addr) {
+                self.persistent_peers.insert(peer_id);
+                self.remember_addresses(peer_id, [&transport], None);
+            }
+        }
+
         if !bootstrap_nodes.is_empty() {
             connect_to_bootstrap_nodes(&mut self.swarm, bootstrap_nodes)?;
         }
 
-        if !relay_servers.is_empty() {
+        // Reservations are requested once the connection to a relay is established
+        if !relay_servers.is_empty() && self.swarm.behaviour().relay_client.is_enabled() {
             connect_to_relay_servers(&mut self.swarm, relay_servers)?;
         }
 
+        for (peer_id, addr) in self.rendezvous_points.clone() {
+            self.persistent_peers.insert(peer_id);
+            let opts = DialOpts::peer_id(peer_id).addresses(vec![addr]).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                eprintln!("Failed to dial rendezvous point {}: {}", peer_id, e);
+                self.reconnect.schedule(peer_id, Instant::now());
+            }
+        }
+
+        if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() {
+            // Fails only when no bootstrap node with a peer ID was given
+            let _ = kademlia.bootstrap();
+        }
+
         Ok(())
     }
 
+    /// Listen on a `/p2p-circuit` address through a relay server, which makes the
+    /// relay transport request a reservation. Does nothing when the relay client is
+    /// disabled or a reservation is already active or pending.
+    fn reserve_relay(&mut self, relay_peer_id: PeerId) {
+        if !self.swarm.behaviour().relay_client.is_enabled()
+            || self.relay_listeners.contains_key(&relay_peer_id)
+        {
+            return;
+        }
+        let Some(relay_addr) = self.relay_servers.get(&relay_peer_id) else {
+            return;
+        };
+
+        let circuit_addr = relay_circuit_address(relay_addr, relay_peer_id);
+        match self.swarm.listen_on(circuit_addr.clone()) {
+            Ok(listener) => {
+                self.relay_listeners.insert(relay_peer_id, listener);
+            }
+            Err(e) => eprintln!("Failed to listen on {}: {}", circuit_addr, e),
+        }
+    }
+
+    /// The opaque key this user's devices register under for wide-area discovery
+    pub fn discovery_namespace(&self) -> String {
+        discovery_namespace(self.user_id)
+    }
+
+    /// Refresh DHT provider records and rendezvous registrations, and look up
+    /// the current addresses of this user's other devices.
+    pub fn run_discovery(&mut self) {
+        let key = discovery_key(self.user_id);
+        if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() {
+            if let Err(e) = kademlia.start_providing(key.clone()) {
+                eprintln!("Failed to publish DHT provider record: {}", e);
+            }
+            kademlia.get_providers(key);
+        }
+
+        let connected_points: Vec<PeerId> = self
+            .rendezvous_points
+            .keys()
+            .filter(|peer_id| self.swarm.is_connected(peer_id))
+            .copied()
+            .collect();
+        for rendezvous_node in connected_points {
+            self.register_and_discover(rendezvous_node);
+        }
+
+        self.next_discovery = Some(Instant::now() + self.discovery_interval);
+    }
+
+    /// Register with a rendezvous point and ask it for this user's other devices
+    fn register_and_discover(&mut self, rendezvous_node: PeerId) {
+        let namespace = rendezvous_namespace(self.user_id);
+        let cookie = self.rendezvous_cookies.get(&rendezvous_node).cloned();
+        let Some(client) = self.swarm.behaviour_mut().rendezvous.as_mut() else {
+            return;
+        };
+
+        if let Err(e) = client.register(
+            namespace.clone(),
+            rendezvous_node,
+            Some(RENDEZVOUS_TTL.as_secs()),
+        ) {
+            // Typically no confirmed external address yet; retried on the next refresh
+            eprintln!(
+                "Cannot register with rendezvous point {}: {}",
+                rendezvous_node, e
+            );
+        }
+        client.discover(Some(namespace), cookie, None, rendezvous_node);
+    }
+
+    /// Dial a device found through discovery, remembering its addresses
+    fn dial_discovered_peer(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        if peer_id == *self.swarm.local_peer_id() {
+            return;
+        }
+
+        self.remember_addresses(peer_id, &addresses, None);
+
+        self.persistent_peers.insert(peer_id);
+        if self.swarm.is_connected(&peer_id) {
+            return;
+        }
+
+        let opts = DialOpts::peer_id(peer_id)
+            .addresses(addresses)
+            .extend_addresses_through_behaviour()
+            .build();
+        match self.swarm.dial(opts) {
+            Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+            Err(e) => {
+                eprintln!("Failed to dial discovered peer {}: {}", peer_id, e);
+                self.reconnect.schedule(peer_id, Instant::now());
+            }
+        }
+    }
+
+    /// Run time-based work that is due: redials, discovery refreshes and capture flushes
+    fn on_timer(&mut self) {
+        self.redial_due_peers();
+        if self.next_discovery.is_some_and(|at| at <= Instant::now()) {
+            self.run_discovery();
+        }
+        if self.next_capture <= Instant::now() {
+            self.capture_local_changes();
+            self.dispatch_live_queries();
+            self.next_capture = Instant::now() + self.capture_interval;
+        }
+    }
+
+    /// Time until the next redial, discovery refresh or capture flush
+    fn next_wakeup_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let discovery_in = self
+            .next_discovery
+            .map(|at| at.saturating_duration_since(now));
+        let capture_in = self.next_capture.saturating_duration_since(now);
+        [
+            self.reconnect.next_due_in(now),
+            discovery_in,
+            Some(capture_in),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Move changes captured from registered app tables into the oplog and send them,
+    /// together with local writes queued in the outbox (e.g. by collections).
+    ///
+    /// Runs every `capture_interval`; call it after a write to sync it right away.
+    /// The changes are taken on the database writer and sent once the event loop
+    /// picks them up.
+    pub fn capture_local_changes(&mut self) {
+        let clock = Arc::clone(&self.clock);
+        let device_id = self.device_id;
+        let mut events = self.db_events_tx.clone();
+        drop(self.database.write(move |conn| {
+            let entries = take_local_changes(conn, clock.as_ref(), device_id);
+            if !entries.is_empty() {
+                // Fails only once the manager is gone
+                let _ = futures::executor::block_on(events.send(DbEvent::Captured(entries)));
+            }
+            Ok(())
+        }));
+    }
+
+    /// Queue and send local changes taken by [`SyncManager::capture_local_changes`]
+    fn changes_captured(&mut self, entries: Vec<OplogEntry>) {
+        for entry in entries {
+            if !self.pending_changes.iter().any(|p| p.id == entry.id) {
+                self.add_pending_change(entry);
+            }
+        }
+        if let Err(e) = self.sync_pending_changes() {
+            eprintln!("Failed to send captured changes: {}", e);
+        }
+    }
+
+    /// Live query subscriptions on this manager's database.
+    ///
+    /// Change sets are delivered after every merge and every `capture_interval`.
+    pub fn live_queries(&self) -> LiveQueries {
+        self.live_queries.clone()
+    }
+
+    /// Deliver changes from merges and local writes to live query subscriptions
+    fn dispatch_live_queries(&self) {
+        if self.live_queries.is_empty() {
+            return;
+        }
+        // Queued behind the merges and captures whose changes it delivers
+        let live_queries = self.live_queries.clone();
+        drop(self.database.write(move |conn| {
+            if let Err(e) = live_queries.dispatch(conn) {
+                eprintln!("Failed to dispatch live query changes: {}", e);
+            }
+            Ok(())
+        }));
+    }
+
+    /// Dial every device of this user found in the address book.
+    ///
+    /// Returns the number of peers dialed. Dialed peers are kept connected with
+    /// backoff redials from then on.
+    pub async fn connect_to_known_peers(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let (user_id, device_id) = (self.user_id, self.device_id);
+        let known = self
+            .database
+            .read(move |conn| {
+                let mut known: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                for peer in operations::get_peers_by_user_id(conn, user_id)? {
+                    if peer.device_id == device_id {
+                        continue;
+                    }
+                    for entry in operations::get_peer_addresses_by_device(conn, peer.device_id)? {
+                        if let (Ok(peer_id), Ok(addr)) =
+                            (entry.peer_id.parse::<PeerId>(), entry.multiaddr.parse())
+                        {
+                            known.entry(peer_id).or_default().push(addr);
+                        }
+                    }
+                }
+                Ok(known)
+            })
+            .await?;
+
+        let mut dialed = 0;
+        for (peer_id, addresses) in known {
+            if peer_id == *self.swarm.local_peer_id() {
+                continue;
+            }
+            self.persistent_peers.insert(peer_id);
+            let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+            match self.swarm.dial(opts) {
+                Ok(()) => dialed += 1,
+                Err(e) => {
+                    eprintln!("Failed to dial known peer {}: {}", peer_id, e);
+                    self.reconnect.schedule(peer_id, Instant::now());
+                }
+            }
+        }
+
+        Ok(dialed)
+    }
+
+    /// Addresses other peers can reach this node at
+    fn local_addresses(&self) -> Vec<String> {
+        self.swarm
+            .listeners()
+            .chain(self.swarm.external_addresses())
+            .filter(|addr| {
+                !addr.iter().any(|p| match p {
+                    libp2p::multiaddr::Protocol::Ip4(ip) => ip.is_unspecified(),
+                    libp2p::multiaddr::Protocol::Ip6(ip) => ip.is_unspecified(),
+                    _ => false,
+                })
+            })
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    /// Look up the addresses of persistent peers whose backoff has elapsed; they
+    /// are redialed once the reader delivers them
+    fn redial_due_peers(&mut self) {
+        let mut due = Vec::new();
+        for peer_id in self.reconnect.take_due(Instant::now()) {
+            if self.swarm.is_connected(&peer_id) {
+                self.reconnect.reset(&peer_id);
+            } else {
+                due.push(peer_id);
+            }
+        }
+        if due.is_empty() {
+            return;
+        }
+
+        let mut events = self.db_events_tx.clone();
+        drop(self.database.read(move |conn| {
+            let redials = due
+                .into_iter()
+                .map(|peer_id| {
+                    let addresses = known_addresses(conn, &peer_id);
+                    (peer_id, addresses)
+                })
+                .collect();
+            // Fails only once the manager is gone
+            let _ = futures::executor::block_on(events.send(DbEvent::Redial(redials)));
+            Ok(())
+        }));
+    }
+
+    /// Redial persistent peers at the addresses from the address book
+    fn redial(&mut self, redials: Vec<(PeerId, Vec<Multiaddr>)>) {
+        let now = Instant::now();
+
+        for (peer_id, addresses) in redials {
+            if self.swarm.is_connected(&peer_id) {
+                self.reconnect.reset(&peer_id);
+                continue;
+            }
+
+            println!(
+                "Redialing {} (attempt {}, {} known addresses)",
+                peer_id,
+                self.reconnect.attempts(&peer_id) + 1,
+                addresses.len()
+            );
+            let opts = DialOpts::peer_id(peer_id)
+                .addresses(addresses)
+                .extend_addresses_through_behaviour()
+                .build();
+            match self.swarm.dial(opts) {
+                Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                Err(e) => {
+                    eprintln!("Failed to redial {}: {}", peer_id, e);
+                    self.reconnect.schedule(peer_id, now);
+                }
+            }
+        }
+    }
+
+    /// Record a dial outcome for an address in the address book
+    fn record_dial_result(&self, peer_id: &PeerId, address: &Multiaddr, success: bool) {
+        let peer = peer_id.to_string();
+        let transport = split_peer_multiaddr(address).0.to_string();
+        let now = Utc::now();
+        self.write_in_background(
+            format!("update address book for {}", peer_id),
+            move |conn| {
+                if success {
+                    operations::record_peer_address_success(conn, &peer, &transport, now)
+                } else {
+                    operations::record_peer_address_failure(conn, &peer, &transport, now)
+                }
+            },
+        );
+    }
+
+    /// Add addresses a peer can be reached at to the address book
+    fn remember_addresses<'a>(
+        &self,
+        peer_id: PeerId,
+        addresses: impl IntoIterator<Item = &'a Multiaddr>,
+        device_id: Option<Uuid>,
+    ) {
+        let peer = peer_id.to_string();
+        let transports: Vec<String> = addresses
+            .into_iter()
+            .map(|addr| split_peer_multiaddr(addr).0.to_string())
+            .collect();
+        let now = Utc::now();
+        self.write_in_background(format!("record addresses of {}", peer_id), move |conn| {
+            for transport in &transports {
+                operations::upsert_peer_address(conn, &peer, transport, device_id, now)?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        });
+    }
+
+    /// Run a bookkeeping write on the database writer, so the event loop never
+    /// waits on SQLite. Failures are logged as failing to do `what`.
+    fn write_in_background<F, E>(&self, what: String, write: F)
+    where
+        F: FnOnce(&mut Connection) -> Result<(), E> + Send + 'static,
+        E: std::fmt::Display,
+    {
+        drop(self.database.write(move |conn| {
+            if let Err(e) = write(conn) {
+                log::warn!("Failed to {}: {}", what, e);
+            }
+            Ok(())
+        }));
+    }
+
     /// Broadcast an announce message to the network
     pub fn announce_presence(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let peer_id = *self.swarm.local_peer_id();
@@ -126,6 +741,7 @@ impl SyncManager {
             user_id: self.user_id,
             device_id: self.device_id,
             peer_id: peer_id.to_string(),
+            addresses: self.local_addresses(),
         };
 
         let encoded = encode_sync_message(&message).map_err(std::io::Error::other)?;
@@ -140,13 +756,31 @@ impl SyncManager {
     }
 
     /// Request sync from peers
+    ///
+    /// Complete responses to a request from the beginning of the oplog are
+    /// acknowledged with a `SyncMessage::Ack`, so peers can compact history this
+    /// device holds.
     pub fn request_sync(
         &mut self,
         since_timestamp: chrono::DateTime<chrono::Utc>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let request_id = Uuid::new_v4();
+        // Only a response to a request for everything proves this device holds
+        // every entry the responder wrote up to its end
+        if since_timestamp == DateTime::UNIX_EPOCH {
+            if self.catch_up_requests.len() == MAX_CATCH_UP_REQUESTS {
+                if let Some(oldest) = self.catch_up_requests.pop_front() {
+                    self.catch_up_responses.retain(|(_, id), _| *id != oldest);
+                }
+            }
+            self.catch_up_requests.push_back(request_id);
+        }
+
+        // Oplog entries are ordered by HLC timestamp, not by wall-clock seconds
         let message = SyncMessage::RequestSync {
             user_id: self.user_id,
-            since_timestamp: since_timestamp.timestamp(),
+            since_timestamp: HybridLogicalClock::new(since_timestamp, 0).to_timestamp(),
+            request_id: Some(request_id),
         };
 
         let encoded = encode_sync_message(&message).map_err(std::io::Error::other)?;
@@ -168,6 +802,7 @@ impl SyncManager {
         let message = SyncMessage::SyncData {
             user_id: self.user_id,
             entries,
+            request_id: None,
         };
 
         let encoded = encode_sync_message(&message).map_err(std::io::Error::other)?;
@@ -181,6 +816,37 @@ impl SyncManager {
         Ok(())
     }
 
+    /// Publish an already-built sync message to the sync topic
+    fn publish_sync_message(
+        &mut self,
+        message: &SyncMessage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let encoded = encode_sync_message(message).map_err(std::io::Error::other)?;
+
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(self.topic.clone(), encoded)
+            .map_err(|e| std::io::Error::other(format!("Failed to publish: {:?}", e)))?;
+
+        Ok(())
+    }
+
+    /// Use a different physical time source for the HLC, e.g. a simulated clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Refuse connections to a peer and close the existing ones
+    pub fn block_peer(&mut self, peer_id: PeerId) {
+        self.swarm.behaviour_mut().blocked.block_peer(peer_id);
+    }
+
+    /// Allow connections to a previously blocked peer again
+    pub fn unblock_peer(&mut self, peer_id: PeerId) {
+        self.swarm.behaviour_mut().blocked.unblock_peer(peer_id);
+    }
+
     /// Get the current syncing status
     pub fn get_is_syncing(&self) -> bool {
         self.is_syncing
@@ -196,6 +862,102 @@ impl SyncManager {
         self.connected_peers.iter().map(|p| p.to_string()).collect()
     }
 
+    /// Get the addresses this node listens on, including relayed `/p2p-circuit` ones
+    pub fn get_listen_addresses(&self) -> Vec<String> {
+        self.swarm
+            .listeners()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    /// Get the relay servers this node holds (or is requesting) a reservation on
+    pub fn get_relay_reservations(&self) -> Vec<String> {
+        self.relay_listeners.keys().map(|p| p.to_string()).collect()
+    }
+
+    /// Get this node's NAT status as determined by AutoNAT
+    pub fn get_nat_status(&self) -> &autonat::NatStatus {
+        &self.nat_status
+    }
+
+    /// Get the addresses connected peers observed this node at
+    pub fn get_observed_addresses(&self) -> &[Multiaddr] {
+        &self.observed_addresses
+    }
+
+    /// Get the latest ping round-trip time to a connected peer
+    pub fn get_peer_rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.peer_rtts.get(peer_id).copied()
+    }
+
+    /// Get this node's reachability in the form persisted for the CLI
+    pub fn get_network_status(&self) -> NetworkStatus {
+        NetworkStatus {
+            nat_status: nat_status_label(&self.nat_status).to_string(),
+            public_address: match &self.nat_status {
+                autonat::NatStatus::Public(addr) => Some(addr.to_string()),
+                _ => None,
+            },
+            observed_addresses: self
+                .observed_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Persist the current network status so `ahenk-cli peer info` can show it
+    fn save_network_status(&self) {
+        let status = self.get_network_status();
+        self.write_in_background("save network status".to_string(), move |conn| {
+            operations::save_network_status(conn, &status)
+        });
+    }
+
+    /// Record what a peer told us via identify
+    fn handle_identify_info(
+        &mut self,
+        peer_id: PeerId,
+        info: identify::Info,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let device_id = self.peer_devices.get(&peer_id).copied();
+        self.remember_addresses(peer_id, &info.listen_addrs, device_id);
+        let peer = peer_id.to_string();
+        let agent_version = info.agent_version.clone();
+        let protocol_version = info.protocol_version.clone();
+        let now = Utc::now();
+        self.write_in_background(format!("record identity of {}", peer_id), move |conn| {
+            operations::upsert_peer_identity(
+                conn,
+                &peer,
+                device_id,
+                &agent_version,
+                &protocol_version,
+                now,
+            )
+        });
+
+        // Peers speaking our DHT protocol can serve Kademlia queries
+        if info.protocols.contains(&KADEMLIA_PROTOCOL) {
+            if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                for addr in info.listen_addrs {
+                    kademlia.add_address(&peer_id, addr);
+                }
+            }
+        }
+
+        if !self.observed_addresses.contains(&info.observed_addr) {
+            self.observed_addresses.push(info.observed_addr);
+            if self.observed_addresses.len() > MAX_OBSERVED_ADDRESSES {
+                self.observed_addresses.remove(0);
+            }
+            self.save_network_status();
+        }
+
+        Ok(())
+    }
+
     #[cfg(feature = "tauri-api")]
     fn emit_sync_status(&self) {
         let status = serde_json::json!({
@@ -215,6 +977,21 @@ impl SyncManager {
         let _ = &self.connected_peers;
     }
 
+    #[cfg(feature = "tauri-api")]
+    fn emit_connection_upgrade(&self, peer_id: &PeerId, error: Option<String>) {
+        let upgrade = serde_json::json!({
+            "peer_id": peer_id.to_string(),
+            "success": error.is_none(),
+            "error": error,
+        });
+        let _ = self.app_handle.emit("connection-upgrade", upgrade);
+    }
+
+    #[cfg(not(feature = "tauri-api"))]
+    fn emit_connection_upgrade(&self, _peer_id: &PeerId, _error: Option<String>) {
+        // No-op when tauri feature is not enabled
+    }
+
     #[cfg(feature = "tauri-api")]
     fn emit_pending_changes(&self) {
         let pending_status = serde_json::json!({
@@ -257,19 +1034,32 @@ impl SyncManager {
             return Ok(());
         }
 
-        // Convert queue to vector for sending
-        let pending_entries: Vec<OplogEntry> = self.pending_changes.drain(..).collect();
-
-        // If no peers are connected, we can't sync yet - return the entries to the queue
+        // If no peers are connected, we can't sync yet - keep the entries queued
         if self.connected_peers.is_empty() {
-            for entry in pending_entries {
-                self.pending_changes.push_back(entry);
-            }
             return Ok(());
         }
 
-        // Send the pending changes
-        self.send_sync_data(pending_entries)?;
+        // Send the pending changes in messages gossipsub accepts, only dropping
+        // each run from the queue once it is published
+        let user_id = self.user_id;
+        let pending_entries: Vec<OplogEntry> = self.pending_changes.iter().cloned().collect();
+        let runs =
+            split_by_encoded_size(pending_entries, self.config.max_message_size, |entries| {
+                SyncMessage::SyncData {
+                    user_id,
+                    entries,
+                    request_id: None,
+                }
+            })
+            .map_err(std::io::Error::other)?;
+        for run in runs {
+            let count = run.len();
+            if let Err(e) = self.send_sync_data(run) {
+                self.emit_pending_changes();
+                return Err(e);
+            }
+            self.pending_changes.drain(..count);
+        }
         self.emit_pending_changes();
 
         Ok(())
@@ -280,31 +1070,266 @@ impl SyncManager {
         self.pending_changes.len()
     }
 
-    /// Process a single network event (non-blocking)
-    pub async fn process_event(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Get a handle that can request a graceful shutdown from another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.shutdown_tx.clone(),
+        }
+    }
+
+    /// Request a graceful shutdown; [`SyncManager::run`] returns once it completes
+    pub fn request_shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    /// Whether a shutdown has been requested
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown_requested
+    }
+
+    /// Persist the outbox and per-peer sync state to SQLite
+    pub async fn checkpoint(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Changes already taken by a capture tick must reach the queue first
+        self.settle_database().await;
+
+        let mut entries: Vec<OplogEntry> = self.pending_changes.iter().cloned().collect();
+        let peer_sync_times: Vec<(Uuid, i64)> = self
+            .peer_sync_times
+            .iter()
+            .map(|(device_id, synced_at)| (*device_id, synced_at.timestamp()))
+            .collect();
+        let clock = Arc::clone(&self.clock);
+        let device_id = self.device_id;
+        self.database
+            .write(move |conn| {
+                // Local writes queued since the last capture tick join the queue;
+                // rows other writers add after this are left for the next run
+                for entry in take_local_changes(conn, clock.as_ref(), device_id) {
+                    if !entries.iter().any(|p| p.id == entry.id) {
+                        entries.push(entry);
+                    }
+                }
+                operations::enqueue_sync_outbox(conn, &entries)?;
+                for (device_id, synced_at) in &peer_sync_times {
+                    operations::update_peer_last_sync_time(conn, *device_id, *synced_at)?;
+                }
+                Ok(())
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Restore pending changes checkpointed by a previous run.
+    ///
+    /// The entries move from the outbox into the in-memory queue, like changes
+    /// picked up by a capture tick. Returns the number of entries restored.
+    pub async fn restore_checkpoint(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let entries = self.database.write(operations::take_sync_outbox).await?;
+
+        let restored = entries.len();
+        for entry in entries {
+            if !self.pending_changes.iter().any(|p| p.id == entry.id) {
+                self.pending_changes.push_back(entry);
+            }
+        }
+        self.emit_pending_changes();
+
+        Ok(restored)
+    }
+
+    /// Wait for the database work submitted so far, handling the events it produces
+    async fn settle_database(&mut self) {
+        use futures::{FutureExt, StreamExt};
+
+        // Writes run in order, so the barrier completes after every earlier one
+        let mut barrier = self.database.write(|_| Ok(())).fuse();
+        loop {
+            futures::select_biased! {
+                _ = barrier => break,
+                event = self.db_events_rx.select_next_some() => {
+                    if let Err(e) = self.handle_db_event(event) {
+                        log::warn!("Error handling database event: {}", e);
+                    }
+                }
+            }
+        }
+        while let Ok(event) = self.db_events_rx.try_recv() {
+            if let Err(e) = self.handle_db_event(event) {
+                log::warn!("Error handling database event: {}", e);
+            }
+        }
+    }
+
+    /// Gracefully shut down the manager.
+    ///
+    /// Publishes queued changes while peers are still connected, keeps driving the
+    /// swarm for the configured grace period so in-flight transfers complete, closes
+    /// all connections and listeners, and checkpoints the outbox and peer state.
+    pub async fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.shutdown_requested = true;
+
+        self.capture_local_changes();
+        self.settle_database().await;
+        if let Err(e) = self.sync_pending_changes() {
+            log::warn!("Failed to flush pending changes during shutdown: {}", e);
+        }
+
+        if !self.connected_peers.is_empty() {
+            let grace_period = self.shutdown_grace_period;
+            let _ = async_std::future::timeout(grace_period, self.drive_swarm(false)).await;
+        }
+
+        for peer_id in self.connected_peers.clone() {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        let relay_listeners = self.relay_listeners.drain().map(|(_, listener)| listener);
+        for listener in self.listeners.drain(..).chain(relay_listeners) {
+            self.swarm.remove_listener(listener);
+        }
+        if !self.connected_peers.is_empty() {
+            let grace_period = self.shutdown_grace_period;
+            let _ = async_std::future::timeout(grace_period, self.drive_swarm(true)).await;
+        }
+
+        self.checkpoint().await?;
+        self.is_syncing = false;
+        self.emit_sync_status();
+
+        Ok(())
+    }
+
+    /// Keep handling swarm events and database results, optionally stopping once
+    /// every connection is closed
+    async fn drive_swarm(&mut self, until_disconnected: bool) {
         use futures::StreamExt;
 
-        match self.swarm.select_next_some().await {
+        while !(until_disconnected && self.connected_peers.is_empty()) {
+            let handled = futures::select_biased! {
+                event = self.db_events_rx.select_next_some() => self.handle_db_event(event),
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            };
+            if let Err(e) = handled {
+                log::warn!("Error processing event during shutdown: {}", e);
+            }
+        }
+    }
+
+    /// Process a single network event, or a pending shutdown request
+    pub async fn process_event(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        use futures::{FutureExt, StreamExt};
+
+        // Wake up for the next scheduled redial or discovery refresh, if there is one
+        let wakeup_in = self.next_wakeup_in();
+        let mut timer = Box::pin(async move {
+            match wakeup_in {
+                Some(delay) => futures_timer::Delay::new(delay).await,
+                None => futures::future::pending::<()>().await,
+            }
+        })
+        .fuse();
+
+        // Shutdown requests take priority over further network events
+        let event = futures::select_biased! {
+            _ = self.shutdown_rx.next() => {
+                self.shutdown_requested = true;
+                return Ok(());
+            }
+            _ = timer => {
+                self.on_timer();
+                return Ok(());
+            }
+            event = self.db_events_rx.select_next_some() => {
+                return self.handle_db_event(event);
+            }
+            event = self.swarm.select_next_some() => event,
+        };
+
+        self.handle_swarm_event(event)
+    }
+
+    /// Handle a single swarm event
+    fn handle_swarm_event(
+        &mut self,
+        event: SwarmEvent<AhenkBehaviourEvent>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("Listening on: {}", address);
             }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                // A lost relay reservation is requested again once the relay is reconnected
+                let before = self.relay_listeners.len();
+                self.relay_listeners
+                    .retain(|_, listener| *listener != listener_id);
+                if self.relay_listeners.len() != before {
+                    println!("Relay reservation closed: {:?}", reason);
+                }
+            }
             SwarmEvent::Behaviour(event) => {
                 self.handle_behaviour_event(event)?;
             }
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
                 println!("Connected to peer: {}", peer_id);
-                self.connected_peers.push(peer_id);
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    self.record_dial_result(&peer_id, address, true);
+                }
+                self.reconnect.reset(&peer_id);
+                if self.rendezvous_points.contains_key(&peer_id) {
+                    self.register_and_discover(peer_id);
+                }
+                if self.relay_servers.contains_key(&peer_id) {
+                    self.reserve_relay(peer_id);
+                }
+                if !self.connected_peers.contains(&peer_id) {
+                    self.connected_peers.push(peer_id);
+                }
                 self.is_syncing = true;
                 self.emit_sync_status();
             }
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause,
+                num_established,
+                ..
+            } => {
                 println!("Connection closed with {}: {:?}", peer_id, cause);
-                self.connected_peers.retain(|p| p != &peer_id);
+                if num_established == 0 {
+                    self.connected_peers.retain(|p| p != &peer_id);
+                    self.peer_rtts.remove(&peer_id);
+                    if !self.shutdown_requested && self.persistent_peers.contains(&peer_id) {
+                        self.reconnect.schedule(peer_id, Instant::now());
+                    }
+                }
                 if self.connected_peers.is_empty() {
                     self.is_syncing = false;
                 }
                 self.emit_sync_status();
             }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } => {
+                eprintln!("Failed to connect to {}: {}", peer_id, error);
+                if let DialError::Transport(failures) = &error {
+                    for (address, _) in failures {
+                        self.record_dial_result(&peer_id, address, false);
+                    }
+                }
+                if !self.shutdown_requested
+                    && self.persistent_peers.contains(&peer_id)
+                    && !self.swarm.is_connected(&peer_id)
+                {
+                    self.reconnect.schedule(peer_id, Instant::now());
+                }
+            }
             _ => {}
         }
 
@@ -318,8 +1343,9 @@ impl SyncManager {
     ) -> Result<(), Box<dyn std::error::Error>> {
         match event {
             AhenkBehaviourEvent::Mdns(mdns::Event::Discovered(peers)) => {
-                for (peer_id, _addr) in peers {
+                for (peer_id, addr) in peers {
                     println!("Discovered peer: {}", peer_id);
+                    self.remember_addresses(peer_id, [&addr], None);
                     self.swarm
                         .behaviour_mut()
                         .gossipsub
@@ -338,6 +1364,138 @@ impl SyncManager {
             AhenkBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. }) => {
                 self.handle_gossipsub_message(message)?;
             }
+            AhenkBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })
+                if topic == self.topic.hash() =>
+            {
+                // Catch up with the peer: hand over what queued up while it was
+                // unreachable, and ask for everything we might have missed
+                println!("Peer {} joined the sync topic", peer_id);
+                if let Err(e) = self.sync_pending_changes() {
+                    eprintln!("Failed to send pending changes to {}: {}", peer_id, e);
+                }
+                if let Err(e) = self.request_sync(DateTime::UNIX_EPOCH) {
+                    eprintln!("Failed to request sync from {}: {}", peer_id, e);
+                }
+            }
+            AhenkBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                relay_peer_id,
+                renewal: false,
+                ..
+            }) => {
+                println!("Relay reservation accepted by {}", relay_peer_id);
+            }
+            AhenkBehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. },
+            ) => {
+                println!("Relayed connection established via {}", relay_peer_id);
+            }
+            AhenkBehaviourEvent::RelayClient(relay::client::Event::InboundCircuitEstablished {
+                src_peer_id,
+                ..
+            }) => {
+                println!("Inbound relayed connection from {}", src_peer_id);
+            }
+            AhenkBehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            }) => match result {
+                Ok(_) => {
+                    println!(
+                        "Upgraded relayed connection to {} to a direct one",
+                        remote_peer_id
+                    );
+                    self.emit_connection_upgrade(&remote_peer_id, None);
+                }
+                Err(e) => {
+                    eprintln!("Hole punching to {} failed: {}", remote_peer_id, e);
+                    self.emit_connection_upgrade(&remote_peer_id, Some(e.to_string()));
+                }
+            },
+            AhenkBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }) => {
+                self.handle_identify_info(peer_id, info)?;
+            }
+            AhenkBehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            }) => {
+                self.peer_rtts.insert(peer, rtt);
+                let peer_id = peer.to_string();
+                let now = Utc::now();
+                self.write_in_background(format!("record RTT of {}", peer), move |conn| {
+                    operations::record_peer_rtt(conn, &peer_id, rtt.as_millis() as u64, now)
+                });
+            }
+            AhenkBehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Err(e),
+                ..
+            }) => {
+                eprintln!("Ping to {} failed: {}", peer, e);
+            }
+            AhenkBehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new }) => {
+                println!(
+                    "NAT status changed: {} -> {}",
+                    nat_status_label(&old),
+                    nat_status_label(&new)
+                );
+                self.nat_status = new;
+                self.save_network_status();
+            }
+            AhenkBehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+                peer, addresses, ..
+            }) => {
+                self.remember_addresses(peer, addresses.iter(), None);
+            }
+            AhenkBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                result:
+                    kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                        providers,
+                        ..
+                    })),
+                ..
+            }) => {
+                for peer_id in providers {
+                    println!("Found device via DHT: {}", peer_id);
+                    // Kademlia supplies the provider's addresses when dialing
+                    self.dial_discovered_peer(peer_id, Vec::new());
+                }
+            }
+            AhenkBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered {
+                rendezvous_node,
+                registrations,
+                cookie,
+            }) => {
+                self.rendezvous_cookies.insert(rendezvous_node, cookie);
+                for registration in registrations {
+                    let peer_id = registration.record.peer_id();
+                    println!(
+                        "Found device via rendezvous {}: {}",
+                        rendezvous_node, peer_id
+                    );
+                    self.dial_discovered_peer(peer_id, registration.record.addresses().to_vec());
+                }
+            }
+            AhenkBehaviourEvent::Rendezvous(rendezvous::client::Event::RegisterFailed {
+                rendezvous_node,
+                error,
+                ..
+            }) => {
+                eprintln!(
+                    "Rendezvous registration with {} failed: {:?}",
+                    rendezvous_node, error
+                );
+            }
+            AhenkBehaviourEvent::Rendezvous(rendezvous::client::Event::DiscoverFailed {
+                rendezvous_node,
+                error,
+                ..
+            }) => {
+                eprintln!(
+                    "Rendezvous discovery at {} failed: {:?}",
+                    rendezvous_node, error
+                );
+            }
             _ => {}
         }
         Ok(())
@@ -349,38 +1507,698 @@ impl SyncManager {
         message: gossipsub::Message,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let sync_message = crate::logic::sync::decode_sync_message(&message.data)?;
-        if let SyncMessage::SyncData { .. } = sync_message {
-            self.last_sync_time = Some(Utc::now());
-            self.emit_sync_status();
+        match sync_message {
+            SyncMessage::Announce {
+                user_id, device_id, ..
+            } => {
+                if let Some(source) = message.source {
+                    self.peer_devices.insert(source, device_id);
+                    if user_id == self.user_id {
+                        self.persistent_peers.insert(source);
+                    }
+                }
+                // Refresh the peer record and address book for our own devices
+                if user_id == self.user_id && device_id != self.device_id {
+                    let local_device_id = self.device_id;
+                    self.write_in_background(
+                        format!("record announce from {}", device_id),
+                        move |conn| {
+                            handle_sync_message(conn, local_device_id, sync_message, |_| Ok(()))
+                        },
+                    );
+                }
+            }
+            SyncMessage::RequestSync {
+                user_id,
+                since_timestamp,
+                request_id,
+            } if user_id == self.user_id => {
+                let device_id = self.device_id;
+                let limits = self.config.sync_limits();
+                // Batches are published by the event loop as the reader produces them
+                let mut events = self.db_events_tx.clone();
+                drop(self.database.read(move |conn| {
+                    let sent = respond_to_sync_request(
+                        conn,
+                        user_id,
+                        device_id,
+                        request_id,
+                        since_timestamp,
+                        limits,
+                        |response| {
+                            futures::executor::block_on(events.send(DbEvent::Publish(response)))
+                                .map_err(|e| e.to_string())
+                        },
+                    );
+                    if let Err(e) = sent {
+                        eprintln!("Failed to answer sync request: {}", e);
+                    }
+                    Ok(())
+                }));
+            }
+            SyncMessage::SyncData {
+                user_id,
+                entries,
+                request_id,
+            } if user_id == self.user_id => {
+                let request_id = self.tracked_catch_up(message.source, request_id);
+                self.merge_sync_entries(entries, None, message.source, request_id);
+            }
+            SyncMessage::Snapshot {
+                user_id,
+                horizon,
+                entries,
+                request_id,
+            } if user_id == self.user_id => {
+                let request_id = self.tracked_catch_up(message.source, request_id);
+                self.merge_sync_entries(entries, Some(horizon), message.source, request_id);
+            }
+            SyncMessage::SyncComplete {
+                user_id,
+                request_id,
+                device_id,
+                through,
+                pages,
+            } if user_id == self.user_id && device_id != self.device_id => {
+                if let (Some(source), Some(request_id)) = (
+                    message.source,
+                    self.tracked_catch_up(message.source, Some(request_id)),
+                ) {
+                    self.catch_up_responses
+                        .entry((source, request_id))
+                        .or_default()
+                        .complete = Some((device_id, through, pages));
+                    self.acknowledge_catch_up(source, request_id);
+                }
+            }
+            SyncMessage::Ack {
+                user_id,
+                device_id,
+                origin_device_id,
+                timestamp,
+            } if user_id == self.user_id && device_id != self.device_id => {
+                self.write_in_background(
+                    format!("record sync ack from {}", device_id),
+                    move |conn| {
+                        compaction::record_device_ack(conn, device_id, origin_device_id, timestamp)
+                    },
+                );
+            }
+            _ => {}
         }
         Ok(())
     }
 
-    /// Run the event loop indefinitely
+    /// `request_id` if it tags a page from `source` answering one of our
+    /// catch-up requests
+    fn tracked_catch_up(&self, source: Option<PeerId>, request_id: Option<Uuid>) -> Option<Uuid> {
+        request_id.filter(|id| source.is_some() && self.catch_up_requests.contains(id))
+    }
+
+    /// Merge entries received from another device, adopting the compaction
+    /// horizon of a snapshot. The merge runs on the database writer and completes
+    /// through a [`DbEvent::Merged`].
+    fn merge_sync_entries(
+        &mut self,
+        entries: Vec<OplogEntry>,
+        horizon: Option<i64>,
+        source: Option<PeerId>,
+        request_id: Option<Uuid>,
+    ) {
+        let clock = Arc::clone(&self.clock);
+        let drift_bound = self.drift_bound;
+        let mut events = self.db_events_tx.clone();
+        drop(self.database.write(move |conn| {
+            let result = merge_entries(conn, &entries, horizon, clock.as_ref(), &drift_bound)
+                .map_err(|e| e.to_string());
+            // Fails only once the manager is gone
+            let _ = futures::executor::block_on(events.send(DbEvent::Merged {
+                source,
+                request_id,
+                result,
+            }));
+            Ok(())
+        }));
+    }
+
+    /// Notify live queries, record the sync and count the page towards its
+    /// catch-up response after entries from `source` were merged
+    fn entries_merged(&mut self, source: Option<PeerId>, request_id: Option<Uuid>) {
+        self.dispatch_live_queries();
+        if let (Some(source), Some(request_id)) = (source, request_id) {
+            self.catch_up_responses
+                .entry((source, request_id))
+                .or_default()
+                .merged += 1;
+            self.acknowledge_catch_up(source, request_id);
+        }
+
+        let now = Utc::now();
+        self.last_sync_time = Some(now);
+        if let Some(device_id) = source.and_then(|source| self.peer_devices.get(&source)) {
+            self.peer_sync_times.insert(*device_id, now);
+        }
+        self.emit_sync_status();
+    }
+
+    /// A page of a catch-up response failed to merge, so the response is never
+    /// acknowledged
+    fn merge_failed(&mut self, source: Option<PeerId>, request_id: Option<Uuid>) {
+        if let (Some(source), Some(request_id)) = (source, request_id) {
+            self.catch_up_responses
+                .entry((source, request_id))
+                .or_default()
+                .failed = true;
+        }
+    }
+
+    /// Once every page of `source`'s response to a catch-up request is merged,
+    /// tell the user's other devices this one holds every entry the responder
+    /// wrote up to the response's end, so they can compact history every device has
+    fn acknowledge_catch_up(&mut self, source: PeerId, request_id: Uuid) {
+        let key = (source, request_id);
+        let Some(response) = self.catch_up_responses.get(&key) else {
+            return;
+        };
+        let Some((origin_device_id, through, pages)) = response.complete else {
+            return;
+        };
+        if response.failed || response.merged < pages {
+            return;
+        }
+        self.catch_up_responses.remove(&key);
+
+        let ack = SyncMessage::Ack {
+            user_id: self.user_id,
+            device_id: self.device_id,
+            origin_device_id,
+            timestamp: through,
+        };
+        if let Err(e) = self.publish_sync_message(&ack) {
+            log::warn!("Failed to acknowledge sync: {}", e);
+        }
+    }
+
+    /// Handle the result of work done on the database workers
+    fn handle_db_event(&mut self, event: DbEvent) -> Result<(), Box<dyn std::error::Error>> {
+        match event {
+            DbEvent::Publish(message) => self.publish_sync_message(&message)?,
+            DbEvent::Captured(entries) => self.changes_captured(entries),
+            DbEvent::Redial(redials) => self.redial(redials),
+            DbEvent::Merged {
+                source,
+                request_id,
+                result: Ok(()),
+            } => self.entries_merged(source, request_id),
+            DbEvent::Merged {
+                source,
+                request_id,
+                result: Err(e),
+            } => {
+                self.merge_failed(source, request_id);
+                eprintln!("Failed to merge sync data: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the event loop until a shutdown is requested, then shut down gracefully
     pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        loop {
+        while !self.shutdown_requested {
             self.process_event().await?;
         }
+        self.shutdown().await
+    }
+}
+
+/// Take local writes queued in the outbox and move captured app table changes
+/// into the oplog, returning the entries to send
+fn take_local_changes(
+    conn: &mut Connection,
+    clock: &dyn Clock,
+    device_id: Uuid,
+) -> Vec<OplogEntry> {
+    let mut entries = match operations::take_sync_outbox(conn) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read queued changes: {}", e);
+            Vec::new()
+        }
+    };
+    match capture::flush_captured_changes(conn, clock, device_id) {
+        Ok(captured) => entries.extend(captured),
+        Err(e) => eprintln!("Failed to record captured changes: {}", e),
+    }
+    entries
+}
+
+/// Addresses from the address book for a peer, most reliable first
+fn known_addresses(conn: &Connection, peer_id: &PeerId) -> Vec<Multiaddr> {
+    operations::get_peer_addresses(conn, &peer_id.to_string())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|entry| entry.multiaddr.parse().ok())
+        .collect()
+}
+
+/// Merge received entries and adopt a snapshot's compaction horizon
+fn merge_entries(
+    conn: &mut Connection,
+    entries: &[OplogEntry],
+    horizon: Option<i64>,
+    clock: &dyn Clock,
+    drift_bound: &DriftBound,
+) -> rusqlite::Result<()> {
+    crdt::merge_with(conn, entries, clock, drift_bound)?;
+    if let Some(horizon) = horizon {
+        compaction::set_horizon(conn, horizon)?;
     }
+    Ok(())
+}
+
+/// Parse `/…/p2p/<peer_id>` multiaddrs into peer IDs and dialable addresses.
+/// Addresses without a peer ID are skipped.
+fn parse_peer_addresses(addresses: &[String]) -> HashMap<PeerId, Multiaddr> {
+    addresses
+        .iter()
+        .filter_map(|raw| raw.parse::<Multiaddr>().ok())
+        .filter_map(|addr| match split_peer_multiaddr(&addr) {
+            (transport, Some(peer_id)) => Some((peer_id, transport)),
+            (_, None) => None,
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::logic::sync::generate_device_id;
+    use std::path::Path;
 
-    #[test]
-    fn test_sync_manager_creation() {
-        use rusqlite::Connection;
+    /// A database file in a fresh temporary directory
+    fn temp_db_path() -> String {
+        let dir = std::env::temp_dir().join(format!("ahenk-sync-db-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("nexus.db").to_string_lossy().into_owned()
+    }
 
-        let conn = Connection::open_in_memory().unwrap();
-        let conn = Arc::new(Mutex::new(conn));
+    fn remove_temp_db(path: &str) {
+        std::fs::remove_dir_all(Path::new(path).parent().unwrap()).unwrap();
+    }
+
+    // mDNS registers its socket with the tokio reactor, so these need a runtime
+
+    #[tokio::test]
+    async fn test_sync_manager_creation() {
+        let database = Database::open(":memory:").unwrap();
         let (_, keypair) = generate_device_id();
         let user_id = Uuid::new_v4();
         let device_id = Uuid::new_v4();
         let config = P2PConfig::default();
 
-        let manager = SyncManager::new(keypair, user_id, device_id, conn, config);
+        let manager = SyncManager::new(keypair, user_id, device_id, database, config);
         assert!(manager.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_merges_run_on_the_database_writer() {
+        let path = temp_db_path();
+        let conn = crate::db::operations::initialize_database(&path).unwrap();
+
+        let (_, keypair) = generate_device_id();
+        let mut manager = SyncManager::new(
+            keypair,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Database::open(&path).unwrap(),
+            P2PConfig {
+                enable_mdns: false,
+                ..P2PConfig::default()
+            },
+        )
+        .unwrap();
+
+        let entry = OplogEntry {
+            id: Uuid::new_v4(),
+            device_id: Uuid::new_v4(),
+            timestamp: 1,
+            table: "notes".to_string(),
+            op_type: "create".to_string(),
+            data: serde_json::json!({"id": "n1"}),
+            schema_version: 0,
+        };
+        manager.merge_sync_entries(vec![entry.clone()], Some(1), None, None);
+        assert!(manager.get_last_sync_time().is_none());
+
+        // The merge completes on the writer thread and is picked up by the event loop
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while manager.get_last_sync_time().is_none() {
+                manager.process_event().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        assert!(operations::oplog_entry_exists(&conn, entry.id).unwrap());
+        assert_eq!(compaction::compaction_horizon(&conn).unwrap(), 1);
+
+        drop(manager);
+        remove_temp_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_checkpoints_and_restores_outbox() {
+        let path = temp_db_path();
+        let conn = crate::db::operations::initialize_database(&path).unwrap();
+        let user_id = Uuid::new_v4();
+        let device_id = Uuid::new_v4();
+
+        let (_, keypair) = generate_device_id();
+        let mut manager = SyncManager::new(
+            keypair,
+            user_id,
+            device_id,
+            Database::open(&path).unwrap(),
+            P2PConfig::default(),
+        )
+        .unwrap();
+        manager.listen(0).unwrap();
+
+        let entry = crate::logic::build_oplog_entry(
+            &conn,
+            device_id,
+            "notes",
+            "create",
+            &serde_json::json!({"id": "n1"}),
+        )
+        .unwrap();
+        manager.add_pending_change(entry.clone());
+        // Queued by an app writing through its own connection
+        let app_entry = crate::logic::build_oplog_entry(
+            &conn,
+            device_id,
+            "notes",
+            "create",
+            &serde_json::json!({"id": "n2"}),
+        )
+        .unwrap();
+        crate::db::operations::enqueue_sync_outbox(&conn, std::slice::from_ref(&app_entry))
+            .unwrap();
+
+        // Requesting shutdown through a handle makes `run` return
+        manager.shutdown_handle().shutdown();
+        manager.run().await.unwrap();
+        assert!(manager.is_shutdown_requested());
+        drop(manager);
+
+        let (_, keypair) = generate_device_id();
+        let database = Database::open(&path).unwrap();
+        let mut restarted =
+            SyncManager::new(keypair, user_id, device_id, database, P2PConfig::default()).unwrap();
+        assert_eq!(restarted.restore_checkpoint().await.unwrap(), 2);
+        assert_eq!(restarted.get_pending_changes_count(), 2);
+        // Restored entries are not picked up again by the next capture tick
+        assert!(crate::db::operations::get_sync_outbox(&conn)
+            .unwrap()
+            .is_empty());
+
+        drop(restarted);
+        remove_temp_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_configuration() {
+        let database = Database::open(":memory:").unwrap();
+        let user_id = Uuid::new_v4();
+        let rendezvous_peer = PeerId::random();
+        let config = P2PConfig {
+            rendezvous_points: vec![
+                format!("/ip4/203.0.113.7/tcp/4001/p2p/{}", rendezvous_peer),
+                // Rendezvous points must name their peer ID
+                "/ip4/203.0.113.8/tcp/4001".to_string(),
+            ],
+            ..P2PConfig::default()
+        };
+
+        let (_, keypair) = generate_device_id();
+        let manager = SyncManager::new(keypair, user_id, Uuid::new_v4(), database, config).unwrap();
+
+        assert_eq!(manager.rendezvous_points.len(), 1);
+        assert_eq!(
+            manager.rendezvous_points[&rendezvous_peer],
+            "/ip4/203.0.113.7/tcp/4001".parse::<Multiaddr>().unwrap()
+        );
+        assert!(manager.swarm.behaviour().kademlia.is_enabled());
+        assert!(manager.swarm.behaviour().rendezvous.is_enabled());
+        assert_eq!(manager.discovery_namespace(), discovery_namespace(user_id));
+        // Discovery runs as soon as the event loop starts
+        assert_eq!(manager.next_wakeup_in(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_network_status_starts_unknown() {
+        let (_, keypair) = generate_device_id();
+        let manager = SyncManager::new(
+            keypair,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Database::open(":memory:").unwrap(),
+            P2PConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(*manager.get_nat_status(), autonat::NatStatus::Unknown);
+        assert!(manager.get_observed_addresses().is_empty());
+        assert!(manager.get_peer_rtt(&PeerId::random()).is_none());
+
+        manager.save_network_status();
+        // Reads of an in-memory database run on the writer, after the save
+        let saved = manager
+            .database
+            .read(operations::get_network_status)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.nat_status, "unknown");
+        assert_eq!(saved.public_address, None);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_relay_and_mdns_are_not_constructed() {
+        let database = Database::open(":memory:").unwrap();
+        let config = P2PConfig {
+            enable_mdns: false,
+            enable_relay: false,
+            ..P2PConfig::default()
+        };
+
+        let (_, keypair) = generate_device_id();
+        let manager =
+            SyncManager::new(keypair, Uuid::new_v4(), Uuid::new_v4(), database, config).unwrap();
+
+        let behaviour = manager.swarm.behaviour();
+        assert!(!behaviour.mdns.is_enabled());
+        assert!(!behaviour.relay_client.is_enabled());
+        assert!(!behaviour.dcutr.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_two_nodes_connect_and_measure_rtt() {
+        let config = P2PConfig {
+            enable_mdns: false,
+            enable_kademlia: false,
+            ping_interval: Duration::from_millis(200),
+            ..P2PConfig::default()
+        };
+        let new_manager = || {
+            let (_, keypair) = generate_device_id();
+            SyncManager::new(
+                keypair,
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                Database::open(":memory:").unwrap(),
+                config.clone(),
+            )
+            .unwrap()
+        };
+
+        let mut listener = new_manager();
+        let listener_peer = *listener.swarm.local_peer_id();
+        listener.listen(0).unwrap();
+        while !listener
+            .get_listen_addresses()
+            .iter()
+            .any(|a| a.starts_with("/ip4/127.0.0.1/"))
+        {
+            listener.process_event().await.unwrap();
+        }
+        let addr = listener
+            .get_listen_addresses()
+            .into_iter()
+            .find(|a| a.starts_with("/ip4/127.0.0.1/"))
+            .unwrap();
+        let listener_handle = listener.shutdown_handle();
+        let listener_task = tokio::spawn(async move { listener.run().await.is_ok() });
+
+        let mut dialer = new_manager();
+        dialer
+            .connect_to_network(&[format!("{}/p2p/{}", addr, listener_peer)], &[])
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while dialer.get_peer_rtt(&listener_peer).is_none() {
+                dialer.process_event().await.unwrap();
+            }
+        })
+        .await
+        .expect("peers should connect and ping each other");
+
+        assert!(dialer
+            .get_connected_peers()
+            .contains(&listener_peer.to_string()));
+
+        listener_handle.shutdown();
+        assert!(listener_task.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sync_acks_advance_the_compaction_horizon() {
+        let config = P2PConfig {
+            enable_mdns: false,
+            enable_kademlia: false,
+            ..P2PConfig::default()
+        };
+        let user_id = Uuid::new_v4();
+        // Each manager works on a database file the test reads through its own connection
+        let new_manager = |device_id: Uuid| {
+            let path = temp_db_path();
+            let conn = crate::db::operations::initialize_database(&path).unwrap();
+            let (_, keypair) = generate_device_id();
+            let database = Database::open(&path).unwrap();
+            let manager =
+                SyncManager::new(keypair, user_id, device_id, database, config.clone()).unwrap();
+            (manager, conn, path)
+        };
+
+        let listener_device = Uuid::new_v4();
+        let dialer_device = Uuid::new_v4();
+        let (mut listener, listener_conn, listener_path) = new_manager(listener_device);
+        let entry = {
+            // The listener knows the dialer, which has not synced anything yet
+            compaction::record_device_ack(&listener_conn, dialer_device, listener_device, 0)
+                .unwrap();
+            let entry = crate::logic::build_oplog_entry(
+                &listener_conn,
+                listener_device,
+                "notes",
+                "create",
+                &serde_json::json!({"id": "n1"}),
+            )
+            .unwrap();
+            operations::insert_oplog_entry(&listener_conn, &entry).unwrap();
+            assert_eq!(
+                compaction::stable_horizon(&listener_conn, listener_device).unwrap(),
+                0
+            );
+            entry
+        };
+
+        let listener_peer = *listener.swarm.local_peer_id();
+        listener.listen(0).unwrap();
+        while !listener
+            .get_listen_addresses()
+            .iter()
+            .any(|a| a.starts_with("/ip4/127.0.0.1/"))
+        {
+            listener.process_event().await.unwrap();
+        }
+        let addr = listener
+            .get_listen_addresses()
+            .into_iter()
+            .find(|a| a.starts_with("/ip4/127.0.0.1/"))
+            .unwrap();
+        let listener_handle = listener.shutdown_handle();
+        let listener_task = tokio::spawn(async move { listener.run().await.is_ok() });
+
+        // The dialer asks for everything once subscribed, merges the whole
+        // response and acknowledges the listener's entries up to its end
+        let (mut dialer, dialer_conn, dialer_path) = new_manager(dialer_device);
+        dialer
+            .connect_to_network(&[format!("{}/p2p/{}", addr, listener_peer)], &[])
+            .unwrap();
+        let dialer_handle = dialer.shutdown_handle();
+        let dialer_task = tokio::spawn(async move { dialer.run().await.is_ok() });
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let horizon = compaction::stable_horizon(&listener_conn, listener_device).unwrap();
+                if horizon == entry.timestamp {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the dialer's ack should advance the listener's horizon");
+        assert!(operations::oplog_entry_exists(&dialer_conn, entry.id).unwrap());
+
+        dialer_handle.shutdown();
+        listener_handle.shutdown();
+        assert!(dialer_task.await.unwrap());
+        assert!(listener_task.await.unwrap());
+        remove_temp_db(&listener_path);
+        remove_temp_db(&dialer_path);
+    }
+
+    #[tokio::test]
+    async fn test_two_nodes_connect_over_quic() {
+        let config = P2PConfig {
+            enable_mdns: false,
+            enable_kademlia: false,
+            enable_websocket: true,
+            listen_address: "127.0.0.1".to_string(),
+            ..P2PConfig::default()
+        };
+        let new_manager = || {
+            let (_, keypair) = generate_device_id();
+            SyncManager::new(
+                keypair,
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                Database::open(":memory:").unwrap(),
+                config.clone(),
+            )
+            .unwrap()
+        };
+
+        // Listens on TCP, QUIC and WebSocket, only on the configured address
+        let mut listener = new_manager();
+        let listener_peer = *listener.swarm.local_peer_id();
+        listener.listen(0).unwrap();
+        while listener.get_listen_addresses().len() < 3 {
+            listener.process_event().await.unwrap();
+        }
+        let addrs = listener.get_listen_addresses();
+        assert!(addrs.iter().all(|a| a.starts_with("/ip4/127.0.0.1/")));
+        assert!(addrs.iter().any(|a| a.ends_with("/ws")));
+        let quic_addr = addrs.into_iter().find(|a| a.ends_with("/quic-v1")).unwrap();
+        let listener_handle = listener.shutdown_handle();
+        let listener_task = tokio::spawn(async move { listener.run().await.is_ok() });
+
+        let mut dialer = new_manager();
+        dialer
+            .connect_to_network(&[format!("{}/p2p/{}", quic_addr, listener_peer)], &[])
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while !dialer
+                .get_connected_peers()
+                .contains(&listener_peer.to_string())
+            {
+                dialer.process_event().await.unwrap();
+            }
+        })
+        .await
+        .expect("peers should connect over QUIC");
+
+        listener_handle.shutdown();
+        assert!(listener_task.await.unwrap());
+    }
 }
@@ -4,6 +4,7 @@
 //! - User and Device models for authentication and device management
 //! - OplogEntry for CRDT-based operation logging
 //! - Peer for P2P network peer tracking
+//! - PeerAddress for the persistent peer address book
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -47,6 +48,34 @@ pub struct OplogEntry {
     pub op_type: String,
     /// The full JSON representation of the entity
     pub data: serde_json::Value,
+    /// Version of the table's payload shape `data` was written with; 0 for
+    /// entries from before payloads were versioned
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+/// Restricts oplog reads to one table, originating device and/or operation
+/// type. `None` fields match every entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OplogFilter {
+    pub table: Option<String>,
+    pub device_id: Option<Uuid>,
+    pub op_type: Option<String>,
+}
+
+/// Position in the oplog's (timestamp, id) order; a page read resumes after it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OplogCursor {
+    pub timestamp: i64,
+    pub id: Uuid,
+}
+
+/// One page of an oplog read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OplogPage {
+    pub entries: Vec<OplogEntry>,
+    /// Cursor to pass to the next read, or `None` once the oplog is exhausted
+    pub next: Option<OplogCursor>,
 }
 
 /// Peer device in the P2P synchronization network
@@ -58,3 +87,99 @@ pub struct Peer {
     pub last_known_ip: Option<String>,
     pub last_sync_time: Option<i64>,
 }
+
+/// Known network address of a libp2p peer, with dial history.
+///
+/// The address book is keyed by libp2p peer ID and multiaddr, so a single peer
+/// can have several addresses (LAN, public, relayed) tracked independently.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerAddress {
+    /// libp2p peer ID (base58)
+    pub peer_id: String,
+    /// Dialable multiaddr, without the trailing `/p2p/<peer_id>`
+    pub multiaddr: String,
+    /// Device that announced this peer, if known
+    pub device_id: Option<Uuid>,
+    /// Number of successful dials to this address
+    pub success_count: u32,
+    /// Number of failed dials to this address
+    pub failure_count: u32,
+    /// When the address was last dialed successfully
+    pub last_success: Option<DateTime<Utc>>,
+    /// When a dial to the address last failed
+    pub last_failure: Option<DateTime<Utc>>,
+    /// When the address was last observed (announce, discovery or connection)
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Connection details of a libp2p peer learned through identify and ping.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerNetworkInfo {
+    /// libp2p peer ID (base58)
+    pub peer_id: String,
+    /// Device behind the peer, if known
+    pub device_id: Option<Uuid>,
+    /// Agent the peer reported via identify (e.g. `ahenk/0.1.0`)
+    pub agent_version: Option<String>,
+    /// Protocol version the peer reported via identify
+    pub protocol_version: Option<String>,
+    /// Last measured ping round-trip time in milliseconds
+    pub rtt_ms: Option<u64>,
+    /// When any of the above was last updated
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Reachability of the local node as determined by AutoNAT and identify.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkStatus {
+    /// `unknown`, `private` or `public`
+    pub nat_status: String,
+    /// Public address confirmed by AutoNAT, if reachable
+    pub public_address: Option<String>,
+    /// Addresses other peers reported observing this node at
+    pub observed_addresses: Vec<String>,
+    /// When the status was last updated
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An application table registered for automatic change capture.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncedTable {
+    /// Name of the application table
+    pub table_name: String,
+    /// Column identifying a row on every device
+    pub primary_key: String,
+    /// Columns captured by the triggers, in declaration order
+    pub columns: Vec<String>,
+    /// When the table was last registered
+    pub registered_at: DateTime<Utc>,
+}
+
+/// Declared schema of a synced table or collection.
+///
+/// Operations on the table must have one of `op_types` and, if set, a payload
+/// matching `payload_schema`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    /// Table or collection name used in the oplog
+    pub table_name: String,
+    /// Current payload version; operations written with older versions are upcast
+    pub version: u32,
+    /// Allowed operation types
+    pub op_types: Vec<String>,
+    /// JSON Schema for operation payloads, `None` to accept any payload
+    pub payload_schema: Option<serde_json::Value>,
+    /// When the schema was last registered
+    pub registered_at: DateTime<Utc>,
+}
+
+/// A remote operation rejected by validation and kept out of the oplog.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuarantinedOp {
+    /// The operation as received
+    pub entry: OplogEntry,
+    /// Why validation failed
+    pub reason: String,
+    /// When it was quarantined
+    pub quarantined_at: DateTime<Utc>,
+}
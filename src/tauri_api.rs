@@ -8,6 +8,7 @@
 
 #[cfg(feature = "tauri-api")]
 mod tauri_commands {
+    use crate::logic::live_query::{ChangeSet, LiveQuery};
     use crate::logic::sync_manager::SyncManager;
     use crate::logic::{login_user, register_user};
     use crate::models::User;
@@ -15,7 +16,7 @@ mod tauri_commands {
     use rusqlite::Connection;
     use std::sync::Arc;
     use std::sync::Mutex;
-    use tauri::State;
+    use tauri::{AppHandle, Emitter, State};
     use uuid::Uuid;
 
     /// Database connection wrapper for Tauri state management
@@ -142,6 +143,52 @@ mod tauri_commands {
             .sync_pending_changes()
             .map_err(|e| e.to_string())
     }
+
+    // ============================================================================
+    // Live Queries
+    // ============================================================================
+
+    /// Subscribe to changes of a collection or synced table
+    ///
+    /// Change sets are emitted as `live-query-changes` events after local writes
+    /// and merges.
+    ///
+    /// # Arguments
+    /// * `query` - Table and optional filter, e.g. `{"table": "todos", "filter": {"eq": {"path": "/done", "value": false}}}`
+    ///
+    /// Returns: (subscription_id, ids of the rows matching now)
+    #[tauri::command]
+    pub fn ahenk_subscribe_live_query(
+        query: LiveQuery,
+        app: AppHandle,
+        conn: State<DbConnection>,
+        sync_manager_state: tauri::State<Arc<Mutex<SyncManager>>>,
+    ) -> Result<(u64, Vec<String>), String> {
+        let live_queries = sync_manager_state
+            .inner()
+            .lock()
+            .map_err(|e| e.to_string())?
+            .live_queries();
+        let db = conn.0.lock().map_err(|e| e.to_string())?;
+        live_queries.subscribe_with(&db, query, move |changes: ChangeSet| {
+            let _ = app.emit("live-query-changes", changes);
+        })
+    }
+
+    /// Stop a live query subscription
+    ///
+    /// Returns: whether the subscription existed
+    #[tauri::command]
+    pub fn ahenk_unsubscribe_live_query(
+        subscription_id: u64,
+        sync_manager_state: tauri::State<Arc<Mutex<SyncManager>>>,
+    ) -> Result<bool, String> {
+        let sync_manager = sync_manager_state
+            .inner()
+            .lock()
+            .map_err(|e| e.to_string())?;
+        Ok(sync_manager.live_queries().unsubscribe(subscription_id))
+    }
 }
 
 #[cfg(feature = "tauri-api")]
@@ -0,0 +1,600 @@
+//! Test support for simulating a user's devices syncing with each other.
+//!
+//! [`Simulation`] runs N [`SyncManager`]s for one user over libp2p's in-memory
+//! transport, so no sockets or mDNS are involved. Every device's persisted
+//! [`HybridLogicalClock`](crdt::HybridLogicalClock) reads a shared
+//! [`ManualClock`], which only moves when the test advances it. The network can
+//! be partitioned and healed, and [`Simulation::converge`] waits until every
+//! device holds the same oplog and the same merged documents.
+//!
+//! Each device keeps `sim_records` as a document collection: its own writes and
+//! the operations it merges update the stored documents one by one, the way an
+//! app's collections are kept, so devices that applied the same operations
+//! differently do not count as converged. Devices keep their databases in a
+//! temporary directory that is removed with the simulation.
+//!
+//! Available in unit tests and with the `test-support` feature. Swarms run on the
+//! tokio executor, so simulations must be driven from within a tokio runtime.
+//!
+//! ```rust,no_run
+//! # async fn example() -> Result<(), String> {
+//! use ahenk::testing::Simulation;
+//! use std::time::Duration;
+//!
+//! let mut sim = Simulation::new(3).await?;
+//! sim.connect_all()?;
+//! sim.write(0, "a", &serde_json::json!("first"))?;
+//!
+//! sim.partition(&[2]);
+//! sim.write(2, "b", &serde_json::json!("offline"))?;
+//! sim.heal()?;
+//!
+//! sim.converge(Duration::from_secs(10)).await?;
+//! sim.assert_converged();
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::crdt::{self, Clock};
+use crate::db::connection::Database;
+use crate::db::{documents, operations};
+use crate::logic::build_oplog_entry_with;
+use crate::logic::sync::{generate_device_id, P2PConfig};
+use crate::logic::sync_manager::SyncManager;
+use crate::models::OplogEntry;
+use chrono::{DateTime, TimeZone, Utc};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use rusqlite::Connection;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Collection every simulated device writes to
+pub const SIM_TABLE: &str = "sim_records";
+
+/// How long each device processes events between convergence checks
+const STEP: Duration = Duration::from_millis(20);
+
+/// A clock that only moves when told to; clones share the same time
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    micros: Arc<AtomicI64>,
+}
+
+impl ManualClock {
+    /// Create a clock reading `start`
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            micros: Arc::new(AtomicI64::new(start.timestamp_micros())),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        self.micros
+            .fetch_add(by.as_micros() as i64, Ordering::SeqCst);
+    }
+
+    /// Set the clock to an arbitrary time, including one in the past
+    pub fn set(&self, time: DateTime<Utc>) {
+        self.micros.store(time.timestamp_micros(), Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    /// A clock starting at 2024-01-01T00:00:00Z
+    fn default() -> Self {
+        Self::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        let micros = self.micros.load(Ordering::SeqCst);
+        DateTime::from_timestamp_micros(micros).expect("manual clock within chrono's range")
+    }
+}
+
+/// An operation on `table` stamped `timestamp` by a new random device, for
+/// tests that build oplog entries by hand
+pub fn oplog_entry(timestamp: i64, table: &str, op_type: &str, data: Value) -> OplogEntry {
+    OplogEntry {
+        id: Uuid::new_v4(),
+        device_id: Uuid::new_v4(),
+        timestamp,
+        table: table.to_string(),
+        op_type: op_type.to_string(),
+        data,
+        schema_version: 0,
+    }
+}
+
+/// One simulated device
+pub struct SimulatedDevice {
+    /// The device's sync manager, listening on a `/memory` address
+    pub manager: SyncManager,
+    /// Device ID stamped on the device's oplog entries
+    pub device_id: Uuid,
+    /// libp2p peer ID of the device
+    pub peer_id: PeerId,
+    /// Address other devices dial, including `/p2p/<peer_id>`
+    pub address: Multiaddr,
+    /// A connection to the device's database, like the one an app on it would use
+    pub conn: Arc<Mutex<Connection>>,
+}
+
+/// A simulated network of one user's devices
+pub struct Simulation {
+    user_id: Uuid,
+    clock: ManualClock,
+    devices: Vec<SimulatedDevice>,
+    /// Device index pairs that currently block each other
+    blocked: HashSet<(usize, usize)>,
+    /// Directory holding the devices' databases
+    dir: PathBuf,
+}
+
+impl Simulation {
+    /// Start `devices` devices with the default [`ManualClock`] and simulation config
+    pub async fn new(devices: usize) -> Result<Self, String> {
+        Self::with_config(devices, ManualClock::default(), Self::default_config()).await
+    }
+
+    /// Config used by [`Simulation::new`]: memory transport only, no discovery
+    /// services, and short heartbeat and redial intervals
+    pub fn default_config() -> P2PConfig {
+        P2PConfig {
+            enable_mdns: false,
+            enable_relay: false,
+            enable_kademlia: false,
+            enable_quic: false,
+            memory_transport: true,
+            heartbeat_interval: Duration::from_millis(100),
+            shutdown_grace_period: Duration::from_millis(100),
+            reconnect_initial_backoff: Duration::from_millis(50),
+            reconnect_max_backoff: Duration::from_millis(500),
+            ..P2PConfig::default()
+        }
+    }
+
+    /// Start `devices` devices sharing `clock`, each listening on a fresh `/memory` address
+    pub async fn with_config(
+        devices: usize,
+        clock: ManualClock,
+        config: P2PConfig,
+    ) -> Result<Self, String> {
+        let user_id = Uuid::new_v4();
+        let dir = std::env::temp_dir().join(format!("ahenk-sim-{}", user_id));
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let mut sim = Self {
+            user_id,
+            clock,
+            devices: Vec::with_capacity(devices),
+            blocked: HashSet::new(),
+            dir,
+        };
+
+        for index in 0..devices {
+            let path = sim.dir.join(format!("device-{}.db", index));
+            let path = path.to_string_lossy();
+            let conn = operations::initialize_database(&path).map_err(|e| e.to_string())?;
+            documents::register_collection(&conn, SIM_TABLE).map_err(|e| e.to_string())?;
+            let conn = Arc::new(Mutex::new(conn));
+            let database = Database::open(&path).map_err(|e| e.to_string())?;
+
+            let (peer_id, keypair) = generate_device_id();
+            let device_id = Uuid::new_v4();
+            let mut manager =
+                SyncManager::new(keypair, user_id, device_id, database, config.clone())
+                    .map_err(|e| format!("Failed to create sync manager: {}", e))?;
+            manager.set_clock(Arc::new(sim.clock.clone()));
+            manager.listen(0).map_err(|e| e.to_string())?;
+
+            sim.devices.push(SimulatedDevice {
+                manager,
+                device_id,
+                peer_id,
+                address: Multiaddr::empty(),
+                conn,
+            });
+        }
+
+        // Memory listeners report their address once the swarms are polled
+        sim.run_until(Duration::from_secs(5), |sim| {
+            sim.devices
+                .iter()
+                .all(|d| !d.manager.get_listen_addresses().is_empty())
+        })
+        .await?;
+        for device in &mut sim.devices {
+            let listen_address: Multiaddr = device.manager.get_listen_addresses()[0]
+                .parse()
+                .map_err(|e| format!("Invalid listen address: {}", e))?;
+            device.address = listen_address.with(Protocol::P2p(device.peer_id));
+        }
+
+        Ok(sim)
+    }
+
+    /// User all simulated devices belong to
+    pub fn user_id(&self) -> Uuid {
+        self.user_id
+    }
+
+    /// The clock every device's HLC reads
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// Number of simulated devices
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether the simulation has no devices
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// A simulated device by index
+    pub fn device(&self, index: usize) -> &SimulatedDevice {
+        &self.devices[index]
+    }
+
+    /// A simulated device by index, e.g. to drive its sync manager directly
+    pub fn device_mut(&mut self, index: usize) -> &mut SimulatedDevice {
+        &mut self.devices[index]
+    }
+
+    /// Dial every other device from each device that does not block it
+    pub fn connect_all(&mut self) -> Result<(), String> {
+        for from in 0..self.devices.len() {
+            for to in (from + 1)..self.devices.len() {
+                if !self.blocked.contains(&(from, to)) {
+                    self.dial(from, to)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Dial one device from another
+    pub fn dial(&mut self, from: usize, to: usize) -> Result<(), String> {
+        let address = self.devices[to].address.to_string();
+        self.devices[from]
+            .manager
+            .connect_to_network(&[address], &[])
+    }
+
+    /// Split `group` off from the remaining devices; connections between the two
+    /// sides are closed and refused until [`Simulation::heal`]
+    pub fn partition(&mut self, group: &[usize]) {
+        for a in 0..self.devices.len() {
+            for b in 0..self.devices.len() {
+                if group.contains(&a) && !group.contains(&b) {
+                    let peer_a = self.devices[a].peer_id;
+                    let peer_b = self.devices[b].peer_id;
+                    self.devices[a].manager.block_peer(peer_b);
+                    self.devices[b].manager.block_peer(peer_a);
+                    self.blocked.insert((a.min(b), a.max(b)));
+                }
+            }
+        }
+    }
+
+    /// Remove all partitions and reconnect the devices that were split apart
+    pub fn heal(&mut self) -> Result<(), String> {
+        let blocked: Vec<(usize, usize)> = self.blocked.drain().collect();
+        for (a, b) in blocked {
+            let peer_a = self.devices[a].peer_id;
+            let peer_b = self.devices[b].peer_id;
+            self.devices[a].manager.unblock_peer(peer_b);
+            self.devices[b].manager.unblock_peer(peer_a);
+            self.dial(a, b)?;
+        }
+        Ok(())
+    }
+
+    /// Set a record on a device and queue the change for sync
+    pub fn write(&mut self, device: usize, id: &str, value: &Value) -> Result<OplogEntry, String> {
+        self.record_local_op(
+            device,
+            crdt::OP_UPDATE,
+            &serde_json::json!({"id": id, "value": value}),
+        )
+    }
+
+    /// Delete a record on a device and queue the change for sync
+    pub fn delete(&mut self, device: usize, id: &str) -> Result<OplogEntry, String> {
+        self.record_local_op(device, crdt::OP_DELETE, &serde_json::json!({ "id": id }))
+    }
+
+    fn record_local_op(
+        &mut self,
+        device: usize,
+        op_type: &str,
+        data: &Value,
+    ) -> Result<OplogEntry, String> {
+        let device = &mut self.devices[device];
+
+        let entry = {
+            let mut conn = device.conn.lock().map_err(|e| e.to_string())?;
+            let tx = conn.transaction().map_err(|e| e.to_string())?;
+            let entry = build_oplog_entry_with(
+                &tx,
+                &self.clock,
+                device.device_id,
+                SIM_TABLE,
+                op_type,
+                data,
+            )?;
+            crdt::local_apply_in(&tx, std::slice::from_ref(&entry)).map_err(|e| e.to_string())?;
+            documents::apply_op(&tx, &entry)
+                .and_then(|_| tx.commit())
+                .map_err(|e| e.to_string())?;
+            entry
+        };
+
+        device.manager.add_pending_change(entry.clone());
+        // Without subscribed peers the change stays queued until one joins
+        if let Err(e) = device.manager.sync_pending_changes() {
+            eprintln!("Change queued on {}: {}", device.device_id, e);
+        }
+
+        Ok(entry)
+    }
+
+    /// Drive every device's event loop concurrently for `duration`
+    pub async fn run_for(&mut self, duration: Duration) {
+        let drivers = self.devices.iter_mut().map(|device| async move {
+            let _ = async_std::future::timeout(duration, async {
+                loop {
+                    if let Err(e) = device.manager.process_event().await {
+                        eprintln!("Error processing event on {}: {}", device.device_id, e);
+                    }
+                }
+            })
+            .await;
+        });
+        futures::future::join_all(drivers).await;
+    }
+
+    /// Drive the network until `done` holds, failing after `timeout`
+    pub async fn run_until<F>(&mut self, timeout: Duration, mut done: F) -> Result<(), String>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        while !done(self) {
+            if Instant::now() >= deadline {
+                return Err(format!("Condition not reached within {:?}", timeout));
+            }
+            self.run_for(STEP).await;
+        }
+        Ok(())
+    }
+
+    /// Drive the network until every device that is not partitioned off is connected
+    /// to every other such device
+    pub async fn wait_connected(&mut self, timeout: Duration) -> Result<(), String> {
+        self.run_until(timeout, |sim| {
+            (0..sim.len()).all(|a| {
+                let connected = sim.devices[a].manager.get_connected_peers();
+                (0..sim.len())
+                    .filter(|&b| b != a && !sim.blocked.contains(&(a.min(b), a.max(b))))
+                    .all(|b| connected.contains(&sim.devices[b].peer_id.to_string()))
+            })
+        })
+        .await
+    }
+
+    /// Drive the network until all devices have converged
+    pub async fn converge(&mut self, timeout: Duration) -> Result<(), String> {
+        self.run_until(timeout, |sim| sim.is_converged()).await
+    }
+
+    /// A device's oplog in HLC order, ties broken by operation ID
+    pub fn oplog(&self, device: usize) -> Vec<OplogEntry> {
+        let conn = self.devices[device].conn.lock().unwrap();
+        let mut entries = operations::get_oplog_entries_since(&conn, i64::MIN).unwrap();
+        entries.sort_by_key(|e| (e.timestamp, e.id));
+        entries
+    }
+
+    /// A device's live documents in [`SIM_TABLE`], as its writes and merges left them
+    pub fn materialized(&self, device: usize) -> BTreeMap<String, Value> {
+        let conn = self.devices[device].conn.lock().unwrap();
+        documents::list_documents(&conn, SIM_TABLE)
+            .unwrap()
+            .into_iter()
+            .filter_map(|doc| doc.data.map(|data| (doc.id, data)))
+            .collect()
+    }
+
+    /// Whether all devices hold the same oplog and the same documents
+    pub fn is_converged(&self) -> bool {
+        let Some(first) = (!self.devices.is_empty()).then(|| self.oplog_ids(0)) else {
+            return true;
+        };
+        (1..self.devices.len()).all(|d| self.oplog_ids(d) == first)
+            && (1..self.devices.len()).all(|d| self.materialized(d) == self.materialized(0))
+    }
+
+    /// Panic with the differing state unless all devices have converged
+    pub fn assert_converged(&self) {
+        let oplog = self.oplog_ids(0);
+        let table = self.materialized(0);
+        for device in 1..self.devices.len() {
+            assert_eq!(
+                self.oplog_ids(device),
+                oplog,
+                "oplog of device {} differs from device 0",
+                device
+            );
+            assert_eq!(
+                self.materialized(device),
+                table,
+                "{} on device {} differs from device 0",
+                SIM_TABLE,
+                device
+            );
+        }
+    }
+
+    fn oplog_ids(&self, device: usize) -> Vec<(i64, Uuid)> {
+        self.oplog(device)
+            .iter()
+            .map(|e| (e.timestamp, e.id))
+            .collect()
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::HybridLogicalClock;
+    use serde_json::json;
+
+    #[test]
+    fn test_manual_clock_drives_hlc() {
+        let clock = ManualClock::default();
+        let mut hlc = HybridLogicalClock::now_with(&clock);
+        let start = hlc;
+
+        // Time only moves when the test moves it
+        assert_eq!(HybridLogicalClock::now_with(&clock), start);
+
+        clock.advance(Duration::from_millis(5));
+        hlc.increment_with(&clock, None);
+        assert_eq!(hlc.physical_time(), start.physical_time() + 5);
+        assert_eq!(hlc.counter(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_devices_converge() {
+        let mut sim = Simulation::new(3).await.unwrap();
+        sim.connect_all().unwrap();
+        sim.wait_connected(Duration::from_secs(10)).await.unwrap();
+
+        sim.write(0, "a", &json!("from 0")).unwrap();
+        sim.clock().advance(Duration::from_millis(1));
+        sim.write(1, "b", &json!("from 1")).unwrap();
+        sim.clock().advance(Duration::from_millis(1));
+        sim.write(2, "a", &json!("from 2")).unwrap();
+
+        sim.converge(Duration::from_secs(10)).await.unwrap();
+        sim.assert_converged();
+        assert_eq!(sim.oplog(0).len(), 3);
+
+        let table = sim.materialized(1);
+        assert_eq!(table["a"], json!("from 2"));
+        assert_eq!(table["b"], json!("from 1"));
+
+        // The same oplog with a document that was applied differently is not converged
+        sim.device(2)
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE collection_documents SET data = '\"stale\"' WHERE collection = ?1 AND id = 'a'",
+                [SIM_TABLE],
+            )
+            .unwrap();
+        assert!(!sim.is_converged());
+    }
+
+    #[tokio::test]
+    async fn test_captured_table_writes_sync() {
+        let mut sim = Simulation::new(2).await.unwrap();
+        for device in 0..sim.len() {
+            let conn = sim.device(device).conn.lock().unwrap();
+            conn.execute_batch("CREATE TABLE notes (id TEXT PRIMARY KEY, title TEXT)")
+                .unwrap();
+            crate::db::capture::register_table(&conn, "notes", "id").unwrap();
+        }
+        sim.connect_all().unwrap();
+        sim.wait_connected(Duration::from_secs(10)).await.unwrap();
+
+        // Plain SQL writes are captured and picked up by the sync manager's timer
+        sim.device(0)
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO notes (id, title) VALUES ('n1', 'Hello');
+                 UPDATE notes SET title = 'Hello, world' WHERE id = 'n1';",
+            )
+            .unwrap();
+
+        let title = |sim: &Simulation, device: usize| -> Option<String> {
+            sim.device(device)
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("SELECT title FROM notes WHERE id = 'n1'", [], |row| {
+                    row.get(0)
+                })
+                .ok()
+        };
+        sim.run_until(Duration::from_secs(10), |sim| {
+            title(sim, 1).as_deref() == Some("Hello, world")
+        })
+        .await
+        .unwrap();
+        sim.converge(Duration::from_secs(10)).await.unwrap();
+        assert_eq!(sim.oplog(1).len(), 2);
+
+        // Applying the remote writes did not capture them again on device 1
+        sim.run_for(Duration::from_millis(300)).await;
+        assert_eq!(sim.oplog(0).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_partition_and_heal() {
+        let mut sim = Simulation::new(3).await.unwrap();
+        sim.connect_all().unwrap();
+        sim.wait_connected(Duration::from_secs(10)).await.unwrap();
+
+        sim.write(0, "shared", &json!("before")).unwrap();
+        sim.converge(Duration::from_secs(10)).await.unwrap();
+
+        // Both sides keep writing while split
+        sim.partition(&[2]);
+        sim.run_until(Duration::from_secs(10), |sim| {
+            sim.device(2).manager.get_connected_peers().is_empty()
+        })
+        .await
+        .unwrap();
+
+        sim.clock().advance(Duration::from_millis(1));
+        sim.write(0, "shared", &json!("majority")).unwrap();
+        sim.write(1, "left", &json!(1)).unwrap();
+        sim.clock().advance(Duration::from_millis(1));
+        sim.write(2, "shared", &json!("minority")).unwrap();
+        sim.delete(2, "left").unwrap();
+
+        sim.run_for(Duration::from_millis(300)).await;
+        assert!(!sim.is_converged());
+        assert_eq!(sim.oplog(2).len(), 3);
+
+        sim.heal().unwrap();
+        sim.converge(Duration::from_secs(15)).await.unwrap();
+        sim.assert_converged();
+
+        // The latest write wins everywhere; the delete came after the insert
+        let table = sim.materialized(0);
+        assert_eq!(table["shared"], json!("minority"));
+        assert!(!table.contains_key("left"));
+    }
+}
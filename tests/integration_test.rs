@@ -7,7 +7,7 @@
 //! - Peer (P2P peer tracking)
 
 use ahenk::db::operations;
-use ahenk::models::{Device, OplogEntry, Peer, User};
+use ahenk::models::{Device, NetworkStatus, OplogEntry, OplogFilter, Peer, User};
 use chrono::Utc;
 use uuid::Uuid;
 
@@ -153,6 +153,7 @@ fn test_oplog_and_peer_crud_operations() {
             "content": "Sample app data",
             "record_id": Uuid::new_v4().to_string(),
         }),
+        schema_version: 0,
     };
     operations::create_oplog_entry(&conn, &oplog_entry).expect("Failed to create oplog entry");
 
@@ -277,6 +278,7 @@ fn test_oplog_ordering_by_timestamp() {
             table: "test_table".to_string(),
             op_type: "create".to_string(),
             data: serde_json::json!({"index": i}),
+            schema_version: 0,
         };
         operations::create_oplog_entry(&conn, &entry).expect("Failed to create oplog entry");
     }
@@ -292,3 +294,180 @@ fn test_oplog_ordering_by_timestamp() {
         assert!(entries[i].timestamp < entries[i + 1].timestamp);
     }
 }
+
+#[test]
+fn test_oplog_pagination_and_filters() {
+    let conn =
+        operations::initialize_database(":memory:").expect("Failed to create in-memory database");
+    let device_a = Uuid::new_v4();
+    let device_b = Uuid::new_v4();
+
+    // Ten entries alternating between two devices and two tables, with
+    // timestamp ties so pages have to break them by entry ID
+    for i in 0..10 {
+        let entry = OplogEntry {
+            id: Uuid::new_v4(),
+            device_id: if i % 2 == 0 { device_a } else { device_b },
+            timestamp: 100 + i / 2,
+            table: if i < 5 { "notes" } else { "tasks" }.to_string(),
+            op_type: if i % 3 == 0 { "delete" } else { "insert" }.to_string(),
+            data: serde_json::json!({"id": i.to_string()}),
+            schema_version: 0,
+        };
+        operations::create_oplog_entry(&conn, &entry).expect("Failed to create oplog entry");
+    }
+    let all = operations::get_oplog_entries_since(&conn, 0).expect("Failed to get entries");
+
+    // Pages resume after their cursor and together cover the whole oplog in order
+    let filter = OplogFilter::default();
+    let mut paged = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = operations::get_oplog_page(&conn, 0, &filter, cursor, 3)
+            .expect("Failed to get oplog page");
+        assert!(page.entries.len() <= 3);
+        paged.extend(page.entries);
+        cursor = page.next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let ids = |entries: &[OplogEntry]| entries.iter().map(|e| e.id).collect::<Vec<_>>();
+    assert_eq!(ids(&paged), ids(&all));
+    for pair in paged.windows(2) {
+        assert!((pair[0].timestamp, pair[0].id) < (pair[1].timestamp, pair[1].id));
+    }
+
+    // The page iterator yields the same entries
+    let pages = operations::oplog_pages(&conn, 0, OplogFilter::default(), 4)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to page through oplog");
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![4, 4, 2]
+    );
+
+    // Filters narrow every read variant
+    let filter = OplogFilter {
+        table: Some("notes".to_string()),
+        device_id: Some(device_a),
+        op_type: Some("insert".to_string()),
+    };
+    let expected: Vec<Uuid> = all
+        .iter()
+        .filter(|e| e.table == "notes" && e.device_id == device_a && e.op_type == "insert")
+        .map(|e| e.id)
+        .collect();
+    let page = operations::get_oplog_page(&conn, 0, &filter, None, 100)
+        .expect("Failed to get filtered page");
+    assert_eq!(ids(&page.entries), expected);
+    assert!(page.next.is_none());
+
+    let mut streamed = Vec::new();
+    let visited = operations::for_each_oplog_entry(&conn, 0, &filter, |entry| {
+        streamed.push(entry.id);
+        Ok(())
+    })
+    .expect("Failed to stream oplog");
+    assert_eq!(visited, expected.len());
+    assert_eq!(streamed, expected);
+
+    // `since` still bounds every read
+    let later = operations::get_oplog_page(&conn, 102, &OplogFilter::default(), None, 100)
+        .expect("Failed to get later page");
+    assert!(later.entries.iter().all(|e| e.timestamp > 102));
+    assert_eq!(later.entries.len(), 4);
+}
+
+#[test]
+fn test_peer_address_book_tracks_dial_history() {
+    let conn =
+        operations::initialize_database(":memory:").expect("Failed to create in-memory database");
+    let peer_id = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    let device_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    operations::upsert_peer_address(&conn, peer_id, "/ip4/10.0.0.1/tcp/4001", None, now)
+        .expect("Failed to record address");
+    operations::upsert_peer_address(
+        &conn,
+        peer_id,
+        "/ip4/192.168.1.7/tcp/4001",
+        Some(device_id),
+        now,
+    )
+    .expect("Failed to record address");
+
+    operations::record_peer_address_failure(&conn, peer_id, "/ip4/10.0.0.1/tcp/4001", now)
+        .expect("Failed to record failure");
+    operations::record_peer_address_success(&conn, peer_id, "/ip4/192.168.1.7/tcp/4001", now)
+        .expect("Failed to record success");
+
+    // Re-observing an address without a device keeps the known device
+    operations::upsert_peer_address(&conn, peer_id, "/ip4/192.168.1.7/tcp/4001", None, now)
+        .expect("Failed to record address");
+
+    let addresses =
+        operations::get_peer_addresses(&conn, peer_id).expect("Failed to get addresses");
+    assert_eq!(addresses.len(), 2);
+
+    // The address that worked is tried first
+    assert_eq!(addresses[0].multiaddr, "/ip4/192.168.1.7/tcp/4001");
+    assert_eq!(addresses[0].success_count, 1);
+    assert_eq!(addresses[0].device_id, Some(device_id));
+    assert!(addresses[0].last_success.is_some());
+
+    assert_eq!(addresses[1].failure_count, 1);
+    assert!(addresses[1].last_failure.is_some());
+}
+
+#[test]
+fn test_peer_network_info_and_status() {
+    let conn =
+        operations::initialize_database(":memory:").expect("Failed to create in-memory database");
+    let peer_id = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    let device_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    // A ping result can arrive before identify
+    operations::record_peer_rtt(&conn, peer_id, 42, now).expect("Failed to record RTT");
+    operations::upsert_peer_identity(&conn, peer_id, None, "ahenk/0.1.0", "/ahenk/1.0.0", now)
+        .expect("Failed to record identity");
+
+    let info = operations::get_peer_network_info(&conn, peer_id)
+        .expect("Failed to get network info")
+        .expect("Network info should exist");
+    assert_eq!(info.rtt_ms, Some(42));
+    assert_eq!(info.agent_version.as_deref(), Some("ahenk/0.1.0"));
+    assert_eq!(info.device_id, None);
+
+    // The device is resolved through the address book
+    operations::upsert_peer_address(
+        &conn,
+        peer_id,
+        "/ip4/10.0.0.1/tcp/4001",
+        Some(device_id),
+        now,
+    )
+    .expect("Failed to record address");
+    let by_device = operations::get_peer_network_info_by_device(&conn, device_id)
+        .expect("Failed to get network info by device");
+    assert_eq!(by_device.len(), 1);
+    assert_eq!(by_device[0].peer_id, peer_id);
+
+    assert!(operations::get_network_status(&conn)
+        .expect("Failed to get network status")
+        .is_none());
+    let status = NetworkStatus {
+        nat_status: "public".to_string(),
+        public_address: Some("/ip4/203.0.113.7/tcp/4001".to_string()),
+        observed_addresses: vec!["/ip4/203.0.113.7/tcp/4001".to_string()],
+        updated_at: now,
+    };
+    operations::save_network_status(&conn, &status).expect("Failed to save network status");
+    let saved = operations::get_network_status(&conn)
+        .expect("Failed to get network status")
+        .expect("Network status should exist");
+    assert_eq!(saved.nat_status, "public");
+    assert_eq!(saved.observed_addresses, status.observed_addresses);
+}
@@ -239,7 +239,7 @@ fn test_generate_device_id() {
 
 #[test]
 fn test_sync_message_encode_decode() {
-    use ahenk::logic::sync::{SyncMessage, decode_sync_message, encode_sync_message};
+    use ahenk::logic::sync::{decode_sync_message, encode_sync_message, SyncMessage};
 
     let user_id = Uuid::new_v4();
     let device_id = Uuid::new_v4();
@@ -249,6 +249,7 @@ fn test_sync_message_encode_decode() {
         user_id,
         device_id,
         peer_id: "test_peer_id".to_string(),
+        addresses: vec!["/ip4/192.168.1.100/tcp/4001".to_string()],
     };
 
     let encoded = encode_sync_message(&announce_msg).unwrap();
@@ -259,10 +260,12 @@ fn test_sync_message_encode_decode() {
             user_id: uid,
             device_id: did,
             peer_id,
+            addresses,
         } => {
             assert_eq!(uid, user_id);
             assert_eq!(did, device_id);
             assert_eq!(peer_id, "test_peer_id");
+            assert_eq!(addresses, vec!["/ip4/192.168.1.100/tcp/4001".to_string()]);
         }
         _ => panic!("Expected Announce message"),
     }
@@ -272,6 +275,7 @@ fn test_sync_message_encode_decode() {
     let request_msg = SyncMessage::RequestSync {
         user_id,
         since_timestamp: timestamp,
+        request_id: None,
     };
 
     let encoded = encode_sync_message(&request_msg).unwrap();
@@ -281,6 +285,7 @@ fn test_sync_message_encode_decode() {
         SyncMessage::RequestSync {
             user_id: uid,
             since_timestamp: ts,
+            ..
         } => {
             assert_eq!(uid, user_id);
             // Allow for small timestamp differences due to serialization
@@ -319,6 +324,47 @@ fn test_update_peer_info() {
     assert!(peers[0].last_sync_time.is_some());
 }
 
+#[test]
+fn test_announce_refreshes_peer_and_address_book() {
+    use ahenk::logic::sync::{handle_sync_message, SyncMessage};
+
+    let (mut conn, user_id, device_id) = setup_db_with_user_and_device();
+    let peer_device_id = Uuid::new_v4();
+    let peer_id = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN".to_string();
+
+    let announce = |addresses: Vec<&str>| SyncMessage::Announce {
+        user_id,
+        device_id: peer_device_id,
+        peer_id: peer_id.clone(),
+        addresses: addresses.into_iter().map(String::from).collect(),
+    };
+
+    handle_sync_message(
+        &mut conn,
+        device_id,
+        announce(vec!["/ip4/192.168.1.10/tcp/4001"]),
+        |_| Ok(()),
+    )
+    .unwrap();
+    handle_sync_message(
+        &mut conn,
+        device_id,
+        announce(vec!["/ip4/127.0.0.1/tcp/4001", "/ip4/10.0.0.5/tcp/4001"]),
+        |_| Ok(()),
+    )
+    .unwrap();
+
+    // Existing peers get their last known IP refreshed instead of being left stale
+    let peers = operations::get_peers_by_user_id(&conn, user_id).unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].last_known_ip, Some("10.0.0.5".to_string()));
+
+    // Every announced address is kept in the address book
+    let addresses = operations::get_peer_addresses_by_device(&conn, peer_device_id).unwrap();
+    assert_eq!(addresses.len(), 3);
+    assert!(addresses.iter().all(|a| a.peer_id == peer_id));
+}
+
 // ============================================================================
 // P2P CRDT Sync Tests (Ignored - Need Refactoring for Generic Data)
 //
@@ -6,7 +6,10 @@
 //! - Schema correctness
 //! - Data preservation across migrations
 
-use ahenk::db::migrations::{apply_migrations, get_current_version, get_migration_history};
+use ahenk::crdt::HybridLogicalClock;
+use ahenk::db::migrations::{
+    apply_migrations, get_current_version, get_migration_history, migrate_up, CORE_NAMESPACE,
+};
 use ahenk::db::operations::{create_user, get_user};
 use ahenk::models::User;
 use chrono::Utc;
@@ -23,7 +26,7 @@ fn test_fresh_database_migration() {
 
     // Verify schema version
     let version = get_current_version(&conn).unwrap();
-    assert_eq!(version, 1, "Fresh database should be at version 1");
+    assert_eq!(version, 16, "Fresh database should be at version 16");
 
     // Verify core tables exist by checking sqlite_master
     let table_count: i32 = conn
@@ -34,8 +37,12 @@ fn test_fresh_database_migration() {
         )
         .unwrap();
 
-    // We should have: users, devices, oplog, peers, schema_version = 5 tables
-    assert_eq!(table_count, 5, "Should have 5 tables in core sync schema");
+    // We should have: users, devices, oplog, peers, sync_outbox, peer_addresses,
+    // peer_network_info, local_network_status, hlc_state, sync_tables, sync_capture,
+    // sync_capture_state, collections, collection_documents, change_feed,
+    // entity_snapshots, table_schemas, quarantined_ops, held_ops, oplog_compaction,
+    // device_sync_acks, schema_version = 22 tables
+    assert_eq!(table_count, 22, "Should have 22 tables in core sync schema");
 }
 
 #[test]
@@ -86,7 +93,7 @@ fn test_idempotent_migrations() {
             |row| row.get(0),
         )
         .unwrap();
-    assert_eq!(table_count, 5);
+    assert_eq!(table_count, 22);
 }
 
 #[test]
@@ -132,6 +139,8 @@ fn test_schema_version_table_structure() {
     assert!(columns.contains(&"version".to_string()));
     assert!(columns.contains(&"applied_at".to_string()));
     assert!(columns.contains(&"description".to_string()));
+    assert!(columns.contains(&"checksum".to_string()));
+    assert!(columns.contains(&"namespace".to_string()));
 }
 
 #[test]
@@ -179,11 +188,28 @@ fn test_all_required_core_tables_exist() {
     apply_migrations(&conn).unwrap();
 
     let required_tables = vec![
-        "users",          // User authentication
-        "devices",        // Device management
-        "oplog",          // CRDT operation log
-        "peers",          // P2P peer tracking
-        "schema_version", // Migration tracking
+        "users",                // User authentication
+        "devices",              // Device management
+        "oplog",                // CRDT operation log
+        "peers",                // P2P peer tracking
+        "sync_outbox",          // Checkpointed pending changes
+        "peer_addresses",       // Peer address book
+        "peer_network_info",    // Identify/ping results
+        "local_network_status", // NAT status and observed addresses
+        "hlc_state",            // Persisted hybrid logical clock
+        "sync_tables",          // App tables registered for change capture
+        "sync_capture",         // Captured changes not yet in the oplog
+        "sync_capture_state",   // Capture suppression flag
+        "collections",          // Synced document collections
+        "collection_documents", // Merged collection state
+        "change_feed",          // Row changes read by live queries
+        "entity_snapshots",     // Bounded replay of patch operations
+        "table_schemas",        // Declared payload schemas
+        "quarantined_ops",      // Remote operations rejected by validation
+        "held_ops",             // Remote operations with a newer payload version
+        "oplog_compaction",     // Compaction horizon
+        "device_sync_acks",     // How far other devices have synced
+        "schema_version",       // Migration tracking
     ];
 
     for table in required_tables {
@@ -247,6 +273,43 @@ fn test_database_upgrade_preserves_data() {
     assert_eq!(device_count, 1, "Device should still exist after upgrade");
 }
 
+#[test]
+fn test_upgrade_from_legacy_timestamps_keeps_the_clock_near_now() {
+    // Before the clock was persisted, oplog entries were stamped with
+    // microseconds shifted into the 48-bit physical field, overflowing it
+    let conn = Connection::open_in_memory().unwrap();
+    migrate_up(&conn, CORE_NAMESPACE, Some(1)).unwrap();
+    let legacy_micros = chrono::DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+        .unwrap()
+        .timestamp_micros();
+    let legacy_stamp = ((legacy_micros as u64) << 16) as i64;
+    conn.execute(
+        "INSERT INTO oplog (id, device_id, timestamp, table_name, op_type, data)
+         VALUES (?1, ?2, ?3, 'notes', 'insert', '{}')",
+        rusqlite::params![
+            Uuid::new_v4().to_string(),
+            Uuid::new_v4().to_string(),
+            legacy_stamp
+        ],
+    )
+    .unwrap();
+    // Read as milliseconds, the legacy stamp lies centuries ahead
+    let now_ms = Utc::now().timestamp_millis() as u64;
+    assert!(HybridLogicalClock::from_timestamp(legacy_stamp).physical_time() > now_ms * 2);
+
+    apply_migrations(&conn).unwrap();
+
+    let next = ahenk::crdt::next_timestamp(&conn).unwrap();
+    let drift = next
+        .physical_time()
+        .abs_diff(Utc::now().timestamp_millis() as u64);
+    assert!(
+        drift < 5_000,
+        "next timestamp is {}ms off the current time",
+        drift
+    );
+}
+
 #[test]
 fn test_users_table_structure() {
     // Verify users table has correct columns